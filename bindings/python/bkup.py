@@ -0,0 +1,168 @@
+"""Python bindings of the bkup engine, built on top of its C API.
+
+The shared library must be built with the `ffi` feature:
+
+    cargo rustc --release --features ffi --lib --crate-type cdylib
+
+and is looked up in the BKUP_LIBRARY environment variable, falling back to
+the default library search path.
+
+Example:
+
+    import bkup
+
+    options = bkup.Options().accuracy(2000).ignore(True)
+    plan = bkup.diff("/home/me/docs", "/mnt/backup/docs", options)
+    print(len(plan), "actions,", plan.bytes(), "bytes")
+    plan.apply(progress=lambda done, total: print(done, "/", total))
+"""
+
+import ctypes
+import ctypes.util
+import os
+
+__all__ = ["BkupError", "Options", "Plan", "diff", "update"]
+
+_OK = 0
+_INVALID_ARGUMENT = 1
+
+_PROGRESS = ctypes.CFUNCTYPE(
+    None, ctypes.c_size_t, ctypes.c_size_t, ctypes.c_void_p
+)
+
+
+def _load():
+    path = os.environ.get("BKUP_LIBRARY") or ctypes.util.find_library("bkup")
+    if path is None:
+        raise ImportError("Cannot find the bkup library, set BKUP_LIBRARY")
+    lib = ctypes.CDLL(path)
+
+    lib.bkup_options_new.restype = ctypes.c_void_p
+    lib.bkup_options_new.argtypes = []
+    lib.bkup_options_free.restype = None
+    lib.bkup_options_free.argtypes = [ctypes.c_void_p]
+    lib.bkup_options_set_accuracy.argtypes = [ctypes.c_void_p, ctypes.c_uint64]
+    for setter in ("ignore", "detect_moves", "hard_links", "reflink"):
+        function = getattr(lib, "bkup_options_set_" + setter)
+        function.argtypes = [ctypes.c_void_p, ctypes.c_bool]
+
+    lib.bkup_plan_new.argtypes = [
+        ctypes.c_char_p,
+        ctypes.c_char_p,
+        ctypes.c_void_p,
+        ctypes.POINTER(ctypes.c_void_p),
+    ]
+    lib.bkup_plan_len.restype = ctypes.c_size_t
+    lib.bkup_plan_len.argtypes = [ctypes.c_void_p]
+    lib.bkup_plan_bytes.restype = ctypes.c_uint64
+    lib.bkup_plan_bytes.argtypes = [ctypes.c_void_p]
+    lib.bkup_plan_apply.argtypes = [ctypes.c_void_p, _PROGRESS, ctypes.c_void_p]
+    lib.bkup_plan_free.restype = None
+    lib.bkup_plan_free.argtypes = [ctypes.c_void_p]
+    lib.bkup_last_error.restype = ctypes.c_char_p
+    lib.bkup_last_error.argtypes = []
+    return lib
+
+
+_lib = _load()
+
+
+class BkupError(Exception):
+    """Error raised when an operation of the engine fails."""
+
+
+def _check(status):
+    if status == _INVALID_ARGUMENT:
+        raise ValueError("Invalid argument")
+    if status != _OK:
+        message = _lib.bkup_last_error()
+        raise BkupError(message.decode() if message else "Unknown error")
+
+
+class Options:
+    """Builder of the options used to compute a plan."""
+
+    def __init__(self):
+        self._handle = _lib.bkup_options_new()
+
+    def __del__(self):
+        if getattr(self, "_handle", None):
+            _lib.bkup_options_free(self._handle)
+            self._handle = None
+
+    def accuracy(self, milliseconds):
+        """Sets the accuracy in ms for a source file to be considered newer."""
+        _check(_lib.bkup_options_set_accuracy(self._handle, milliseconds))
+        return self
+
+    def ignore(self, enabled=True):
+        """Parses the .gitignore file of the visited directories."""
+        _check(_lib.bkup_options_set_ignore(self._handle, enabled))
+        return self
+
+    def detect_moves(self, enabled=True):
+        """Renames the destination files that were moved in the source."""
+        _check(_lib.bkup_options_set_detect_moves(self._handle, enabled))
+        return self
+
+    def hard_links(self, enabled=True):
+        """Recreates the hard links of the source files."""
+        _check(_lib.bkup_options_set_hard_links(self._handle, enabled))
+        return self
+
+    def reflink(self, enabled=True):
+        """Clones the source files on copy-on-write filesystems."""
+        _check(_lib.bkup_options_set_reflink(self._handle, enabled))
+        return self
+
+
+class Plan:
+    """Actions that bring a destination up to date with its source."""
+
+    def __init__(self, handle):
+        self._handle = handle
+
+    def __del__(self):
+        if getattr(self, "_handle", None):
+            _lib.bkup_plan_free(self._handle)
+            self._handle = None
+
+    def __len__(self):
+        return _lib.bkup_plan_len(self._handle)
+
+    def bytes(self):
+        """Gets the number of bytes copied by the plan."""
+        return _lib.bkup_plan_bytes(self._handle)
+
+    def apply(self, progress=None):
+        """Applies the plan, calling progress(done, total) after each action."""
+        errors = []
+
+        def callback(done, total, _user_data):
+            try:
+                progress(done, total)
+            except Exception as e:  # exceptions cannot cross the C boundary
+                errors.append(e)
+
+        callback = _PROGRESS(callback if progress else 0)
+        _check(_lib.bkup_plan_apply(self._handle, callback, None))
+        if errors:
+            raise errors[0]
+
+
+def diff(source, dest, options=None):
+    """Scans the source and destination directories and returns the plan."""
+    options = options or Options()
+    handle = ctypes.c_void_p()
+    status = _lib.bkup_plan_new(
+        os.fsencode(source), os.fsencode(dest), options._handle, handle
+    )
+    _check(status)
+    return Plan(handle.value)
+
+
+def update(source, dest, options=None, progress=None):
+    """Updates the destination directory with the content of the source."""
+    plan = diff(source, dest, options)
+    plan.apply(progress)
+    return plan
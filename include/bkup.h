@@ -0,0 +1,122 @@
+#ifndef BKUP_H
+#define BKUP_H
+
+/* This file is generated by cbindgen, do not edit it manually. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Status codes returned by the C API.
+ */
+typedef enum BkupStatus {
+  /**
+   * The operation completed successfully.
+   */
+  BKUP_OK = 0,
+  /**
+   * A null pointer or an invalid UTF-8 string was passed.
+   */
+  BKUP_INVALID_ARGUMENT = 1,
+  /**
+   * The operation failed, see `bkup_last_error`.
+   */
+  BKUP_ERROR = 2,
+} BkupStatus;
+
+/**
+ * Opaque handle to the options used to compute a plan.
+ */
+typedef struct BkupOptions BkupOptions;
+
+/**
+ * Opaque handle to the plan that brings a destination up to date.
+ */
+typedef struct BkupPlan BkupPlan;
+
+/**
+ * Callback invoked after each applied action with the number of applied
+ * actions, the total number of actions, and the user data.
+ */
+typedef void (*BkupProgressCallback)(size_t done, size_t total, void *user_data);
+
+/**
+ * Creates new options with the default values. The returned handle must be
+ * released with `bkup_options_free`.
+ */
+struct BkupOptions *bkup_options_new(void);
+
+/**
+ * Releases the given options.
+ */
+void bkup_options_free(struct BkupOptions *options);
+
+/**
+ * Sets the accuracy in ms for a source file to be considered newer than its
+ * destination.
+ */
+enum BkupStatus bkup_options_set_accuracy(struct BkupOptions *options, uint64_t accuracy_ms);
+
+/**
+ * Sets whether the `.gitignore` files of the visited directories are parsed.
+ */
+enum BkupStatus bkup_options_set_ignore(struct BkupOptions *options, bool ignore);
+
+/**
+ * Sets whether moved and renamed files are detected.
+ */
+enum BkupStatus bkup_options_set_detect_moves(struct BkupOptions *options, bool detect_moves);
+
+/**
+ * Sets whether the source files are cloned on copy-on-write filesystems.
+ */
+enum BkupStatus bkup_options_set_reflink(struct BkupOptions *options, bool reflink);
+
+/**
+ * Sets whether the hard links of the source files are preserved.
+ */
+enum BkupStatus bkup_options_set_hard_links(struct BkupOptions *options, bool hard_links);
+
+/**
+ * Scans the source and destination directories and computes the plan that
+ * brings the destination up to date, without modifying it. On success the
+ * plan is stored in `plan` and must be released with `bkup_plan_free`.
+ */
+enum BkupStatus bkup_plan_new(const char *source,
+                              const char *dest,
+                              const struct BkupOptions *options,
+                              struct BkupPlan **plan);
+
+/**
+ * Gets the number of actions of the plan.
+ */
+size_t bkup_plan_len(const struct BkupPlan *plan);
+
+/**
+ * Gets the number of bytes copied by the plan.
+ */
+uint64_t bkup_plan_bytes(const struct BkupPlan *plan);
+
+/**
+ * Applies the plan to the destination, invoking the given progress callback
+ * (if not null) after each action.
+ */
+enum BkupStatus bkup_plan_apply(const struct BkupPlan *plan,
+                                BkupProgressCallback progress,
+                                void *user_data);
+
+/**
+ * Releases the given plan.
+ */
+void bkup_plan_free(struct BkupPlan *plan);
+
+/**
+ * Gets the message of the last error occurred in the calling thread, or null
+ * if no error occurred. The message is valid until the next call that fails.
+ */
+const char *bkup_last_error(void);
+
+#endif /* BKUP_H */
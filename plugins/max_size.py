@@ -0,0 +1,36 @@
+#!/usr/bin/env python3
+"""Reference bkup plugin.
+
+Excludes every file larger than BKUP_MAX_SIZE bytes (100 MiB by default) and
+prints the outcome of the run on stderr.
+
+Usage: bkup update -s <source> -d <destination> -p "python3 plugins/max_size.py"
+"""
+
+import json
+import os
+import sys
+
+MAX_SIZE = int(os.environ.get("BKUP_MAX_SIZE", 100 * 1024 * 1024))
+
+
+def reply(message):
+    print(json.dumps(message), flush=True)
+
+
+for line in sys.stdin:
+    message = json.loads(line)
+    kind = message.get("type")
+    if kind == "hello":
+        reply({"capabilities": ["filter", "notify"]})
+    elif kind == "filter":
+        reply({"include": message["dir"] or message["size"] <= MAX_SIZE})
+    elif kind == "completed":
+        print(
+            "bkup: {} actions, {} bytes copied".format(
+                message["actions"], message["bytes"]
+            ),
+            file=sys.stderr,
+        )
+    elif kind == "failed":
+        print("bkup: failed: {}".format(message["error"]), file=sys.stderr)
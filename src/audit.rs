@@ -0,0 +1,202 @@
+//! Audit logs of the updates, that record every action applied to a
+//! destination directory with its outcome, as one JSON object per line.
+//!
+//! Each update writes its own log in the `.bkup/logs` directory of the
+//! destination, named after the time it started, so that the logs of the past
+//! updates tell exactly what each of them changed.
+
+use crate::{
+    date,
+    error::BkupError,
+    hash::Digest,
+    json,
+    metadata::{self, LOGS_DIR},
+    plan::Action,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, LineWriter, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Extension of the audit logs.
+const LOG_EXTENSION: &str = "jsonl";
+
+/// Record of an action in the audit log.
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    action: &'static str,
+    #[serde(serialize_with = "json::lossy")]
+    path: &'a Path,
+    size: u64,
+    // digest of the copied file, keyed by the name of its algorithm
+    #[serde(flatten)]
+    digest: Option<BTreeMap<&'static str, String>>,
+    result: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// Writes the records of the actions applied by an update, flushing every
+/// record as soon as it's written.
+pub struct AuditLog {
+    dest: PathBuf,
+    path: PathBuf,
+    writer: LineWriter<File>,
+}
+
+impl AuditLog {
+    /// Creates the audit log of a new update of the given destination.
+    pub fn create(dest: &Path) -> Result<Self, BkupError> {
+        let dir = metadata::path(dest, LOGS_DIR);
+        fs::create_dir_all(&dir).map_err(|e| BkupError::Io(e).at(&dir))?;
+        // the identifier of the run is the time it started, with a counter
+        // for the runs started in the same second
+        let id = date::format_date(SystemTime::now()).replace([':', '-'], "");
+        let mut n = 1;
+        loop {
+            let name = match n {
+                1 => format!("{}.{}", id, LOG_EXTENSION),
+                n => format!("{}-{}.{}", id, n, LOG_EXTENSION),
+            };
+            let path = dir.join(name);
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => {
+                    return Ok(AuditLog {
+                        dest: dest.to_path_buf(),
+                        path,
+                        writer: LineWriter::new(file),
+                    })
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(BkupError::Io(e).at(&path)),
+            }
+        }
+    }
+
+    /// Gets the path of the log.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records the given applied action, with the digest of the copied file
+    /// if it was computed (to verify the copy).
+    pub fn applied(
+        &mut self,
+        action: &Action,
+        digest: Option<&Digest>,
+    ) -> Result<(), BkupError> {
+        let digest = digest.map(|digest| {
+            BTreeMap::from([(digest.algorithm().name(), digest.to_string())])
+        });
+        self.write(action, "ok", digest, None)
+    }
+
+    /// Records the given action that failed with the given error, where the
+    /// result is `failed` or `locked` (if its source file is locked).
+    pub fn failed(
+        &mut self,
+        action: &Action,
+        result: &str,
+        error: &str,
+    ) -> Result<(), BkupError> {
+        self.write(action, result, None, Some(error))
+    }
+
+    /// Writes the record of the given action.
+    fn write(
+        &mut self,
+        action: &Action,
+        result: &str,
+        digest: Option<BTreeMap<&'static str, String>>,
+        error: Option<&str>,
+    ) -> Result<(), BkupError> {
+        let path = action.path();
+        let record = Record {
+            time: date::format_date(SystemTime::now()),
+            action: action.name(),
+            path: path.strip_prefix(&self.dest).unwrap_or(path),
+            size: match action {
+                Action::Copy { size, .. } => *size,
+                _ => 0,
+            },
+            digest,
+            result,
+            error,
+        };
+        writeln!(self.writer, "{}", serde_json::to_string(&record)?)
+            .map_err(|e| BkupError::Io(e).at(&self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        hash::{self, HashAlgorithm},
+        json::Value,
+    };
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_audit_log() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(root.join("dir")).expect("Cannot create dir");
+        fs::write(root.join("dir").join("file"), "content")
+            .expect("Cannot write file");
+        let copy = Action::Copy {
+            source: PathBuf::from("source"),
+            dest: root.join("dir").join("file"),
+            size: 7,
+            overwrite: false,
+        };
+        let rename = Action::Rename {
+            from: root.join("old"),
+            to: root.join("new"),
+        };
+
+        let digest = hash::hash_file(
+            &root.join("dir").join("file"),
+            HashAlgorithm::Blake3,
+        )
+        .expect("Cannot hash file");
+
+        let mut log = AuditLog::create(&root).expect("Cannot create log");
+        log.applied(&copy, Some(&digest))
+            .expect("Cannot write record");
+        log.failed(&rename, "failed", "Not found")
+            .expect("Cannot write record");
+        let path = log.path().to_path_buf();
+        assert!(path.starts_with(root.join(".bkup").join("logs")));
+        assert!(metadata::is_metadata(path.strip_prefix(&root).unwrap()));
+        drop(log);
+        // the logs of the runs started in the same second are kept apart
+        let other = AuditLog::create(&root).expect("Cannot create log");
+        assert_ne!(other.path(), path);
+
+        let content = fs::read_to_string(&path).expect("Cannot read log");
+        let records: Vec<_> = content
+            .lines()
+            .map(|line| json::parse(line).expect("Invalid record"))
+            .collect();
+        assert_eq!(records.len(), 2);
+        let field = |i: usize, name| records[i].get(name).cloned();
+        assert_eq!(field(0, "action"), Some(Value::from("copy")));
+        assert_eq!(field(0, "path"), Some(Value::from("dir/file")));
+        assert_eq!(field(0, "size"), Some(Value::from(7u64)));
+        assert_eq!(field(0, "blake3"), Some(Value::from(digest.to_string())));
+        assert_eq!(field(0, "result"), Some(Value::from("ok")));
+        assert_eq!(field(1, "path"), Some(Value::from("new")));
+        assert_eq!(field(1, "result"), Some(Value::from("failed")));
+        assert_eq!(field(1, "error"), Some(Value::from("Not found")));
+        assert!(field(1, "time").is_some());
+
+        fs::remove_dir_all(root).expect("Cannot remove dir");
+    }
+}
@@ -0,0 +1,84 @@
+//! Cancellation of a running update, requested by the user with a signal or
+//! by the host application with a `CancellationToken`.
+//!
+//! Once cancelled, the update stops visiting the directories and applying new
+//! actions, while the copies in progress are either completed or aborted
+//! (removing their temporary file), so that the destination is never left
+//! with a truncated file.
+
+use crate::error::BkupError;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Exit code of a process whose update was cancelled (as for SIGINT).
+pub const CANCELLED_EXIT_CODE: i32 = 130;
+
+/// Set once the running update must be cancelled.
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+/// Requests the cancellation of the running update.
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// Returns true if the cancellation of the running update was requested.
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Token used to cancel a single update (or scan), that can be cloned and
+/// shared with other threads. An update is also cancelled by `cancel`, that
+/// cancels every update of the process.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a new token, not cancelled yet.
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Requests the cancellation of the updates that use this token.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns true if the cancellation of the updates that use this token
+    /// (or of every update) was requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst) || is_cancelled()
+    }
+
+    /// Fails with `BkupError::Cancelled` if the cancellation was requested.
+    pub fn check(&self) -> Result<(), BkupError> {
+        if self.is_cancelled() {
+            Err(BkupError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Cancels the running update on SIGINT (Ctrl-C) and SIGTERM. A second signal
+/// terminates the process immediately.
+#[cfg(unix)]
+pub fn cancel_on_signals() {
+    extern "C" fn handle(_signal: libc::c_int) {
+        // only async-signal-safe operations are allowed here
+        if CANCELLED.swap(true, Ordering::SeqCst) {
+            unsafe { libc::_exit(CANCELLED_EXIT_CODE) };
+        }
+    }
+    let handler = handle as extern "C" fn(libc::c_int) as *const ();
+    // safe since the handler only accesses an atomic and calls _exit
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+}
+
+/// Signals are not handled on this platform.
+#[cfg(not(unix))]
+pub fn cancel_on_signals() {}
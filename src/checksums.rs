@@ -0,0 +1,373 @@
+//! Checksum files of a directory in the formats of the standard tools, so
+//! that a backup can be verified independently of bkup (and a directory
+//! verified against the checksums of another tool): the GNU format of
+//! `sha256sum` and `b3sum` (`<digest>  <path>`), and the BSD format of
+//! `shasum --tag` and of the `sha256` command of the BSDs
+//! (`SHA256 (<path>) = <digest>`).
+//!
+//! The paths are relative to the directory, with `/` as separator, and the
+//! names containing a backslash or a newline are escaped as `sha256sum` does,
+//! with a leading backslash on their line.
+
+use crate::{
+    entry::Entry,
+    error::BkupError,
+    filter::Filter,
+    hash::{self, Digest, HashAlgorithm},
+    json::{self, Value},
+    rules,
+};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    fmt::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Enumerates the formats of the checksum files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChecksumFormat {
+    /// One `<digest>  <path>` line per file, as written by `sha256sum`.
+    #[default]
+    Gnu,
+    /// One `<ALGORITHM> (<path>) = <digest>` line per file, as written by
+    /// `shasum --tag`.
+    Bsd,
+}
+
+impl FromStr for ChecksumFormat {
+    type Err = BkupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gnu" => Ok(ChecksumFormat::Gnu),
+            "bsd" => Ok(ChecksumFormat::Bsd),
+            _ => Err(BkupError::Invalid(format!(
+                "Invalid checksum format {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Enumerates the differences of a directory from its checksum file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ChecksumStatus {
+    /// The file is listed but doesn't exist.
+    Missing,
+    /// The file exists but is not listed.
+    Extra,
+    /// The content of the file doesn't match its checksum.
+    Mismatched,
+}
+
+impl ChecksumStatus {
+    /// Gets the name of the status.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumStatus::Missing => "missing",
+            ChecksumStatus::Extra => "extra",
+            ChecksumStatus::Mismatched => "mismatched",
+        }
+    }
+}
+
+/// Represents a file of a directory that differs from its checksum file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ChecksumMismatch {
+    /// Path of the file, relative to the directory.
+    pub path: PathBuf,
+    /// How the file differs.
+    pub status: ChecksumStatus,
+}
+
+impl ChecksumMismatch {
+    /// Serializes self into a JSON object.
+    pub fn to_json(&self) -> Value {
+        json::object(vec![
+            (
+                "path",
+                Value::from(self.path.to_string_lossy().into_owned()),
+            ),
+            ("status", Value::from(self.status.name())),
+        ])
+    }
+}
+
+/// Gets the tag of the given algorithm in the BSD format.
+fn tag(algorithm: HashAlgorithm) -> &'static str {
+    match algorithm {
+        HashAlgorithm::Blake3 => "BLAKE3",
+        HashAlgorithm::Xxh3 => "XXH3",
+        HashAlgorithm::Sha256 => "SHA256",
+        HashAlgorithm::Crc32 => "CRC32",
+    }
+}
+
+/// Gets the algorithm with the given tag in the BSD format.
+fn tag_algorithm(name: &str) -> Option<HashAlgorithm> {
+    HashAlgorithm::ALL.iter().copied().find(|a| tag(*a) == name)
+}
+
+/// Gets the paths of the files of the given directory, relative to it and
+/// sorted (the scan skips the files stored by bkup in a destination
+/// directory).
+fn files(dir: &Path, ignore: bool) -> Result<BTreeSet<PathBuf>, BkupError> {
+    let entry = Entry::directory(dir, ignore, &Filter::new())?;
+    Ok(entry
+        .all_files()
+        .into_iter()
+        .filter_map(|file| file.path().strip_prefix(dir).ok())
+        .map(Path::to_path_buf)
+        .collect())
+}
+
+/// Hashes every file of the given directory with the given algorithm, and
+/// gets their paths relative to the directory with their digests, sorted by
+/// path. The files stored by bkup in a destination directory are skipped.
+pub fn checksums(
+    dir: &Path,
+    ignore: bool,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<(PathBuf, Digest)>, BkupError> {
+    files(dir, ignore)?
+        .into_iter()
+        .map(|path| {
+            let file = dir.join(&path);
+            let digest =
+                hash::hash_file(&file, algorithm).map_err(|e| e.at(&file))?;
+            Ok((path, digest))
+        })
+        .collect()
+}
+
+/// Parses the given checksum file in any of the formats. The algorithm of
+/// the lines in the BSD format is given by their tag, while the lines in the
+/// GNU format have the given one.
+pub fn parse(
+    text: &str,
+    algorithm: HashAlgorithm,
+) -> Result<Vec<(PathBuf, Digest)>, BkupError> {
+    let mut checksums = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid =
+            || BkupError::Parse(format!("Invalid checksum at line {}", i + 1));
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let bsd = line
+            .split_once(" (")
+            .and_then(|(tag, rest)| Some((tag_algorithm(tag)?, rest)));
+        let (algorithm, path, digest) = match bsd {
+            Some((algorithm, rest)) => {
+                let (path, digest) =
+                    rest.rsplit_once(") = ").ok_or_else(invalid)?;
+                (algorithm, path, digest)
+            }
+            None => {
+                // the path follows a space and a ' ' (text) or '*' (binary)
+                let (digest, path) =
+                    line.split_once(' ').ok_or_else(invalid)?;
+                let path = path
+                    .strip_prefix(' ')
+                    .or_else(|| path.strip_prefix('*'))
+                    .ok_or_else(invalid)?;
+                (algorithm, path, digest)
+            }
+        };
+        let path = if escaped {
+            unescape(path).ok_or_else(invalid)?
+        } else {
+            path.to_string()
+        };
+        if path.is_empty() {
+            return Err(invalid());
+        }
+        let digest = Digest::parse(algorithm, &digest.to_lowercase())?;
+        checksums.push((PathBuf::from(path), digest));
+    }
+    Ok(checksums)
+}
+
+/// Unescapes the backslashes and newlines of the given escaped path.
+fn unescape(path: &str) -> Option<String> {
+    let mut unescaped = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        unescaped.push(match c {
+            '\\' => match chars.next()? {
+                '\\' => '\\',
+                'n' => '\n',
+                _ => return None,
+            },
+            c => c,
+        });
+    }
+    Some(unescaped)
+}
+
+/// Compares the files of the given directory with the given checksums, and
+/// gets the listed files that are missing or whose content doesn't match
+/// their checksum, and the files that are not listed (but the given one, such
+/// as the checksum file itself), sorted by path.
+pub fn verify(
+    dir: &Path,
+    checksums: &[(PathBuf, Digest)],
+    ignore: bool,
+    skip: Option<&Path>,
+) -> Result<Vec<ChecksumMismatch>, BkupError> {
+    let mut extra = files(dir, ignore)?;
+    let mut mismatches = Vec::new();
+    for (path, digest) in checksums {
+        extra.remove(path);
+        let file = dir.join(path);
+        let status = if !file.is_file() {
+            ChecksumStatus::Missing
+        } else if hash::hash_file(&file, digest.algorithm())
+            .map_err(|e| e.at(&file))?
+            != *digest
+        {
+            ChecksumStatus::Mismatched
+        } else {
+            continue;
+        };
+        mismatches.push(ChecksumMismatch {
+            path: path.clone(),
+            status,
+        });
+    }
+    let skip = skip.and_then(|skip| skip.strip_prefix(dir).ok());
+    mismatches.extend(
+        extra
+            .into_iter()
+            .filter(|p| Some(p.as_path()) != skip)
+            .map(|path| ChecksumMismatch {
+                path,
+                status: ChecksumStatus::Extra,
+            }),
+    );
+    mismatches.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(mismatches)
+}
+
+/// Writes the given checksums in the given format, one line per file.
+pub fn format(
+    checksums: &[(PathBuf, Digest)],
+    format: ChecksumFormat,
+) -> String {
+    let mut text = String::new();
+    for (path, digest) in checksums {
+        let path = rules::slash_path(path);
+        let escaped = path.contains(['\\', '\n']);
+        let path = path.replace('\\', "\\\\").replace('\n', "\\n");
+        let prefix = if escaped { "\\" } else { "" };
+        let _ = match format {
+            ChecksumFormat::Gnu => {
+                writeln!(text, "{}{}  {}", prefix, digest, path)
+            }
+            ChecksumFormat::Bsd => writeln!(
+                text,
+                "{}{} ({}) = {}",
+                prefix,
+                tag(digest.algorithm()),
+                path,
+                digest
+            ),
+        };
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::metadata;
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_checksums() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(root.join("dir")).expect("Cannot create dir");
+        fs::write(root.join("dir").join("abc"), "abc").expect("Cannot write");
+        fs::write(root.join("empty"), "").expect("Cannot write");
+        fs::create_dir_all(root.join(metadata::BKUP_DIR))
+            .expect("Cannot create dir");
+        fs::write(metadata::path(&root, "manifest"), "[]")
+            .expect("Cannot write");
+
+        let checksums = checksums(&root, false, HashAlgorithm::Sha256)
+            .expect("Cannot hash directory");
+        assert_eq!(
+            format(&checksums, ChecksumFormat::Gnu),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  \
+             dir/abc\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  \
+             empty\n"
+        );
+        assert_eq!(
+            format(&checksums[..1], ChecksumFormat::Bsd),
+            "SHA256 (dir/abc) = \
+             ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\n"
+        );
+        if cfg!(unix) {
+            let escaped = [(PathBuf::from("a\\b"), checksums[1].1)];
+            assert!(
+                format(&escaped, ChecksumFormat::Gnu).starts_with("\\e3b0c442")
+            );
+            assert!(
+                format(&escaped, ChecksumFormat::Gnu).ends_with("  a\\\\b\n")
+            );
+        }
+        assert!("sfv".parse::<ChecksumFormat>().is_err());
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(root.join("dir")).expect("Cannot create dir");
+        fs::write(root.join("dir").join("abc"), "abc").expect("Cannot write");
+        fs::write(root.join("changed"), "CHANGED").expect("Cannot write");
+        fs::write(root.join("extra"), "").expect("Cannot write");
+        let text = "\
+            # written by sha256sum\n\
+            ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad *dir/abc\n\
+            BLAKE3 (changed) = \
+            6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85\n\
+            \\CRC32 (a\\nb) = 352441C2\n\
+            SUMS\n";
+        assert!(parse(text, HashAlgorithm::Sha256).is_err());
+        let text = text.trim_end_matches("SUMS\n");
+        let checksums =
+            parse(text, HashAlgorithm::Sha256).expect("Invalid checksums");
+        assert_eq!(checksums[2].0, PathBuf::from("a\nb"));
+        assert_eq!(checksums[2].1.algorithm(), HashAlgorithm::Crc32);
+        fs::write(root.join("SUMS"), text).expect("Cannot write");
+
+        let skip = root.join("SUMS");
+        let mismatches = verify(&root, &checksums, false, Some(&skip))
+            .expect("Cannot verify");
+        let status = |path: &str, status| ChecksumMismatch {
+            path: PathBuf::from(path),
+            status,
+        };
+        assert_eq!(
+            mismatches,
+            vec![
+                status("a\nb", ChecksumStatus::Missing),
+                status("changed", ChecksumStatus::Mismatched),
+                status("extra", ChecksumStatus::Extra),
+            ]
+        );
+    }
+}
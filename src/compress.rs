@@ -0,0 +1,334 @@
+//! Compression of the content of the files sent to a remote agent, to trade
+//! the CPU of both machines for the bandwidth of the network.
+//!
+//! The content is split into blocks of at most `BLOCK_SIZE` bytes, each
+//! compressed independently and sent with its compressed and original length
+//! (as two big-endian `u32`), so that the agent never buffers more than a
+//! block of a file whatever its size.
+//!
+//! The files whose content is already compressed (such as photos, videos and
+//! archives) are sent uncompressed, as compressing them again only wastes
+//! CPU: they are detected by their extension, or by the entropy of their
+//! first block.
+
+use crate::error::BkupError;
+use log::*;
+use std::{
+    fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    str::FromStr,
+};
+
+/// Size of the blocks compressed independently.
+pub const BLOCK_SIZE: usize = 1024 * 1024;
+/// Level of zstd when not given.
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+/// Size of the first block of a file whose entropy is measured.
+const PROBE_SIZE: usize = 64 * 1024;
+/// Entropy (in bits per byte) above which a block is considered compressed.
+const MAX_ENTROPY: f64 = 7.5;
+/// Extensions (in lowercase) of the formats whose content is compressed.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "cab", "deb", "docx",
+    "epub", "flac", "gif", "gz", "heic", "jar", "jpeg", "jpg", "lz", "lz4",
+    "lzma", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odp", "ods", "odt",
+    "ogg", "opus", "png", "pptx", "rar", "rpm", "tgz", "webm", "webp", "xlsx",
+    "xz", "zip", "zst",
+];
+
+/// Enumerates the compression algorithms of the transferred files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// The files are sent as they are.
+    #[default]
+    None,
+    /// LZ4, that is fast enough not to slow down a gigabit link even on a
+    /// small board, with a lower ratio.
+    Lz4,
+    /// zstd at the given level (1 to 22), that compresses better at the
+    /// price of more CPU as the level increases.
+    Zstd(i32),
+}
+
+impl Compression {
+    /// Gets the name of the algorithm, without its level.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+            Compression::Zstd(_) => "zstd",
+        }
+    }
+
+    /// Gets the compression of the given file, that is none if its content is
+    /// already compressed. The file is read from its start, where it's left.
+    pub fn for_file<R: Read + Seek>(
+        self,
+        path: &Path,
+        file: &mut R,
+    ) -> Result<Compression, BkupError> {
+        if self == Compression::None {
+            return Ok(self);
+        }
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_lowercase);
+        if let Some(extension) = extension {
+            if COMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+                debug!("Not compressing {:?}, compressed format", path);
+                return Ok(Compression::None);
+            }
+        }
+        let mut block = vec![0; PROBE_SIZE];
+        let n = read_full(file, &mut block)?;
+        file.seek(SeekFrom::Start(0))?;
+        if entropy(&block[..n]) > MAX_ENTROPY {
+            debug!("Not compressing {:?}, compressed content", path);
+            return Ok(Compression::None);
+        }
+        Ok(self)
+    }
+
+    /// Compresses the given block.
+    fn compress(&self, block: &[u8]) -> Result<Vec<u8>, BkupError> {
+        match self {
+            Compression::None => Ok(block.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::block::compress(block)),
+            Compression::Zstd(level) => {
+                Ok(zstd::bulk::compress(block, *level)?)
+            }
+        }
+    }
+
+    /// Decompresses the given block into its original length.
+    fn decompress(
+        &self,
+        block: &[u8],
+        len: usize,
+    ) -> Result<Vec<u8>, BkupError> {
+        let data = match self {
+            Compression::None => block.to_vec(),
+            Compression::Lz4 => lz4_flex::block::decompress(block, len)
+                .map_err(|e| BkupError::Parse(e.to_string()))?,
+            Compression::Zstd(_) => zstd::bulk::decompress(block, len)?,
+        };
+        if data.len() != len {
+            return Err(BkupError::Parse(format!(
+                "Invalid block of {} bytes, expected {}",
+                data.len(),
+                len
+            )));
+        }
+        Ok(data)
+    }
+
+    /// Writes the given number of bytes read from the reader as compressed
+    /// blocks, and gets the number of bytes read (that is less than the size
+    /// if the reader ended before).
+    pub fn write_blocks<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        size: u64,
+        writer: &mut W,
+    ) -> Result<u64, BkupError> {
+        let mut block = vec![0; BLOCK_SIZE];
+        let mut read = 0;
+        while read < size {
+            let len = (size - read).min(BLOCK_SIZE as u64) as usize;
+            let n = read_full(reader, &mut block[..len])?;
+            if n == 0 {
+                break;
+            }
+            let compressed = self.compress(&block[..n])?;
+            writer.write_all(&(compressed.len() as u32).to_be_bytes())?;
+            writer.write_all(&(n as u32).to_be_bytes())?;
+            writer.write_all(&compressed)?;
+            read += n as u64;
+        }
+        Ok(read)
+    }
+
+    /// Reads the compressed blocks of the given number of bytes into the
+    /// writer, and gets the number of bytes written.
+    pub fn read_blocks<R: Read, W: Write>(
+        &self,
+        reader: &mut R,
+        size: u64,
+        writer: &mut W,
+    ) -> Result<u64, BkupError> {
+        let mut written = 0;
+        while written < size {
+            let mut header = [0; 8];
+            if read_full(reader, &mut header)? < header.len() {
+                break;
+            }
+            let compressed = u32::from_be_bytes([
+                header[0], header[1], header[2], header[3],
+            ]) as usize;
+            let len = u32::from_be_bytes([
+                header[4], header[5], header[6], header[7],
+            ]) as usize;
+            // the lengths come from the other side and bound the allocations
+            if len == 0 || len > BLOCK_SIZE || compressed > 2 * BLOCK_SIZE {
+                return Err(BkupError::Parse(format!(
+                    "Invalid block of {} bytes ({} compressed)",
+                    len, compressed
+                )));
+            }
+            let mut block = vec![0; compressed];
+            if read_full(reader, &mut block)? < compressed {
+                break;
+            }
+            writer.write_all(&self.decompress(&block, len)?)?;
+            written += len as u64;
+        }
+        Ok(written)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Zstd(level) => write!(f, "zstd:{}", level),
+            compression => f.write_str(compression.name()),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = BkupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || BkupError::Invalid(format!("Invalid compression {:?}", s));
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+        match (name, level) {
+            ("none", None) => Ok(Compression::None),
+            ("lz4", None) => Ok(Compression::Lz4),
+            ("zstd", None) => Ok(Compression::Zstd(DEFAULT_ZSTD_LEVEL)),
+            ("zstd", Some(level)) => match level.parse() {
+                Ok(level @ 1..=22) => Ok(Compression::Zstd(level)),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Gets the Shannon entropy of the given bytes, in bits per byte (from 0 for
+/// a repeated byte to 8 for random bytes).
+fn entropy(bytes: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &b in bytes {
+        counts[b as usize] += 1;
+    }
+    let len = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Fills the given buffer from the reader, and gets the number of bytes read
+/// (that is less than its length only at the end of the reader).
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(read) => n += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_compression() {
+        assert_eq!("none".parse::<Compression>().ok(), Some(Compression::None));
+        assert_eq!("lz4".parse::<Compression>().ok(), Some(Compression::Lz4));
+        assert_eq!(
+            "zstd".parse::<Compression>().ok(),
+            Some(Compression::Zstd(3))
+        );
+        assert_eq!(
+            "zstd:19".parse::<Compression>().ok(),
+            Some(Compression::Zstd(19))
+        );
+        assert!("zstd:0".parse::<Compression>().is_err());
+        assert!("lz4:1".parse::<Compression>().is_err());
+        assert!("gzip".parse::<Compression>().is_err());
+        assert_eq!(Compression::Zstd(19).to_string(), "zstd:19");
+
+        let data: Vec<u8> =
+            (0..BLOCK_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect();
+        for compression in [
+            Compression::None,
+            Compression::Lz4,
+            "zstd".parse().expect("Invalid compression"),
+        ] {
+            let mut blocks = Vec::new();
+            let size = data.len() as u64;
+            let read = compression
+                .write_blocks(&mut data.as_slice(), size, &mut blocks)
+                .expect("Cannot compress");
+            assert_eq!(read, size);
+            if compression != Compression::None {
+                assert!(blocks.len() < data.len() / 10);
+            }
+            let mut decompressed = Vec::new();
+            let written = compression
+                .read_blocks(&mut blocks.as_slice(), size, &mut decompressed)
+                .expect("Cannot decompress");
+            assert_eq!(written, size);
+            assert_eq!(decompressed, data);
+        }
+    }
+
+    #[test]
+    fn test_skip_compressed() {
+        let zstd = Compression::Zstd(3);
+        let text = b"hello world\n".repeat(10_000);
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let random: Vec<u8> = (0..100_000)
+            .map(|_| {
+                // xorshift, as random as a compressed file
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let for_file = |compression: Compression, name: &str, data: &[u8]| {
+            let mut cursor = io::Cursor::new(data);
+            let compression = compression
+                .for_file(Path::new(name), &mut cursor)
+                .expect("Cannot probe file");
+            assert_eq!(cursor.position(), 0);
+            compression
+        };
+        assert_eq!(for_file(zstd, "notes.txt", &text), zstd);
+        assert_eq!(for_file(zstd, "IMG_0001.JPG", &text), Compression::None);
+        assert_eq!(for_file(zstd, "backup.tar", &random), Compression::None);
+        assert_eq!(
+            for_file(Compression::None, "notes.txt", &text),
+            Compression::None
+        );
+        assert_eq!(for_file(zstd, "empty", &[]), zstd);
+    }
+}
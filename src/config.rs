@@ -0,0 +1,161 @@
+//! Global configuration of the machine, read from `bkup/config.toml` in the
+//! configuration directory of the platform.
+//!
+//! The configuration is a flat TOML document of `key = value` pairs, where
+//! each key is the long name of an option (such as `accuracy` or
+//! `notify-url`). Its values are the defaults of the `BKUP_*` environment
+//! variables, so that the options given on the command line or in the
+//! environment (or in a `.env` file) take precedence.
+
+use bkup::BkupError as Error;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// Environment variable with the path of the configuration file, overriding
+/// the default one.
+const CONFIG_VAR: &str = "BKUP_CONFIG";
+/// Key of the log level, that sets `RUST_LOG`.
+const LOG_KEY: &str = "log";
+
+/// Content of the configuration file.
+#[derive(Debug, Deserialize)]
+struct Config {
+    // options by their long name
+    #[serde(flatten)]
+    options: BTreeMap<String, Scalar>,
+}
+
+/// Value of an option, that can't be a table or an array.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Scalar {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+impl Scalar {
+    /// Gets the value of the environment variable of the option.
+    fn into_var(self) -> String {
+        match self {
+            Scalar::Bool(b) => b.to_string(),
+            Scalar::Integer(n) => n.to_string(),
+            Scalar::Float(x) => x.to_string(),
+            Scalar::String(s) => s,
+        }
+    }
+}
+
+/// Gets the path of the configuration file: `$XDG_CONFIG_HOME/bkup` (or
+/// `~/.config/bkup`) on Linux, `~/Library/Application Support/bkup` on macOS
+/// and `%APPDATA%\bkup` on Windows.
+pub fn path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os(CONFIG_VAR) {
+        return Some(PathBuf::from(path));
+    }
+    let dir = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME")
+            .map(|home| Path::new(&home).join("Library/Application Support"))
+    } else {
+        env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| Path::new(dir).is_absolute())
+            .map(PathBuf::from)
+            .or_else(|| {
+                env::var_os("HOME").map(|home| Path::new(&home).join(".config"))
+            })
+    };
+    dir.map(|dir| dir.join("bkup").join("config.toml"))
+}
+
+/// Loads the configuration file, if any, setting the environment variables
+/// of its options that are not set yet.
+pub fn load() -> Result<(), Error> {
+    let path = match path() {
+        Some(path) if path.is_file() => path,
+        _ => return Ok(()),
+    };
+    let text = fs::read_to_string(&path)?;
+    for (key, value) in parse(&text).map_err(|e| {
+        Error::Parse(format!("Invalid configuration {:?}: {}", path, e))
+    })? {
+        let var = variable(&key);
+        if env::var_os(&var).is_none() {
+            env::set_var(var, value);
+        }
+    }
+    Ok(())
+}
+
+/// Gets the environment variable of the given key, that is the long name of
+/// an option.
+pub fn variable(key: &str) -> String {
+    if key == LOG_KEY {
+        "RUST_LOG".to_string()
+    } else {
+        format!("BKUP_{}", key.to_uppercase().replace('-', "_"))
+    }
+}
+
+/// Parses the given value of a flag, if valid.
+pub fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "" | "0" | "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parses the given flat TOML document into its keys (with `-` as word
+/// separator) and values.
+fn parse(text: &str) -> Result<Vec<(String, String)>, toml::de::Error> {
+    let config: Config = toml::from_str(text)?;
+    Ok(config
+        .options
+        .into_iter()
+        .map(|(key, value)| (key.replace('_', "-"), value.into_var()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let text = r#"
+            # machine-wide defaults
+            accuracy = 4000 # FAT
+            notify_url = "http://hc-ping.com/\"id\"" # healthchecks
+            keep-going = true
+            log = "debug"
+        "#;
+        let pairs = parse(text).expect("Invalid configuration");
+        let pair =
+            |key: &str, value: &str| (key.to_string(), value.to_string());
+        assert_eq!(
+            pairs,
+            vec![
+                pair("accuracy", "4000"),
+                pair("keep-going", "true"),
+                pair("log", "debug"),
+                pair("notify-url", "http://hc-ping.com/\"id\""),
+            ]
+        );
+        assert_eq!(variable("notify-url"), "BKUP_NOTIFY_URL");
+        assert_eq!(variable("log"), "RUST_LOG");
+        assert_eq!(parse_bool("TRUE"), Some(true));
+        assert_eq!(parse_bool("off"), Some(false));
+        assert_eq!(parse_bool("2"), None);
+        assert!(parse("accuracy").is_err());
+        assert!(parse("[update]\naccuracy = 1").is_err());
+        assert!(parse("url = \"http://").is_err());
+    }
+}
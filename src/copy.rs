@@ -0,0 +1,869 @@
+use crate::{
+    cancel::CancellationToken,
+    device,
+    error::BkupError,
+    event::{CopyProgress, EventHandler},
+    hash::{self, Digest, HashAlgorithm},
+    metadata::TEMP_SUFFIX,
+    pause,
+    rate::RateLimiter,
+    resume,
+    transfer::{Transfer, TransferContext},
+};
+use log::*;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+/// Size of the buffer used by the buffered copy when not given.
+const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+/// Alignment of the buffer, offsets and lengths required by direct I/O.
+const DIRECT_IO_ALIGNMENT: usize = 4096;
+/// Maximum delay between two attempts to copy a file.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Enumerates the engines that can be used to copy the file contents.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Engine {
+    /// Synchronous copy provided by the standard library.
+    #[default]
+    Std,
+    /// Asynchronous copy that keeps multiple reads and writes in flight with
+    /// io_uring (Linux only, requires the `io_uring` feature).
+    IoUring,
+}
+
+impl FromStr for Engine {
+    type Err = BkupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "std" => Ok(Engine::Std),
+            "io_uring" => Ok(Engine::IoUring),
+            _ => {
+                Err(BkupError::Invalid(format!("Invalid copy engine {:?}", s)))
+            }
+        }
+    }
+}
+
+/// Represents the options used to copy the files into the destination.
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    /// When set try to clone the source file (sharing its data blocks) before
+    /// falling back to a regular copy.
+    pub reflink: bool,
+    /// Engine used to copy the file contents when they are not cloned.
+    pub engine: Engine,
+    /// Size of the buffer used to copy the file contents, when set the files
+    /// are copied with an explicit loop instead of `fs::copy`.
+    pub buffer_size: Option<usize>,
+    /// When set bypass the page cache while copying the file contents, if
+    /// supported by the filesystem.
+    pub direct_io: bool,
+    /// Rate limiter shared by all the copies, when the bandwidth is limited.
+    pub limiter: Option<Arc<RateLimiter>>,
+    /// Maximum number of bytes copied by the plan, after which no other
+    /// action is started.
+    pub max_transfer: Option<u64>,
+    /// When set flush each copied file and its parent directory to the
+    /// storage device.
+    pub fsync: bool,
+    /// When set the content of the replaced destination files is overwritten
+    /// before they are replaced.
+    pub shred: bool,
+    /// When set the content of each copy is flushed, read again from the
+    /// storage device and compared with the source before replacing the
+    /// destination file.
+    pub verify: bool,
+    /// Algorithm of the digests compared to verify the copies.
+    pub hash: HashAlgorithm,
+    /// When set the copies of large files can be resumed after an
+    /// interruption, keeping their partial content and a checkpoint.
+    pub partial: bool,
+    /// Number of times a copy that failed with a transient error is retried.
+    pub retries: u32,
+    /// Delay before the first retry, doubled at every following retry.
+    pub retry_delay: Duration,
+    /// Number of times a copy whose source file is locked by another process
+    /// is retried.
+    pub locked_retries: u32,
+    /// When set the actions that fail don't stop the others from being
+    /// applied.
+    pub keep_going: bool,
+    /// Number of files copied at the same time (at least one).
+    pub jobs: usize,
+    /// Maximum number of files copied at the same time from or to the same
+    /// device, if limited.
+    pub device_jobs: Option<usize>,
+    /// Handler of the progress of the copies, that are buffered when set.
+    pub events: Option<EventHandler>,
+    /// Token checked before each buffer is copied.
+    pub cancel: CancellationToken,
+    /// Transfer used instead of the local copy, if any.
+    pub transfer: Option<Arc<dyn Transfer>>,
+}
+
+/// Copies the source file into the destination path according to the given
+/// options, overwriting the destination file if it already exists. The copies
+/// that fail with a transient error, or because the source file is locked,
+/// are retried with exponential backoff. Returns the digest of the copy, if
+/// it was verified.
+pub fn copy(
+    source: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Result<Option<Digest>, BkupError> {
+    let mut delay = options.retry_delay;
+    for attempt in 1.. {
+        match copy_once(source, dest, options) {
+            Err(e)
+                if !options.cancel.is_cancelled()
+                    && attempt <= retries(&e, options) =>
+            {
+                warn!(
+                    "Cannot copy {:?} ({}), retrying in {:?} ({}/{})",
+                    source,
+                    e,
+                    delay,
+                    attempt,
+                    retries(&e, options)
+                );
+                thread::sleep(delay);
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+    unreachable!("The copy attempts are unbounded")
+}
+
+/// Gets the number of times a copy that failed with the given error is
+/// retried.
+fn retries(error: &BkupError, options: &CopyOptions) -> u32 {
+    if is_locked(error) {
+        options.locked_retries
+    } else if is_transient(error) {
+        options.retries
+    } else {
+        0
+    }
+}
+
+/// Returns true if the given error is caused by a file opened exclusively
+/// (or locked) by another process.
+pub fn is_locked(error: &BkupError) -> bool {
+    error
+        .io_error()
+        .and_then(io::Error::raw_os_error)
+        .is_some_and(is_locked_os_error)
+}
+
+/// Returns true if the given OS error code is a sharing or lock violation.
+#[cfg(windows)]
+fn is_locked_os_error(code: i32) -> bool {
+    code == 32 || code == 33
+}
+
+/// The files cannot be opened exclusively on this platform, where the locks
+/// are only advisory.
+#[cfg(not(windows))]
+fn is_locked_os_error(_code: i32) -> bool {
+    false
+}
+
+/// Returns true if the given error may not occur again when retrying the
+/// operation, as the errors of busy or unreachable network shares.
+fn is_transient(error: &BkupError) -> bool {
+    let error = match error.io_error() {
+        Some(error) => error,
+        None => return false,
+    };
+    match error.kind() {
+        io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted => true,
+        _ => error.raw_os_error().is_some_and(is_transient_os_error),
+    }
+}
+
+/// Returns true if the given OS error code is a transient error.
+#[cfg(unix)]
+fn is_transient_os_error(code: i32) -> bool {
+    code == libc::EBUSY || code == libc::ETXTBSY
+}
+
+/// Returns true if the given OS error code is a transient error (network name
+/// deleted and semaphore timeout).
+#[cfg(windows)]
+fn is_transient_os_error(code: i32) -> bool {
+    [64, 121].contains(&code)
+}
+
+/// No OS error code is known to be transient on this platform.
+#[cfg(not(any(unix, windows)))]
+fn is_transient_os_error(_code: i32) -> bool {
+    false
+}
+
+/// Copies the source file into the destination path according to the given
+/// options, overwriting the destination file if it already exists.
+///
+/// The content is copied into a temporary file in the same directory, that is
+/// then renamed over the destination path, so that an interrupted copy never
+/// leaves a truncated destination file. When partial copies are kept, the
+/// temporary file of a large file is kept on failure to resume the copy later.
+fn copy_once(
+    source: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Result<Option<Digest>, BkupError> {
+    let temp = temp_path(dest);
+    let resumable = options.transfer.is_none()
+        && options.partial
+        && fs::metadata(source).is_ok_and(|m| m.len() >= resume::MIN_SIZE);
+    let result =
+        copy_into(source, dest, &temp, resumable, options).and_then(|()| {
+            if options.fsync || options.verify {
+                // data must be durable before the file is visible under its
+                // name, and written back before its cached pages are dropped
+                let file = OpenOptions::new().write(true).open(&temp)?;
+                file.sync_all()?;
+                if options.verify {
+                    // the copy is read back from the storage device, not
+                    // from the page cache that would hide its corruptions
+                    drop_cache(&file)?;
+                }
+            }
+            let digest = if options.verify {
+                Some(verify(source, &temp, options.hash)?)
+            } else {
+                None
+            };
+            if options.shred {
+                shred(dest)?;
+            }
+            fs::rename(&temp, dest)?;
+            if options.fsync {
+                sync_parent(dest)?;
+            }
+            Ok(digest)
+        });
+    if result.is_err() && !resumable {
+        // the temporary file may not exist if the copy failed early
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Compares the digest of the given copy with the one of its source, removing
+/// the copy if they differ so that a partial copy is not resumed from it.
+/// Returns the digest of the copy.
+fn verify(
+    source: &Path,
+    copy: &Path,
+    algorithm: HashAlgorithm,
+) -> Result<Digest, BkupError> {
+    debug!("Verifying the copy of {:?}", source);
+    // the source and the copy are hashed at the same time
+    let (source_digest, copy_digest) = thread::scope(|scope| {
+        let source = scope.spawn(|| hash::hash_file(source, algorithm));
+        let copy = hash::hash_file(copy, algorithm);
+        let source = source.join().expect("The hashing thread panicked");
+        (source, copy)
+    });
+    let copy_digest = copy_digest?;
+    if source_digest? == copy_digest {
+        return Ok(copy_digest);
+    }
+    let _ = fs::remove_file(copy);
+    Err(BkupError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "The copy differs from the source",
+    )))
+}
+
+/// Evicts the pages of the given file, that must have been flushed, from the
+/// page cache so that they are read again from the storage device.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn drop_cache(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let result = unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED)
+    };
+    match result {
+        0 => Ok(()),
+        code => Err(io::Error::from_raw_os_error(code)),
+    }
+}
+
+/// The page cache cannot be dropped for a single file on this platform.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd"
+)))]
+fn drop_cache(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Flushes the directory containing the given path, so that the creation or
+/// renaming of its entries is durable.
+#[cfg(unix)]
+pub fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => File::open(parent)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// Directories cannot be flushed on this platform, where the metadata is
+/// written with the files.
+#[cfg(not(unix))]
+pub fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Gets the path of the temporary file used to replace the given path.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(TEMP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Overwrites the content of the given file with zeros and flushes it to the
+/// storage device, before the file is replaced or removed. Nothing is done if
+/// the file doesn't exist, or if its content is shared with other hard links.
+///
+/// This is ineffective on SSDs, that remap the written blocks, and on
+/// copy-on-write filesystems (such as btrfs, ZFS and APFS), that write the
+/// zeros elsewhere.
+pub fn shred(path: &Path) -> Result<(), BkupError> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if metadata.nlink() > 1 {
+            warn!("Not shredding {:?}, that has other hard links", path);
+            return Ok(());
+        }
+    }
+    debug!("Shredding {:?}", path);
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    let zeros = vec![0; DEFAULT_BUFFER_SIZE];
+    let mut left = metadata.len();
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..n])?;
+        left -= n as u64;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Returns true if the given path is a temporary file left by a copy.
+pub fn is_temp(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(TEMP_SUFFIX))
+}
+
+/// Copies the source file into the temporary file used to replace the
+/// destination path, with the transfer or the local copy given by the
+/// options.
+fn copy_into(
+    source: &Path,
+    dest: &Path,
+    temp: &Path,
+    resumable: bool,
+    options: &CopyOptions,
+) -> Result<(), BkupError> {
+    if let Some(transfer) = &options.transfer {
+        debug!("Transferring {:?} with {}", source, transfer.name());
+        let size = fs::metadata(source)?.len();
+        let progress = CopyProgress::new(options.events.as_ref(), dest, size);
+        let mut context = TransferContext::new(progress, &options.cancel);
+        return transfer.transfer(source, temp, &mut context);
+    }
+    if options.reflink {
+        match reflink(source, temp) {
+            Ok(()) => {
+                debug!("Cloned {:?} to {:?}", source, temp);
+                return Ok(());
+            }
+            Err(e) => {
+                debug!("Cannot clone {:?} ({}), copying it instead", source, e)
+            }
+        }
+    }
+    let size = fs::metadata(source)?.len();
+    let mut progress = CopyProgress::new(options.events.as_ref(), dest, size);
+    if resumable {
+        return resume::copy(
+            source,
+            dest,
+            temp,
+            options.limiter.as_deref(),
+            &options.cancel,
+            &mut progress,
+        );
+    }
+    // the options that need to see every buffer rule out both the server side
+    // copy and fs::copy, that copy the whole file at once
+    let buffered = options.buffer_size.is_some()
+        || options.direct_io
+        || options.limiter.is_some()
+        || options.events.is_some();
+    if !buffered
+        && matches!(options.engine, Engine::Std)
+        && device::same_remote(source, temp)
+    {
+        // copy_file_range (NFS 4.2 and SMB 3) and CopyFileEx (SMB) let the
+        // server copy the file without sending its content to this machine
+        debug!("Copying {:?} on the server", source);
+        fs::copy(source, temp)?;
+        progress.add(size);
+        return Ok(());
+    }
+    let dest = temp;
+    match options.engine {
+        Engine::Std if buffered => {
+            copy_buffered(source, dest, options, &mut progress)?
+        }
+        // on Linux this already uses copy_file_range when possible
+        Engine::Std => fs::copy(source, dest).map(|_| ())?,
+        Engine::IoUring => copy_io_uring(source, dest, options)?,
+    }
+    Ok(())
+}
+
+/// Copies the source file into the destination path through a buffer of the
+/// given size, bypassing the page cache and limiting the bandwidth according
+/// to the given options, and reporting the copied bytes to the progress.
+fn copy_buffered(
+    source: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+    progress: &mut CopyProgress,
+) -> io::Result<()> {
+    let buffer_size = options.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE);
+    let (mut src, mut dst, mut direct) = if options.direct_io {
+        match direct::open(source, dest) {
+            Ok((src, dst)) => (src, dst, true),
+            Err(e) => {
+                debug!("Cannot use direct I/O for {:?} ({})", source, e);
+                (File::open(source)?, File::create(dest)?, false)
+            }
+        }
+    } else {
+        (File::open(source)?, File::create(dest)?, false)
+    };
+
+    // direct I/O requires the buffer address and length to be aligned
+    let size = if direct {
+        let blocks = (buffer_size.max(1) - 1) / DIRECT_IO_ALIGNMENT + 1;
+        blocks * DIRECT_IO_ALIGNMENT
+    } else {
+        buffer_size.max(1)
+    };
+    let mut raw = vec![0u8; size + DIRECT_IO_ALIGNMENT];
+    let offset = raw.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+    let buffer = &mut raw[offset..offset + size];
+
+    loop {
+        pause::wait(&options.cancel);
+        if options.cancel.is_cancelled() {
+            return Err(io::Error::other("Copy cancelled"));
+        }
+        let n = match src.read(buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(limiter) = &options.limiter {
+            limiter.acquire(n as u64);
+        }
+        // the last block (or a short read) leaves the offsets unaligned
+        if direct && n % DIRECT_IO_ALIGNMENT != 0 {
+            direct::disable(&src)?;
+            direct::disable(&dst)?;
+            direct = false;
+        }
+        dst.write_all(&buffer[..n])?;
+        progress.add(n as u64);
+    }
+    dst.set_permissions(src.metadata()?.permissions())
+}
+
+/// Direct I/O with O_DIRECT, that requires aligned buffers and offsets.
+#[cfg(target_os = "linux")]
+mod direct {
+    use std::{
+        fs::{File, OpenOptions},
+        io,
+        os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+        path::Path,
+    };
+
+    /// Opens the source and destination files bypassing the page cache.
+    pub fn open(source: &Path, dest: &Path) -> io::Result<(File, File)> {
+        let src = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(source)?;
+        let dst = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(dest)?;
+        Ok((src, dst))
+    }
+
+    /// Disables direct I/O on the given file.
+    pub fn disable(file: &File) -> io::Result<()> {
+        let fd = file.as_raw_fd();
+        // safe since the file descriptor is valid for the whole call
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            if flags == -1
+                || libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Direct I/O with F_NOCACHE, that has no alignment requirements.
+#[cfg(target_os = "macos")]
+mod direct {
+    use std::{fs::File, io, os::unix::io::AsRawFd, path::Path};
+
+    /// Opens the source and destination files bypassing the page cache.
+    pub fn open(source: &Path, dest: &Path) -> io::Result<(File, File)> {
+        let src = File::open(source)?;
+        let dst = File::create(dest)?;
+        for file in &[&src, &dst] {
+            // safe since the file descriptor is valid for the whole call
+            if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_NOCACHE, 1) }
+                == -1
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok((src, dst))
+    }
+
+    /// Nothing to do since the offsets don't need to be aligned.
+    pub fn disable(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Direct I/O is not supported on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod direct {
+    use std::{fs::File, io, path::Path};
+
+    /// Always fails since direct I/O is not supported.
+    pub fn open(_source: &Path, _dest: &Path) -> io::Result<(File, File)> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Direct I/O is not supported on this platform",
+        ))
+    }
+
+    /// Nothing to do since direct I/O is never enabled.
+    pub fn disable(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Copies the source file into the destination path with io_uring.
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+fn copy_io_uring(
+    source: &Path,
+    dest: &Path,
+    options: &CopyOptions,
+) -> Result<(), BkupError> {
+    crate::uring::copy(source, dest, options.limiter.as_deref())?;
+    Ok(())
+}
+
+/// The io_uring engine is not available in this build.
+#[cfg(not(all(target_os = "linux", feature = "io_uring")))]
+fn copy_io_uring(
+    _source: &Path,
+    _dest: &Path,
+    _options: &CopyOptions,
+) -> Result<(), BkupError> {
+    Err(BkupError::Unsupported(
+        "The io_uring engine requires Linux and the io_uring feature"
+            .to_string(),
+    ))
+}
+
+/// Clones the source file into the destination path with the FICLONE ioctl,
+/// supported by btrfs and XFS.
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, dest: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // _IOW(0x94, 9, int)
+    const FICLONE: u64 = 0x4004_9409;
+
+    let src = File::open(source)?;
+    let dst = File::create(dest)?;
+    // safe since both file descriptors are valid for the whole call
+    let ret =
+        unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    dst.set_permissions(src.metadata()?.permissions())
+}
+
+/// Clones the source file into the destination path with clonefile, supported
+/// by APFS.
+#[cfg(target_os = "macos")]
+fn reflink(source: &Path, dest: &Path) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let to_cstring = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let src = to_cstring(source)?;
+    let dst = to_cstring(dest)?;
+    // clonefile fails if the destination already exists
+    if dest.exists() {
+        fs::remove_file(dest)?;
+    }
+    // safe since both paths are valid NUL terminated strings
+    if unsafe { libc::clonefile(src.as_ptr(), dst.as_ptr(), 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Cloning files is not supported on this platform.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_source: &Path, _dest: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Cloning files is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_copy_with_reflink() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::write(&source, "content").expect("Cannot write file");
+        fs::write(&dest, "previous content").expect("Cannot write file");
+
+        // the copy must succeed whether the filesystem supports cloning or not
+        let options = CopyOptions {
+            reflink: true,
+            ..CopyOptions::default()
+        };
+        copy(&source, &dest, &options).expect("Cannot copy file");
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "content");
+        assert!(!temp_path(&dest).exists());
+    }
+
+    #[test]
+    fn test_copy_with_transfer() {
+        #[derive(Debug)]
+        struct Uppercase;
+
+        impl Transfer for Uppercase {
+            fn name(&self) -> &str {
+                "uppercase"
+            }
+
+            fn transfer(
+                &self,
+                source: &Path,
+                temp: &Path,
+                context: &mut TransferContext,
+            ) -> Result<(), BkupError> {
+                context.check()?;
+                let content = fs::read_to_string(source)?.to_uppercase();
+                fs::write(temp, &content)?;
+                context.add(content.len() as u64);
+                Ok(())
+            }
+        }
+
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::write(&source, "content").expect("Cannot write file");
+
+        let options = CopyOptions {
+            transfer: Some(Arc::new(Uppercase)),
+            ..CopyOptions::default()
+        };
+        copy(&source, &dest, &options).expect("Cannot copy file");
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "CONTENT");
+        assert!(!temp_path(&dest).exists());
+
+        // a copy that differs from its source is not verified
+        let verified = CopyOptions {
+            verify: true,
+            ..options.clone()
+        };
+        assert!(copy(&source, &dest, &verified).is_err());
+        assert!(!temp_path(&dest).exists());
+        let local = CopyOptions {
+            verify: true,
+            ..CopyOptions::default()
+        };
+        copy(&source, &dest, &local).expect("Cannot copy file");
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "content");
+
+        // the transfer stops once the copy is cancelled
+        options.cancel.cancel();
+        let result = copy(&source, &root.join("cancelled"), &options);
+        assert!(matches!(result, Err(BkupError::Cancelled)));
+        assert!(!root.join("cancelled").exists());
+    }
+
+    #[test]
+    fn test_copy_failure_keeps_dest() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        let dest = root.join("dest");
+        fs::write(&dest, "previous content").expect("Cannot write file");
+
+        // a failed copy must leave neither a temporary file nor a truncated
+        // destination
+        let options = CopyOptions::default();
+        assert!(copy(&root.join("missing"), &dest, &options).is_err());
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "previous content");
+        assert!(!temp_path(&dest).exists());
+        assert!(is_temp(&temp_path(&dest)));
+        assert!(!is_temp(&dest));
+    }
+
+    #[test]
+    fn test_shred() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        let (source, dest) = (root.join("source"), root.join("dest"));
+        fs::write(&source, "new").expect("Cannot write file");
+        fs::write(&dest, "sensitive").expect("Cannot write file");
+
+        // the content shared with another hard link is kept
+        #[cfg(unix)]
+        {
+            let link = root.join("link");
+            fs::hard_link(&dest, &link).expect("Cannot link file");
+            shred(&dest).expect("Cannot shred file");
+            assert_eq!(fs::read(&dest).unwrap(), b"sensitive");
+            fs::remove_file(link).expect("Cannot remove link");
+        }
+        shred(&dest).expect("Cannot shred file");
+        assert_eq!(fs::read(&dest).unwrap(), [0; 9]);
+        shred(&root.join("missing")).expect("Cannot shred missing file");
+
+        let options = CopyOptions {
+            shred: true,
+            ..CopyOptions::default()
+        };
+        copy(&source, &dest, &options).expect("Cannot copy file");
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "new");
+        fs::remove_dir_all(root).expect("Cannot remove test directory");
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        let error = |kind| BkupError::from(io::Error::from(kind));
+        assert!(is_transient(&error(io::ErrorKind::TimedOut)));
+        assert!(is_transient(&error(io::ErrorKind::WouldBlock)));
+        assert!(!is_transient(&error(io::ErrorKind::NotFound)));
+        assert!(!is_transient(&BkupError::Invalid(
+            "Invalid copy".to_string()
+        )));
+
+        // permanent errors are not retried
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        let options = CopyOptions {
+            retries: 3,
+            retry_delay: Duration::from_secs(60),
+            ..CopyOptions::default()
+        };
+        assert!(
+            copy(&root.join("missing"), &root.join("dest"), &options).is_err()
+        );
+    }
+
+    #[test]
+    fn test_locked_errors() {
+        // sharing violations are only reported by Windows, and are retried
+        // separately from the transient errors
+        let error = BkupError::from(io::Error::from_raw_os_error(32));
+        assert_eq!(is_locked(&error), cfg!(windows));
+        assert!(!is_transient(&error));
+        let options = CopyOptions {
+            retries: 3,
+            locked_retries: 5,
+            ..CopyOptions::default()
+        };
+        let expected = if cfg!(windows) { 5 } else { 0 };
+        assert_eq!(retries(&error, &options), expected);
+        let error = BkupError::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(retries(&error, &options), 3);
+    }
+
+    #[test]
+    fn test_copy_buffered() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        let source = root.join("source");
+        let dest = root.join("dest");
+        // content spanning more buffers, with a partial last block
+        let content: Vec<u8> = (0..DIRECT_IO_ALIGNMENT * 3 + 123)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        fs::write(&source, &content).expect("Cannot write file");
+
+        // direct I/O falls back to the page cache if not supported
+        for direct_io in &[false, true] {
+            let options = CopyOptions {
+                buffer_size: Some(5000),
+                direct_io: *direct_io,
+                ..CopyOptions::default()
+            };
+            copy(&source, &dest, &options).expect("Cannot copy file");
+            assert_eq!(fs::read(&dest).unwrap(), content);
+        }
+    }
+}
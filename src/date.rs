@@ -0,0 +1,131 @@
+use crate::error::BkupError;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Parses a UTC date such as `2021-03-14`, optionally followed by a time such
+/// as `2021-03-14T15:09:26` (or with a space instead of the `T`).
+pub fn parse_date(s: &str) -> Result<SystemTime, BkupError> {
+    let invalid = || BkupError::Invalid(format!("Invalid date {:?}", s));
+    let s = s.trim();
+    let (date, time) = match s.find(['T', ' ']) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let fields = |s: &str, separator| -> Result<Vec<u64>, BkupError> {
+        s.split(separator)
+            .map(|field| field.parse::<u64>().map_err(|_| invalid()))
+            .collect()
+    };
+
+    let date = fields(date, '-')?;
+    let (year, month, day) = match date[..] {
+        [year, month, day]
+            if year >= 1970
+                && (1..=12).contains(&month)
+                && (1..=days_in_month(year, month)).contains(&day) =>
+        {
+            (year, month, day)
+        }
+        _ => return Err(invalid()),
+    };
+    let seconds = match time.map(|time| fields(time, ':')).transpose()? {
+        None => 0,
+        Some(time) => match time[..] {
+            [h, m] if h < 24 && m < 60 => h * 3600 + m * 60,
+            [h, m, s] if h < 24 && m < 60 && s < 60 => h * 3600 + m * 60 + s,
+            _ => return Err(invalid()),
+        },
+    };
+    let days = days_since_epoch(year, month, day);
+    Ok(UNIX_EPOCH + Duration::from_secs(days * 86400 + seconds))
+}
+
+/// Formats the given time as a UTC date and time such as
+/// `2021-03-14T15:09:26Z`, rounded down to the second.
+#[cfg(feature = "update")]
+pub fn format_date(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut days = seconds / 86400;
+    let mut year = 1970;
+    while days >= if is_leap(year) { 366 } else { 365 } {
+        days -= if is_leap(year) { 366 } else { 365 };
+        year += 1;
+    }
+    let mut month = 1;
+    while days >= days_in_month(year, month) {
+        days -= days_in_month(year, month);
+        month += 1;
+    }
+    let time = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        days + 1,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Returns true if the given year is a leap year.
+fn is_leap(year: u64) -> bool {
+    year.is_multiple_of(4)
+        && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+/// Gets the number of days of the given month (from 1 to 12).
+fn days_in_month(year: u64, month: u64) -> u64 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Gets the number of days from the UNIX epoch to the given date.
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    let years: u64 = (1970..year)
+        .map(|year| if is_leap(year) { 366 } else { 365 })
+        .sum();
+    let months: u64 = (1..month).map(|month| days_in_month(year, month)).sum();
+    years + months + day - 1
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        let seconds = |s| {
+            parse_date(s)
+                .expect("Cannot parse date")
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        };
+        assert_eq!(seconds("1970-01-01"), 0);
+        assert_eq!(seconds("2000-03-01"), 951868800);
+        assert_eq!(seconds("2021-03-14T15:09:26"), 1615734566);
+        assert_eq!(seconds("2021-03-14 15:09"), 1615734540);
+        assert!(parse_date("2021-02-29").is_err());
+        assert!(parse_date("2021-13-01").is_err());
+        assert!(parse_date("2021-03-14T24:00").is_err());
+        assert!(parse_date("yesterday").is_err());
+    }
+
+    #[cfg(feature = "update")]
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        for date in ["2021-03-14T15:09:26", "2024-02-29T23:59:59"] {
+            let time = parse_date(date).expect("Cannot parse date");
+            assert_eq!(format_date(time), format!("{}Z", date));
+        }
+    }
+}
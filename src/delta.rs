@@ -0,0 +1,267 @@
+//! Owned delta between a source and a destination directory, that doesn't
+//! borrow the visited entries and can be kept, sent to other threads and
+//! serialized.
+
+use crate::{
+    cancel::CancellationToken,
+    entry::Compare,
+    filter::Predicate,
+    json::{self, Value},
+    names::NameMatching,
+};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::Metadata,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Represents the options used to compare a source and a destination
+/// directory.
+#[derive(Debug, Clone, Default)]
+pub struct DiffOptions {
+    /// Accuracy used to consider a source file newer than its destination.
+    pub accuracy: Duration,
+    /// When set the files whose modification times differ by one hour
+    /// (within the accuracy) are considered equal.
+    pub ignore_dst: bool,
+    /// Criterion used to tell whether a source file changed.
+    pub compare: Compare,
+    /// When set parse the `.gitignore` file of the visited directories.
+    pub ignore: bool,
+    /// Token used to cancel the visit of the directories.
+    pub cancel: CancellationToken,
+    /// Predicates that decide which entries are included.
+    pub predicates: Vec<Predicate>,
+    /// How the names of the source entries are matched with the ones of the
+    /// destination.
+    pub names: NameMatching,
+    /// Number of largest files to copy reported by the estimate.
+    pub largest: usize,
+}
+
+impl DiffOptions {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        DiffOptions::default()
+    }
+
+    /// Sets the accuracy used to consider a source file newer than its
+    /// destination.
+    pub fn accuracy(mut self, accuracy: Duration) -> Self {
+        self.accuracy = accuracy;
+        self
+    }
+
+    /// Sets whether the modification times that differ by one hour are
+    /// considered equal.
+    pub fn ignore_dst(mut self, ignore_dst: bool) -> Self {
+        self.ignore_dst = ignore_dst;
+        self
+    }
+
+    /// Sets the criterion used to tell whether a source file changed.
+    pub fn compare(mut self, compare: Compare) -> Self {
+        self.compare = compare;
+        self
+    }
+
+    /// Sets whether the `.gitignore` files of the visited directories are
+    /// parsed.
+    pub fn ignore(mut self, ignore: bool) -> Self {
+        self.ignore = ignore;
+        self
+    }
+
+    /// Adds a predicate that decides whether the entry with the given path and
+    /// metadata is included.
+    pub fn filter<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Path, &Metadata) -> bool + Send + Sync + 'static,
+    {
+        self.predicates.push(Predicate::new(predicate));
+        self
+    }
+
+    /// Sets how the names of the source entries are matched with the ones of
+    /// the destination.
+    pub fn names(mut self, names: NameMatching) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Sets the number of largest files to copy reported by the estimate.
+    pub fn largest(mut self, largest: usize) -> Self {
+        self.largest = largest;
+        self
+    }
+
+    /// Sets the token used to cancel the visit of the directories.
+    pub fn cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+}
+
+/// Enumerates the differences of a destination entry from its source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum Change {
+    /// The source directory (with all its content of the given size in bytes)
+    /// doesn't exist in the destination.
+    MissingDir {
+        source: PathBuf,
+        dest: PathBuf,
+        size: u64,
+    },
+    /// The source file doesn't exist in the destination.
+    MissingFile {
+        source: PathBuf,
+        dest: PathBuf,
+        size: u64,
+    },
+    /// The source file is newer than its destination.
+    NewerFile {
+        source: PathBuf,
+        dest: PathBuf,
+        size: u64,
+    },
+}
+
+impl Change {
+    /// Gets the source path of the changed entry.
+    pub fn source(&self) -> &Path {
+        match self {
+            Change::MissingDir { source, .. }
+            | Change::MissingFile { source, .. }
+            | Change::NewerFile { source, .. } => source,
+        }
+    }
+
+    /// Gets the destination path of the changed entry.
+    pub fn dest(&self) -> &Path {
+        match self {
+            Change::MissingDir { dest, .. }
+            | Change::MissingFile { dest, .. }
+            | Change::NewerFile { dest, .. } => dest,
+        }
+    }
+
+    /// Gets the number of bytes to copy to update the destination entry.
+    pub fn size(&self) -> u64 {
+        match self {
+            Change::MissingDir { size, .. }
+            | Change::MissingFile { size, .. }
+            | Change::NewerFile { size, .. } => *size,
+        }
+    }
+
+    /// Gets the type of the change.
+    fn name(&self) -> &'static str {
+        match self {
+            Change::MissingDir { .. } => "missing_dir",
+            Change::MissingFile { .. } => "missing_file",
+            Change::NewerFile { .. } => "newer_file",
+        }
+    }
+
+    /// Serializes self into a JSON object, whose `type` member is the type of
+    /// the change.
+    pub fn to_json(&self) -> Value {
+        let path = |p: &Path| Value::from(p.to_string_lossy().into_owned());
+        json::object(vec![
+            ("type", Value::from(self.name())),
+            ("source", path(self.source())),
+            ("dest", path(self.dest())),
+            ("size", Value::from(self.size())),
+        ])
+    }
+}
+
+/// Represents the amount of data an update of a destination directory would
+/// transfer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Estimate {
+    /// Number of files to copy.
+    pub files: usize,
+    /// Number of bytes to copy.
+    pub bytes: u64,
+    /// Source paths and sizes of the largest files to copy, sorted by
+    /// decreasing size.
+    pub largest: Vec<(PathBuf, u64)>,
+}
+
+impl Estimate {
+    /// Gets the time needed to transfer the bytes at the given bandwidth in
+    /// bytes per second, if not zero.
+    pub fn duration(&self, bandwidth: u64) -> Option<Duration> {
+        if bandwidth == 0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(
+            self.bytes as f64 / bandwidth as f64,
+        ))
+    }
+}
+
+/// Enumerates how the copy of a source file in the destination directory
+/// differs from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum MismatchKind {
+    /// The source file has no copy in the destination.
+    Missing,
+    /// The copy has a different content than the source file.
+    Different,
+}
+
+impl MismatchKind {
+    /// Gets the name of the kind.
+    pub fn name(&self) -> &'static str {
+        match self {
+            MismatchKind::Missing => "missing",
+            MismatchKind::Different => "different",
+        }
+    }
+}
+
+/// Represents a source file whose copy in the destination directory is
+/// missing or different.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Mismatch {
+    /// Path of the file, relative to the source directory.
+    pub path: PathBuf,
+    /// How the copy differs from the source file.
+    pub status: MismatchKind,
+}
+
+/// Represents all the differences of a destination directory from its source,
+/// sorted by destination path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Delta {
+    /// Changes of the destination entries.
+    pub changes: Vec<Change>,
+}
+
+impl Delta {
+    /// Returns true if the destination is up to date.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Gets the number of bytes to copy to update the destination.
+    pub fn bytes(&self) -> u64 {
+        self.changes.iter().map(Change::size).sum()
+    }
+
+    /// Serializes self into a JSON array of changes.
+    pub fn to_json(&self) -> Value {
+        Value::Array(self.changes.iter().map(Change::to_json).collect())
+    }
+}
@@ -0,0 +1,53 @@
+//! Desktop notification shown once an update is over, for interactive use.
+
+use crate::{error::BkupError, format_size};
+use log::*;
+use notify_rust::Notification;
+
+/// Shows the desktop notification of an update with the given status, number
+/// of copied bytes and failed actions, and fatal error if any. Nothing is
+/// shown if the process doesn't run in a graphical session.
+pub fn notify(
+    status: &str,
+    bytes: u64,
+    failures: usize,
+    error: Option<&BkupError>,
+) -> Result<(), BkupError> {
+    if !has_session() {
+        debug!("No graphical session, skipping the desktop notification");
+        return Ok(());
+    }
+    let copied = format!("{} copied", format_size(bytes));
+    let (summary, body) = match status {
+        "completed" => ("Backup complete", format!("{}, 0 errors", copied)),
+        "cancelled" => ("Backup cancelled", copied),
+        _ if failures > 0 => (
+            "Backup completed with errors",
+            format!("{}, {} errors", copied, failures),
+        ),
+        _ => (
+            "Backup failed",
+            error.map_or_else(String::new, |e| e.to_string()),
+        ),
+    };
+    Notification::new()
+        .appname("bkup")
+        .summary(summary)
+        .body(&body)
+        .show()
+        .map_err(|e| BkupError::Unsupported(e.to_string()))?;
+    Ok(())
+}
+
+/// Returns true if the process runs in a graphical session.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn has_session() -> bool {
+    use std::env;
+    env::var_os("DISPLAY").is_some() || env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+/// The notifications are always shown on this platform.
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn has_session() -> bool {
+    true
+}
@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    sync::{Condvar, Mutex},
+};
+
+/// Limits the number of concurrent copies that read from or write to each
+/// physical device, so that spinning disks are not thrashed by competing I/O.
+pub struct DeviceLimits {
+    // maximum number of concurrent copies per device, if limited
+    max: Option<usize>,
+    // number of copies in progress for each device
+    busy: Mutex<HashMap<u64, usize>>,
+    // notified every time a copy releases its devices
+    released: Condvar,
+}
+
+impl DeviceLimits {
+    /// Creates new limits with the given maximum number of concurrent copies
+    /// per device, or no limit if not given.
+    pub fn new(max: Option<usize>) -> Self {
+        DeviceLimits {
+            max: max.map(|max| max.max(1)),
+            busy: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Blocks until none of the given devices is at its limit, and takes a
+    /// slot on each of them until the returned guard is dropped.
+    pub fn acquire(&self, devices: Vec<u64>) -> DeviceSlots<'_> {
+        if let Some(max) = self.max {
+            let mut busy = self.busy.lock().expect("Poisoned device limits");
+            while devices
+                .iter()
+                .any(|d| busy.get(d).copied().unwrap_or(0) >= max)
+            {
+                busy =
+                    self.released.wait(busy).expect("Poisoned device limits");
+            }
+            for device in &devices {
+                *busy.entry(*device).or_default() += 1;
+            }
+        }
+        DeviceSlots {
+            limits: self,
+            devices,
+        }
+    }
+}
+
+/// Slots taken on the devices used by a copy, released when dropped.
+pub struct DeviceSlots<'a> {
+    limits: &'a DeviceLimits,
+    devices: Vec<u64>,
+}
+
+impl<'a> Drop for DeviceSlots<'a> {
+    fn drop(&mut self) {
+        if self.limits.max.is_none() {
+            return;
+        }
+        let mut busy = self.limits.busy.lock().expect("Poisoned device limits");
+        for device in &self.devices {
+            if let Some(count) = busy.get_mut(device) {
+                *count -= 1;
+            }
+        }
+        self.limits.released.notify_all();
+    }
+}
+
+/// Gets the devices read and written when copying the source file into the
+/// destination path (the device of its parent directory).
+pub fn devices(source: &Path, dest: &Path) -> Vec<u64> {
+    let mut devices: Vec<u64> = [Some(source), dest.parent()]
+        .iter()
+        .filter_map(|path| path.and_then(device))
+        .collect();
+    devices.dedup();
+    devices
+}
+
+/// Gets the identifier of the device containing the given path.
+#[cfg(unix)]
+fn device(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    fs::metadata(path).ok().map(|metadata| metadata.dev())
+}
+
+/// Device identifiers are not available on this platform.
+#[cfg(not(unix))]
+fn device(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Returns true if the source file and the destination path (its parent
+/// directory) are on the same network share, where the server can copy the
+/// file without sending its content to the client.
+pub fn same_remote(source: &Path, dest: &Path) -> bool {
+    match (share(source), dest.parent().and_then(share)) {
+        (Some(source), Some(dest)) => source == dest,
+        _ => false,
+    }
+}
+
+/// Gets the identifier of the network share (NFS or SMB) containing the
+/// given path, that is its device.
+#[cfg(target_os = "linux")]
+fn share(path: &Path) -> Option<String> {
+    use std::{ffi::CString, mem, os::unix::ffi::OsStrExt};
+
+    // magic numbers of the NFS, SMB and CIFS filesystems
+    const REMOTE_TYPES: [u32; 4] = [0x6969, 0x517b, 0xff53_4d42, 0xfe53_4d42];
+    let c_path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    // safe since the path is a valid NUL terminated string and stat is
+    // initialized by statfs on success
+    let fs_type = unsafe {
+        let mut stat: libc::statfs = mem::zeroed();
+        if libc::statfs(c_path.as_ptr(), &mut stat) == -1 {
+            return None;
+        }
+        stat.f_type as u32
+    };
+    if !REMOTE_TYPES.contains(&fs_type) {
+        return None;
+    }
+    device(path).map(|device| device.to_string())
+}
+
+/// Gets the server and share of the given UNC path (`\\server\share`).
+#[cfg(windows)]
+fn share(path: &Path) -> Option<String> {
+    use std::path::{Component, Prefix};
+
+    match path.components().next()? {
+        Component::Prefix(prefix) => match prefix.kind() {
+            Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+                let share = format!(
+                    "{}\\{}",
+                    server.to_string_lossy(),
+                    share.to_string_lossy()
+                );
+                Some(share.to_lowercase())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Network shares are not detected on this platform.
+#[cfg(not(any(target_os = "linux", windows)))]
+fn share(_path: &Path) -> Option<String> {
+    None
+}
+
+/// Gets the number of bytes available to unprivileged users on the
+/// filesystem containing the given path.
+#[cfg(unix)]
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    use std::{ffi::CString, mem, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // safe since the path is a valid NUL terminated string and stat is
+    // initialized by statvfs on success
+    unsafe {
+        let mut stat: libc::statvfs = mem::zeroed();
+        if libc::statvfs(path.as_ptr(), &mut stat) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+    }
+}
+
+/// Getting the free space is not supported on this platform.
+#[cfg(not(unix))]
+pub fn free_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Getting the free space is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn test_same_remote() {
+        let dir = std::env::temp_dir();
+        assert!(!same_remote(&dir, &dir.join("copy")));
+    }
+
+    #[test]
+    fn test_device_limits() {
+        let limits = DeviceLimits::new(Some(2));
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let _slots = limits.acquire(vec![1]);
+                    let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(n, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+    }
+}
@@ -1,16 +1,123 @@
-use failure::{err_msg, Error};
+#[cfg(feature = "update")]
+use crate::{
+    copy,
+    itemize::{Item, ItemKind},
+    journal, resume,
+};
+use crate::{
+    delta::{Change, Delta},
+    error::BkupError,
+    filter::{Filter, Pin},
+    hash::{self, HashAlgorithm},
+    json::{self, Value},
+    metadata,
+    plan::{Action, Plan},
+    special::{SpecialFiles, SpecialKind},
+};
 use ignore::gitignore::Gitignore;
 use log::*;
 use std::{
     cmp::Ordering,
     collections::HashMap,
-    fmt, fs,
+    fmt, fs, io,
     path::{Path, PathBuf},
-    time::Duration,
+    str::FromStr,
+    sync::{
+        atomic::{self, AtomicUsize},
+        OnceLock,
+    },
+    thread,
+    time::{Duration, UNIX_EPOCH},
 };
 
 type EntryDeltaMap<'a> = HashMap<&'a Path, EntryDelta<'a>>;
 
+/// Offset of the local times stored by FAT after a daylight saving time
+/// change.
+const DST_SHIFT: Duration = Duration::from_secs(3600);
+
+/// Enumerates the criteria used to tell whether a source file changed since
+/// it was copied to the destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compare {
+    /// Only the sizes are compared.
+    Size,
+    /// Only the modification times are compared.
+    Mtime,
+    /// The modification times are compared, and the files of the same time
+    /// but of different sizes are copied as well.
+    #[default]
+    SizeMtime,
+    /// The files of the same size are compared by content, regardless of
+    /// their modification times.
+    Checksum,
+    /// The files of the same size are compared by a sample of their content,
+    /// made of blocks evenly spaced from their start to their end.
+    Sample,
+}
+
+impl Compare {
+    /// Gets the name of the criterion.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compare::Size => "size",
+            Compare::Mtime => "mtime",
+            Compare::SizeMtime => "size+mtime",
+            Compare::Checksum => "checksum",
+            Compare::Sample => "sample",
+        }
+    }
+}
+
+impl FromStr for Compare {
+    type Err = BkupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "size" => Ok(Compare::Size),
+            "mtime" => Ok(Compare::Mtime),
+            "size+mtime" => Ok(Compare::SizeMtime),
+            "checksum" => Ok(Compare::Checksum),
+            "sample" => Ok(Compare::Sample),
+            _ => Err(BkupError::Invalid(format!(
+                "Invalid comparison criterion {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Represents the accuracy used to compare the modification times of the
+/// source and destination files.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Accuracy {
+    /// Criterion used to tell whether a source file changed.
+    pub compare: Compare,
+    /// Algorithm of the digests compared with the checksum criterion.
+    pub hash: HashAlgorithm,
+    /// Maximum difference of the times considered equal.
+    pub window: Duration,
+    /// When set the times that differ by one hour (within the window) are
+    /// also considered equal.
+    pub ignore_dst: bool,
+    /// Offset in nanoseconds of the clock of the destination from the local
+    /// clock, subtracted from the destination times before comparing them.
+    pub skew: i64,
+}
+
+impl Accuracy {
+    /// Creates a new accuracy with the given window.
+    pub fn new(window: Duration) -> Self {
+        Accuracy {
+            compare: Compare::default(),
+            hash: HashAlgorithm::default(),
+            window,
+            ignore_dst: false,
+            skew: 0,
+        }
+    }
+}
+
 /// Represents the delta between the directory entry it points to and the
 /// directory entry it has been compared to.
 #[derive(Debug, PartialEq)]
@@ -36,7 +143,7 @@ impl<'a> DirDelta<'a> {
 
     /// Gets an iterator over the directory entries.
     pub fn entries(&self) -> impl Iterator<Item = &EntryDelta<'a>> {
-        self.entries.iter().map(|(_, e)| e)
+        self.entries.values()
     }
 }
 
@@ -45,20 +152,34 @@ impl<'a> DirDelta<'a> {
 pub struct DirEntry {
     // directory path
     path: PathBuf,
-    // sub-entries where the key is the entry name
+    // sub-entries where the key is the entry name (as matched with the
+    // other directory)
     entries: HashMap<PathBuf, Entry>,
+    // files left in the directory by the interrupted updates, that are not
+    // entries (temporary files, checkpoints and journal)
+    #[cfg(feature = "update")]
+    leftovers: Vec<PathBuf>,
 }
 
 impl DirEntry {
-    /// Creates a new directory entry by visiting it.
+    /// Creates a new directory entry by visiting it, in the given root.
     /// If the `ignore` flags is set and a ".gitignore" file exists in the
     /// directory, it will be parsed to ignore all the specified files and folders.
-    fn new<P: Into<PathBuf>>(path: P, ignore: bool) -> Result<DirEntry, Error> {
+    /// Only the entries accepted by the given filter are included, while the
+    /// files of bkup are never included.
+    fn new<P: Into<PathBuf>>(
+        path: P,
+        root: &Path,
+        ignore: bool,
+        filter: &Filter,
+    ) -> Result<DirEntry, BkupError> {
         let path = path.into();
         if path.is_dir() {
             let mut entry = DirEntry {
                 path,
                 entries: HashMap::new(),
+                #[cfg(feature = "update")]
+                leftovers: Vec::new(),
             };
             let ignore = if ignore {
                 let gitignore: PathBuf =
@@ -68,42 +189,65 @@ impl DirEntry {
             } else {
                 None
             };
-            entry.visit(ignore.as_ref())?;
+            entry.visit(root, ignore.as_ref(), filter)?;
             Ok(entry)
         } else {
-            Err(format_err!("The given directory {:?} does not exist", path))
+            Err(BkupError::Scan {
+                path,
+                source: io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "The directory does not exist",
+                ),
+            })
         }
     }
 
-    /// Copies self into the given destination.
-    fn copy(&self, dest: &Path) -> Result<(), Error> {
-        info!("Copying directory {:?} to {:?}", self.path, dest);
+    /// Adds to the plan the actions needed to copy self into the given
+    /// destination.
+    fn plan_copy(&self, dest: &Path, plan: &mut Plan) {
         // create destination directory
-        if !dest.is_dir() {
-            fs::create_dir(dest)?;
-        }
+        plan.push(Action::CreateDir {
+            path: dest.to_path_buf(),
+        });
         // iterate over each source entry to copy it
-        for (filename, entry) in &self.entries {
+        for (key, entry) in &self.entries {
+            // the key may not be the original name
+            let filename = entry.path().file_name().unwrap_or(key.as_os_str());
             let dest_entry: PathBuf =
                 [dest, Path::new(filename)].iter().collect();
             match entry {
-                Entry::Dir(dir) => {
-                    dir.copy(&dest_entry)?;
-                }
-                Entry::File(file) => {
-                    file.copy(&dest_entry)?;
+                Entry::Dir(dir) => dir.plan_copy(&dest_entry, plan),
+                Entry::File(file) => file.plan_copy(&dest_entry, plan),
+                Entry::Special(special) => special.plan_copy(&dest_entry, plan),
+            }
+        }
+    }
+
+    /// Collects the files of self that don't have a counterpart in the other
+    /// directory.
+    #[cfg(feature = "update")]
+    fn missing_files<'a>(
+        &'a self,
+        other: &DirEntry,
+        files: &mut Vec<&'a FileEntry>,
+    ) {
+        for (name, e1) in &self.entries {
+            match (e1, other.entries.get(name)) {
+                (Entry::Dir(dir1), Some(Entry::Dir(dir2))) => {
+                    dir1.missing_files(dir2, files)
                 }
+                (_, Some(_)) => (),
+                (e1, None) => e1.files(files),
             }
         }
-        Ok(())
     }
 
     /// Compares self with another directory entry and returns the delta.
     fn cmp<'a>(
         &'a self,
         other: &'a DirEntry,
-        accuracy: &'a Duration,
-    ) -> Result<Option<DirDelta<'a>>, Error> {
+        accuracy: &'a Accuracy,
+    ) -> Result<Option<DirDelta<'a>>, BkupError> {
         let mut entries = HashMap::new();
         // compare each entry of the first directory with the content of
         // the second directory
@@ -137,44 +281,143 @@ impl DirEntry {
         Ok(delta)
     }
 
-    /// Visit and populate the directory entry.
-    fn visit(&mut self, ignore: Option<&Gitignore>) -> Result<(), Error> {
+    /// Visit and populate the directory entry, in the given root.
+    fn visit(
+        &mut self,
+        root: &Path,
+        ignore: Option<&Gitignore>,
+        filter: &Filter,
+    ) -> Result<(), BkupError> {
+        filter.cancel().check()?;
         // iterate over the directory entries
-        let dirs = fs::read_dir(&self.path)?.filter_map(|e| match e {
-            Ok(e) => Some(e),
-            Err(e) => {
-                warn!("Cannot read directory: {}", e);
-                None
-            }
-        });
+        let dirs =
+            fs::read_dir(&self.path).map_err(|source| BkupError::Scan {
+                path: self.path.clone(),
+                source,
+            })?;
+        let mut dirs: Vec<_> = dirs
+            .filter_map(|e| match e {
+                Ok(e) => Some(e),
+                Err(e) => {
+                    warn!("Cannot read directory: {}", e);
+                    None
+                }
+            })
+            .collect();
+        if !filter.names().is_exact() {
+            // of the names that collide, the smallest one by byte order is
+            // kept, regardless of the order the entries are read in
+            dirs.sort_by_key(|e| e.file_name());
+        }
 
         for e in dirs {
             let path = e.path();
-            let is_dir = path.is_dir();
-
-            // check if this path must be ignored
-            if let Some(ignore) = ignore {
-                if ignore.matched(&path, is_dir).is_ignore() {
-                    info!("Ignoring {:?}", path);
+            // the files of bkup are neither compared nor copied, while the
+            // ones left by the interrupted updates are kept to remove them
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            if metadata::is_metadata(relative) {
+                #[cfg(feature = "update")]
+                if relative == Path::new(metadata::BKUP_DIR) {
+                    self.leftovers.extend(leftovers(&path));
+                } else if copy::is_temp(&path) || resume::is_checkpoint(&path) {
+                    self.leftovers.push(path);
+                    continue;
+                }
+                debug!("Skipping {:?}", path);
+                continue;
+            }
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    debug!("Cannot read metadata of {:?}: {}", path, e);
                     continue;
                 }
+            };
+            let is_dir = metadata.is_dir();
+
+            // pinned paths are included regardless of the other rules, while
+            // the excluded directories containing them are only visited to
+            // include the pinned paths
+            let pin = filter.pin(&path);
+            let mut pins_only = None;
+            if pin != Pin::Inside {
+                // check if this path must be ignored or excluded by the filter
+                let ignored = ignore.is_some_and(|ignore| {
+                    ignore.matched(&path, is_dir).is_ignore()
+                });
+                if ignored || !filter.is_included(&path, &metadata)? {
+                    if pin == Pin::Ancestor && is_dir {
+                        debug!("Visiting {:?} for its pinned paths", path);
+                        pins_only = Some(filter.pins_only());
+                    } else {
+                        info!("Ignoring {:?}", path);
+                        continue;
+                    }
+                }
             }
 
             // get the entry filename if any
             let file_name =
                 path.file_name().map(PathBuf::from).ok_or_else(|| {
-                    format_err!("Cannot get the filename for {:?}", path)
+                    BkupError::Scan {
+                        path: path.clone(),
+                        source: io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "The path has no filename",
+                        ),
+                    }
                 })?;
 
+            // the names that match on the destination cannot both be copied
+            let key = filter.names().key(file_name.as_os_str());
+            if !filter.names().is_exact() {
+                if let Some(other) = self.entries.get(&key) {
+                    warn!(
+                        "Skipping {:?}, whose name collides with {:?} on the \
+                         destination",
+                        path,
+                        other.path()
+                    );
+                    continue;
+                }
+            }
+
             if is_dir {
                 debug!("New sub-directory: {:?}", path);
                 // dfs with recursion, carry ignore settings into sub-directory
-                let dir = Entry::directory(&path, ignore.is_some())?;
-                self.entries.insert(file_name, dir);
-            } else if path.is_file() {
+                let filter = pins_only.as_ref().unwrap_or(filter);
+                let dir = DirEntry::new(&path, root, ignore.is_some(), filter)?;
+                let dir = Entry::Dir(dir);
+                self.entries.insert(key, dir);
+            } else if metadata.is_file() {
                 debug!("New file: {:?}", path);
                 self.entries
-                    .insert(file_name, Entry::File(FileEntry::new(&path)?));
+                    .insert(key, Entry::File(FileEntry::new(&path)?));
+            } else if let Some(kind) = SpecialKind::of(&metadata) {
+                match filter.specials() {
+                    SpecialFiles::Error => {
+                        return Err(BkupError::Scan {
+                            path,
+                            source: io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!(
+                                    "Unsupported special file ({})",
+                                    kind.name()
+                                ),
+                            ),
+                        })
+                    }
+                    SpecialFiles::Recreate if kind.can_recreate() => {
+                        debug!("New special file: {:?}", path);
+                        let special = SpecialEntry { path, kind };
+                        self.entries.insert(key, Entry::Special(special));
+                    }
+                    _ => warn!(
+                        "Skipping special file {:?} ({})",
+                        path,
+                        kind.name()
+                    ),
+                }
             }
         }
         Ok(())
@@ -186,6 +429,48 @@ impl DirEntry {
     }
 }
 
+/// Gets the files left by the interrupted updates in the given bkup directory
+/// of a destination: the journal and the temporary files.
+#[cfg(feature = "update")]
+fn leftovers(dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Cannot read directory {:?}: {}", dir, e);
+            return Vec::new();
+        }
+    };
+    entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|path| copy::is_temp(path) || journal::is_journal(path))
+        .collect()
+}
+
+/// Represents a special file entry (FIFO, socket or device node).
+#[derive(Debug, PartialEq)]
+pub struct SpecialEntry {
+    // special file path
+    path: PathBuf,
+    // type of special file
+    kind: SpecialKind,
+}
+
+impl SpecialEntry {
+    /// Adds to the plan the action needed to recreate self at the given
+    /// destination.
+    fn plan_copy(&self, dest: &Path, plan: &mut Plan) {
+        plan.push(Action::CreateSpecial {
+            source: self.path.clone(),
+            dest: dest.to_path_buf(),
+        });
+    }
+
+    /// Gets the special file path.
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+}
+
 /// Enumerates the possible results of a file comparison.
 #[derive(Debug, PartialEq)]
 enum FileTimeDelta {
@@ -228,38 +513,104 @@ impl<'a> FileDelta<'a> {
     }
 }
 
+/// Identifies a file that has more than one hard link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId {
+    dev: u64, // device containing the file
+    ino: u64, // inode number
+}
+
+impl FileId {
+    /// Gets the identifier of the file with the given metadata, only if the
+    /// file has more than one hard link.
+    #[cfg(unix)]
+    fn linked(metadata: &fs::Metadata) -> Option<FileId> {
+        use std::os::unix::fs::MetadataExt;
+        if metadata.nlink() > 1 {
+            Some(FileId {
+                dev: metadata.dev(),
+                ino: metadata.ino(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Gets the identifier of the file with the given metadata, only if the
+    /// file has more than one hard link.
+    #[cfg(not(unix))]
+    fn linked(_metadata: &fs::Metadata) -> Option<FileId> {
+        None
+    }
+}
+
 /// Represents a file entry.
 #[derive(Debug, PartialEq)]
 pub struct FileEntry {
     // file path
     path: PathBuf,
+    // file size in bytes
+    size: u64,
+    // last modification time since the UNIX epoch
+    modified: Duration,
+    // identifier shared with the other hard links of the file (if any)
+    id: Option<FileId>,
+    // digest of the content, once hashed to compare it
+    digest: OnceLock<hash::Digest>,
 }
 
 impl FileEntry {
     /// Creates a new file entry.
-    fn new<P: Into<PathBuf>>(path: P) -> Result<FileEntry, Error> {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Result<FileEntry, BkupError> {
         let path = path.into();
-        if path.is_file() {
-            Ok(FileEntry { path })
-        } else {
-            Err(format_err!("The given file {:?} does not exist", path))
-        }
+        let metadata = fs::metadata(&path)
+            .and_then(|metadata| {
+                if metadata.is_file() {
+                    Ok(metadata)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "The file does not exist",
+                    ))
+                }
+            })
+            .map_err(|source| BkupError::Scan {
+                path: path.clone(),
+                source,
+            })?;
+        let modified = metadata
+            .modified()
+            .and_then(|modified| {
+                modified
+                    .duration_since(UNIX_EPOCH)
+                    .map_err(io::Error::other)
+            })
+            .map_err(|source| BkupError::Scan {
+                path: path.clone(),
+                source,
+            })?;
+        Ok(FileEntry {
+            size: metadata.len(),
+            modified,
+            id: FileId::linked(&metadata),
+            digest: OnceLock::new(),
+            path,
+        })
     }
 
-    /// Copies self into the given destination.
-    pub fn copy(&self, dest: &Path) -> Result<(), Error> {
-        info!("Copying file {:?} to {:?}", self.path, dest);
-        fs::copy(self.path(), dest)?;
-        Ok(())
+    /// Adds to the plan the action needed to copy self into the given
+    /// destination.
+    fn plan_copy(&self, dest: &Path, plan: &mut Plan) {
+        let overwrite = false;
+        plan.copy_file(self, dest, overwrite);
     }
 
     /// Compares self with another file entry.
     fn cmp<'a>(
         &'a self,
         other: &'a FileEntry,
-        accuracy: &'a Duration,
-    ) -> Result<Option<FileDelta<'a>>, Error> {
-        use std::time::UNIX_EPOCH;
+        accuracy: &'a Accuracy,
+    ) -> Result<Option<FileDelta<'a>>, BkupError> {
         let path1 = self.path.as_path();
         let path2 = other.path.as_path();
         let name1 = path1.file_name();
@@ -272,25 +623,66 @@ impl FileEntry {
                 if name1 != name2 {
                     warn!("Comparing files with different file names");
                 }
-                // check modification time
-                let t1 = fs::metadata(path1)?
-                    .modified()?
-                    .duration_since(UNIX_EPOCH)?;
-                let t2 = fs::metadata(path2)?
-                    .modified()?
-                    .duration_since(UNIX_EPOCH)?;
-                // compare timestamps
-                let time_delta = FileEntry::cmp_modified(t1, t2, accuracy);
-                let delta =
-                    time_delta.map(|delta| FileDelta::new(self, other, delta));
-                Ok(delta)
+                // the files that changed are replaced as the newer ones
+                let resized = || {
+                    (self.size != other.size).then_some(FileTimeDelta::Newer)
+                };
+                let modified = || {
+                    // compare the modification times read during the visit
+                    FileEntry::cmp_modified(
+                        self.modified,
+                        other.modified,
+                        accuracy,
+                    )
+                };
+                let diff = match accuracy.compare {
+                    Compare::Size => resized(),
+                    Compare::Mtime => modified(),
+                    Compare::SizeMtime => modified().or_else(resized),
+                    Compare::Checksum | Compare::Sample => match resized() {
+                        None => self.cmp_content(other, accuracy)?,
+                        resized => resized,
+                    },
+                };
+                Ok(diff.map(|diff| FileDelta::new(self, other, diff)))
             }
-            _ => Err(format_err!(
+            _ => Err(BkupError::Compare(format!(
                 "Invalid filenames for {:?} {:?}!",
-                path1,
-                path2
-            )),
+                path1, path2
+            ))),
+        }
+    }
+
+    /// Compares the content of self with the one of another file of the same
+    /// size, that is newer if they differ.
+    fn cmp_content(
+        &self,
+        other: &FileEntry,
+        accuracy: &Accuracy,
+    ) -> Result<Option<FileTimeDelta>, BkupError> {
+        if self.digest(accuracy)? == other.digest(accuracy)? {
+            Ok(None)
+        } else {
+            trace!("{:?} differs from {:?}", self.path, other.path);
+            Ok(Some(FileTimeDelta::Newer))
+        }
+    }
+
+    /// Gets the digest of the content (or of its sample) compared with the
+    /// given accuracy, hashing it only once.
+    pub(crate) fn digest(
+        &self,
+        accuracy: &Accuracy,
+    ) -> Result<hash::Digest, BkupError> {
+        if let Some(digest) = self.digest.get() {
+            return Ok(*digest);
         }
+        let digest = match accuracy.compare {
+            Compare::Sample => hash::hash_sample(&self.path, accuracy.hash),
+            _ => hash::hash_file(&self.path, accuracy.hash),
+        }
+        .map_err(|e| e.at(&self.path))?;
+        Ok(*self.digest.get_or_init(|| digest))
     }
 
     /// Gets the file path.
@@ -298,17 +690,48 @@ impl FileEntry {
         self.path.as_path()
     }
 
+    /// Gets the file size in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Gets the last modification time since the UNIX epoch.
+    pub fn modified(&self) -> Duration {
+        self.modified
+    }
+
+    /// Gets the identifier shared with the other hard links of the file, if
+    /// the file has more than one link.
+    pub fn id(&self) -> Option<FileId> {
+        self.id
+    }
+
     /// Compares the source and destination modified times taking into account the
     /// given accuracy.
     fn cmp_modified(
         source: Duration,
         dest: Duration,
-        accuracy: &Duration,
+        accuracy: &Accuracy,
     ) -> Option<FileTimeDelta> {
+        let window = accuracy.window;
+        // the destination time according to the local clock
+        let dest = match accuracy.skew {
+            skew if skew >= 0 => {
+                dest.saturating_sub(Duration::from_nanos(skew as u64))
+            }
+            skew => dest + Duration::from_nanos(skew.unsigned_abs()),
+        };
+        if accuracy.ignore_dst {
+            // FAT stores the local time, that shifts by one hour with DST
+            let shift = source.abs_diff(dest).abs_diff(DST_SHIFT);
+            if shift <= window {
+                return None;
+            }
+        }
         match source.cmp(&dest) {
             Ordering::Greater => {
                 // source may be newer
-                if (source - *accuracy) > dest {
+                if (source - window) > dest {
                     Some(FileTimeDelta::Newer)
                 } else {
                     None
@@ -316,7 +739,7 @@ impl FileEntry {
             }
             Ordering::Less => {
                 // source may be older (dest may be newer)
-                if (dest - *accuracy) > source {
+                if (dest - window) > source {
                     Some(FileTimeDelta::Older)
                 } else {
                     None
@@ -335,28 +758,101 @@ pub enum EntryDelta<'a> {
 }
 
 impl<'a> EntryDelta<'a> {
-    /// Updates the destination entry according to its given delta with the
-    /// source entry.
-    pub fn clear(&self) -> Result<(), Error> {
+    /// Adds to the plan the actions needed to update the destination entry
+    /// according to its given delta with the source entry.
+    pub fn plan(&self, plan: &mut Plan) {
         match self {
             EntryDelta::Dir(delta) => {
                 debug!("Directory delta: {:?}", delta);
                 for entry in delta.entries() {
-                    entry.clear()?;
+                    entry.plan(plan);
                 }
             }
             EntryDelta::File(delta) => {
                 debug!("File delta: {:?}", delta);
                 if delta.is_newer() {
-                    delta.source().copy(&delta.destination().path())?;
+                    let overwrite = true;
+                    plan.copy_file(
+                        delta.source(),
+                        delta.destination().path(),
+                        overwrite,
+                    );
                 }
             }
             EntryDelta::NotFound { entry, path } => {
                 debug!("Not found: {:?} in {:?}", entry, path);
-                entry.copy(path)?;
+                entry.plan_copy(path, plan);
+            }
+        };
+    }
+
+    /// Adds the differing entries to the itemized changes.
+    #[cfg(feature = "update")]
+    pub fn itemize(&self, items: &mut Vec<Item>) {
+        match self {
+            EntryDelta::Dir(dir) => {
+                for entry in dir.entries() {
+                    entry.itemize(items);
+                }
+            }
+            EntryDelta::File(file) => items.push(Item {
+                path: file.destination().path().to_path_buf(),
+                kind: if file.is_newer() {
+                    ItemKind::Newer
+                } else {
+                    ItemKind::Older
+                },
+            }),
+            EntryDelta::NotFound { entry, path } => items.push(Item {
+                path: path.clone(),
+                kind: match entry {
+                    Entry::Dir(_) => ItemKind::NewDir,
+                    Entry::File(_) => ItemKind::NewFile,
+                    Entry::Special(_) => ItemKind::NewSpecial,
+                },
+            }),
+        };
+    }
+
+    /// Adds to the owned delta the changes of the destination entry.
+    pub fn collect(&self, delta: &mut Delta) {
+        match self {
+            EntryDelta::Dir(dir) => {
+                for entry in dir.entries() {
+                    entry.collect(delta);
+                }
+            }
+            EntryDelta::File(file) => {
+                if file.is_newer() {
+                    delta.changes.push(Change::NewerFile {
+                        source: file.source().path().to_path_buf(),
+                        dest: file.destination().path().to_path_buf(),
+                        size: file.source().size(),
+                    });
+                }
+            }
+            EntryDelta::NotFound { entry, path } => {
+                let source = entry.path().to_path_buf();
+                let dest = path.clone();
+                delta.changes.push(match entry {
+                    Entry::Dir(_) => Change::MissingDir {
+                        source,
+                        dest,
+                        size: entry.all_files().iter().map(|f| f.size()).sum(),
+                    },
+                    Entry::File(file) => Change::MissingFile {
+                        source,
+                        dest,
+                        size: file.size(),
+                    },
+                    Entry::Special(_) => Change::MissingFile {
+                        source,
+                        dest,
+                        size: 0,
+                    },
+                });
             }
         };
-        Ok(())
     }
 }
 
@@ -366,6 +862,8 @@ pub enum Entry {
     Dir(DirEntry),
     // File
     File(FileEntry),
+    // Special file
+    Special(SpecialEntry),
 }
 
 impl Entry {
@@ -374,43 +872,156 @@ impl Entry {
     pub fn directory<P: Into<PathBuf>>(
         path: P,
         ignore: bool,
-    ) -> Result<Entry, Error> {
-        Ok(Entry::Dir(DirEntry::new(path, ignore)?))
+        filter: &Filter,
+    ) -> Result<Entry, BkupError> {
+        let path = path.into();
+        Ok(Entry::Dir(DirEntry::new(&path, &path, ignore, filter)?))
     }
 
     /// Gets the path of the entry.
-    fn path(&self) -> &Path {
+    pub fn path(&self) -> &Path {
         match self {
             Entry::Dir(e) => e.path(),
             Entry::File(e) => e.path(),
+            Entry::Special(e) => e.path(),
+        }
+    }
+
+    /// Gets the entries of self, that are none if it's not a directory.
+    pub fn entries(&self) -> Vec<&Entry> {
+        match self {
+            Entry::Dir(dir) => dir.entries.values().collect(),
+            Entry::File(_) | Entry::Special(_) => Vec::new(),
         }
     }
 
     /// Gets the filename of the entry.
-    fn file_name(&self) -> Result<&Path, Error> {
-        self.path()
-            .file_name()
-            .map(|s| Path::new(s))
-            .ok_or_else(|| {
-                format_err!("Cannot get the filename for '{}'", self)
-            })
+    fn file_name(&self) -> Result<&Path, BkupError> {
+        self.path().file_name().map(Path::new).ok_or_else(|| {
+            BkupError::Compare(format!(
+                "Cannot get the filename for '{}'",
+                self
+            ))
+        })
     }
 
-    /// Copies self into the given destination.
-    fn copy(&self, dest: &Path) -> Result<(), Error> {
+    /// Adds to the plan the actions needed to copy self into the given
+    /// destination.
+    fn plan_copy(&self, dest: &Path, plan: &mut Plan) {
         match self {
-            Entry::Dir(e) => e.copy(dest)?,
-            Entry::File(e) => e.copy(dest)?,
+            Entry::Dir(e) => e.plan_copy(dest, plan),
+            Entry::File(e) => e.plan_copy(dest, plan),
+            Entry::Special(e) => e.plan_copy(dest, plan),
         };
-        Ok(())
+    }
+
+    /// Collects all the files contained in self.
+    fn files<'a>(&'a self, files: &mut Vec<&'a FileEntry>) {
+        match self {
+            Entry::Dir(dir) => {
+                for entry in dir.entries.values() {
+                    entry.files(files);
+                }
+            }
+            Entry::File(file) => files.push(file),
+            Entry::Special(_) => (),
+        }
+    }
+
+    /// Gets all the files contained in self.
+    pub fn all_files(&self) -> Vec<&FileEntry> {
+        let mut files = Vec::new();
+        self.files(&mut files);
+        files
+    }
+
+    /// Hashes on every available core the files of self that have a file of
+    /// the same size at the same path in the given destination, ahead of
+    /// their comparison by content with the given accuracy (if any).
+    pub fn hash_contents(&self, dest: &Entry, accuracy: &Accuracy) {
+        if !matches!(accuracy.compare, Compare::Checksum | Compare::Sample) {
+            return;
+        }
+        let relative = |file: &'_ FileEntry, root: &Entry| {
+            file.path
+                .strip_prefix(root.path())
+                .ok()
+                .map(Path::to_path_buf)
+        };
+        let dest_files: HashMap<_, _> = dest
+            .all_files()
+            .into_iter()
+            .filter_map(|file| Some((relative(file, dest)?, file)))
+            .collect();
+        let mut files = Vec::new();
+        for file in self.all_files() {
+            let other = relative(file, self).and_then(|p| dest_files.get(&p));
+            if let Some(other) = other.filter(|other| other.size == file.size) {
+                files.extend([file, *other]);
+            }
+        }
+        // the failures are reported by the comparison
+        hash_files(&files, accuracy);
+    }
+
+    /// Removes the temporary files and journals left by interrupted updates
+    /// from self and from the filesystem, and returns how many were removed. If
+    /// `keep_partial` is set, the partial copies that can be resumed are only
+    /// removed from self. If `shred` is set, their content is overwritten
+    /// before they are removed. If `dry_run` is set, the files are only
+    /// removed from self.
+    #[cfg(feature = "update")]
+    pub fn remove_temp_files(
+        &mut self,
+        keep_partial: bool,
+        shred: bool,
+        dry_run: bool,
+    ) -> Result<usize, BkupError> {
+        let dir = match self {
+            Entry::Dir(dir) => dir,
+            Entry::File(_) | Entry::Special(_) => return Ok(0),
+        };
+        let mut removed = 0;
+        for entry in dir.entries.values_mut() {
+            removed += entry.remove_temp_files(keep_partial, shred, dry_run)?;
+        }
+        for path in dir.leftovers.drain(..) {
+            if keep_partial && resume::is_partial(&path) {
+                debug!("Keeping partial copy {:?}", path);
+                continue;
+            }
+            if dry_run {
+                info!("Dry run: remove {:?}", path);
+                removed += 1;
+                continue;
+            }
+            info!("Removing stale temporary file {:?}", path);
+            if shred {
+                copy::shred(&path)?;
+            }
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Gets the files of self that don't have a counterpart in the other
+    /// entry.
+    #[cfg(feature = "update")]
+    pub fn missing_files<'a>(&'a self, other: &Entry) -> Vec<&'a FileEntry> {
+        let mut files = Vec::new();
+        if let (Entry::Dir(dir1), Entry::Dir(dir2)) = (self, other) {
+            dir1.missing_files(dir2, &mut files);
+        }
+        files
     }
 
     /// Compares self with another entry.
     pub fn cmp<'a>(
         &'a self,
         other: &'a Entry,
-        accuracy: &'a Duration,
-    ) -> Result<Option<EntryDelta<'a>>, Error> {
+        accuracy: &'a Accuracy,
+    ) -> Result<Option<EntryDelta<'a>>, BkupError> {
         debug!(
             "Comparing: '{}' to '{}' ({:?} accuracy)",
             self, other, accuracy
@@ -424,7 +1035,103 @@ impl Entry {
                 let delta = f1.cmp(f2, accuracy)?.map(EntryDelta::File);
                 Ok(delta)
             }
-            _ => Err(err_msg("Cannot compare different type of entries!")),
+            // special files have no content nor modification time to update
+            (Entry::Special(s1), Entry::Special(s2)) if s1.kind == s2.kind => {
+                Ok(None)
+            }
+            _ => Err(BkupError::Compare(
+                "Cannot compare different type of entries!".to_string(),
+            )),
+        }
+    }
+
+    /// Serializes self and all its sub-entries into a JSON value.
+    pub fn to_json(&self) -> Value {
+        let path = Value::from(self.path().to_string_lossy().into_owned());
+        match self {
+            Entry::Dir(dir) => {
+                // sort the entries to always get the same serialization
+                let mut entries: Vec<_> = dir.entries.iter().collect();
+                entries.sort_by_key(|(name, _)| *name);
+                let entries = entries.into_iter().map(|(_, e)| e.to_json());
+                json::object(vec![
+                    ("type", Value::from("dir")),
+                    ("path", path),
+                    ("entries", Value::Array(entries.collect())),
+                ])
+            }
+            Entry::File(file) => json::object(vec![
+                ("type", Value::from("file")),
+                ("path", path),
+                ("size", Value::from(file.size)),
+                ("modified", Value::from(file.modified.as_nanos() as u64)),
+            ]),
+            Entry::Special(special) => json::object(vec![
+                ("type", Value::from("special")),
+                ("path", path),
+                ("kind", Value::from(special.kind.name())),
+            ]),
+        }
+    }
+
+    /// Deserializes an entry serialized with `to_json`.
+    pub fn from_json(value: &Value) -> Result<Entry, BkupError> {
+        let field = |name: &str| {
+            value.get(name).ok_or_else(|| {
+                BkupError::Parse(format!("Missing entry field {:?}", name))
+            })
+        };
+        let path =
+            field("path")?.as_str().map(PathBuf::from).ok_or_else(|| {
+                BkupError::Parse("Invalid entry path".to_string())
+            })?;
+        match field("type")?.as_str() {
+            Some("dir") => {
+                let mut entries = HashMap::new();
+                let values = field("entries")?.as_array().ok_or_else(|| {
+                    BkupError::Parse(format!("Invalid entries of {:?}", path))
+                })?;
+                for value in values {
+                    let entry = Entry::from_json(value)?;
+                    entries.insert(entry.file_name()?.to_path_buf(), entry);
+                }
+                Ok(Entry::Dir(DirEntry {
+                    path,
+                    entries,
+                    #[cfg(feature = "update")]
+                    leftovers: Vec::new(),
+                }))
+            }
+            Some("file") => {
+                let number = |name: &str| {
+                    field(name)?.as_u64().ok_or_else(|| {
+                        BkupError::Parse(format!(
+                            "Invalid {} of {:?}",
+                            name, path
+                        ))
+                    })
+                };
+                Ok(Entry::File(FileEntry {
+                    size: number("size")?,
+                    modified: Duration::from_nanos(number("modified")?),
+                    id: None,
+                    digest: OnceLock::new(),
+                    path,
+                }))
+            }
+            Some("special") => {
+                let kind = field("kind")?.as_str().ok_or_else(|| {
+                    BkupError::Parse(format!("Invalid kind of {:?}", path))
+                })?;
+                Ok(Entry::Special(SpecialEntry {
+                    kind: kind.parse()?,
+                    path,
+                }))
+            }
+            t => Err(BkupError::Parse(format!(
+                "Invalid type {:?} for {:?}",
+                t, path
+            ))),
         }
     }
 }
@@ -435,17 +1142,43 @@ impl fmt::Display for Entry {
     }
 }
 
+/// Hashes the contents of the given files on as many threads as available,
+/// so that their digests are computed once for the given accuracy (the
+/// failures are only returned when the digests are read).
+pub(crate) fn hash_files(files: &[&FileEntry], accuracy: &Accuracy) {
+    let jobs = thread::available_parallelism()
+        .map_or(1, usize::from)
+        .min(files.len());
+    debug!("Hashing {} files on {} threads", files.len(), jobs);
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                while let Some(file) =
+                    files.get(next.fetch_add(1, atomic::Ordering::Relaxed))
+                {
+                    let _ = file.digest(accuracy);
+                }
+            });
+        }
+    });
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
+    use crate::names::NameMatching;
     use std::{env, thread, time};
     use uuid::Uuid;
 
     lazy_static! {
         /// Interval used to write files with significant difference on the
         /// modification time stored in the metadata.
-        static ref ACCURACY: time::Duration = time::Duration::from_millis(2000);
+        static ref ACCURACY: Accuracy =
+            Accuracy::new(time::Duration::from_millis(2000));
+        /// Filter that includes every entry.
+        static ref FILTER: Filter = Filter::new();
     }
 
     // Empty gitignore matcher that never matches anything.
@@ -473,7 +1206,7 @@ mod tests {
         write_file(&source_path, file1_name);
 
         // file1 exists only on the source
-        source.visit(IGNORE).expect("Cannot visit source directory");
+        revisit(&mut source, IGNORE).expect("Cannot visit source directory");
         let delta = source
             .cmp(&dest, &ACCURACY)
             .expect("Cannot compare directory entries")
@@ -491,7 +1224,7 @@ mod tests {
         write_file(&dest_path, file1_name);
 
         // file 1 now exists in both directories
-        dest.visit(IGNORE).expect("Cannot visit dest directory");
+        revisit(&mut dest, IGNORE).expect("Cannot visit dest directory");
         let delta = source
             .cmp(&dest, &ACCURACY)
             .expect("Cannot compare directory entries")
@@ -514,7 +1247,7 @@ mod tests {
             .expect("Delta should be some");
         // only file 1 is seen from source an it is older than file 1 in dest
         assert_delta_cmp_with_file(&delta, file1_name, FileTimeDelta::Older, 1);
-        dest.visit(IGNORE).expect("Cannot visit dest directory");
+        revisit(&mut dest, IGNORE).expect("Cannot visit dest directory");
         let delta = dest
             .cmp(&source, &ACCURACY)
             .expect("Cannot compare directory entries")
@@ -534,7 +1267,7 @@ mod tests {
         let source_dir1 = create_dir(source.path(), dir1_name);
 
         // dir 1 only exists in source
-        source.visit(IGNORE).expect("Cannot visit source directory");
+        revisit(&mut source, IGNORE).expect("Cannot visit source directory");
         let delta = source
             .cmp(&dest, &ACCURACY)
             .expect("Cannot compare directory entries")
@@ -552,8 +1285,8 @@ mod tests {
         let dest_dir1 = create_dir(dest.path(), dir1_name);
 
         // dir 1 exists both in source and destination
-        source.visit(IGNORE).expect("Cannot visit source directory");
-        dest.visit(IGNORE).expect("Cannot visit dest directory");
+        revisit(&mut source, IGNORE).expect("Cannot visit source directory");
+        revisit(&mut dest, IGNORE).expect("Cannot visit dest directory");
         let delta = source
             .cmp(&dest, &ACCURACY)
             .expect("Cannot compare directory entries");
@@ -562,7 +1295,7 @@ mod tests {
         // create sub-dir in source
         let sub_dir1_name = "sub_dir1";
         let mut source_sub_dir1 = create_dir(source_dir1.path(), sub_dir1_name);
-        source.visit(IGNORE).expect("Cannot visit source directory");
+        revisit(&mut source, IGNORE).expect("Cannot visit source directory");
         let delta = source
             .cmp(&dest, &ACCURACY)
             .expect("Cannot compare directory entries")
@@ -580,7 +1313,7 @@ mod tests {
 
         // create sub-dir in dest
         let mut dest_sub_dir1 = create_dir(dest_dir1.path(), sub_dir1_name);
-        dest.visit(IGNORE).expect("Cannot visit dest directory");
+        revisit(&mut dest, IGNORE).expect("Cannot visit dest directory");
         let delta = source
             .cmp(&dest, &ACCURACY)
             .expect("Cannot compare directory entries");
@@ -590,7 +1323,7 @@ mod tests {
         // add file 1 to source sub-directory
         let file1_name = "file1";
         write_file(source_sub_dir1.path(), file1_name);
-        source.visit(IGNORE).expect("Cannot visit source directory");
+        revisit(&mut source, IGNORE).expect("Cannot visit source directory");
         let delta = source
             .cmp(&dest, &ACCURACY)
             .expect("Cannot compare directory entries")
@@ -605,8 +1338,8 @@ mod tests {
         write_file(dest_sub_dir1.path(), file1_name);
         write_file(dest_sub_dir1.path(), file2_name);
         write_file(source_sub_dir1.path(), file2_name);
-        source.visit(IGNORE).expect("Cannot visit source directory");
-        dest.visit(IGNORE).expect("Cannot visit dest directory");
+        revisit(&mut source, IGNORE).expect("Cannot visit source directory");
+        revisit(&mut dest, IGNORE).expect("Cannot visit dest directory");
         let delta = source
             .cmp(&dest, &ACCURACY)
             .expect("Cannot compare directory entries")
@@ -616,11 +1349,9 @@ mod tests {
         assert_delta_cmp_with_dir(&delta, dir1_name, 1);
 
         // compare the sub-directories with files
-        source_sub_dir1
-            .visit(IGNORE)
+        revisit(&mut source_sub_dir1, IGNORE)
             .expect("Cannot visit source directory");
-        dest_sub_dir1
-            .visit(IGNORE)
+        revisit(&mut dest_sub_dir1, IGNORE)
             .expect("Cannot visit dest directory");
 
         // source vs dest
@@ -671,9 +1402,7 @@ mod tests {
         assert!(delta.is_none());
 
         // create a copy of the older file
-        older
-            .copy(newer.path.as_path())
-            .expect("Cannot create a copy");
+        fs::copy(older.path(), newer.path()).expect("Cannot create a copy");
         let copy = FileEntry::new(newer.path.as_path())
             .expect("Cannot create FileEntry");
         let delta =
@@ -684,6 +1413,158 @@ mod tests {
         assert!(delta.is_none() || delta.unwrap().diff == FileTimeDelta::Newer);
     }
 
+    #[test]
+    fn test_cmp_modified_dst() {
+        let hour = Duration::from_secs(3600);
+        let time = Duration::from_secs(1_000_000);
+        let second = Duration::from_secs(1);
+        let mut accuracy = Accuracy::new(Duration::from_secs(2));
+        assert_eq!(
+            FileEntry::cmp_modified(time + hour, time, &accuracy),
+            Some(FileTimeDelta::Newer)
+        );
+
+        accuracy.ignore_dst = true;
+        assert_eq!(FileEntry::cmp_modified(time + hour, time, &accuracy), None);
+        assert_eq!(
+            FileEntry::cmp_modified(time, time + hour + second, &accuracy),
+            None
+        );
+        assert_eq!(
+            FileEntry::cmp_modified(time + second, time, &accuracy),
+            None
+        );
+        assert_eq!(
+            FileEntry::cmp_modified(time + hour * 2, time, &accuracy),
+            Some(FileTimeDelta::Newer)
+        );
+        assert_eq!(
+            FileEntry::cmp_modified(time + hour / 2, time, &accuracy),
+            Some(FileTimeDelta::Newer)
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        let (source, dest) = (root.join("source"), root.join("dest"));
+        let modified = time::SystemTime::now();
+        let write = |path: &Path, content: &str| {
+            fs::write(path, content).expect("Cannot write file");
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .and_then(|f| f.set_modified(modified))
+                .expect("Cannot set modification time");
+            FileEntry::new(path).expect("Cannot read file")
+        };
+        let changed = |source: &FileEntry, dest: &FileEntry, compare| {
+            let accuracy = Accuracy {
+                compare,
+                ..Accuracy::default()
+            };
+            let delta = source.cmp(dest, &accuracy).expect("Cannot compare");
+            delta.is_some_and(|delta| delta.is_newer())
+        };
+
+        // same modification time, different size
+        let (s, d) = (write(&source, "content"), write(&dest, "old"));
+        assert!(!changed(&s, &d, Compare::Mtime));
+        assert!(changed(&s, &d, Compare::SizeMtime));
+        assert!(changed(&s, &d, Compare::Size));
+        assert!(changed(&s, &d, Compare::Checksum));
+        // same modification time and size, different content
+        let d = write(&dest, "CONTENT");
+        assert!(!changed(&s, &d, Compare::SizeMtime));
+        assert!(!changed(&s, &d, Compare::Size));
+        assert!(changed(&s, &d, Compare::Checksum));
+        assert!(changed(&s, &d, Compare::Sample));
+        let d = write(&dest, "content");
+        assert!(!changed(&s, &d, Compare::Checksum));
+        assert!(!changed(&s, &d, Compare::Sample));
+
+        assert_eq!(
+            "size+mtime".parse::<Compare>().unwrap(),
+            Compare::SizeMtime
+        );
+        assert!("ctime".parse::<Compare>().is_err());
+    }
+
+    #[test]
+    fn test_hash_contents() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        for dir in &["source", "dest"] {
+            fs::create_dir_all(root.join(dir)).expect("Cannot create dir");
+            fs::write(root.join(dir).join("same"), "content")
+                .expect("Cannot write file");
+        }
+        fs::write(root.join("source").join("resized"), "content")
+            .expect("Cannot write file");
+        fs::write(root.join("dest").join("resized"), "old")
+            .expect("Cannot write file");
+        let visit = |dir| {
+            Entry::directory(root.join(dir), false, &FILTER)
+                .expect("Cannot visit directory")
+        };
+        let (source, dest) = (visit("source"), visit("dest"));
+        let hashed = |entry: &Entry| {
+            let mut files = entry.all_files();
+            files.sort_by_key(|file| file.path().to_path_buf());
+            files
+                .iter()
+                .map(|file| file.digest.get().is_some())
+                .collect::<Vec<_>>()
+        };
+
+        // only the comparisons by content hash the files
+        source.hash_contents(&dest, &ACCURACY);
+        assert_eq!(hashed(&source), vec![false, false]);
+        let accuracy = Accuracy {
+            compare: Compare::Checksum,
+            ..*ACCURACY
+        };
+        source.hash_contents(&dest, &accuracy);
+        assert_eq!(hashed(&source), vec![false, true]);
+        assert_eq!(hashed(&dest), vec![false, true]);
+        assert!(source.cmp(&dest, &accuracy).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_cmp_modified_skew() {
+        let minute = Duration::from_secs(60);
+        let time = Duration::from_secs(1_000_000);
+        let mut accuracy = Accuracy::new(Duration::from_secs(2));
+        // copied a minute after the change, by a destination 5 minutes late
+        assert_eq!(
+            FileEntry::cmp_modified(
+                time,
+                time + minute - minute * 5,
+                &accuracy
+            ),
+            Some(FileTimeDelta::Newer)
+        );
+        accuracy.skew = -((minute * 5).as_nanos() as i64);
+        assert_eq!(
+            FileEntry::cmp_modified(
+                time,
+                time + minute - minute * 5,
+                &accuracy
+            ),
+            Some(FileTimeDelta::Older)
+        );
+        // modified a minute after the copy, by a destination 5 minutes early
+        accuracy.skew = (minute * 5).as_nanos() as i64;
+        assert_eq!(
+            FileEntry::cmp_modified(
+                time + minute * 2,
+                time + minute * 6,
+                &accuracy
+            ),
+            Some(FileTimeDelta::Newer)
+        );
+    }
+
     #[test]
     fn test_entries_to_ignore() {
         let (mut source, dest) = create_source_and_dest_dirs();
@@ -705,8 +1586,7 @@ mod tests {
 
         // file1 exists only on the source but since it has to be ignored the
         // only difference must be the .gitignore file itself
-        source
-            .visit(Some(&ignore))
+        revisit(&mut source, Some(&ignore))
             .expect("Cannot visit source directory");
         let delta = source
             .cmp(&dest, &ACCURACY)
@@ -715,20 +1595,194 @@ mod tests {
         assert_entry_not_found_in_dest(&delta, ignore_filename, 1);
     }
 
+    #[test]
+    fn test_json_roundtrip() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(root.join("dir").join("sub_dir"))
+            .expect("Cannot create directory");
+        fs::write(root.join("file1"), "content").expect("Cannot write file");
+        fs::write(root.join("dir").join("file2"), "")
+            .expect("Cannot write file");
+
+        let ignore = false;
+        let entry =
+            Entry::directory(&root, ignore, &FILTER).expect("Cannot visit");
+        let json = entry.to_json();
+        let copy = Entry::from_json(&json).expect("Cannot deserialize entry");
+        assert_eq!(copy, entry);
+        assert_eq!(copy.to_json().to_string(), json.to_string());
+
+        assert!(Entry::from_json(&Value::Null).is_err());
+    }
+
+    #[cfg(feature = "update")]
+    #[test]
+    fn test_pinned_paths() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let build = root.join("build");
+        fs::create_dir_all(build.join("generated").join("sub"))
+            .expect("Cannot create directory");
+        fs::write(root.join(".gitignore"), "build/\n*.log\n")
+            .expect("Cannot write file");
+        fs::write(root.join("run.log"), "").expect("Cannot write file");
+        fs::write(build.join("other"), "").expect("Cannot write file");
+        fs::write(build.join("generated").join("sub").join("file.log"), "")
+            .expect("Cannot write file");
+
+        // the pinned directory is included with all its content, even if
+        // matched by the ignore rules, while its siblings are still ignored
+        let ignore = true;
+        let filter =
+            FILTER.with_pins(&root, &[PathBuf::from("build/generated")]);
+        let entry =
+            Entry::directory(&root, ignore, &filter).expect("Cannot visit");
+        let mut files = Vec::new();
+        entry.files(&mut files);
+        let mut paths: Vec<_> = files.iter().map(|f| f.path()).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                root.join(".gitignore").as_path(),
+                build
+                    .join("generated")
+                    .join("sub")
+                    .join("file.log")
+                    .as_path(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_name_collisions() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create directory");
+        for name in &["Readme.md", "README.md", "readme.md"] {
+            fs::write(root.join(name), name).expect("Cannot write file");
+        }
+
+        // the same file is kept in every visit, whatever the order of the
+        // directory entries
+        let mut filter = Filter::new();
+        filter.set_names(NameMatching {
+            case_insensitive: true,
+            normalization: None,
+        });
+        let entry =
+            Entry::directory(&root, false, &filter).expect("Cannot visit");
+        let mut files = Vec::new();
+        entry.files(&mut files);
+        let paths: Vec<_> = files.iter().map(|f| f.path()).collect();
+        assert_eq!(paths, vec![root.join("README.md").as_path()]);
+
+        fs::remove_dir_all(root).expect("Cannot remove dir");
+    }
+
+    #[cfg(feature = "update")]
+    #[test]
+    fn test_remove_temp_files() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(root.join("dir")).expect("Cannot create directory");
+        fs::write(root.join("file"), "content").expect("Cannot write file");
+        let temp = root.join("dir").join("file.bkup-tmp");
+        fs::write(&temp, "partial").expect("Cannot write file");
+        // the journal of an interrupted update is removed as well, but not the
+        // other files of the bkup directory
+        let bkup = root.join(".bkup");
+        fs::create_dir(&bkup).expect("Cannot create directory");
+        fs::write(bkup.join("journal"), "").expect("Cannot write file");
+        fs::write(bkup.join("manifest"), "[]").expect("Cannot write file");
+
+        let ignore = false;
+        let mut entry =
+            Entry::directory(&root, ignore, &FILTER).expect("Cannot visit");
+        let keep_partial = false;
+        assert_eq!(
+            entry
+                .remove_temp_files(keep_partial, false, false)
+                .expect("Cannot remove"),
+            2
+        );
+        assert!(!temp.exists());
+        assert!(!bkup.join("journal").exists());
+        assert!(bkup.join("manifest").exists());
+        assert!(root.join("file").exists());
+        let expected =
+            Entry::directory(&root, ignore, &FILTER).expect("Cannot visit");
+        assert_eq!(entry, expected);
+    }
+
+    #[cfg(all(unix, feature = "update"))]
+    #[test]
+    fn test_special_files() {
+        use std::{ffi::CString, os::unix::fs::FileTypeExt};
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let (source, dest) = (root.join("source"), root.join("dest"));
+        fs::create_dir_all(&source).expect("Cannot create directory");
+        fs::create_dir_all(&dest).expect("Cannot create directory");
+        let fifo = CString::new(source.join("fifo").to_str().unwrap()).unwrap();
+        // SAFETY: the path is a valid NUL-terminated string
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o644) }, 0);
+
+        // skipped by default, or failing the visit
+        let ignore = false;
+        let entry =
+            Entry::directory(&source, ignore, &FILTER).expect("Cannot visit");
+        assert!(entry.entries().is_empty());
+        let mut filter = Filter::new();
+        filter.set_specials(SpecialFiles::Error);
+        assert!(Entry::directory(&source, ignore, &filter).is_err());
+
+        // recreated in the destination
+        filter.set_specials(SpecialFiles::Recreate);
+        let entry =
+            Entry::directory(&source, ignore, &filter).expect("Cannot visit");
+        assert_eq!(Entry::from_json(&entry.to_json()).unwrap(), entry);
+        let other =
+            Entry::directory(&dest, ignore, &filter).expect("Cannot visit");
+        let mut plan = Plan::new();
+        entry
+            .cmp(&other, &ACCURACY)
+            .unwrap()
+            .unwrap()
+            .plan(&mut plan);
+        let action = Action::CreateSpecial {
+            source: source.join("fifo"),
+            dest: dest.join("fifo"),
+        };
+        assert_eq!(plan.actions(), &[action]);
+        plan.apply(&copy::CopyOptions::default())
+            .expect("Cannot apply plan");
+        let metadata = fs::symlink_metadata(dest.join("fifo")).unwrap();
+        assert!(metadata.file_type().is_fifo());
+        let other =
+            Entry::directory(&dest, ignore, &filter).expect("Cannot visit");
+        assert!(entry.cmp(&other, &ACCURACY).unwrap().is_none());
+    }
+
+    /// Visits the given directory, as the root of the visit.
+    fn revisit(
+        dir: &mut DirEntry,
+        ignore: Option<&Gitignore>,
+    ) -> Result<(), BkupError> {
+        let root = dir.path.clone();
+        dir.visit(&root, ignore, &FILTER)
+    }
+
     /// Creates a new directory in the given root path.
     fn create_dir(root: &Path, name: &str) -> DirEntry {
         let dir: PathBuf = [root, Path::new(name)].iter().collect();
         fs::create_dir(&dir)
             .unwrap_or_else(|_| panic!("Cannot create directory {:?}", dir));
         let ignore = false;
-        DirEntry::new(&dir, ignore)
+        DirEntry::new(&dir, &dir, ignore, &FILTER)
             .unwrap_or_else(|_| panic!("Cannot create DirEntry {:?}", dir))
     }
 
     /// Writes a new empty fule in the given root path.
     fn write_file(root: &Path, name: &str) -> FileEntry {
         let file: PathBuf = [root, Path::new(name)].iter().collect();
-        thread::sleep(*ACCURACY + Duration::from_millis(10));
+        thread::sleep(ACCURACY.window + Duration::from_millis(10));
         fs::write(&file, "")
             .unwrap_or_else(|_| panic!("Cannot writes file {:?}", file));
         FileEntry::new(&file)
@@ -0,0 +1,113 @@
+//! Errors returned by bkup, that tell the kind of failure and the path it
+//! refers to.
+
+use crate::plan::Failures;
+use std::{
+    error, fmt, io,
+    path::{Path, PathBuf},
+};
+
+/// Enumerates the errors returned by bkup.
+#[derive(Debug)]
+pub enum BkupError {
+    /// A directory or file cannot be visited.
+    Scan { path: PathBuf, source: io::Error },
+    /// The source and destination entries cannot be compared.
+    Compare(String),
+    /// A destination path cannot be created, copied, renamed or linked.
+    Copy { path: PathBuf, source: io::Error },
+    /// Any other I/O error, not related to a single visited or updated path.
+    Io(io::Error),
+    /// A JSON document (scan, plan, journal or checkpoint) is invalid.
+    Parse(String),
+    /// An argument or option has an invalid value.
+    Invalid(String),
+    /// The operation is not supported by this platform or build.
+    Unsupported(String),
+    /// The destination is locked by another update.
+    Locked(String),
+    /// The update was aborted before modifying the destination, because it
+    /// was not confirmed or doesn't fit into the destination.
+    Aborted(String),
+    /// A plugin cannot be launched, or doesn't follow the protocol.
+    Plugin(String),
+    /// Some actions failed while the others were applied.
+    Failures(Failures),
+    /// The update was cancelled.
+    Cancelled,
+    /// The runtime was shut down before the operation completed (with the
+    /// asynchronous API).
+    Shutdown(String),
+}
+
+impl BkupError {
+    /// Attaches the given destination path to an I/O error not related to any
+    /// path yet.
+    pub(crate) fn at(self, path: &Path) -> Self {
+        match self {
+            BkupError::Io(source) => BkupError::Copy {
+                path: path.to_path_buf(),
+                source,
+            },
+            error => error,
+        }
+    }
+
+    /// Gets the underlying I/O error, if any.
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            BkupError::Scan { source, .. }
+            | BkupError::Copy { source, .. }
+            | BkupError::Io(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BkupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BkupError::Scan { path, source } => {
+                write!(f, "Cannot visit {:?}: {}", path, source)
+            }
+            BkupError::Copy { path, source } => {
+                write!(f, "Cannot update {:?}: {}", path, source)
+            }
+            BkupError::Io(source) => write!(f, "{}", source),
+            BkupError::Compare(message)
+            | BkupError::Parse(message)
+            | BkupError::Invalid(message)
+            | BkupError::Unsupported(message)
+            | BkupError::Locked(message)
+            | BkupError::Aborted(message)
+            | BkupError::Plugin(message)
+            | BkupError::Shutdown(message) => write!(f, "{}", message),
+            BkupError::Failures(failures) => write!(f, "{}", failures),
+            BkupError::Cancelled => write!(f, "Update cancelled"),
+        }
+    }
+}
+
+impl error::Error for BkupError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        self.io_error().map(|e| e as _)
+    }
+}
+
+impl From<io::Error> for BkupError {
+    fn from(error: io::Error) -> Self {
+        BkupError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for BkupError {
+    fn from(error: serde_json::Error) -> Self {
+        BkupError::Parse(error.to_string())
+    }
+}
+
+impl From<Failures> for BkupError {
+    fn from(failures: Failures) -> Self {
+        BkupError::Failures(failures)
+    }
+}
@@ -0,0 +1,471 @@
+//! Events reported while an update runs, for the tools that monitor it.
+
+use crate::{entry::Entry, json, plan::Action};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Minimum interval between two `Copying` events of the same file.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+/// Interval over which the current transfer rate is measured.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+/// Time after the plan before which the remaining time is not estimated.
+const WARMUP: Duration = Duration::from_secs(2);
+
+/// Enumerates the events reported by an update.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The source and destination directories are being visited.
+    ScanStarted {
+        #[serde(serialize_with = "json::lossy")]
+        source: PathBuf,
+        #[serde(serialize_with = "json::lossy")]
+        dest: PathBuf,
+    },
+    /// A directory was visited, and the given number of files (with their
+    /// total size in bytes) were discovered.
+    Scanned {
+        #[serde(serialize_with = "json::lossy")]
+        path: PathBuf,
+        files: usize,
+        bytes: u64,
+    },
+    /// The plan of the update was computed, or resumed from its journal.
+    Planned { actions: usize, bytes: u64 },
+    /// A file is being copied to the given destination, of which the given
+    /// number of bytes were already copied.
+    Copying {
+        #[serde(serialize_with = "json::lossy")]
+        path: PathBuf,
+        bytes: u64,
+        size: u64,
+    },
+    /// An action of the plan was applied, where `action` is its type
+    /// (`create_dir`, `copy`, `rename` or `link`) and `path` its destination.
+    Applied {
+        action: &'static str,
+        #[serde(serialize_with = "json::lossy")]
+        path: PathBuf,
+        bytes: u64,
+    },
+    /// An action failed, or the whole update if the path is not given.
+    Error {
+        #[serde(serialize_with = "json::lossy_option")]
+        path: Option<PathBuf>,
+        error: String,
+    },
+    /// A copy was skipped because its source file is locked by another
+    /// process.
+    Locked {
+        #[serde(serialize_with = "json::lossy")]
+        path: PathBuf,
+    },
+    /// The update is over, with the given status (`completed`, `failed` or
+    /// `cancelled`), number of applied actions and copied bytes, failed
+    /// actions and skipped locked files.
+    Summary {
+        status: &'static str,
+        actions: usize,
+        bytes: u64,
+        failures: usize,
+        locked: usize,
+    },
+}
+
+impl Event {
+    /// Creates the event of the given visited directory.
+    pub(crate) fn scanned(entry: &Entry) -> Event {
+        let files = entry.all_files();
+        Event::Scanned {
+            path: entry.path().to_path_buf(),
+            files: files.len(),
+            bytes: files.iter().map(|file| file.size()).sum(),
+        }
+    }
+
+    /// Creates the event of the given applied action.
+    pub(crate) fn applied(action: &Action) -> Event {
+        Event::Applied {
+            action: action.name(),
+            path: action.path().to_path_buf(),
+            bytes: match action {
+                Action::Copy { size, .. } => *size,
+                _ => 0,
+            },
+        }
+    }
+
+    /// Serializes the event into a single line JSON object, whose `event`
+    /// member is the type of the event.
+    pub fn to_json(&self) -> String {
+        // the paths are serialized lossily, so the serialization cannot fail
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Callback invoked with every event reported by an update.
+#[derive(Clone)]
+pub struct EventHandler(Arc<dyn Fn(&Event) + Send + Sync>);
+
+impl EventHandler {
+    /// Creates a new handler that invokes the given function.
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        EventHandler(Arc::new(handler))
+    }
+
+    /// Creates a new handler that sends the events to the returned channel,
+    /// for the consumers that receive them on another thread.
+    pub fn channel() -> (Self, Receiver<Event>) {
+        let (sender, receiver) = mpsc::channel();
+        let handler = EventHandler::new(move |event: &Event| {
+            // the events are dropped once the receiver is gone
+            let _ = sender.send(event.clone());
+        });
+        (handler, receiver)
+    }
+
+    /// Reports the given event.
+    pub fn emit(&self, event: &Event) {
+        (self.0)(event)
+    }
+}
+
+impl fmt::Debug for EventHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventHandler")
+    }
+}
+
+/// Reports the bytes copied into a destination file with `Copying` events, at
+/// most once every `PROGRESS_INTERVAL`.
+pub(crate) struct CopyProgress<'a> {
+    events: Option<&'a EventHandler>,
+    path: &'a Path,
+    size: u64,
+    bytes: u64,
+    reported: Instant,
+}
+
+impl<'a> CopyProgress<'a> {
+    /// Creates the progress of the copy of a file of the given size into the
+    /// given destination.
+    pub fn new(
+        events: Option<&'a EventHandler>,
+        path: &'a Path,
+        size: u64,
+    ) -> Self {
+        CopyProgress {
+            events,
+            path,
+            size,
+            bytes: 0,
+            reported: Instant::now(),
+        }
+    }
+
+    /// Adds the given number of copied bytes, reporting them if enough time
+    /// passed since the last event.
+    pub fn add(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        let events = match self.events {
+            Some(events) => events,
+            None => return,
+        };
+        if self.reported.elapsed() >= PROGRESS_INTERVAL {
+            self.reported = Instant::now();
+            events.emit(&Event::Copying {
+                path: self.path.to_path_buf(),
+                bytes: self.bytes,
+                size: self.size,
+            });
+        }
+    }
+}
+
+/// Measures the transfer rates of an update from its events, and estimates
+/// the time left to apply its plan.
+#[derive(Debug, Default)]
+pub struct RateMeter {
+    // time of the plan, with its number of actions and bytes
+    planned: Option<(Instant, usize, u64)>,
+    actions: usize,
+    files: usize,
+    bytes: u64,
+    // bytes already copied of the files being copied
+    copying: HashMap<PathBuf, u64>,
+    // bytes transferred at the events of the last window
+    samples: VecDeque<(Instant, u64)>,
+    // highest rate measured over a window, in bytes per second
+    peak: f64,
+}
+
+/// Represents the progress and the transfer rates of an update.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Throughput {
+    /// Number of transferred bytes, including the files being copied.
+    pub bytes: u64,
+    /// Number of bytes to transfer.
+    pub total_bytes: u64,
+    /// Number of applied actions.
+    pub actions: usize,
+    /// Number of actions of the plan.
+    pub total_actions: usize,
+    /// Bytes per second transferred over the last seconds.
+    pub current: f64,
+    /// Bytes per second transferred since the plan.
+    pub average: f64,
+    /// Files copied per second since the plan.
+    pub files: f64,
+    /// Estimated time left, unknown during the first seconds (unless the
+    /// whole plan was applied).
+    pub remaining: Option<Duration>,
+}
+
+impl RateMeter {
+    /// Creates a new meter, that measures nothing until the plan is reported.
+    pub fn new() -> Self {
+        RateMeter::default()
+    }
+
+    /// Updates the meter with the given event.
+    pub fn update(&mut self, event: &Event) {
+        self.update_at(event, Instant::now());
+    }
+
+    /// Gets the current progress and transfer rates.
+    pub fn throughput(&self) -> Throughput {
+        self.throughput_at(Instant::now())
+    }
+
+    /// Updates the meter with the given event reported at the given time.
+    fn update_at(&mut self, event: &Event, now: Instant) {
+        match event {
+            Event::Planned { actions, bytes } => {
+                *self = RateMeter {
+                    planned: Some((now, *actions, *bytes)),
+                    ..RateMeter::default()
+                };
+                return;
+            }
+            Event::Copying { path, bytes, .. } => {
+                self.copying.insert(path.clone(), *bytes);
+            }
+            Event::Applied {
+                action,
+                path,
+                bytes,
+            } => {
+                self.actions += 1;
+                if *action == "copy" {
+                    self.files += 1;
+                    self.copying.remove(path);
+                    self.bytes += bytes;
+                }
+            }
+            _ => return,
+        }
+        let transferred = self.transferred();
+        self.samples.push_back((now, transferred));
+        while self.samples.len() > 1
+            && now.duration_since(self.samples[0].0) > RATE_WINDOW
+        {
+            self.samples.pop_front();
+        }
+        let (start, start_bytes) = self.samples[0];
+        let span = now.duration_since(start);
+        if span >= RATE_WINDOW / 2 {
+            let rate = transferred.saturating_sub(start_bytes) as f64
+                / span.as_secs_f64();
+            self.peak = self.peak.max(rate);
+        }
+    }
+
+    /// Gets the progress and transfer rates at the given time.
+    fn throughput_at(&self, now: Instant) -> Throughput {
+        let (planned, total_actions, total_bytes) = match self.planned {
+            Some(planned) => planned,
+            None => return Throughput::default(),
+        };
+        let bytes = self.transferred();
+        let elapsed = now.duration_since(planned).as_secs_f64();
+        let per_second = |n: f64| if elapsed > 0.0 { n / elapsed } else { 0.0 };
+        let current = match self.samples.front() {
+            Some(&(start, start_bytes)) if now > start => {
+                let span = now.duration_since(start).as_secs_f64();
+                bytes.saturating_sub(start_bytes) as f64 / span
+            }
+            _ => 0.0,
+        };
+        let average = per_second(bytes as f64);
+        Throughput {
+            bytes,
+            total_bytes,
+            actions: self.actions,
+            total_actions,
+            current,
+            average,
+            files: per_second(self.files as f64),
+            remaining: self.remaining(elapsed, bytes, average).filter(|_| {
+                elapsed >= WARMUP.as_secs_f64() && self.actions > 0
+                    || self.actions >= total_actions
+            }),
+        }
+    }
+
+    /// Estimates the time left from the given elapsed seconds, transferred
+    /// bytes and average rate.
+    ///
+    /// The bytes left are transferred at the highest rate measured so far,
+    /// and the time spent beyond that rate is the overhead of every action:
+    /// the first files of a run dominated by many small files don't make the
+    /// large ones left look as slow, nor the other way around.
+    fn remaining(
+        &self,
+        elapsed: f64,
+        bytes: u64,
+        average: f64,
+    ) -> Option<Duration> {
+        let (_, total_actions, total_bytes) = self.planned?;
+        let bandwidth = self.peak.max(average);
+        let bytes_left = total_bytes.saturating_sub(bytes) as f64;
+        if bandwidth <= 0.0 && bytes_left > 0.0 {
+            return None;
+        }
+        let transfer = |bytes: f64| {
+            if bytes > 0.0 {
+                bytes / bandwidth
+            } else {
+                0.0
+            }
+        };
+        let overhead = (elapsed - transfer(bytes as f64)).max(0.0)
+            / self.actions.max(1) as f64;
+        let actions_left = total_actions.saturating_sub(self.actions) as f64;
+        Some(Duration::from_secs_f64(
+            actions_left * overhead + transfer(bytes_left),
+        ))
+    }
+
+    /// Gets the number of bytes transferred, including the files being
+    /// copied.
+    fn transferred(&self) -> u64 {
+        self.bytes + self.copying.values().sum::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_event_to_json() {
+        let event = Event::Applied {
+            action: "copy",
+            path: PathBuf::from("dest"),
+            bytes: 42,
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"event":"applied","action":"copy","path":"dest","bytes":42}"#
+        );
+        let event = Event::Error {
+            path: None,
+            error: "Invalid \"path\"".to_string(),
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"event":"error","path":null,"error":"Invalid \"path\""}"#
+        );
+    }
+
+    #[test]
+    fn test_event_channel() {
+        let (handler, events) = EventHandler::channel();
+        let sender = handler.clone();
+        std::thread::spawn(move || {
+            sender.emit(&Event::Planned {
+                actions: 1,
+                bytes: 2,
+            })
+        })
+        .join()
+        .unwrap();
+        drop(handler);
+        assert_eq!(
+            events.iter().collect::<Vec<_>>(),
+            vec![Event::Planned {
+                actions: 1,
+                bytes: 2
+            }]
+        );
+    }
+
+    #[test]
+    fn test_rate_meter() {
+        let start = Instant::now();
+        let at = |secs: f64| start + Duration::from_secs_f64(secs);
+        let mut meter = RateMeter::new();
+        assert_eq!(meter.throughput_at(start), Throughput::default());
+        meter.update_at(
+            &Event::Planned {
+                actions: 12,
+                bytes: 2100,
+            },
+            start,
+        );
+        // ten small files in ten seconds, then a large one in a second
+        for i in 0..10 {
+            let event = Event::Applied {
+                action: "copy",
+                path: PathBuf::from(format!("small{}", i)),
+                bytes: 10,
+            };
+            meter.update_at(&event, at(i as f64 + 1.0));
+            if i == 0 {
+                assert_eq!(meter.throughput_at(at(1.0)).remaining, None);
+            }
+        }
+        let large = PathBuf::from("large");
+        let copying = Event::Copying {
+            path: large.clone(),
+            bytes: 500,
+            size: 1000,
+        };
+        meter.update_at(&copying, at(10.5));
+        let throughput = meter.throughput_at(at(10.5));
+        assert_eq!(throughput.bytes, 600);
+        assert_eq!(throughput.actions, 10);
+        let applied = Event::Applied {
+            action: "copy",
+            path: large,
+            bytes: 1000,
+        };
+        meter.update_at(&applied, at(11.0));
+
+        let throughput = meter.throughput_at(at(11.0));
+        assert_eq!(throughput.bytes, 1100);
+        assert_eq!(throughput.total_bytes, 2100);
+        assert_eq!(throughput.actions, 11);
+        assert_eq!(throughput.average, 100.0);
+        assert_eq!(throughput.files, 1.0);
+        // 1040 bytes between the sixth and the eleventh second
+        assert_eq!(throughput.current, 208.0);
+        // the last large file is faster than the average suggests
+        let remaining = throughput.remaining.expect("Unknown remaining time");
+        assert!(remaining > Duration::from_secs(1));
+        assert!(remaining < Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,112 @@
+//! Events of the updates written to the Windows Application event log under
+//! the `bkup` source, for the monitoring of the servers.
+
+use crate::error::BkupError;
+
+/// Name of the source of the events.
+#[cfg(windows)]
+const SOURCE: &str = "bkup";
+
+/// Enumerates the levels of the events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    /// The update started or completed.
+    Information,
+    /// The update completed with failures, or was cancelled.
+    Warning,
+    /// The update failed.
+    Error,
+}
+
+// Identifiers of the events, by outcome of the update
+pub const STARTED_ID: u32 = 1;
+pub const COMPLETED_ID: u32 = 2;
+pub const FAILURES_ID: u32 = 3;
+pub const CANCELLED_ID: u32 = 4;
+pub const FAILED_ID: u32 = 5;
+
+/// Writes the event with the given level, identifier and message to the
+/// Application event log.
+#[cfg(windows)]
+pub fn write(level: Level, id: u32, message: &str) -> Result<(), BkupError> {
+    use std::{ffi::OsStr, io, iter, os::windows::ffi::OsStrExt, ptr};
+
+    let wide = |s: &str| {
+        OsStr::new(s)
+            .encode_wide()
+            .chain(iter::once(0))
+            .collect::<Vec<u16>>()
+    };
+    let kind = match level {
+        Level::Information => sys::EVENTLOG_INFORMATION_TYPE,
+        Level::Warning => sys::EVENTLOG_WARNING_TYPE,
+        Level::Error => sys::EVENTLOG_ERROR_TYPE,
+    };
+    let source = wide(SOURCE);
+    let message = wide(message);
+    let strings = [message.as_ptr()];
+    // SAFETY: the strings are NUL-terminated and outlive the calls, and the
+    // handle is only used while registered
+    unsafe {
+        let log = sys::RegisterEventSourceW(ptr::null(), source.as_ptr());
+        if log.is_null() {
+            return Err(io::Error::last_os_error().into());
+        }
+        let reported = sys::ReportEventW(
+            log,
+            kind,
+            0,
+            id,
+            ptr::null_mut(),
+            strings.len() as u16,
+            0,
+            strings.as_ptr(),
+            ptr::null_mut(),
+        );
+        let error = io::Error::last_os_error();
+        sys::DeregisterEventSource(log);
+        if reported == 0 {
+            return Err(error.into());
+        }
+    }
+    Ok(())
+}
+
+/// There is no event log on this platform.
+#[cfg(not(windows))]
+pub fn write(_level: Level, _id: u32, _message: &str) -> Result<(), BkupError> {
+    Err(BkupError::Unsupported(
+        "The event log is only available on Windows".to_string(),
+    ))
+}
+
+/// Bindings of the event logging functions of advapi32.
+#[cfg(windows)]
+#[allow(non_snake_case)]
+mod sys {
+    use std::ffi::c_void;
+
+    pub const EVENTLOG_ERROR_TYPE: u16 = 0x0001;
+    pub const EVENTLOG_WARNING_TYPE: u16 = 0x0002;
+    pub const EVENTLOG_INFORMATION_TYPE: u16 = 0x0004;
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        pub fn RegisterEventSourceW(
+            server: *const u16,
+            source: *const u16,
+        ) -> *mut c_void;
+        pub fn ReportEventW(
+            log: *mut c_void,
+            kind: u16,
+            category: u16,
+            id: u32,
+            sid: *mut c_void,
+            strings_count: u16,
+            data_size: u32,
+            strings: *const *const u16,
+            data: *mut c_void,
+        ) -> i32;
+        pub fn DeregisterEventSource(log: *mut c_void) -> i32;
+    }
+}
@@ -0,0 +1,357 @@
+//! C-compatible API of the backup engine.
+//!
+//! The header `include/bkup.h` can be regenerated with
+//! `cbindgen --config cbindgen.toml --crate bkup --output include/bkup.h`.
+
+use crate::{error::BkupError, filter::Filter, plan::Plan, UpdateOptions};
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_void},
+    path::PathBuf,
+    ptr,
+    time::Duration,
+};
+
+/// Default accuracy in ms used by the options created through the C API.
+const DEFAULT_ACCURACY_MS: u64 = 2000;
+
+thread_local! {
+    /// Message of the last error occurred in the current thread.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Status codes returned by the C API.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BkupStatus {
+    /// The operation completed successfully.
+    BkupOk = 0,
+    /// A null pointer or an invalid UTF-8 string was passed.
+    BkupInvalidArgument = 1,
+    /// The operation failed, see `bkup_last_error`.
+    BkupError = 2,
+}
+
+/// Opaque handle to the options used to compute a plan.
+pub struct BkupOptions(UpdateOptions);
+
+/// Opaque handle to the plan that brings a destination up to date.
+pub struct BkupPlan(Plan, UpdateOptions);
+
+/// Callback invoked after each applied action with the number of applied
+/// actions, the total number of actions, and the user data.
+pub type BkupProgressCallback =
+    Option<extern "C" fn(done: usize, total: usize, user_data: *mut c_void)>;
+
+/// Creates new options with the default values. The returned handle must be
+/// released with `bkup_options_free`.
+#[no_mangle]
+pub extern "C" fn bkup_options_new() -> *mut BkupOptions {
+    let options = UpdateOptions {
+        accuracy: Duration::from_millis(DEFAULT_ACCURACY_MS),
+        ..UpdateOptions::default()
+    };
+    Box::into_raw(Box::new(BkupOptions(options)))
+}
+
+/// Releases the given options.
+///
+/// # Safety
+///
+/// `options` must be null or a handle returned by `bkup_options_new` that was
+/// not already released.
+#[no_mangle]
+pub unsafe extern "C" fn bkup_options_free(options: *mut BkupOptions) {
+    if !options.is_null() {
+        drop(Box::from_raw(options));
+    }
+}
+
+/// Sets the accuracy in ms for a source file to be considered newer than its
+/// destination.
+///
+/// # Safety
+///
+/// `options` must be null or a valid handle returned by `bkup_options_new`.
+#[no_mangle]
+pub unsafe extern "C" fn bkup_options_set_accuracy(
+    options: *mut BkupOptions,
+    accuracy_ms: u64,
+) -> BkupStatus {
+    match options.as_mut() {
+        Some(options) => {
+            options.0.accuracy = Duration::from_millis(accuracy_ms);
+            BkupStatus::BkupOk
+        }
+        None => BkupStatus::BkupInvalidArgument,
+    }
+}
+
+/// Sets whether the `.gitignore` files of the visited directories are parsed.
+///
+/// # Safety
+///
+/// `options` must be null or a valid handle returned by `bkup_options_new`.
+#[no_mangle]
+pub unsafe extern "C" fn bkup_options_set_ignore(
+    options: *mut BkupOptions,
+    ignore: bool,
+) -> BkupStatus {
+    match options.as_mut() {
+        Some(options) => {
+            options.0.ignore = ignore;
+            BkupStatus::BkupOk
+        }
+        None => BkupStatus::BkupInvalidArgument,
+    }
+}
+
+/// Sets whether moved and renamed files are detected.
+///
+/// # Safety
+///
+/// `options` must be null or a valid handle returned by `bkup_options_new`.
+#[no_mangle]
+pub unsafe extern "C" fn bkup_options_set_detect_moves(
+    options: *mut BkupOptions,
+    detect_moves: bool,
+) -> BkupStatus {
+    match options.as_mut() {
+        Some(options) => {
+            options.0.detect_moves = detect_moves;
+            BkupStatus::BkupOk
+        }
+        None => BkupStatus::BkupInvalidArgument,
+    }
+}
+
+/// Sets whether the source files are cloned on copy-on-write filesystems.
+///
+/// # Safety
+///
+/// `options` must be null or a valid handle returned by `bkup_options_new`.
+#[no_mangle]
+pub unsafe extern "C" fn bkup_options_set_reflink(
+    options: *mut BkupOptions,
+    reflink: bool,
+) -> BkupStatus {
+    match options.as_mut() {
+        Some(options) => {
+            options.0.reflink = reflink;
+            BkupStatus::BkupOk
+        }
+        None => BkupStatus::BkupInvalidArgument,
+    }
+}
+
+/// Sets whether the hard links of the source files are preserved.
+///
+/// # Safety
+///
+/// `options` must be null or a valid handle returned by `bkup_options_new`.
+#[no_mangle]
+pub unsafe extern "C" fn bkup_options_set_hard_links(
+    options: *mut BkupOptions,
+    hard_links: bool,
+) -> BkupStatus {
+    match options.as_mut() {
+        Some(options) => {
+            options.0.hard_links = hard_links;
+            BkupStatus::BkupOk
+        }
+        None => BkupStatus::BkupInvalidArgument,
+    }
+}
+
+/// Scans the source and destination directories and computes the plan that
+/// brings the destination up to date, without modifying it. On success the
+/// plan is stored in `plan` and must be released with `bkup_plan_free`.
+///
+/// # Safety
+///
+/// `source` and `dest` must be null or valid NUL terminated strings, `options`
+/// must be null or a valid handle, and `plan` must be null or a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn bkup_plan_new(
+    source: *const c_char,
+    dest: *const c_char,
+    options: *const BkupOptions,
+    plan: *mut *mut BkupPlan,
+) -> BkupStatus {
+    let (source, dest, options) =
+        match (path(source), path(dest), options.as_ref()) {
+            (Some(source), Some(dest), Some(options)) => {
+                (source, dest, options)
+            }
+            _ => return BkupStatus::BkupInvalidArgument,
+        };
+    if plan.is_null() {
+        return BkupStatus::BkupInvalidArgument;
+    }
+    match crate::update::plan(source, dest, &options.0, &Filter::new()) {
+        Ok(p) => {
+            *plan = Box::into_raw(Box::new(BkupPlan(p, options.0.clone())));
+            BkupStatus::BkupOk
+        }
+        Err(e) => fail(e),
+    }
+}
+
+/// Gets the number of actions of the plan.
+///
+/// # Safety
+///
+/// `plan` must be null or a valid handle returned by `bkup_plan_new`.
+#[no_mangle]
+pub unsafe extern "C" fn bkup_plan_len(plan: *const BkupPlan) -> usize {
+    plan.as_ref().map_or(0, |plan| plan.0.len())
+}
+
+/// Gets the number of bytes copied by the plan.
+///
+/// # Safety
+///
+/// `plan` must be null or a valid handle returned by `bkup_plan_new`.
+#[no_mangle]
+pub unsafe extern "C" fn bkup_plan_bytes(plan: *const BkupPlan) -> u64 {
+    plan.as_ref().map_or(0, |plan| plan.0.bytes())
+}
+
+/// Applies the plan to the destination, invoking the given progress callback
+/// (if not null) after each action.
+///
+/// # Safety
+///
+/// `plan` must be null or a valid handle returned by `bkup_plan_new`, and
+/// `user_data` is passed as is to the callback.
+#[no_mangle]
+pub unsafe extern "C" fn bkup_plan_apply(
+    plan: *const BkupPlan,
+    progress: BkupProgressCallback,
+    user_data: *mut c_void,
+) -> BkupStatus {
+    let (plan, options) = match plan.as_ref() {
+        Some(plan) => (&plan.0, plan.1.copy_options()),
+        None => return BkupStatus::BkupInvalidArgument,
+    };
+    let total = plan.len();
+    let mut done = 0;
+    let result = plan.apply_with(&options, |_, _, _| {
+        done += 1;
+        if let Some(progress) = progress {
+            progress(done, total, user_data);
+        }
+    });
+    match result {
+        Ok(_) => BkupStatus::BkupOk,
+        Err(e) => fail(e),
+    }
+}
+
+/// Releases the given plan.
+///
+/// # Safety
+///
+/// `plan` must be null or a handle returned by `bkup_plan_new` that was not
+/// already released.
+#[no_mangle]
+pub unsafe extern "C" fn bkup_plan_free(plan: *mut BkupPlan) {
+    if !plan.is_null() {
+        drop(Box::from_raw(plan));
+    }
+}
+
+/// Gets the message of the last error occurred in the calling thread, or null
+/// if no error occurred. The message is valid until the next call that fails.
+#[no_mangle]
+pub extern "C" fn bkup_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Converts the given C string into a path.
+unsafe fn path(s: *const c_char) -> Option<PathBuf> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok().map(PathBuf::from)
+}
+
+/// Stores the given error as the last error of the thread.
+fn fail(error: BkupError) -> BkupStatus {
+    let message = CString::new(error.to_string().replace('\0', ""))
+        .expect("Error message must not contain NUL bytes");
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+    BkupStatus::BkupError
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    extern "C" fn count(done: usize, total: usize, user_data: *mut c_void) {
+        assert!(done <= total);
+        unsafe { *(user_data as *mut usize) += 1 };
+    }
+
+    #[test]
+    fn test_plan_and_apply() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(source.join("dir")).expect("Cannot create dir");
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+        fs::write(source.join("dir").join("file"), "abc")
+            .expect("Cannot write");
+
+        let c_source = CString::new(source.to_str().unwrap()).unwrap();
+        let c_dest = CString::new(dest.to_str().unwrap()).unwrap();
+        unsafe {
+            let options = bkup_options_new();
+            assert_eq!(
+                bkup_options_set_accuracy(options, 0),
+                BkupStatus::BkupOk
+            );
+            let mut plan = ptr::null_mut();
+            let status = bkup_plan_new(
+                c_source.as_ptr(),
+                c_dest.as_ptr(),
+                options,
+                &mut plan,
+            );
+            assert_eq!(status, BkupStatus::BkupOk);
+            assert_eq!(bkup_plan_len(plan), 2);
+            assert_eq!(bkup_plan_bytes(plan), 3);
+
+            let mut calls = 0usize;
+            let user_data = &mut calls as *mut usize as *mut c_void;
+            let status = bkup_plan_apply(plan, Some(count), user_data);
+            assert_eq!(status, BkupStatus::BkupOk);
+            assert_eq!(calls, 2);
+            assert!(dest.join("dir").join("file").is_file());
+
+            bkup_plan_free(plan);
+
+            // a missing source directory must be reported as an error
+            let missing =
+                CString::new(root.join("missing").to_str().unwrap()).unwrap();
+            let status = bkup_plan_new(
+                missing.as_ptr(),
+                c_dest.as_ptr(),
+                options,
+                &mut plan,
+            );
+            assert_eq!(status, BkupStatus::BkupError);
+            assert!(!bkup_last_error().is_null());
+
+            assert_eq!(
+                bkup_plan_new(ptr::null(), c_dest.as_ptr(), options, &mut plan),
+                BkupStatus::BkupInvalidArgument
+            );
+            bkup_options_free(options);
+        }
+    }
+}
@@ -0,0 +1,578 @@
+#[cfg(feature = "update")]
+use crate::plugin::Plugin;
+use crate::{
+    cancel::CancellationToken,
+    error::BkupError,
+    names::NameMatching,
+    rules::{self, FilterRules},
+    special::SpecialFiles,
+};
+use log::*;
+use regex::Regex;
+use std::{
+    fmt,
+    fs::{self, Metadata},
+    io::Read,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::SystemTime,
+};
+
+/// Enumerates the relations between a path and the pinned paths.
+#[derive(Debug, PartialEq)]
+pub enum Pin {
+    /// The path is not related to any pinned path.
+    None,
+    /// The path is a directory containing a pinned path.
+    Ancestor,
+    /// The path is pinned or it's contained in a pinned directory.
+    Inside,
+}
+
+/// Function that returns true if the entry with the given path and metadata
+/// must be included.
+type PredicateFn = dyn Fn(&Path, &Metadata) -> bool + Send + Sync;
+
+/// Predicate that decides whether an entry with the given path and metadata
+/// must be included, provided by the library users.
+#[derive(Clone)]
+pub struct Predicate(Arc<PredicateFn>);
+
+impl Predicate {
+    /// Creates a new predicate that invokes the given function.
+    pub fn new<F>(predicate: F) -> Self
+    where
+        F: Fn(&Path, &Metadata) -> bool + Send + Sync + 'static,
+    {
+        Predicate(Arc::new(predicate))
+    }
+
+    /// Returns true if the entry with the given path and metadata must be
+    /// included.
+    pub fn includes(&self, path: &Path, metadata: &Metadata) -> bool {
+        (self.0)(path, metadata)
+    }
+}
+
+impl fmt::Debug for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Predicate")
+    }
+}
+
+/// Enumerates the types of the entries selected by `--type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryType {
+    /// Regular files (the directories are still visited).
+    File,
+    /// Directories only, without any file.
+    Dir,
+}
+
+impl FromStr for EntryType {
+    type Err = BkupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "f" => Ok(EntryType::File),
+            "d" => Ok(EntryType::Dir),
+            _ => Err(BkupError::Invalid(format!("Invalid entry type {:?}", s))),
+        }
+    }
+}
+
+/// Limits on the size, modification time and type of the source entries
+/// that are included.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AttributeFilter {
+    /// Minimum size of the included files.
+    pub min_size: Option<u64>,
+    /// Maximum size of the included files.
+    pub max_size: Option<u64>,
+    /// Only the files modified after this time are included.
+    pub newer_than: Option<SystemTime>,
+    /// Type of the included entries.
+    pub entry_type: Option<EntryType>,
+}
+
+impl AttributeFilter {
+    /// Returns true if the entry with the given metadata must be included.
+    /// The directories are always included (unless only files are), so that
+    /// their content is visited.
+    pub fn includes(&self, metadata: &Metadata) -> bool {
+        if metadata.is_dir() {
+            return true;
+        }
+        if self.entry_type == Some(EntryType::Dir)
+            || (self.entry_type == Some(EntryType::File) && !metadata.is_file())
+        {
+            return false;
+        }
+        let size = metadata.len();
+        if self.min_size.is_some_and(|min| size < min)
+            || self.max_size.is_some_and(|max| size > max)
+        {
+            return false;
+        }
+        match (self.newer_than, metadata.modified()) {
+            (Some(time), Ok(modified)) => modified > time,
+            _ => true,
+        }
+    }
+}
+
+/// Name of the file that tags a cache directory, as defined by the Cache
+/// Directory Tagging Specification.
+pub const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+
+/// Header a `CACHEDIR.TAG` file must start with to tag its directory.
+const CACHEDIR_SIGNATURE: &[u8] =
+    b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Names of the directories of well-known caches, whose content can be
+/// rebuilt.
+const KNOWN_CACHES: &[&str] = &[
+    ".cache",
+    ".gradle",
+    ".npm",
+    ".pytest_cache",
+    ".tox",
+    "__pycache__",
+    "node_modules",
+    "target",
+];
+
+/// Represents which cache directories are excluded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Caches {
+    /// When set the directories containing a valid `CACHEDIR.TAG` file are
+    /// excluded.
+    pub tagged: bool,
+    /// When set the directories with the name of a well-known cache (such as
+    /// `.cache`, `node_modules` or `target`) are excluded.
+    pub known: bool,
+}
+
+impl Caches {
+    /// Returns true if the given directory is an excluded cache.
+    pub fn excludes(&self, dir: &Path) -> bool {
+        let known = || {
+            dir.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| KNOWN_CACHES.contains(&name))
+        };
+        (self.known && known()) || (self.tagged && is_tagged(dir))
+    }
+}
+
+/// Returns true if the given directory contains a `CACHEDIR.TAG` file with
+/// the signature of the specification.
+fn is_tagged(dir: &Path) -> bool {
+    let mut header = [0; CACHEDIR_SIGNATURE.len()];
+    fs::File::open(dir.join(CACHEDIR_TAG))
+        .and_then(|mut tag| tag.read_exact(&mut header))
+        .is_ok_and(|()| header == CACHEDIR_SIGNATURE)
+}
+
+/// Regular expressions matched against the paths of the entries relative to
+/// the visited root directory, with `/` as separator.
+#[derive(Debug, Clone, Default)]
+pub struct RegexFilter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl RegexFilter {
+    /// Creates a new filter that only includes the files matching at least
+    /// one of the `include` expressions (if any), and excludes the entries
+    /// matching any of the `exclude` expressions.
+    pub fn new<S: AsRef<str>>(
+        include: &[S],
+        exclude: &[S],
+    ) -> Result<Self, BkupError> {
+        let compile = |patterns: &[S]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    Regex::new(pattern.as_ref()).map_err(|e| {
+                        BkupError::Invalid(format!(
+                            "Invalid regular expression {:?}: {}",
+                            pattern.as_ref(),
+                            e
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(RegexFilter {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    /// Returns true if no expression is given.
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Returns true if the entry with the given relative path must be
+    /// included. The directories are only excluded, so that the files they
+    /// contain can still be included.
+    fn includes(&self, relative: &Path, is_dir: bool) -> bool {
+        let path = rules::slash_path(relative);
+        if self.exclude.iter().any(|regex| regex.is_match(&path)) {
+            return false;
+        }
+        is_dir
+            || self.include.is_empty()
+            || self.include.iter().any(|regex| regex.is_match(&path))
+    }
+}
+
+/// Decides which of the visited entries must be included, in addition to the
+/// `.gitignore` rules.
+#[derive(Default, Clone)]
+pub struct Filter {
+    // plugins with the filter capability
+    #[cfg(feature = "update")]
+    plugins: Vec<Arc<Plugin>>,
+    // predicates of the library users
+    predicates: Vec<Predicate>,
+    // limits on the attributes of the entries
+    attributes: AttributeFilter,
+    // when set only the entries on this device are included
+    device: Option<u64>,
+    // how the entry names are matched with the other directory
+    names: NameMatching,
+    // how the special files are handled
+    specials: SpecialFiles,
+    // cache directories that are excluded
+    caches: Caches,
+    // when set the hidden entries are excluded
+    skip_hidden: bool,
+    // regular expressions matched against the paths relative to the root
+    regexes: Option<(PathBuf, RegexFilter)>,
+    // ordered rules matched against the paths relative to the root
+    rules: Option<(PathBuf, FilterRules)>,
+    // paths always included, overriding every other exclusion rule
+    pins: Vec<PathBuf>,
+    // when set only the pinned paths (and their ancestors) are included
+    pins_only: bool,
+    // token checked before visiting each directory
+    cancel: CancellationToken,
+}
+
+impl Filter {
+    /// Creates a new filter that includes every entry.
+    pub fn new() -> Self {
+        Filter::default()
+    }
+
+    /// Adds a plugin that will be asked whether each entry must be included.
+    #[cfg(feature = "update")]
+    pub fn add_plugin(&mut self, plugin: Arc<Plugin>) {
+        self.plugins.push(plugin);
+    }
+
+    /// Sets the token checked before visiting each directory.
+    pub fn set_cancel(&mut self, cancel: CancellationToken) {
+        self.cancel = cancel;
+    }
+
+    /// Gets the token checked before visiting each directory.
+    pub fn cancel(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    /// Sets how the names of the visited entries are matched with the ones of
+    /// the other directory.
+    pub fn set_names(&mut self, names: NameMatching) {
+        self.names = names;
+    }
+
+    /// Gets how the names of the visited entries are matched with the ones
+    /// of the other directory.
+    pub fn names(&self) -> &NameMatching {
+        &self.names
+    }
+
+    /// Sets how the visited special files are handled.
+    #[cfg(feature = "update")]
+    pub fn set_specials(&mut self, specials: SpecialFiles) {
+        self.specials = specials;
+    }
+
+    /// Gets how the visited special files are handled.
+    pub fn specials(&self) -> SpecialFiles {
+        self.specials
+    }
+
+    /// Sets which cache directories are excluded.
+    #[cfg(feature = "update")]
+    pub fn set_caches(&mut self, caches: Caches) {
+        self.caches = caches;
+    }
+
+    /// Sets whether the hidden entries are excluded.
+    #[cfg(feature = "update")]
+    pub fn set_skip_hidden(&mut self, skip_hidden: bool) {
+        self.skip_hidden = skip_hidden;
+    }
+
+    /// Adds a predicate that decides whether each entry must be included.
+    pub fn add_predicate(&mut self, predicate: Predicate) {
+        self.predicates.push(predicate);
+    }
+
+    /// Gets a copy of self that also checks the attributes of the entries,
+    /// only used for the source directory.
+    #[cfg(feature = "update")]
+    pub fn with_attributes(&self, attributes: &AttributeFilter) -> Filter {
+        Filter {
+            attributes: attributes.clone(),
+            ..self.clone()
+        }
+    }
+
+    /// Gets a copy of self that only includes the entries on the same device
+    /// (file system) as the given root directory, if the device is known.
+    #[cfg(feature = "update")]
+    pub fn on_device_of(&self, root: &Path) -> Filter {
+        Filter {
+            device: fs::metadata(root).ok().as_ref().and_then(device),
+            ..self.clone()
+        }
+    }
+
+    /// Gets a copy of self that always includes the given paths, relative to
+    /// the given root directory.
+    #[cfg(feature = "update")]
+    pub fn with_pins(&self, root: &Path, pins: &[PathBuf]) -> Filter {
+        Filter {
+            pins: pins.iter().map(|pin| root.join(pin)).collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Gets a copy of self that also matches the given regular expressions
+    /// against the paths relative to the given root directory.
+    #[cfg(feature = "update")]
+    pub fn with_regexes(&self, root: &Path, regexes: &RegexFilter) -> Filter {
+        Filter {
+            regexes: (!regexes.is_empty())
+                .then(|| (root.to_path_buf(), regexes.clone())),
+            ..self.clone()
+        }
+    }
+
+    /// Gets a copy of self that also matches the given ordered rules against
+    /// the paths relative to the given root directory.
+    #[cfg(feature = "update")]
+    pub fn with_rules(&self, root: &Path, rules: &FilterRules) -> Filter {
+        Filter {
+            rules: (!rules.is_empty())
+                .then(|| (root.to_path_buf(), rules.clone())),
+            ..self.clone()
+        }
+    }
+
+    /// Gets a copy of self that only includes the pinned paths, used to visit
+    /// the excluded directories that contain a pinned path.
+    pub fn pins_only(&self) -> Filter {
+        Filter {
+            pins_only: true,
+            ..self.clone()
+        }
+    }
+
+    /// Gets the relation between the given path and the pinned paths.
+    pub fn pin(&self, path: &Path) -> Pin {
+        if self.pins.iter().any(|pin| path.starts_with(pin)) {
+            Pin::Inside
+        } else if self.pins.iter().any(|pin| pin.starts_with(path)) {
+            Pin::Ancestor
+        } else {
+            Pin::None
+        }
+    }
+
+    /// Returns true if the entry with the given path and metadata must be
+    /// included.
+    pub fn is_included(
+        &self,
+        path: &Path,
+        metadata: &Metadata,
+    ) -> Result<bool, BkupError> {
+        if self.pins_only {
+            return Ok(false);
+        }
+        if self.device.is_some() && device(metadata) != self.device {
+            info!("{:?} is on another file system", path);
+            return Ok(false);
+        }
+        if self.skip_hidden && is_hidden(path, metadata) {
+            debug!("{:?} is hidden", path);
+            return Ok(false);
+        }
+        if let Some((root, rules)) = &self.rules {
+            let relative = path.strip_prefix(root).unwrap_or(path);
+            if !rules.includes(relative, metadata.is_dir()) {
+                debug!("{:?} excluded by filter rule", path);
+                return Ok(false);
+            }
+        }
+        if let Some((root, regexes)) = &self.regexes {
+            let relative = path.strip_prefix(root).unwrap_or(path);
+            if !regexes.includes(relative, metadata.is_dir()) {
+                debug!("{:?} excluded by regular expression", path);
+                return Ok(false);
+            }
+        }
+        if metadata.is_dir() && self.caches.excludes(path) {
+            info!("{:?} is a cache directory", path);
+            return Ok(false);
+        }
+        if !self.attributes.includes(metadata) {
+            debug!("{:?} excluded by its attributes", path);
+            return Ok(false);
+        }
+        for predicate in &self.predicates {
+            if !predicate.includes(path, metadata) {
+                debug!("{:?} excluded by predicate", path);
+                return Ok(false);
+            }
+        }
+        #[cfg(feature = "update")]
+        for plugin in &self.plugins {
+            if !plugin.is_included(path, metadata.is_dir(), metadata.len())? {
+                debug!("{:?} excluded by plugin", path);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Returns true if the entry with the given path and metadata is hidden, that
+/// is its name starts with a dot (or it has the hidden attribute on Windows).
+fn is_hidden(path: &Path, metadata: &Metadata) -> bool {
+    let dotted = path
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+    dotted || has_hidden_attribute(metadata)
+}
+
+/// Returns true if the entry with the given metadata has the hidden
+/// attribute.
+#[cfg(windows)]
+fn has_hidden_attribute(metadata: &Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0
+}
+
+/// There is no hidden attribute on this platform.
+#[cfg(not(windows))]
+fn has_hidden_attribute(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Gets the identifier of the device of the entry with the given metadata.
+#[cfg(unix)]
+fn device(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+/// Device identifiers are not available on this platform.
+#[cfg(not(unix))]
+fn device(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    #[cfg(feature = "update")]
+    #[test]
+    #[cfg(unix)]
+    fn test_one_file_system() {
+        let root = env::temp_dir();
+        let metadata = fs::metadata(&root).expect("Cannot read metadata");
+        let filter = Filter::new().on_device_of(&root);
+        assert!(filter.is_included(&root, &metadata).unwrap());
+
+        let filter = Filter {
+            device: device(&metadata).map(|dev| dev.wrapping_add(1)),
+            ..Filter::new()
+        };
+        assert!(!filter.is_included(&root, &metadata).unwrap());
+    }
+
+    #[test]
+    fn test_caches() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let (tagged, known) = (root.join("tagged"), root.join("node_modules"));
+        fs::create_dir_all(&tagged).expect("Cannot create dir");
+        fs::create_dir_all(&known).expect("Cannot create dir");
+        let mut tag = CACHEDIR_SIGNATURE.to_vec();
+        tag.extend_from_slice(b"\n# This file is a cache directory tag.\n");
+        fs::write(tagged.join(CACHEDIR_TAG), &tag).expect("Cannot write tag");
+
+        let caches = |tagged, known| Caches { tagged, known };
+        assert!(!caches(false, false).excludes(&tagged));
+        assert!(caches(true, false).excludes(&tagged));
+        assert!(!caches(true, false).excludes(&known));
+        assert!(caches(false, true).excludes(&known));
+        assert!(!caches(false, true).excludes(&tagged));
+
+        // a tag without the signature is ignored
+        fs::write(tagged.join(CACHEDIR_TAG), "Signature: 0").unwrap();
+        assert!(!caches(true, true).excludes(&tagged));
+    }
+
+    #[test]
+    fn test_regex_filter() {
+        let regexes = RegexFilter::new(
+            &[r"\.(jpg|png)$"],
+            &[r"^tmp-\d+(/|$)", r"dump-\d{8}\.sql$"],
+        )
+        .expect("Invalid regexes");
+        let includes =
+            |path: &str, is_dir| regexes.includes(&PathBuf::from(path), is_dir);
+        assert!(includes("photos/a.jpg", false));
+        assert!(!includes("photos/a.txt", false));
+        assert!(includes("photos", true));
+        assert!(!includes("tmp-42", true));
+        assert!(!includes("tmp-42/b.png", false));
+        assert!(includes("tmp-x/b.png", false));
+        assert!(!includes("db/dump-20210314.sql", false));
+        assert!(RegexFilter::new(&["("], &[]).is_err());
+    }
+
+    #[cfg(feature = "update")]
+    #[test]
+    fn test_skip_hidden() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(root.join(".thumbnails"))
+            .expect("Cannot create dir");
+        fs::write(root.join(".DS_Store"), "").expect("Cannot write file");
+        fs::write(root.join("photo.jpg"), "").expect("Cannot write file");
+
+        let mut filter = Filter::new();
+        filter.set_skip_hidden(true);
+        let included = |name: &str| {
+            let path = root.join(name);
+            let metadata = fs::metadata(&path).expect("Cannot read metadata");
+            filter.is_included(&path, &metadata).unwrap()
+        };
+        assert!(!included(".thumbnails"));
+        assert!(!included(".DS_Store"));
+        assert!(included("photo.jpg"));
+    }
+}
@@ -0,0 +1,356 @@
+use crate::error::BkupError;
+use std::{
+    fmt,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+    str::FromStr,
+};
+use xxhash_rust::xxh3::Xxh3;
+
+/// Size of the buffer used to read the files to hash.
+const BUFFER_SIZE: usize = 64 * 1024;
+/// Size of each block hashed by a sample of a file.
+const SAMPLE_BLOCK_SIZE: u64 = 1024 * 1024;
+/// Number of blocks hashed by a sample of a file, evenly spaced from its
+/// first to its last block.
+const SAMPLE_BLOCKS: u64 = 16;
+
+/// Enumerates the algorithms used to hash the file contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum HashAlgorithm {
+    /// BLAKE3, cryptographic and faster than SHA-256.
+    Blake3,
+    /// 64-bit XXH3, the fastest but not cryptographic.
+    Xxh3,
+    /// SHA-256, the FIPS-approved one.
+    #[default]
+    Sha256,
+    /// CRC-32 (IEEE), only fit to detect accidental changes.
+    Crc32,
+}
+
+impl HashAlgorithm {
+    /// All the algorithms.
+    pub const ALL: [HashAlgorithm; 4] = [
+        HashAlgorithm::Blake3,
+        HashAlgorithm::Xxh3,
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Crc32,
+    ];
+
+    /// Gets the name of the algorithm.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3 => "xxh3",
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Crc32 => "crc32",
+        }
+    }
+
+    /// Gets the number of bytes of the digests.
+    fn len(&self) -> usize {
+        match self {
+            HashAlgorithm::Blake3 | HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Xxh3 => 8,
+            HashAlgorithm::Crc32 => 4,
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = BkupError;
+
+    /// Parses the name of an algorithm.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HashAlgorithm::ALL
+            .iter()
+            .find(|algorithm| algorithm.name() == s.to_lowercase())
+            .copied()
+            .ok_or_else(|| {
+                BkupError::Parse(format!("Invalid hash algorithm {:?}", s))
+            })
+    }
+}
+
+/// Represents the digest of a content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Digest {
+    algorithm: HashAlgorithm,
+    bytes: [u8; 32], // only the first bytes are used by the short digests
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for b in self.as_bytes() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl Digest {
+    /// Creates the digest of the given algorithm with the given bytes.
+    fn new(algorithm: HashAlgorithm, digest: &[u8]) -> Self {
+        let mut bytes = [0; 32];
+        bytes[..digest.len()].copy_from_slice(digest);
+        Digest { algorithm, bytes }
+    }
+
+    /// Parses the hexadecimal representation of a digest of the given
+    /// algorithm.
+    pub fn parse(algorithm: HashAlgorithm, s: &str) -> Result<Self, BkupError> {
+        let invalid = || BkupError::Parse(format!("Invalid digest {:?}", s));
+        let len = algorithm.len();
+        if s.len() != len * 2 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0; 32];
+        for (i, b) in bytes[..len].iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                .map_err(|_| invalid())?;
+        }
+        Ok(Digest { algorithm, bytes })
+    }
+
+    /// Gets the algorithm of the digest.
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Gets the bytes of the digest.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.algorithm.len()]
+    }
+}
+
+/// Incremental hasher of any algorithm.
+#[derive(Clone)]
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<Xxh3>),
+    Sha256(Sha256),
+    Crc32(crc32fast::Hasher),
+}
+
+impl Hasher {
+    /// Creates a new hasher of the given algorithm.
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+            HashAlgorithm::Xxh3 => Hasher::Xxh3(Box::default()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+        }
+    }
+
+    /// Feeds the given bytes to the hasher.
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Xxh3(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Crc32(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Consumes the hasher and returns the digest of the bytes fed so far.
+    pub fn finalize(self) -> Digest {
+        match self {
+            Hasher::Blake3(hasher) => {
+                Digest::new(HashAlgorithm::Blake3, hasher.finalize().as_bytes())
+            }
+            // the digests are written big-endian, as xxhsum and crc32 print them
+            Hasher::Xxh3(hasher) => {
+                Digest::new(HashAlgorithm::Xxh3, &hasher.digest().to_be_bytes())
+            }
+            Hasher::Sha256(hasher) => hasher.finalize(),
+            Hasher::Crc32(hasher) => Digest::new(
+                HashAlgorithm::Crc32,
+                &hasher.finalize().to_be_bytes(),
+            ),
+        }
+    }
+}
+
+/// Incremental SHA-256 hasher.
+#[derive(Clone, Default)]
+pub struct Sha256(sha2::Sha256);
+
+impl Sha256 {
+    /// Creates a new hasher.
+    pub fn new() -> Self {
+        Sha256::default()
+    }
+
+    /// Feeds the given bytes to the hasher.
+    pub fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(&mut self.0, data);
+    }
+
+    /// Consumes the hasher and returns the digest of the bytes fed so far.
+    pub fn finalize(self) -> Digest {
+        Digest::new(HashAlgorithm::Sha256, &sha2::Digest::finalize(self.0))
+    }
+}
+
+/// Computes the digest of the content of the given file with the given
+/// algorithm.
+pub fn hash_file(
+    path: &Path,
+    algorithm: HashAlgorithm,
+) -> Result<Digest, BkupError> {
+    let mut file = File::open(path)?;
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; BUFFER_SIZE];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Computes the digest of a sample of the content of the given file, made of
+/// blocks evenly spaced from its start to its end. The files that are not
+/// larger than the sample are hashed in full.
+pub fn hash_sample(
+    path: &Path,
+    algorithm: HashAlgorithm,
+) -> Result<Digest, BkupError> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len <= SAMPLE_BLOCK_SIZE * SAMPLE_BLOCKS {
+        return hash_file(path, algorithm);
+    }
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; SAMPLE_BLOCK_SIZE as usize];
+    for i in 0..SAMPLE_BLOCKS {
+        let offset = (len - SAMPLE_BLOCK_SIZE) * i / (SAMPLE_BLOCKS - 1);
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buffer)?;
+        hasher.update(&buffer);
+    }
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_sha256() {
+        let digest = |data: &[u8]| {
+            let mut hasher = Sha256::new();
+            hasher.update(data);
+            hasher.finalize().to_string()
+        };
+        assert_eq!(
+            digest(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            digest(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn test_sha256_incremental() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let mut hasher = Sha256::new();
+        hasher.update(&data);
+        let expected = hasher.finalize();
+        // feeding the same bytes in chunks of different sizes must not change
+        // the digest
+        for size in &[1, 3, 63, 64, 65, 200] {
+            let mut hasher = Sha256::new();
+            for chunk in data.chunks(*size) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finalize(), expected);
+        }
+        let parse = |s: &str| Digest::parse(HashAlgorithm::Sha256, s);
+        assert_eq!(parse(&expected.to_string()).unwrap(), expected);
+        assert!(parse("abc").is_err());
+    }
+
+    #[test]
+    fn test_hash_algorithms() {
+        let digest = |algorithm: HashAlgorithm| {
+            let mut hasher = Hasher::new(algorithm);
+            hasher.update(b"abc");
+            hasher.finalize()
+        };
+        assert_eq!(
+            digest(HashAlgorithm::Blake3).to_string(),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(digest(HashAlgorithm::Xxh3).to_string(), "78af5f94892f3950");
+        assert_eq!(digest(HashAlgorithm::Crc32).to_string(), "352441c2");
+        for algorithm in &HashAlgorithm::ALL {
+            let digest = digest(*algorithm);
+            assert_eq!(digest.algorithm(), *algorithm);
+            assert_eq!(
+                Digest::parse(*algorithm, &digest.to_string()).unwrap(),
+                digest
+            );
+            assert_eq!(
+                algorithm.name().parse::<HashAlgorithm>().unwrap(),
+                *algorithm
+            );
+        }
+        assert!(
+            Digest::parse(HashAlgorithm::Crc32, "78af5f94892f3950").is_err()
+        );
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn test_hash_sample() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        let path = root.join("video");
+        let block = SAMPLE_BLOCK_SIZE as usize;
+        let mut content = vec![0; block * (SAMPLE_BLOCKS as usize + 1)];
+        fs::write(&path, &content).expect("Cannot write file");
+        let sample = || {
+            hash_sample(&path, HashAlgorithm::Xxh3).expect("Cannot hash file")
+        };
+        let expected = sample();
+        assert_ne!(expected, hash_file(&path, HashAlgorithm::Xxh3).unwrap());
+
+        // the bytes between the first two blocks are not sampled
+        content[block + 1] = 1;
+        fs::write(&path, &content).expect("Cannot write file");
+        assert_eq!(sample(), expected);
+        for offset in [0, block - 1, content.len() - 1] {
+            content[offset] = 1;
+            fs::write(&path, &content).expect("Cannot write file");
+            assert_ne!(sample(), expected);
+            content[offset] = 0;
+        }
+
+        // the small files are hashed in full
+        fs::write(&path, "content").expect("Cannot write file");
+        assert_eq!(sample(), hash_file(&path, HashAlgorithm::Xxh3).unwrap());
+    }
+}
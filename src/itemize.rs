@@ -0,0 +1,117 @@
+//! Itemized changes of an update, printed one line per differing entry with
+//! rsync-style change flags.
+
+use std::{
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+/// Enumerates the differences of an entry reported by the itemized output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemKind {
+    /// The source file is missing at the destination.
+    NewFile,
+    /// The source directory is missing at the destination.
+    NewDir,
+    /// The source special file is missing at the destination.
+    NewSpecial,
+    /// The source file is newer than its destination, that is replaced.
+    Newer,
+    /// The source file is older than its destination, that is kept.
+    Older,
+}
+
+impl ItemKind {
+    /// Gets the change flags of the kind, as printed by `rsync -i`: the type
+    /// of update (`>` copied, `c` created, `.` unchanged), the type of entry
+    /// (`f` file, `d` directory, `D` special file) and the changed attributes (`+` new, `t`
+    /// modification time).
+    pub fn flags(&self) -> &'static str {
+        match self {
+            ItemKind::NewFile => ">f+++++++",
+            ItemKind::NewDir => "cd+++++++",
+            ItemKind::NewSpecial => "cD+++++++",
+            ItemKind::Newer => ">f..t....",
+            ItemKind::Older => ".f..t....",
+        }
+    }
+
+    /// Gets the ANSI color code of the kind.
+    fn color(&self) -> &'static str {
+        match self {
+            ItemKind::NewFile => "32",
+            ItemKind::NewDir => "34",
+            ItemKind::NewSpecial => "36",
+            ItemKind::Newer => "33",
+            ItemKind::Older => "35",
+        }
+    }
+}
+
+/// Represents a differing entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    /// Destination path of the entry.
+    pub path: PathBuf,
+    /// Difference of the entry.
+    pub kind: ItemKind,
+}
+
+impl Item {
+    /// Formats the line of the item, with its path relative to the given
+    /// destination directory, optionally colored.
+    fn line(&self, dest: &Path, color: bool) -> String {
+        let path = self.path.strip_prefix(dest).unwrap_or(&self.path);
+        let slash = if self.kind == ItemKind::NewDir {
+            "/"
+        } else {
+            ""
+        };
+        if color {
+            format!(
+                "\x1b[{}m{}\x1b[0m {}{}",
+                self.kind.color(),
+                self.kind.flags(),
+                path.display(),
+                slash
+            )
+        } else {
+            format!("{} {}{}", self.kind.flags(), path.display(), slash)
+        }
+    }
+}
+
+/// Prints the given items sorted by path to the standard output, colored
+/// when it's a terminal.
+pub fn print(mut items: Vec<Item>, dest: &Path) {
+    items.sort_by(|a, b| a.path.cmp(&b.path));
+    let stdout = io::stdout();
+    let color = stdout.is_terminal();
+    let mut stdout = stdout.lock();
+    for item in items {
+        let _ = writeln!(stdout, "{}", item.line(dest, color));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_item_line() {
+        let dest = Path::new("/dst");
+        let item = |path: &str, kind| Item {
+            path: dest.join(path),
+            kind,
+        };
+        let dir = item("docs", ItemKind::NewDir);
+        assert_eq!(dir.line(dest, false), "cd+++++++ docs/");
+        let newer = item("docs/a.txt", ItemKind::Newer);
+        assert_eq!(newer.line(dest, false), ">f..t.... docs/a.txt");
+        assert_eq!(
+            newer.line(dest, true),
+            "\x1b[33m>f..t....\x1b[0m docs/a.txt"
+        );
+    }
+}
@@ -0,0 +1,328 @@
+//! Append-only journal of the actions applied by an update.
+//!
+//! The first line of the journal records the source directory and the planned
+//! actions, and each following line the index of an action once it has been
+//! applied. If the update is interrupted, the journal lets the next update
+//! apply only the remaining actions, without visiting the directories again.
+
+use crate::{
+    copy,
+    error::BkupError,
+    json,
+    metadata::{self, JOURNAL_NAME},
+    plan::{Action, Plan},
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Gets the path of the journal of the given destination directory.
+pub fn journal_path(dest: &Path) -> PathBuf {
+    metadata::path(dest, JOURNAL_NAME)
+}
+
+/// Returns true if the given path is a journal file.
+pub fn is_journal(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == JOURNAL_NAME)
+}
+
+/// First line of the journal, with the source directory and the planned
+/// actions.
+#[derive(Serialize, Deserialize)]
+struct Header<'a> {
+    #[serde(serialize_with = "json::lossy")]
+    source: Cow<'a, Path>,
+    actions: Cow<'a, [Action]>,
+}
+
+/// Line of the journal recording the index of an applied action.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    done: usize,
+}
+
+/// Records the actions applied to a destination directory.
+pub struct Journal {
+    file: File,
+    path: PathBuf,
+    // source directory of the journaled update
+    source: PathBuf,
+    // index in the journaled plan of each action being applied
+    indexes: Vec<usize>,
+    // when set every record is flushed to the storage device
+    sync: bool,
+}
+
+impl Journal {
+    /// Creates the journal of the plan applied to the destination directory,
+    /// replacing the journal of any previous update.
+    pub fn create(
+        source: &Path,
+        dest: &Path,
+        plan: &Plan,
+        sync: bool,
+    ) -> Result<Self, BkupError> {
+        metadata::create_dir(dest)?;
+        let path = journal_path(dest);
+        let header = Header {
+            source: Cow::Borrowed(source),
+            actions: Cow::Borrowed(plan.actions()),
+        };
+        // the header is written to a temporary file that is renamed, so that
+        // the journal never exists without it
+        let temp = copy::temp_path(&path);
+        let mut file = File::create(&temp)?;
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        if sync {
+            file.sync_all()?;
+        }
+        drop(file);
+        fs::rename(&temp, &path)?;
+        if sync {
+            copy::sync_parent(&path)?;
+        }
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(Journal {
+            file,
+            path,
+            source: source.to_path_buf(),
+            indexes: (0..plan.len()).collect(),
+            sync,
+        })
+    }
+
+    /// Opens the journal left by an interrupted update of the destination
+    /// directory, if any, and gets the plan of the actions not yet applied. A
+    /// journal without a valid header is removed, as if there was none.
+    pub fn resume(
+        dest: &Path,
+        sync: bool,
+    ) -> Result<Option<(Self, Plan)>, BkupError> {
+        let path = journal_path(dest);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path)?;
+        let mut lines = content.lines();
+        let header: Header = match lines.next().map(serde_json::from_str) {
+            Some(Ok(header)) => header,
+            // the update was interrupted before it applied any action
+            _ => {
+                warn!("Removing the incomplete journal {:?}", path);
+                fs::remove_file(&path)?;
+                return Ok(None);
+            }
+        };
+        let source = header.source.into_owned();
+        let planned = Plan::from(header.actions.into_owned());
+        // the last record may have been only partially written
+        let applied: HashSet<usize> = lines
+            .filter_map(|line| serde_json::from_str::<Record>(line).ok())
+            .map(|record| record.done)
+            .collect();
+
+        let mut plan = Plan::new();
+        let mut indexes = Vec::new();
+        for (i, action) in planned.actions().iter().enumerate() {
+            if applied.contains(&i) {
+                continue;
+            }
+            if let Some(action) = pending(action) {
+                plan.push(action);
+                indexes.push(i);
+            }
+        }
+        info!(
+            "Resuming update of {:?}: {} of {} actions left",
+            dest,
+            plan.len(),
+            planned.len()
+        );
+
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        if !content.is_empty() && !content.ends_with('\n') {
+            writeln!(file)?;
+        }
+        let journal = Journal {
+            file,
+            path,
+            source,
+            indexes,
+            sync,
+        };
+        Ok(Some((journal, plan)))
+    }
+
+    /// Gets the source directory of the journaled update.
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    /// Records that the action with the given index in the applied plan was
+    /// completed.
+    pub fn record(&mut self, index: usize) -> Result<(), BkupError> {
+        let index = self.indexes[index];
+        let record = serde_json::to_string(&Record { done: index })?;
+        writeln!(self.file, "{}", record)?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Removes the journal once all its actions were applied.
+    pub fn finish(self) -> Result<(), BkupError> {
+        drop(self.file);
+        fs::remove_file(&self.path)?;
+        if self.sync {
+            copy::sync_parent(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Gets the action that completes the given action that may have been applied
+/// before the update was interrupted, but not recorded.
+fn pending(action: &Action) -> Option<Action> {
+    match action {
+        Action::Rename { from, to } if !from.exists() && to.exists() => None,
+        Action::CreateSpecial { dest, .. }
+            if dest.symlink_metadata().is_ok() =>
+        {
+            None
+        }
+        Action::Copy {
+            source, dest, size, ..
+        } => Some(Action::Copy {
+            source: source.clone(),
+            dest: dest.clone(),
+            size: *size,
+            overwrite: dest.exists(),
+        }),
+        Action::Link { target, dest, .. } => Some(Action::Link {
+            target: target.clone(),
+            dest: dest.clone(),
+            overwrite: dest.exists(),
+        }),
+        action => Some(action.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        copy::CopyOptions,
+        entry::{Accuracy, Entry},
+        filter::Filter,
+    };
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_resume_journal() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(source.join("dir")).expect("Cannot create dir");
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+        for name in &["file1", "file2", "file3"] {
+            fs::write(source.join("dir").join(name), name)
+                .expect("Cannot write file");
+        }
+
+        let ignore = false;
+        let filter = Filter::new();
+        let source_entry =
+            Entry::directory(&source, ignore, &filter).expect("Cannot visit");
+        let dest_entry =
+            Entry::directory(&dest, ignore, &filter).expect("Cannot visit");
+        let mut plan = Plan::new();
+        source_entry
+            .cmp(&dest_entry, &Accuracy::default())
+            .expect("Cannot compare entries")
+            .expect("Delta should be some")
+            .plan(&mut plan);
+        assert_eq!(plan.len(), 4);
+
+        // simulate an update interrupted after applying its actions, when
+        // only the first of them was recorded and the last record was being
+        // written
+        let sync = false;
+        let mut journal = Journal::create(&source, &dest, &plan, sync)
+            .expect("Cannot create");
+        let options = CopyOptions::default();
+        let mut applied = 0;
+        plan.apply_with(&options, |i, _, _| {
+            if i == 0 {
+                journal.record(i).expect("Cannot record");
+            }
+            applied += 1;
+        })
+        .expect("Cannot apply plan");
+        assert_eq!(applied, 4);
+        drop(journal);
+        OpenOptions::new()
+            .append(true)
+            .open(journal_path(&dest))
+            .and_then(|mut file| write!(file, "{{\"do"))
+            .expect("Cannot write journal");
+        fs::remove_file(dest.join("dir").join("file3")).expect("Cannot remove");
+
+        let (mut journal, remaining) = Journal::resume(&dest, sync)
+            .expect("Cannot resume")
+            .expect("Journal should be some");
+        assert_eq!(journal.source(), source);
+        assert_eq!(remaining.len(), 3);
+        remaining
+            .apply_with(&options, |i, _, _| {
+                journal.record(i).expect("Cannot record")
+            })
+            .expect("Cannot apply plan");
+        journal.finish().expect("Cannot finish journal");
+        assert!(!journal_path(&dest).exists());
+        assert_eq!(
+            fs::read_to_string(dest.join("dir").join("file3")).unwrap(),
+            "file3"
+        );
+    }
+
+    #[test]
+    fn test_incomplete_journal() {
+        let dest = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(dest.join(metadata::BKUP_DIR))
+            .expect("Cannot create dir");
+        let sync = false;
+        for content in &["", "{\"source\": \"/sou"] {
+            fs::write(journal_path(&dest), content).expect("Cannot write file");
+            assert!(Journal::resume(&dest, sync)
+                .expect("Cannot resume")
+                .is_none());
+            assert!(!journal_path(&dest).exists());
+        }
+
+        let plan = Plan::new();
+        let journal = Journal::create(Path::new("/source"), &dest, &plan, sync)
+            .expect("Cannot create");
+        drop(journal);
+        // only the journal is left, with its header
+        let files: Vec<_> = fs::read_dir(dest.join(metadata::BKUP_DIR))
+            .expect("Cannot read dir")
+            .map(|entry| entry.expect("Cannot read entry").file_name())
+            .collect();
+        assert_eq!(files, vec![JOURNAL_NAME]);
+        let (journal, remaining) = Journal::resume(&dest, sync)
+            .expect("Cannot resume")
+            .expect("Journal should be some");
+        assert_eq!(journal.source(), Path::new("/source"));
+        assert!(remaining.is_empty());
+        fs::remove_dir_all(dest).expect("Cannot remove dir");
+    }
+}
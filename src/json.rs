@@ -0,0 +1,83 @@
+use crate::error::BkupError;
+use serde::Serializer;
+use std::path::Path;
+
+pub use serde_json::Value;
+
+/// Creates a new JSON object from the given members, in insertion order.
+pub fn object<K: Into<String>>(members: Vec<(K, Value)>) -> Value {
+    Value::Object(members.into_iter().map(|(k, v)| (k.into(), v)).collect())
+}
+
+/// Parses the given JSON document.
+pub fn parse(s: &str) -> Result<Value, BkupError> {
+    Ok(serde_json::from_str(s)?)
+}
+
+/// Serializes the given path as a string, replacing the invalid UTF-8
+/// sequences (that serde would reject) with the replacement character.
+pub fn lossy<P, S>(path: P, serializer: S) -> Result<S::Ok, S::Error>
+where
+    P: AsRef<Path>,
+    S: Serializer,
+{
+    serializer.serialize_str(&path.as_ref().to_string_lossy())
+}
+
+/// Serializes the given optional path as a string, or null if missing.
+#[cfg(feature = "update")]
+pub fn lossy_option<S>(
+    path: &Option<impl AsRef<Path>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match path {
+        Some(path) => lossy(path, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        let value = object(vec![
+            ("path", Value::from("dir/\"file\"\n")),
+            ("size", Value::from(42u64)),
+            ("dir", Value::from(false)),
+            ("tags", Value::Array(vec![Value::Null, Value::from("a")])),
+        ]);
+        assert_eq!(
+            value.to_string(),
+            r#"{"path":"dir/\"file\"\n","size":42,"dir":false,"tags":[null,"a"]}"#
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let value = parse(
+            r#" { "include": true, "size": 18446744073709551615,
+                "name": "café 😀", "list": [1, -2.5e3, {}] } "#,
+        )
+        .expect("Cannot parse JSON");
+        assert_eq!(value.get("include").and_then(Value::as_bool), Some(true));
+        assert_eq!(value.get("size").and_then(Value::as_u64), Some(u64::MAX));
+        assert_eq!(value.get("name").and_then(Value::as_str), Some("café 😀"));
+        assert_eq!(
+            value.get("list").and_then(Value::as_array).unwrap().len(),
+            3
+        );
+
+        // serialized values must be parsed back to the same value
+        assert_eq!(parse(&value.to_string()).unwrap(), value);
+
+        assert!(parse("{\"a\": }").is_err());
+        assert!(parse("[1, 2").is_err());
+        assert!(parse("true false").is_err());
+    }
+}
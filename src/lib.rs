@@ -1,52 +1,615 @@
-#[macro_use]
-extern crate failure;
-
 #[cfg(test)]
 #[macro_use]
 extern crate lazy_static;
 
+#[cfg(feature = "update")]
+mod audit;
+mod cancel;
+mod checksums;
+#[cfg(feature = "update")]
+mod compress;
+#[cfg(feature = "update")]
+mod copy;
+mod date;
+mod delta;
+#[cfg(feature = "desktop")]
+mod desktop;
+#[cfg(feature = "update")]
+mod device;
 mod entry;
+mod error;
+#[cfg(feature = "update")]
+mod event;
+#[cfg(feature = "update")]
+mod eventlog;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod filter;
+mod hash;
+#[cfg(feature = "update")]
+mod itemize;
+#[cfg(feature = "update")]
+mod journal;
+mod json;
+#[cfg(feature = "update")]
+mod lock;
+#[cfg(feature = "update")]
+mod manifest;
+mod metadata;
+#[cfg(feature = "update")]
+mod migrate;
+mod names;
+#[cfg(feature = "async")]
+mod nonblocking;
+#[cfg(feature = "update")]
+mod notify;
+#[cfg(feature = "update")]
+mod parity;
+#[cfg(feature = "update")]
+mod pause;
+mod plan;
+#[cfg(feature = "update")]
+mod plugin;
+#[cfg(feature = "update")]
+mod rate;
+#[cfg(feature = "update")]
+mod rclone;
+#[cfg(feature = "update")]
+mod remote;
+#[cfg(feature = "update")]
+mod resume;
+mod rules;
+mod scans;
+mod size;
+mod special;
+mod state;
+mod stats;
+#[cfg(feature = "update")]
+mod transfer;
+#[cfg(feature = "update")]
+mod update;
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+mod uring;
+
+pub use cancel::{
+    cancel, cancel_on_signals, CancellationToken, CANCELLED_EXIT_CODE,
+};
+pub use checksums::{ChecksumFormat, ChecksumMismatch, ChecksumStatus};
+#[cfg(feature = "update")]
+pub use compress::Compression;
+#[cfg(feature = "update")]
+pub use copy::Engine;
+pub use date::parse_date;
+pub use delta::{Change, Delta, DiffOptions, Estimate, Mismatch, MismatchKind};
+pub use entry::Compare;
+pub use error::BkupError;
+#[cfg(feature = "update")]
+pub use event::{Event, EventHandler, RateMeter, Throughput};
+pub use filter::{AttributeFilter, Caches, EntryType, Predicate, RegexFilter};
+pub use hash::HashAlgorithm;
+#[cfg(feature = "update")]
+pub use manifest::{Damage, ScrubStatus};
+#[cfg(feature = "update")]
+pub use migrate::Migration;
+pub use names::{NameMatching, Normalization};
+#[cfg(feature = "async")]
+pub use nonblocking::{diff_async, update_async};
+#[cfg(feature = "update")]
+pub use notify::{NotifyOn, Webhook};
+#[cfg(feature = "update")]
+pub use pause::{pause, pause_on_signals, unpause};
+#[cfg(feature = "update")]
+pub use plan::{Failure, Failures, TransferOrder};
+#[cfg(feature = "update")]
+pub use remote::{push, serve, serve_stdio};
+pub use rules::{FilterRules, PriorityRules};
+pub use scans::{ScanChange, ScanStatus};
+pub use size::{format_size, parse_size};
+pub use special::{SpecialFiles, SpecialKind};
+pub use state::{SyncChange, SyncStatus};
+pub use stats::{Duplicates, Stats, Usage};
+#[cfg(feature = "update")]
+pub use transfer::{Transfer, TransferContext};
+#[cfg(feature = "update")]
+pub use update::{
+    update, update_with, update_with_confirm, ConfirmThreshold, Destruction,
+    UpdateOptions,
+};
 
-use entry::Entry;
-use failure::Error;
+use entry::{Accuracy, Entry};
+use filter::Filter;
 use log::*;
-use std::{path::PathBuf, thread, time::Duration};
+#[cfg(feature = "update")]
+use manifest::Manifest;
+use plan::{Action, Plan};
+use state::State;
+use std::{fs, path::PathBuf, time::Duration};
+
+/// Hashes every file of the given directory with the given algorithm, and
+/// writes their checksums in the given format (such as the one of
+/// `sha256sum`), to verify the directory with third-party tools.
+pub fn checksums(
+    path: PathBuf,
+    ignore: bool,
+    hash: HashAlgorithm,
+    format: ChecksumFormat,
+) -> Result<String, BkupError> {
+    info!("Hashing directory {:?}", path);
+    let checksums = checksums::checksums(&path, ignore, hash)?;
+    Ok(checksums::format(&checksums, format))
+}
+
+/// Compares the files of the given directory with the checksum file at the
+/// given path, in the format of `sha256sum` or of the BSD tools (whose lines
+/// without a tag are read as digests of the given algorithm), and gets the
+/// files that are missing, not listed or whose content doesn't match.
+pub fn verify_checksums(
+    path: PathBuf,
+    against: PathBuf,
+    ignore: bool,
+    hash: HashAlgorithm,
+) -> Result<Vec<ChecksumMismatch>, BkupError> {
+    info!("Verifying directory {:?} against {:?}", path, against);
+    let text = fs::read_to_string(&against)
+        .map_err(|e| BkupError::from(e).at(&against))?;
+    let checksums = checksums::parse(&text, hash)?;
+    // the checksum file is not listed if it's in the directory
+    let against = fs::canonicalize(&against)?;
+    let skip = fs::canonicalize(&path)
+        .ok()
+        .and_then(|dir| against.strip_prefix(dir).ok().map(|p| path.join(p)));
+    checksums::verify(&path, &checksums, ignore, skip.as_deref())
+}
+
+/// Visits the given directory and serializes its structure into a JSON scan,
+/// that can be compared later with `diff_scans`.
+pub fn scan(path: PathBuf, ignore: bool) -> Result<String, BkupError> {
+    info!("Scanning directory {:?}", path);
+    let entry = Entry::directory(&path, ignore, &Filter::new())?;
+    Ok(entry.to_json().to_string())
+}
+
+/// Reads the files of the destination directory again and gets the ones
+/// damaged since their digests were recorded by the last update (see
+/// `UpdateOptions::manifest`). When the source directory is given, the damaged
+/// files are copied again from their source, if it didn't change.
+#[cfg(feature = "update")]
+pub fn scrub(
+    dest: PathBuf,
+    source: Option<PathBuf>,
+) -> Result<Vec<Damage>, BkupError> {
+    info!("Scrubbing directory {:?}", dest);
+    let _lock = lock::Lock::acquire(&dest, false, false)?;
+    let mut manifest = Manifest::load(&dest)?;
+    if manifest.is_empty() {
+        return Err(BkupError::Invalid(format!(
+            "No manifest in {:?}, update it with the manifest option first",
+            dest
+        )));
+    }
+    let damages = manifest.scrub(&dest, source.as_deref(), false)?;
+    if damages.iter().any(|damage| damage.repaired) {
+        manifest.save(&dest)?;
+    }
+    Ok(damages)
+}
+
+/// Scrubs the destination directory as `scrub` does, and rebuilds the
+/// corrupted files with their parity (see `UpdateOptions::parity`), copying
+/// the ones that cannot be rebuilt again from the source directory, if given.
+#[cfg(feature = "update")]
+pub fn repair(
+    dest: PathBuf,
+    source: Option<PathBuf>,
+) -> Result<Vec<Damage>, BkupError> {
+    info!("Repairing directory {:?}", dest);
+    let _lock = lock::Lock::acquire(&dest, false, false)?;
+    let mut manifest = Manifest::load(&dest)?;
+    if manifest.is_empty() {
+        return Err(BkupError::Invalid(format!(
+            "No manifest in {:?}, update it with the parity option first",
+            dest
+        )));
+    }
+    let damages = manifest.scrub(&dest, source.as_deref(), true)?;
+    if damages.iter().any(|damage| damage.repaired) {
+        manifest.save(&dest)?;
+    }
+    Ok(damages)
+}
+
+/// Moves the files stored in the root of the destination directory by the
+/// previous releases (such as `.bkup-manifest`) to its `.bkup` directory,
+/// after backing them up, and gets the files moved. When `dry_run` is set the
+/// files to move are only returned.
+#[cfg(feature = "update")]
+pub fn migrate(
+    dest: PathBuf,
+    dry_run: bool,
+) -> Result<Vec<Migration>, BkupError> {
+    info!("Migrating directory {:?}", dest);
+    migrate::migrate(&dest, dry_run)
+}
+
+/// Visits the given directory and gets its size breakdown by top-level entry,
+/// with the given number of largest files.
+pub fn stats(
+    path: PathBuf,
+    ignore: bool,
+    largest: usize,
+) -> Result<Stats, BkupError> {
+    info!("Scanning directory {:?}", path);
+    let entry = Entry::directory(&path, ignore, &Filter::new())?;
+    Ok(Stats::new(&entry, largest))
+}
+
+/// Visits the given directory and gets the sets of its files with the same
+/// content (compared by their digests of the given algorithm), sorted by
+/// decreasing wasted space.
+pub fn duplicates(
+    path: PathBuf,
+    ignore: bool,
+    hash: HashAlgorithm,
+) -> Result<Vec<Duplicates>, BkupError> {
+    info!("Scanning directory {:?}", path);
+    let entry = Entry::directory(&path, ignore, &Filter::new())?;
+    info!("Hashing the files of the same size");
+    Ok(stats::duplicates(&entry, hash))
+}
+
+/// Computes the actions needed to update the destination with the source,
+/// given their JSON scans, and serializes them into a JSON array. The scans
+/// are only compared without accessing the filesystem.
+pub fn diff_scans(
+    source: &str,
+    dest: &str,
+    accuracy: Duration,
+) -> Result<String, BkupError> {
+    let source = Entry::from_json(&json::parse(source)?)?;
+    let dest = Entry::from_json(&json::parse(dest)?)?;
+    let mut plan = Plan::new();
+    if let Some(delta) = source.cmp(&dest, &Accuracy::new(accuracy))? {
+        delta.plan(&mut plan);
+    }
+    Ok(plan.to_json().to_string())
+}
+
+/// Compares two JSON scans of the same directory taken at different times, and
+/// gets which files were added, removed or modified from the older to the
+/// newer one, sorted by path, without accessing the filesystem.
+pub fn compare_scans(
+    older: &str,
+    newer: &str,
+) -> Result<Vec<ScanChange>, BkupError> {
+    let older = Entry::from_json(&json::parse(older)?)?;
+    let newer = Entry::from_json(&json::parse(newer)?)?;
+    Ok(scans::changes(&older, &newer))
+}
 
-/// Updates the destination directory according to its delta with the source
-/// directory.
-pub fn update(
+/// Gets the filter of the entries visited to compare a source and a
+/// destination directory with the given options.
+fn filter(options: &DiffOptions) -> Filter {
+    let mut filter = Filter::new();
+    filter.set_cancel(options.cancel.clone());
+    filter.set_names(options.names);
+    for predicate in &options.predicates {
+        filter.add_predicate(predicate.clone());
+    }
+    filter
+}
+
+/// Visits the source and destination directories and gets the differences of
+/// the destination from the source, without modifying either of them.
+pub fn diff(
+    source: PathBuf,
+    dest: PathBuf,
+    options: &DiffOptions,
+) -> Result<Delta, BkupError> {
+    info!("Comparing directory {:?} with {:?}", dest, source);
+    let filter = filter(options);
+    let source = Entry::directory(&source, options.ignore, &filter)?;
+    let dest = Entry::directory(&dest, options.ignore, &filter)?;
+    let mut delta = Delta::default();
+    let accuracy = Accuracy {
+        compare: options.compare,
+        ignore_dst: options.ignore_dst,
+        ..Accuracy::new(options.accuracy)
+    };
+    source.hash_contents(&dest, &accuracy);
+    if let Some(entry) = source.cmp(&dest, &accuracy)? {
+        entry.collect(&mut delta);
+    }
+    delta.changes.sort_by(|a, b| a.dest().cmp(b.dest()));
+    Ok(delta)
+}
+
+/// Visits the source and destination directories and estimates how many files
+/// and bytes an update would copy (with the largest of them, up to the number
+/// of the options), without modifying either of them.
+pub fn estimate(
+    source: PathBuf,
+    dest: PathBuf,
+    options: &DiffOptions,
+) -> Result<Estimate, BkupError> {
+    info!("Estimating the update of {:?} from {:?}", dest, source);
+    let filter = filter(options);
+    let source = Entry::directory(&source, options.ignore, &filter)?;
+    let dest = Entry::directory(&dest, options.ignore, &filter)?;
+    let accuracy = Accuracy {
+        compare: options.compare,
+        ignore_dst: options.ignore_dst,
+        ..Accuracy::new(options.accuracy)
+    };
+    source.hash_contents(&dest, &accuracy);
+    let mut plan = Plan::new();
+    if let Some(delta) = source.cmp(&dest, &accuracy)? {
+        delta.plan(&mut plan);
+    }
+    let mut files: Vec<_> = plan
+        .actions()
+        .iter()
+        .filter_map(|action| match action {
+            Action::Copy { source, size, .. } => Some((source.clone(), *size)),
+            _ => None,
+        })
+        .collect();
+    let count = files.len();
+    files.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    files.truncate(options.largest);
+    Ok(Estimate {
+        files: count,
+        bytes: plan.bytes(),
+        largest: files,
+    })
+}
+
+/// Visits the source and destination directories and gets how their files
+/// changed since the last update that saved their state (see
+/// `UpdateOptions::save_state`), sorted by path. Every file is new if the
+/// state was never saved.
+pub fn sync_changes(
+    source: PathBuf,
+    dest: PathBuf,
+    options: &DiffOptions,
+) -> Result<Vec<SyncChange>, BkupError> {
+    let state = State::load(&dest)?;
+    let filter = filter(options);
+    let source = Entry::directory(&source, options.ignore, &filter)?;
+    let dest = Entry::directory(&dest, options.ignore, &filter)?;
+    Ok(state.changes(&source, &dest))
+}
+
+/// Visits the source and destination directories and serializes the actions
+/// needed to update the destination into a JSON array, without modifying
+/// either of them.
+pub fn diff_plan(
     source: PathBuf,
     dest: PathBuf,
     accuracy: Duration,
     ignore: bool,
-) -> Result<(), Error> {
-    info!(
-        "Updating directory {:?} with content of {:?} ({:?} accuracy - ignore: {})",
-        dest, source, accuracy, ignore
-    );
+) -> Result<String, BkupError> {
+    info!("Comparing directory {:?} with {:?}", dest, source);
+    let source = Entry::directory(&source, ignore, &Filter::new())?;
+    let dest = Entry::directory(&dest, ignore, &Filter::new())?;
+    let mut plan = Plan::new();
+    if let Some(delta) = source.cmp(&dest, &Accuracy::new(accuracy))? {
+        delta.plan(&mut plan);
+    }
+    Ok(plan.to_json().to_string())
+}
 
-    // spawn thread used to visit the destination directory
-    let handle = thread::spawn(move || {
-        info!("Exploring destination directory {:?}", dest);
-        Entry::directory(&dest, ignore)
-    });
+/// Verifies that every file of the source directory has a copy with the same
+/// content (compared with the given hash algorithm) in the destination
+/// directory, and gets the files that don't, without modifying either of
+/// them.
+pub fn verify(
+    source: PathBuf,
+    dest: PathBuf,
+    ignore: bool,
+    hash: HashAlgorithm,
+) -> Result<Vec<Mismatch>, BkupError> {
+    info!("Verifying directory {:?} against {:?}", dest, source);
+    let entry = Entry::directory(&source, ignore, &Filter::new())?;
+    let mut mismatches = Vec::new();
+    for file in entry.all_files() {
+        let path = file
+            .path()
+            .strip_prefix(&source)
+            .map_err(|e| BkupError::Compare(e.to_string()))?;
+        let copy = dest.join(path);
+        let status = if !copy.is_file() {
+            MismatchKind::Missing
+        } else if copy.metadata()?.len() != file.size()
+            || hash::hash_file(&copy, hash)?
+                != hash::hash_file(file.path(), hash)?
+        {
+            MismatchKind::Different
+        } else {
+            continue;
+        };
+        debug!("{:?} is {} in {:?}", path, status.name(), dest);
+        mismatches.push(Mismatch {
+            path: path.to_path_buf(),
+            status,
+        });
+    }
+    Ok(mismatches)
+}
 
-    info!("Exploring source directory {:?}", source);
-    let source = Entry::directory(&source, ignore)?;
+#[cfg(test)]
+mod tests {
 
-    let dest = handle
-        .join()
-        .expect("Couldn't join on the destination visit thread")?;
+    use super::*;
+    use json::Value;
+    use std::{env, fs, path::Path, thread};
+    use uuid::Uuid;
 
-    info!("Computing difference");
-    let delta = source.cmp(&dest, &accuracy)?;
-    debug!("Delta: {:?}", delta);
+    #[test]
+    fn test_diff_scans() {
+        let source = r#"{"type": "dir", "path": "/src", "entries": [
+            {"type": "file", "path": "/src/new", "size": 1, "modified": 0},
+            {"type": "file", "path": "/src/newer", "size": 2,
+             "modified": 5000000000},
+            {"type": "file", "path": "/src/older", "size": 3, "modified": 0}
+        ]}"#;
+        let dest = r#"{"type": "dir", "path": "/dst", "entries": [
+            {"type": "file", "path": "/dst/newer", "size": 2, "modified": 0},
+            {"type": "file", "path": "/dst/older", "size": 3,
+             "modified": 5000000000}
+        ]}"#;
+        let plan = diff_scans(source, dest, Duration::from_millis(2000))
+            .expect("Cannot compare scans");
+        let plan = json::parse(&plan).expect("Invalid plan");
+        let mut actions: Vec<_> = plan
+            .as_array()
+            .expect("The plan must be an array")
+            .iter()
+            .map(|action| {
+                let dest = action.get("dest").and_then(Value::as_str);
+                let overwrite =
+                    action.get("overwrite").and_then(Value::as_bool);
+                (dest.unwrap().to_string(), overwrite.unwrap())
+            })
+            .collect();
+        actions.sort();
+        assert_eq!(
+            actions,
+            vec![
+                ("/dst/new".to_string(), false),
+                ("/dst/newer".to_string(), true)
+            ]
+        );
 
-    if let Some(delta) = delta {
-        info!("Updating destination");
-        delta.clear()?;
+        assert!(diff_scans("{}", dest, Duration::from_millis(0)).is_err());
     }
 
-    info!("Update completed");
-    Ok(())
+    #[test]
+    fn test_verify() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(source.join("dir")).expect("Cannot create dir");
+        fs::create_dir_all(dest.join("dir")).expect("Cannot create dir");
+        for name in &["same", "different", "missing"] {
+            fs::write(source.join("dir").join(name), "content")
+                .expect("Cannot write file");
+        }
+        fs::write(dest.join("dir").join("same"), "content")
+            .expect("Cannot write file");
+        fs::write(dest.join("dir").join("different"), "CONTENT")
+            .expect("Cannot write file");
+
+        let ignore = false;
+        let mut mismatches =
+            verify(source, dest.clone(), ignore, HashAlgorithm::Crc32)
+                .expect("Cannot verify");
+        mismatches.sort_by(|a, b| a.path.cmp(&b.path));
+        let mismatch = |name: &str, status| Mismatch {
+            path: Path::new("dir").join(name),
+            status,
+        };
+        assert_eq!(
+            mismatches,
+            vec![
+                mismatch("different", MismatchKind::Different),
+                mismatch("missing", MismatchKind::Missing)
+            ]
+        );
+        // the destination is left untouched
+        assert!(!dest.join("dir").join("missing").exists());
+    }
+
+    #[test]
+    fn test_diff() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(source.join("new")).expect("Cannot create dir");
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+        fs::write(source.join("new").join("file"), "content")
+            .expect("Cannot write file");
+        fs::write(source.join("file"), "new").expect("Cannot write file");
+
+        let options = DiffOptions::new();
+        let delta = diff(source.clone(), dest.clone(), &options)
+            .expect("Cannot compare directories");
+        assert_eq!(
+            delta.changes,
+            vec![
+                Change::MissingFile {
+                    source: source.join("file"),
+                    dest: dest.join("file"),
+                    size: 3,
+                },
+                Change::MissingDir {
+                    source: source.join("new"),
+                    dest: dest.join("new"),
+                    size: 7,
+                },
+            ]
+        );
+        assert_eq!(delta.bytes(), 10);
+        // the delta doesn't borrow the visited entries
+        let delta = thread::spawn(move || delta).join().unwrap();
+        assert_eq!(delta.to_json().as_array().map(Vec::len), Some(2));
+
+        // the entries excluded by the predicates are not compared
+        let options = DiffOptions::new()
+            .filter(|_, metadata| metadata.is_dir() || metadata.len() > 3);
+        let delta = diff(source.clone(), dest.clone(), &options)
+            .expect("Cannot compare directories");
+        assert_eq!(
+            delta.changes,
+            vec![Change::MissingDir {
+                source: source.join("new"),
+                dest: dest.join("new"),
+                size: 7,
+            }]
+        );
+
+        // the files of the missing directories are counted one by one
+        let options = DiffOptions::new().largest(1);
+        let estimate = estimate(source.clone(), dest, &options)
+            .expect("Cannot estimate update");
+        assert_eq!(
+            estimate,
+            Estimate {
+                files: 2,
+                bytes: 10,
+                largest: vec![(source.join("new").join("file"), 7)],
+            }
+        );
+        assert_eq!(estimate.duration(4), Some(Duration::from_millis(2500)));
+    }
+
+    #[test]
+    fn test_diff_case_insensitive() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(source.join("Dir")).expect("Cannot create dir");
+        fs::create_dir_all(dest.join("dir")).expect("Cannot create dir");
+        fs::write(dest.join("dir").join("README.md"), "old")
+            .expect("Cannot write file");
+        thread::sleep(Duration::from_millis(20));
+        fs::write(source.join("Dir").join("Readme.md"), "new")
+            .expect("Cannot write file");
+        fs::write(source.join("Dir").join("README.MD"), "new")
+            .expect("Cannot write file");
+
+        let options = DiffOptions::new().names(NameMatching {
+            case_insensitive: true,
+            normalization: None,
+        });
+        let delta = diff(source.clone(), dest.clone(), &options)
+            .expect("Cannot compare directories");
+        // only one of the colliding files replaces the destination file
+        assert_eq!(delta.changes.len(), 1);
+        match &delta.changes[0] {
+            Change::NewerFile { dest: path, .. } => {
+                assert_eq!(path, &dest.join("dir").join("README.md"))
+            }
+            change => panic!("Unexpected change {:?}", change),
+        }
+    }
 }
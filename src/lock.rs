@@ -0,0 +1,271 @@
+//! Advisory lock of a destination directory, that prevents concurrent updates.
+//!
+//! The lock is a file in the destination directory that records the process
+//! holding it, and whose modification time is refreshed periodically as a
+//! heartbeat. A lock is stale if its heartbeat stopped, or if its process is
+//! no longer running on this host.
+
+use crate::{
+    error::BkupError,
+    metadata::{self, LOCK_NAME},
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime},
+};
+
+/// Interval between two heartbeats of the lock.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Time without heartbeats after which the lock is stale.
+const STALE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Interval between two attempts to take a lock held by another process.
+const WAIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Lock of a destination directory, released when dropped.
+pub struct Lock {
+    path: PathBuf,
+    // dropped to stop the heartbeat
+    stop: Option<Sender<()>>,
+    heartbeat: Option<JoinHandle<()>>,
+}
+
+impl Lock {
+    /// Takes the lock of the destination directory. If the lock is held by
+    /// another process, waits until it is released if `wait` is set, and
+    /// breaks it if `break_stale` is set and the lock is stale.
+    pub fn acquire(
+        dest: &Path,
+        wait: bool,
+        break_stale: bool,
+    ) -> Result<Self, BkupError> {
+        metadata::create_dir(dest)?;
+        let path = metadata::path(dest, LOCK_NAME);
+        let mut waiting = false;
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(owner().as_bytes())?;
+                    debug!("Destination {:?} locked", dest);
+                    return Ok(Lock::with_heartbeat(path));
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(e.into()),
+            }
+
+            let holder = Holder::read(&path)?;
+            if holder.is_stale() {
+                if break_stale {
+                    warn!("Breaking stale lock of {:?} ({})", dest, holder);
+                    remove_lock(&path)?;
+                    continue;
+                }
+                return Err(BkupError::Locked(format!(
+                    "The destination {:?} has a stale lock ({}), use \
+                     --break-stale-lock to break it",
+                    dest, holder
+                )));
+            }
+            if !wait {
+                return Err(BkupError::Locked(format!(
+                    "The destination {:?} is locked by another update ({}), \
+                     use --wait to wait for it",
+                    dest, holder
+                )));
+            }
+            if !waiting {
+                info!("Waiting for the lock of {:?} ({})", dest, holder);
+                waiting = true;
+            }
+            thread::sleep(WAIT_INTERVAL);
+        }
+    }
+
+    /// Creates the lock of the given file, refreshing it periodically.
+    fn with_heartbeat(path: PathBuf) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let lock = path.clone();
+        let heartbeat = thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(HEARTBEAT_INTERVAL)
+            {
+                if let Err(e) = fs::write(&lock, owner()) {
+                    warn!("Cannot refresh lock {:?}: {}", lock, e);
+                }
+            }
+        });
+        Lock {
+            path,
+            stop: Some(stop),
+            heartbeat: Some(heartbeat),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(heartbeat) = self.heartbeat.take() {
+            let _ = heartbeat.join();
+        }
+        if let Err(e) = remove_lock(&self.path) {
+            warn!("Cannot release lock {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Process that took a lock, as written in the lock file.
+#[derive(Default, Serialize, Deserialize)]
+struct Owner {
+    pid: Option<u64>,
+    host: Option<String>,
+}
+
+/// Process holding a lock, as recorded in the lock file.
+struct Holder {
+    pid: Option<u64>,
+    host: Option<String>,
+    // time elapsed since the last heartbeat
+    elapsed: Duration,
+}
+
+impl Holder {
+    /// Reads the holder of the given lock file.
+    fn read(path: &Path) -> Result<Self, BkupError> {
+        // the lock may be being written, in which case its holder is unknown
+        let owner = fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Owner>(&content).ok())
+            .unwrap_or_default();
+        let modified = fs::metadata(path)?.modified()?;
+        Ok(Holder {
+            pid: owner.pid,
+            host: owner.host,
+            elapsed: SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Returns true if the holder stopped refreshing the lock, or if it's a
+    /// process of this host that is no longer running.
+    fn is_stale(&self) -> bool {
+        if self.elapsed > STALE_TIMEOUT {
+            return true;
+        }
+        match (self.pid, &self.host) {
+            (Some(pid), Some(host)) if Some(host) == hostname().as_ref() => {
+                !is_running(pid)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "process {}", pid)?,
+            None => write!(f, "unknown process")?,
+        }
+        if let Some(host) = &self.host {
+            write!(f, " on {}", host)?;
+        }
+        write!(f, ", last heartbeat {}s ago", self.elapsed.as_secs())
+    }
+}
+
+/// Gets the content of the locks taken by this process.
+fn owner() -> String {
+    let owner = Owner {
+        pid: Some(process::id() as u64),
+        host: hostname(),
+    };
+    serde_json::to_string(&owner).unwrap_or_default()
+}
+
+/// Removes the given lock file, if it still exists.
+fn remove_lock(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Gets the name of this host.
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut name = [0u8; 256];
+    // safe since the buffer is valid for its whole length
+    if unsafe { libc::gethostname(name.as_mut_ptr() as _, name.len()) } != 0 {
+        return None;
+    }
+    let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+    Some(String::from_utf8_lossy(&name[..len]).into_owned())
+}
+
+/// The host name is not available on this platform.
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    None
+}
+
+/// Returns true if the process with the given identifier is running.
+#[cfg(unix)]
+fn is_running(pid: u64) -> bool {
+    // safe since no signal is actually sent
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0
+        || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+/// The processes cannot be checked on this platform.
+#[cfg(not(unix))]
+fn is_running(_pid: u64) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_lock() {
+        let dest = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+        let (wait, break_stale) = (false, true);
+
+        let lock =
+            Lock::acquire(&dest, wait, break_stale).expect("Cannot lock");
+        assert!(metadata::path(&dest, LOCK_NAME).is_file());
+        // the lock is held by a running process
+        assert!(Lock::acquire(&dest, wait, break_stale).is_err());
+        drop(lock);
+        assert!(!metadata::path(&dest, LOCK_NAME).exists());
+
+        // the lock is held by a process that is no longer running
+        if let Some(host) = hostname() {
+            let owner = Owner {
+                pid: Some(i32::MAX as u64),
+                host: Some(host),
+            };
+            let owner = serde_json::to_string(&owner).unwrap();
+            fs::write(metadata::path(&dest, LOCK_NAME), owner)
+                .expect("Cannot write lock");
+            assert!(Lock::acquire(&dest, wait, false).is_err());
+            let lock =
+                Lock::acquire(&dest, wait, break_stale).expect("Cannot lock");
+            drop(lock);
+        }
+    }
+}
@@ -1,45 +1,458 @@
 #[macro_use]
 extern crate clap;
 
-use clap::{App, ArgMatches};
+use bkup::BkupError as Error;
+use clap::{App, ArgMatches, Shell};
 use dotenv::dotenv;
-use failure::{err_msg, Error};
-use std::{env, path::PathBuf, time::Duration};
+mod config;
+#[cfg(feature = "tui")]
+mod tui;
+
+use std::{
+    env, fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
 /// CLI commands
+const COMPLETIONS_CMD: &str = "completions";
+const DIFF_CMD: &str = "diff";
+const DUPES_CMD: &str = "dupes";
+const ESTIMATE_CMD: &str = "estimate";
+const MANIFEST_CMD: &str = "manifest";
+const MIGRATE_CMD: &str = "migrate";
+const PUSH_CMD: &str = "push";
+const REPAIR_CMD: &str = "repair";
+const SCAN_CMD: &str = "scan";
+const SCRUB_CMD: &str = "scrub";
+const SERVE_CMD: &str = "serve";
+const STATS_CMD: &str = "stats";
+const TUI_CMD: &str = "tui";
 const UPDATE_CMD: &str = "update";
+const VERIFY_CMD: &str = "verify";
 // CLI commands args
 const ACCURACY_ARG: &str = "accuracy";
+const AGAINST_ARG: &str = "against";
+const AUDIT_LOG_ARG: &str = "audit-log";
+const BANDWIDTH_ARG: &str = "bandwidth";
+const BREAK_STALE_LOCK_ARG: &str = "break-stale-lock";
+const BUFFER_SIZE_ARG: &str = "buffer-size";
+const BWLIMIT_ARG: &str = "bwlimit";
+const CASE_INSENSITIVE_ARG: &str = "case-insensitive";
+const COMPARE_ARG: &str = "compare";
+const COMPRESSION_ARG: &str = "compression";
+const CONFIRM_BYTES_ARG: &str = "confirm-bytes";
+const CONFIRM_FILES_ARG: &str = "confirm-files";
 const DEST_ARG: &str = "dest";
+const DETECT_MOVES_ARG: &str = "detect-moves";
+const DETECT_SKEW_ARG: &str = "detect-skew";
+const DEVICE_JOBS_ARG: &str = "device-jobs";
+const DIRECT_IO_ARG: &str = "direct-io";
+const DRY_RUN_ARG: &str = "dry-run";
+const ENGINE_ARG: &str = "engine";
+const EVENT_LOG_ARG: &str = "event-log";
+const EXCLUDE_CACHES_ARG: &str = "exclude-caches";
+const EXCLUDE_KNOWN_CACHES_ARG: &str = "exclude-known-caches";
+const EXCLUDE_REGEX_ARG: &str = "exclude-regex";
+const FILTER_FROM_ARG: &str = "filter-from";
+const FORCE_ARG: &str = "force";
+const FORMAT_ARG: &str = "format";
+const FSYNC_ARG: &str = "fsync";
+const HARD_LINKS_ARG: &str = "hard-links";
+const HASH_ARG: &str = "hash";
 const IGNORE_ARG: &str = "ignore";
+const INCLUDE_REGEX_ARG: &str = "include-regex";
+const IGNORE_DST_ARG: &str = "ignore-dst";
+const ITEMIZE_ARG: &str = "itemize";
+const JOBS_ARG: &str = "jobs";
+const KEEP_GOING_ARG: &str = "keep-going";
+const LISTEN_ARG: &str = "listen";
+const LOCKED_RETRIES_ARG: &str = "locked-retries";
+const LOG_FORMAT_ARG: &str = "log-format";
+const MANIFEST_ARG: &str = "manifest";
+const MAX_SIZE_ARG: &str = "max-size";
+const MAX_TRANSFER_ARG: &str = "max-transfer";
+const MIN_SIZE_ARG: &str = "min-size";
+const NEWER_THAN_ARG: &str = "newer-than";
+const NORMALIZE_ARG: &str = "normalize";
+const NOTIFY_ARG: &str = "notify";
+const NOTIFY_ON_ARG: &str = "notify-on";
+const NOTIFY_URL_ARG: &str = "notify-url";
+const ORDER_ARG: &str = "order";
+const ONE_FILE_SYSTEM_ARG: &str = "one-file-system";
+const OUTPUT_ARG: &str = "output";
+const PARITY_ARG: &str = "parity";
+const PARTIAL_ARG: &str = "partial";
+const PATH_ARG: &str = "path";
+const PIN_ARG: &str = "pin";
+const PLUGIN_ARG: &str = "plugin";
+const PRIORITY_HIGH_ARG: &str = "priority-high";
+const PRIORITY_LOW_ARG: &str = "priority-low";
+const PROGRESS_ARG: &str = "progress";
+const QUIET_ARG: &str = "quiet";
+const REMOTE_ARG: &str = "remote";
+const REFLINK_ARG: &str = "reflink";
+const RESUME_ARG: &str = "resume";
+const RETRIES_ARG: &str = "retries";
+const RETRY_DELAY_ARG: &str = "retry-delay";
+const SAVE_STATE_ARG: &str = "save-state";
+const SCAN_ARG: &str = "scan";
+const SHELL_ARG: &str = "shell";
+const SHRED_ARG: &str = "shred";
+const SKIP_HIDDEN_ARG: &str = "skip-hidden";
 const SOURCE_ARG: &str = "source";
+const SPECIAL_FILES_ARG: &str = "special-files";
+const STDIO_ARG: &str = "stdio";
+const TOP_ARG: &str = "top";
+const TYPE_ARG: &str = "type";
+const VERBOSE_ARG: &str = "verbose";
+const VERIFY_ARG: &str = "verify";
+const WAIT_ARG: &str = "wait";
+const YES_ARG: &str = "yes";
 
 // Default accuracy in ms (2s for FAT filesystem as worst case scenario)
 const DEFAULT_ACCURACY: &str = "2000";
+// Default address of the agent serving a destination
+const DEFAULT_LISTEN: &str = "127.0.0.1:7373";
+// Minimum interval between two progress lines of an update
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+// Default number of largest files reported by the stats
+const DEFAULT_TOP: usize = 10;
+// Default delay in ms before retrying a failed copy
+const DEFAULT_RETRY_DELAY: u64 = 1000;
 
-fn main() -> Result<(), Error> {
-    // set default value for logger priority to INFO if not set
-    if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "bkup=info");
-    }
+// Exit codes (130 when cancelled)
+const UP_TO_DATE_EXIT_CODE: i32 = 0;
+const FATAL_EXIT_CODE: i32 = 1;
+const UPDATED_EXIT_CODE: i32 = 2;
+const FAILURES_EXIT_CODE: i32 = 3;
 
+fn main() {
+    // the environment takes precedence over the .env file, that takes
+    // precedence over the configuration file
     dotenv().ok();
-    env_logger::init();
-
+    let config = config::load();
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
 
-    match matches.subcommand() {
+    // the verbosity flags take precedence over RUST_LOG, whose default
+    // logger priority is INFO
+    match log_filter(&matches) {
+        Some(filter) => env::set_var("RUST_LOG", filter),
+        None if env::var("RUST_LOG").is_err() => {
+            env::set_var("RUST_LOG", "bkup=info")
+        }
+        None => {}
+    }
+    env_logger::init();
+    if let Err(e) = config {
+        process::exit(exit_code(Err(e)));
+    }
+
+    let result = match matches.subcommand() {
+        (COMPLETIONS_CMD, Some(matches)) => {
+            cmd::completions(App::from_yaml(yaml), matches).map(|_| 0)
+        }
+        (DIFF_CMD, Some(matches)) => cmd::diff(matches).map(|_| 0),
+        (DUPES_CMD, Some(matches)) => cmd::dupes(matches).map(|_| 0),
+        (ESTIMATE_CMD, Some(matches)) => cmd::estimate(matches).map(|_| 0),
+        (MANIFEST_CMD, Some(matches)) => cmd::manifest(matches).map(|_| 0),
+        (MIGRATE_CMD, Some(matches)) => cmd::migrate(matches),
+        (SCAN_CMD, Some(matches)) => cmd::scan(matches).map(|_| 0),
+        (PUSH_CMD, Some(matches)) => cmd::push(matches),
+        (REPAIR_CMD, Some(matches)) => cmd::scrub(matches, true),
+        (SCRUB_CMD, Some(matches)) => cmd::scrub(matches, false),
+        (SERVE_CMD, Some(matches)) => cmd::serve(matches).map(|_| 0),
+        (STATS_CMD, Some(matches)) => cmd::stats(matches).map(|_| 0),
+        (TUI_CMD, Some(matches)) => cmd::tui(matches),
         (UPDATE_CMD, Some(matches)) => cmd::update(matches),
-        _ => Err(err_msg("Invalid command")),
+        (VERIFY_CMD, Some(matches)) => cmd::verify(matches),
+        _ => Err(Error::Invalid("Invalid command".to_string())),
+    };
+    process::exit(exit_code(result));
+}
+
+/// Gets the filter of the logs set by the verbosity flags, if any.
+fn log_filter(matches: &ArgMatches) -> Option<&'static str> {
+    let subcommand = matches.subcommand().1;
+    let present = |arg| {
+        matches.is_present(arg) || subcommand.is_some_and(|m| m.is_present(arg))
+    };
+    // the global flags given after the subcommand are also counted by its
+    // parent
+    let verbose = matches
+        .occurrences_of(VERBOSE_ARG)
+        .max(subcommand.map_or(0, |m| m.occurrences_of(VERBOSE_ARG)));
+    match verbose {
+        _ if present(QUIET_ARG) => Some("bkup=error"),
+        0 => None,
+        1 => Some("bkup=debug"),
+        2 => Some("bkup=trace"),
+        _ => Some("trace"),
+    }
+}
+
+/// Gets the exit code of the process according to the outcome of the command,
+/// that is the number of actions applied or the error that made it fail.
+fn exit_code(result: Result<usize, Error>) -> i32 {
+    match result {
+        Ok(0) => UP_TO_DATE_EXIT_CODE,
+        Ok(_) => UPDATED_EXIT_CODE,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            match e {
+                Error::Cancelled => bkup::CANCELLED_EXIT_CODE,
+                Error::Failures(_) => FAILURES_EXIT_CODE,
+                _ => FATAL_EXIT_CODE,
+            }
+        }
     }
 }
 
 mod cmd {
     use super::*;
 
-    /// Runs the update command.
-    pub fn update(matches: &ArgMatches) -> Result<(), Error> {
+    /// Runs the completions command, that prints the completion script of
+    /// the given shell generated from the definition of the CLI.
+    pub fn completions(
+        mut app: App,
+        matches: &ArgMatches,
+    ) -> Result<(), Error> {
+        let shell = matches
+            .value_of(SHELL_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", SHELL_ARG))
+            .parse::<Shell>()
+            .map_err(Error::Invalid)?;
+        app.gen_completions_to(
+            env!("CARGO_PKG_NAME"),
+            shell,
+            &mut io::stdout(),
+        );
+        Ok(())
+    }
+
+    /// Runs the scan command.
+    pub fn scan(matches: &ArgMatches) -> Result<(), Error> {
+        let source = matches
+            .value_of(SOURCE_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", SOURCE_ARG));
+        let ignore = flag(matches, IGNORE_ARG)?;
+        println!("{}", bkup::scan(PathBuf::from(source), ignore)?);
+        Ok(())
+    }
+
+    /// Runs the manifest command, that writes the checksums of the files of
+    /// a folder to the output file (or prints them).
+    pub fn manifest(matches: &ArgMatches) -> Result<(), Error> {
+        let path = matches
+            .value_of(PATH_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", PATH_ARG));
+        let hash = matches
+            .value_of(HASH_ARG)
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default();
+        let format = matches
+            .value_of(FORMAT_ARG)
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default();
+        let ignore = flag(matches, IGNORE_ARG)?;
+        let checksums =
+            bkup::checksums(PathBuf::from(path), ignore, hash, format)?;
+        match matches.value_of(OUTPUT_ARG) {
+            Some(output) => fs::write(output, checksums)?,
+            None => print!("{}", checksums),
+        }
+        Ok(())
+    }
+
+    /// Runs the verify command, that fails if the folder differs from the
+    /// checksum file.
+    pub fn verify(matches: &ArgMatches) -> Result<usize, Error> {
+        let path = matches
+            .value_of(PATH_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", PATH_ARG));
+        let against = matches
+            .value_of(AGAINST_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", AGAINST_ARG));
+        let hash = matches
+            .value_of(HASH_ARG)
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default();
+        let mismatches = bkup::verify_checksums(
+            PathBuf::from(path),
+            PathBuf::from(against),
+            flag(matches, IGNORE_ARG)?,
+            hash,
+        )?;
+        for mismatch in &mismatches {
+            println!(
+                "{:<10}  {}",
+                mismatch.status.name(),
+                mismatch.path.display()
+            );
+        }
+        match mismatches.len() {
+            0 => Ok(0),
+            n => Err(Error::Compare(format!("{} files differ", n))),
+        }
+    }
+
+    /// Runs the dupes command.
+    pub fn dupes(matches: &ArgMatches) -> Result<(), Error> {
+        let path = matches
+            .value_of(PATH_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", PATH_ARG));
+        let hash = matches
+            .value_of(HASH_ARG)
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default();
+        let path = PathBuf::from(path);
+        let duplicates =
+            bkup::duplicates(path.clone(), flag(matches, IGNORE_ARG)?, hash)?;
+        for duplicates in &duplicates {
+            println!(
+                "{} files of {} ({} wasted)",
+                duplicates.paths.len(),
+                bkup::format_size(duplicates.size),
+                bkup::format_size(duplicates.wasted())
+            );
+            for file in &duplicates.paths {
+                let file = file.strip_prefix(&path).unwrap_or(file);
+                println!("  {}", file.display());
+            }
+        }
+        let wasted = duplicates.iter().map(bkup::Duplicates::wasted).sum();
+        println!(
+            "{} sets of duplicates, {} wasted",
+            duplicates.len(),
+            bkup::format_size(wasted)
+        );
+        Ok(())
+    }
+
+    /// Runs the diff command.
+    pub fn diff(matches: &ArgMatches) -> Result<(), Error> {
+        let scans = matches
+            .values_of(SCAN_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", SCAN_ARG))
+            .map(fs::read_to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        let (older, newer) = match &scans[..] {
+            [older, newer] => (older, newer),
+            _ => {
+                return Err(Error::Invalid(format!(
+                    "'{}' must be provided twice",
+                    SCAN_ARG
+                )))
+            }
+        };
+        for change in bkup::compare_scans(older, newer)? {
+            println!("{:<8}  {}", change.status.name(), change.path.display());
+        }
+        Ok(())
+    }
+
+    /// Runs the scrub command (or the repair command, that also uses the
+    /// parity), that fails if some damaged files were not repaired.
+    pub fn scrub(matches: &ArgMatches, parity: bool) -> Result<usize, Error> {
+        let dest = PathBuf::from(
+            matches
+                .value_of(DEST_ARG)
+                .unwrap_or_else(|| panic!("'{}' must be provided", DEST_ARG)),
+        );
+        let source = matches.value_of(SOURCE_ARG).map(PathBuf::from);
+        let damages = if parity {
+            bkup::repair(dest, source)?
+        } else {
+            bkup::scrub(dest, source)?
+        };
+        for damage in &damages {
+            println!(
+                "{:<9}  {}{}",
+                damage.status.name(),
+                damage.path.display(),
+                if damage.repaired { " (repaired)" } else { "" }
+            );
+        }
+        match damages.iter().filter(|damage| !damage.repaired).count() {
+            0 => Ok(damages.len()),
+            n => Err(Error::Compare(format!("{} files are damaged", n))),
+        }
+    }
+
+    /// Runs the migrate command, printing the files moved (or to move) to the
+    /// bkup directory of the destination.
+    pub fn migrate(matches: &ArgMatches) -> Result<usize, Error> {
+        let dest = PathBuf::from(
+            matches
+                .value_of(DEST_ARG)
+                .unwrap_or_else(|| panic!("'{}' must be provided", DEST_ARG)),
+        );
+        let dry_run = flag(matches, DRY_RUN_ARG)?;
+        let migrations = bkup::migrate(dest.clone(), dry_run)?;
+        for migration in &migrations {
+            let relative = |path: &Path| {
+                let path = path.strip_prefix(&dest).unwrap_or(path);
+                path.display().to_string()
+            };
+            // the outdated files are removed, but kept in the backup
+            if migration.outdated {
+                println!("remove  {}", relative(&migration.from));
+            } else {
+                println!(
+                    "move    {} -> {}",
+                    relative(&migration.from),
+                    relative(&migration.to)
+                );
+            }
+        }
+        Ok(migrations.len())
+    }
+
+    /// Runs the stats command.
+    pub fn stats(matches: &ArgMatches) -> Result<(), Error> {
+        let source = matches
+            .value_of(SOURCE_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", SOURCE_ARG));
+        let ignore = flag(matches, IGNORE_ARG)?;
+        let top = count(matches, TOP_ARG)?.unwrap_or(DEFAULT_TOP);
+        let stats = bkup::stats(PathBuf::from(source), ignore, top)?;
+        let relative = |path: &Path| {
+            let path = path.strip_prefix(stats.path()).unwrap_or(path);
+            path.display().to_string()
+        };
+        println!("{:>10}  {:>8}  {:>6}  PATH", "SIZE", "FILES", "DIRS");
+        for usage in stats.entries.iter().chain(Some(&stats.total)) {
+            let path = match relative(&usage.path) {
+                path if path.is_empty() => "(total)".to_string(),
+                path => path,
+            };
+            println!(
+                "{:>10}  {:>8}  {:>6}  {}",
+                bkup::format_size(usage.bytes),
+                usage.files,
+                usage.dirs,
+                path
+            );
+        }
+        if !stats.largest.is_empty() {
+            println!("\nLargest files:");
+        }
+        for (path, size) in &stats.largest {
+            println!("{:>10}  {}", bkup::format_size(*size), relative(path));
+        }
+        Ok(())
+    }
+
+    /// Runs the estimate command.
+    pub fn estimate(matches: &ArgMatches) -> Result<(), Error> {
         let source = matches
             .value_of(SOURCE_ARG)
             .unwrap_or_else(|| panic!("'{}' must be provided", SOURCE_ARG));
@@ -52,12 +465,480 @@ mod cmd {
             .parse::<u64>()
             .map(Duration::from_millis)
             .expect("Accuracy must be a valid u64");
-        let ignore = matches.is_present(IGNORE_ARG);
-        bkup::update(
+        let bandwidth = matches
+            .value_of(BANDWIDTH_ARG)
+            .map(bkup::parse_size)
+            .transpose()?;
+        let compare = matches
+            .value_of(COMPARE_ARG)
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default();
+        let options = bkup::DiffOptions::new()
+            .accuracy(accuracy)
+            .ignore(flag(matches, IGNORE_ARG)?)
+            .ignore_dst(flag(matches, IGNORE_DST_ARG)?)
+            .compare(compare)
+            .largest(count(matches, TOP_ARG)?.unwrap_or(DEFAULT_TOP));
+        let estimate = bkup::estimate(
             PathBuf::from(source),
             PathBuf::from(dest),
+            &options,
+        )?;
+        println!(
+            "{} files to copy ({})",
+            estimate.files,
+            bkup::format_size(estimate.bytes)
+        );
+        if let Some(bandwidth) = bandwidth {
+            let duration = estimate.duration(bandwidth).ok_or_else(|| {
+                Error::Invalid("The bandwidth must not be zero".to_string())
+            })?;
+            println!(
+                "About {} at {}/s",
+                format_duration(duration),
+                bkup::format_size(bandwidth)
+            );
+        }
+        if !estimate.largest.is_empty() {
+            println!("\nLargest files to copy:");
+        }
+        for (path, size) in &estimate.largest {
+            let path = path.strip_prefix(source).unwrap_or(path);
+            println!("{:>10}  {}", bkup::format_size(*size), path.display());
+        }
+        Ok(())
+    }
+
+    /// Formats the given duration in hours, minutes and seconds, rounded up
+    /// to the second.
+    fn format_duration(duration: Duration) -> String {
+        let seconds =
+            duration.as_secs() + u64::from(duration.subsec_nanos() > 0);
+        match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+            (0, 0, s) => format!("{}s", s),
+            (0, m, s) => format!("{}m {:02}s", m, s),
+            (h, m, s) => format!("{}h {:02}m {:02}s", h, m, s),
+        }
+    }
+
+    /// Gets the handler of the update events, that prints them as JSON on
+    /// stdout and (or) a progress line on stderr.
+    fn events(ndjson: bool, progress: bool) -> Option<bkup::EventHandler> {
+        if !ndjson && !progress {
+            return None;
+        }
+        // meter, time of the last printed line and its width
+        let line = Mutex::new((bkup::RateMeter::new(), None::<Instant>, 0));
+        Some(bkup::EventHandler::new(move |event| {
+            if ndjson {
+                println!("{}", event.to_json());
+            }
+            if !progress {
+                return;
+            }
+            let mut line = line.lock().unwrap_or_else(|e| e.into_inner());
+            let (meter, printed, width) = &mut *line;
+            meter.update(event);
+            let planned = matches!(event, bkup::Event::Planned { .. });
+            let done = matches!(event, bkup::Event::Summary { .. });
+            let due = match printed {
+                // nothing is printed until the plan is known
+                None => planned,
+                Some(printed) => done || printed.elapsed() >= PROGRESS_INTERVAL,
+            };
+            if !due {
+                return;
+            }
+            let text = format_throughput(&meter.throughput());
+            eprint!("\r{:<1$}", text, *width);
+            if done {
+                eprintln!();
+            }
+            *printed = Some(Instant::now());
+            *width = text.len();
+        }))
+    }
+
+    /// Formats the given progress and transfer rates of an update.
+    fn format_throughput(throughput: &bkup::Throughput) -> String {
+        let remaining = match throughput.remaining {
+            Some(remaining) => format!("{} left", format_duration(remaining)),
+            None => "estimating time left".to_string(),
+        };
+        format!(
+            "{} / {}, {}/{} actions, {}/s (average {}/s), {:.1} files/s, {}",
+            bkup::format_size(throughput.bytes),
+            bkup::format_size(throughput.total_bytes),
+            throughput.actions,
+            throughput.total_actions,
+            bkup::format_size(throughput.current as u64),
+            bkup::format_size(throughput.average as u64),
+            throughput.files,
+            remaining
+        )
+    }
+
+    /// Runs the serve command, that only returns if the agent cannot listen
+    /// on its address (or once the update is applied with the stdio flag).
+    pub fn serve(matches: &ArgMatches) -> Result<(), Error> {
+        let dest = matches
+            .value_of(DEST_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", DEST_ARG));
+        let ignore = flag(matches, IGNORE_ARG)?;
+        if flag(matches, STDIO_ARG)? {
+            return bkup::serve_stdio(PathBuf::from(dest), ignore).map(|_| ());
+        }
+        let addr = matches.value_of(LISTEN_ARG).unwrap_or(DEFAULT_LISTEN);
+        bkup::serve(PathBuf::from(dest), addr, ignore)
+    }
+
+    /// Runs the push command.
+    pub fn push(matches: &ArgMatches) -> Result<usize, Error> {
+        let source = matches
+            .value_of(SOURCE_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", SOURCE_ARG));
+        let addr = matches
+            .value_of(REMOTE_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", REMOTE_ARG));
+        let accuracy = matches
+            .value_of(ACCURACY_ARG)
+            .unwrap_or(DEFAULT_ACCURACY)
+            .parse::<u64>()
+            .map(Duration::from_millis)
+            .expect("Accuracy must be a valid u64");
+        let ignore = flag(matches, IGNORE_ARG)?;
+        let compression = matches
+            .value_of(COMPRESSION_ARG)
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default();
+        bkup::push(PathBuf::from(source), addr, accuracy, ignore, compression)
+    }
+
+    /// Runs the update command.
+    pub fn update(matches: &ArgMatches) -> Result<usize, Error> {
+        bkup::cancel_on_signals();
+        bkup::pause_on_signals();
+        let source = matches
+            .value_of(SOURCE_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", SOURCE_ARG));
+        let dest = matches
+            .value_of(DEST_ARG)
+            .unwrap_or_else(|| panic!("'{}' must be provided", DEST_ARG));
+        let accuracy = matches
+            .value_of(ACCURACY_ARG)
+            .unwrap_or(DEFAULT_ACCURACY)
+            .parse::<u64>()
+            .map(Duration::from_millis)
+            .expect("Accuracy must be a valid u64");
+        let options = bkup::UpdateOptions {
             accuracy,
-            ignore,
+            ignore_dst: flag(matches, IGNORE_DST_ARG)?,
+            compare: matches
+                .value_of(COMPARE_ARG)
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            detect_skew: flag(matches, DETECT_SKEW_ARG)?,
+            ignore: flag(matches, IGNORE_ARG)?,
+            detect_moves: flag(matches, DETECT_MOVES_ARG)?,
+            hard_links: flag(matches, HARD_LINKS_ARG)?,
+            predicates: Vec::new(),
+            names: bkup::NameMatching {
+                case_insensitive: flag(matches, CASE_INSENSITIVE_ARG)?,
+                normalization: matches
+                    .value_of(NORMALIZE_ARG)
+                    .map(str::parse)
+                    .transpose()?,
+            },
+            one_file_system: flag(matches, ONE_FILE_SYSTEM_ARG)?,
+            caches: bkup::Caches {
+                tagged: flag(matches, EXCLUDE_CACHES_ARG)?,
+                known: flag(matches, EXCLUDE_KNOWN_CACHES_ARG)?,
+            },
+            skip_hidden: flag(matches, SKIP_HIDDEN_ARG)?,
+            specials: matches
+                .value_of(SPECIAL_FILES_ARG)
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            attributes: bkup::AttributeFilter {
+                min_size: matches
+                    .value_of(MIN_SIZE_ARG)
+                    .map(bkup::parse_size)
+                    .transpose()?,
+                max_size: matches
+                    .value_of(MAX_SIZE_ARG)
+                    .map(bkup::parse_size)
+                    .transpose()?,
+                newer_than: matches
+                    .value_of(NEWER_THAN_ARG)
+                    .map(bkup::parse_date)
+                    .transpose()?,
+                entry_type: matches
+                    .value_of(TYPE_ARG)
+                    .map(str::parse)
+                    .transpose()?,
+            },
+            rules: matches
+                .value_of(FILTER_FROM_ARG)
+                .map(|path| bkup::FilterRules::load(Path::new(path)))
+                .transpose()?
+                .unwrap_or_default(),
+            regexes: bkup::RegexFilter::new(
+                &values(matches, INCLUDE_REGEX_ARG),
+                &values(matches, EXCLUDE_REGEX_ARG),
+            )?,
+            pins: values(matches, PIN_ARG)
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            plugins: values(matches, PLUGIN_ARG),
+            reflink: flag(matches, REFLINK_ARG)?,
+            engine: matches
+                .value_of(ENGINE_ARG)
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            buffer_size: matches
+                .value_of(BUFFER_SIZE_ARG)
+                .map(|size| bkup::parse_size(size).map(|size| size as usize))
+                .transpose()?,
+            direct_io: flag(matches, DIRECT_IO_ARG)?,
+            bwlimit: matches
+                .value_of(BWLIMIT_ARG)
+                .map(bkup::parse_size)
+                .transpose()?,
+            max_transfer: matches
+                .value_of(MAX_TRANSFER_ARG)
+                .map(bkup::parse_size)
+                .transpose()?,
+            fsync: flag(matches, FSYNC_ARG)?,
+            shred: flag(matches, SHRED_ARG)?,
+            verify: flag(matches, VERIFY_ARG)?,
+            hash: matches
+                .value_of(HASH_ARG)
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            resume: flag(matches, RESUME_ARG)?,
+            retries: count(matches, RETRIES_ARG)?.unwrap_or(0) as u32,
+            locked_retries: count(matches, LOCKED_RETRIES_ARG)?.unwrap_or(0)
+                as u32,
+            retry_delay: Duration::from_millis(
+                count(matches, RETRY_DELAY_ARG)?
+                    .map_or(DEFAULT_RETRY_DELAY, |ms| ms as u64),
+            ),
+            partial: flag(matches, PARTIAL_ARG)?,
+            keep_going: flag(matches, KEEP_GOING_ARG)?,
+            jobs: count(matches, JOBS_ARG)?.unwrap_or(1),
+            device_jobs: count(matches, DEVICE_JOBS_ARG)?,
+            order: matches
+                .value_of(ORDER_ARG)
+                .map(str::parse)
+                .transpose()?
+                .unwrap_or_default(),
+            priorities: bkup::PriorityRules::new(
+                &values(matches, PRIORITY_HIGH_ARG),
+                &values(matches, PRIORITY_LOW_ARG),
+            )?,
+            wait_lock: flag(matches, WAIT_ARG)?,
+            break_stale_lock: flag(matches, BREAK_STALE_LOCK_ARG)?,
+            confirm: confirm_threshold(matches)?,
+            force: flag(matches, FORCE_ARG)?,
+            events: events(
+                matches.value_of(LOG_FORMAT_ARG) == Some("ndjson"),
+                flag(matches, PROGRESS_ARG)? && !matches.is_present(QUIET_ARG),
+            ),
+            webhook: matches
+                .value_of(NOTIFY_URL_ARG)
+                .map(|url| {
+                    let on = matches
+                        .value_of(NOTIFY_ON_ARG)
+                        .map(str::parse)
+                        .transpose()?
+                        .unwrap_or_default();
+                    bkup::Webhook::new(url, on)
+                })
+                .transpose()?,
+            notify_desktop: flag(matches, NOTIFY_ARG)?,
+            event_log: flag(matches, EVENT_LOG_ARG)?,
+            dry_run: flag(matches, DRY_RUN_ARG)?,
+            itemize: flag(matches, ITEMIZE_ARG)?,
+            manifest: flag(matches, MANIFEST_ARG)?,
+            parity: flag(matches, PARITY_ARG)?,
+            save_state: flag(matches, SAVE_STATE_ARG)?,
+            audit_log: flag(matches, AUDIT_LOG_ARG)?,
+            // cancelled by the signals
+            cancel: bkup::CancellationToken::new(),
+            transfer: None,
+        };
+        bkup::update_with_confirm(
+            PathBuf::from(source),
+            PathBuf::from(dest),
+            &options,
+            confirm,
         )
     }
+
+    /// Runs the tui command, that applies the changes selected by the user.
+    #[cfg(feature = "tui")]
+    pub fn tui(matches: &ArgMatches) -> Result<usize, Error> {
+        let source =
+            PathBuf::from(matches.value_of(SOURCE_ARG).unwrap_or_else(|| {
+                panic!("'{}' must be provided", SOURCE_ARG)
+            }));
+        let dest = PathBuf::from(
+            matches
+                .value_of(DEST_ARG)
+                .unwrap_or_else(|| panic!("'{}' must be provided", DEST_ARG)),
+        );
+        let accuracy = matches
+            .value_of(ACCURACY_ARG)
+            .unwrap_or(DEFAULT_ACCURACY)
+            .parse::<u64>()
+            .map(Duration::from_millis)
+            .expect("Accuracy must be a valid u64");
+        let ignore = flag(matches, IGNORE_ARG)?;
+        let ignore_dst = flag(matches, IGNORE_DST_ARG)?;
+        let compare = matches
+            .value_of(COMPARE_ARG)
+            .map(str::parse)
+            .transpose()?
+            .unwrap_or_default();
+        let options = bkup::DiffOptions::new()
+            .accuracy(accuracy)
+            .ignore(ignore)
+            .ignore_dst(ignore_dst)
+            .compare(compare);
+        let delta = bkup::diff(source.clone(), dest.clone(), &options)?;
+        if delta.is_empty() {
+            println!("The destination folder is up to date");
+            return Ok(0);
+        }
+        let mut review = tui::Review::new(delta, &dest);
+        if !tui::run(&mut review)? {
+            return Ok(0);
+        }
+
+        // only visit the selected entries, their ancestors and their content
+        let selection = review.selection();
+        let (root, dest_root) = (source.clone(), dest.clone());
+        let options = bkup::UpdateOptions::new()
+            .accuracy(accuracy)
+            .ignore(ignore)
+            .ignore_dst(ignore_dst)
+            .compare(compare)
+            .filter(move |path, _| {
+                let path = path
+                    .strip_prefix(&root)
+                    .or_else(|_| path.strip_prefix(&dest_root));
+                match path {
+                    Ok(path) => selection.iter().any(|selected| {
+                        selected.starts_with(path) || path.starts_with(selected)
+                    }),
+                    Err(_) => true,
+                }
+            });
+        bkup::cancel_on_signals();
+        bkup::update_with(source, dest, &options)
+    }
+
+    /// Runs the tui command, that requires the tui feature.
+    #[cfg(not(feature = "tui"))]
+    pub fn tui(_matches: &ArgMatches) -> Result<usize, Error> {
+        Err(Error::Unsupported(
+            "The tui command requires the tui feature".to_string(),
+        ))
+    }
+
+    /// Gets the confirmation threshold, that is disabled by the yes flag.
+    fn confirm_threshold(
+        matches: &ArgMatches,
+    ) -> Result<bkup::ConfirmThreshold, Error> {
+        if flag(matches, YES_ARG)? {
+            return Ok(bkup::ConfirmThreshold::default());
+        }
+        let files = count(matches, CONFIRM_FILES_ARG)?;
+        let bytes = matches
+            .value_of(CONFIRM_BYTES_ARG)
+            .map(bkup::parse_size)
+            .transpose()?;
+        Ok(bkup::ConfirmThreshold { files, bytes })
+    }
+
+    /// Gets the value of the given argument as a count, if present.
+    fn count(matches: &ArgMatches, arg: &str) -> Result<Option<usize>, Error> {
+        matches
+            .value_of(arg)
+            .map(|value| {
+                value.parse::<usize>().map_err(|_| {
+                    Error::Invalid(format!(
+                        "Invalid value {:?} for '{}'",
+                        value, arg
+                    ))
+                })
+            })
+            .transpose()
+    }
+
+    /// Returns true if the given flag is present, or if its environment
+    /// variable is set to a true value (`1`, `true`, `yes` or `on`).
+    fn flag(matches: &ArgMatches, arg: &str) -> Result<bool, Error> {
+        if matches.is_present(arg) {
+            return Ok(true);
+        }
+        let var = config::variable(arg);
+        match env::var(&var) {
+            Ok(value) => config::parse_bool(&value).ok_or_else(|| {
+                Error::Invalid(format!("Invalid value {:?} for {}", value, var))
+            }),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Gets all the values of the given argument, or the lines of its
+    /// environment variable when the argument is not present.
+    fn values(matches: &ArgMatches, arg: &str) -> Vec<String> {
+        match matches.values_of(arg) {
+            Some(values) => values.map(String::from).collect(),
+            None => env::var(config::variable(arg))
+                .map(|value| {
+                    value
+                        .lines()
+                        .filter(|line| !line.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Shows the largest files that would be replaced and asks the user to
+    /// confirm the update.
+    fn confirm(destruction: &bkup::Destruction) -> bool {
+        let mut stderr = io::stderr();
+        let _ = writeln!(
+            stderr,
+            "The update would replace {} destination files ({}), including:",
+            destruction.files,
+            bkup::format_size(destruction.bytes)
+        );
+        for (path, size) in &destruction.largest {
+            let _ = writeln!(
+                stderr,
+                "  {:>10}  {}",
+                bkup::format_size(*size),
+                path.display()
+            );
+        }
+        let _ = write!(stderr, "Continue? [y/N] ");
+        let _ = stderr.flush();
+        let mut answer = String::new();
+        match io::stdin().lock().read_line(&mut answer) {
+            Ok(_) => matches!(answer.trim(), "y" | "Y" | "yes"),
+            Err(_) => false,
+        }
+    }
 }
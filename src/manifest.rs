@@ -0,0 +1,368 @@
+//! Manifest of a destination directory, that records the digest of the
+//! content of every file, used to detect the files silently corrupted by the
+//! storage (bitrot).
+//!
+//! The manifest is refreshed once each update completes, only hashing the
+//! files whose size or modification time changed since they were recorded.
+//! A scrub then reads every unchanged file again and compares its digest with
+//! the recorded one.
+
+use crate::{
+    copy::{self, CopyOptions},
+    entry::{Entry, FileEntry},
+    error::BkupError,
+    hash::{self, Digest, HashAlgorithm},
+    json::{self, Value},
+    metadata::{self, MANIFEST_NAME},
+    parity,
+};
+use log::*;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Enumerates the problems found by a scrub.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ScrubStatus {
+    /// The content of the file changed, while its size and modification time
+    /// didn't.
+    Corrupted,
+    /// The file no longer exists.
+    Missing,
+}
+
+impl ScrubStatus {
+    /// Gets the name of the status.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScrubStatus::Corrupted => "corrupted",
+            ScrubStatus::Missing => "missing",
+        }
+    }
+}
+
+/// Represents a damaged file of the destination found by a scrub.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Damage {
+    /// Path of the file, relative to the destination directory.
+    pub path: PathBuf,
+    /// How the file is damaged.
+    pub status: ScrubStatus,
+    /// Whether the file was copied again from the source.
+    pub repaired: bool,
+}
+
+impl Damage {
+    /// Serializes self into a JSON object.
+    pub fn to_json(&self) -> Value {
+        json::object(vec![
+            (
+                "path",
+                Value::from(self.path.to_string_lossy().into_owned()),
+            ),
+            ("status", Value::from(self.status.name())),
+            ("repaired", Value::from(self.repaired)),
+        ])
+    }
+}
+
+/// Size, modification time and digest of a file when it was recorded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Record {
+    size: u64,
+    modified: Duration,
+    digest: Digest,
+}
+
+impl Record {
+    /// Returns true if the given file has the recorded size and modification
+    /// time, that is its content is expected to be unchanged.
+    fn matches(&self, file: &FileEntry) -> bool {
+        self.size == file.size() && self.modified == file.modified()
+    }
+}
+
+/// Digests of the files of a destination directory.
+#[derive(Debug, Default, PartialEq)]
+pub struct Manifest {
+    files: BTreeMap<PathBuf, Record>,
+}
+
+impl Manifest {
+    /// Loads the manifest of the given destination directory, that is empty if
+    /// it was never saved.
+    pub fn load(dest: &Path) -> Result<Self, BkupError> {
+        let path = metadata::path(dest, MANIFEST_NAME);
+        if !path.is_file() {
+            return Ok(Manifest::default());
+        }
+        let value = json::parse(&fs::read_to_string(&path)?)?;
+        let invalid =
+            || BkupError::Parse(format!("Invalid manifest {:?}", path));
+        let mut files = BTreeMap::new();
+        for file in value.as_array().ok_or_else(invalid)? {
+            let number = |name| file.get(name).and_then(Value::as_u64);
+            let text = |name| file.get(name).and_then(Value::as_str);
+            match (text("path"), number("size"), number("modified")) {
+                (Some(path), Some(size), Some(modified)) => {
+                    // the digest is keyed by the name of its algorithm
+                    let (algorithm, digest) = HashAlgorithm::ALL
+                        .iter()
+                        .find_map(|a| text(a.name()).map(|d| (*a, d)))
+                        .ok_or_else(invalid)?;
+                    let digest = Digest::parse(algorithm, digest)?;
+                    let record = Record {
+                        size,
+                        modified: Duration::from_nanos(modified),
+                        digest,
+                    };
+                    files.insert(PathBuf::from(path), record);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(Manifest { files })
+    }
+
+    /// Returns true if no file is recorded.
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Saves self as the manifest of the given destination directory.
+    pub fn save(&self, dest: &Path) -> Result<(), BkupError> {
+        let files = self
+            .files
+            .iter()
+            .map(|(path, record)| {
+                json::object(vec![
+                    ("path", Value::from(path.to_string_lossy().into_owned())),
+                    ("size", Value::from(record.size)),
+                    (
+                        "modified",
+                        Value::from(record.modified.as_nanos() as u64),
+                    ),
+                    (
+                        record.digest.algorithm().name(),
+                        Value::from(record.digest.to_string()),
+                    ),
+                ])
+            })
+            .collect();
+        // the previous manifest is only replaced once the new one is complete
+        metadata::create_dir(dest)?;
+        let path = metadata::path(dest, MANIFEST_NAME);
+        let temp = copy::temp_path(&path);
+        fs::write(&temp, Value::Array(files).to_string())?;
+        fs::rename(&temp, &path)?;
+        Ok(())
+    }
+
+    /// Records the files of the given destination directory, hashing with the
+    /// given algorithm the ones that changed since they were recorded (or that
+    /// were recorded with another algorithm), and forgets the removed ones.
+    /// Returns the paths of the hashed files, relative to the directory.
+    pub fn refresh(
+        &mut self,
+        dest: &Entry,
+        algorithm: HashAlgorithm,
+    ) -> Result<Vec<PathBuf>, BkupError> {
+        let mut files = BTreeMap::new();
+        let mut hashed = Vec::new();
+        for file in dest.all_files() {
+            let path = match file.path().strip_prefix(dest.path()) {
+                Ok(path) => path.to_path_buf(),
+                Err(_) => continue,
+            };
+            let record = match self.files.get(&path) {
+                Some(record)
+                    if record.matches(file)
+                        && record.digest.algorithm() == algorithm =>
+                {
+                    *record
+                }
+                _ => {
+                    hashed.push(path.clone());
+                    Record {
+                        size: file.size(),
+                        modified: file.modified(),
+                        digest: hash::hash_file(file.path(), algorithm)?,
+                    }
+                }
+            };
+            files.insert(path, record);
+        }
+        self.files = files;
+        Ok(hashed)
+    }
+
+    /// Reads the recorded files of the given destination directory again and
+    /// gets the ones whose content no longer matches their digest, or that
+    /// were removed. The files that changed since they were recorded are
+    /// skipped. When `parity` is set, the corrupted files are rebuilt with
+    /// their parity (if they have one), and when the source directory is
+    /// given, the damaged files whose source has the recorded content are
+    /// copied again.
+    pub fn scrub(
+        &mut self,
+        dest: &Path,
+        source: Option<&Path>,
+        parity: bool,
+    ) -> Result<Vec<Damage>, BkupError> {
+        let mut damages = Vec::new();
+        for (path, record) in self.files.iter_mut() {
+            let file = dest.join(path);
+            let status = match FileEntry::new(&file) {
+                Err(_) if !file.exists() => ScrubStatus::Missing,
+                Err(e) => return Err(e),
+                Ok(entry) if !record.matches(&entry) => {
+                    debug!("Skipping {:?}, changed since recorded", file);
+                    continue;
+                }
+                Ok(_) if hash_file(&file, record)? == record.digest => continue,
+                Ok(_) => ScrubStatus::Corrupted,
+            };
+            warn!("{:?} is {}", file, status.name());
+            let parity = parity.then(|| parity::parity_path(dest, path));
+            let repaired = match (parity, source) {
+                (Some(parity), _)
+                    if status == ScrubStatus::Corrupted
+                        && repair_from_parity(&parity, &file, record)? =>
+                {
+                    true
+                }
+                (_, Some(source)) => {
+                    repair_from_source(&source.join(path), &file, record)?
+                }
+                _ => false,
+            };
+            damages.push(Damage {
+                path: path.clone(),
+                status,
+                repaired,
+            });
+        }
+        Ok(damages)
+    }
+}
+
+/// Rebuilds the corrupted destination file with its parity, and records the
+/// new modification time of the file. Returns true if the file was repaired.
+fn repair_from_parity(
+    parity: &Path,
+    dest: &Path,
+    record: &mut Record,
+) -> Result<bool, BkupError> {
+    info!("Repairing {:?} from its parity", dest);
+    if !parity::repair(dest, parity)? {
+        return Ok(false);
+    }
+    let file = FileEntry::new(dest)?;
+    record.size = file.size();
+    record.modified = file.modified();
+    Ok(true)
+}
+
+/// Copies the source file over the damaged destination file if its content
+/// is the recorded one, and records the new modification time of the copy.
+/// Returns true if the file was repaired.
+fn repair_from_source(
+    source: &Path,
+    dest: &Path,
+    record: &mut Record,
+) -> Result<bool, BkupError> {
+    if !source.is_file() || hash_file(source, record)? != record.digest {
+        warn!("Cannot repair {:?}, its source changed or is missing", dest);
+        return Ok(false);
+    }
+    info!("Repairing {:?} from {:?}", dest, source);
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    copy::copy(source, dest, &CopyOptions::default())?;
+    let copy = FileEntry::new(dest)?;
+    record.size = copy.size();
+    record.modified = copy.modified();
+    Ok(true)
+}
+
+/// Computes the digest of the given file with the algorithm of the record.
+fn hash_file(path: &Path, record: &Record) -> Result<Digest, BkupError> {
+    hash::hash_file(path, record.digest.algorithm())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::filter::Filter;
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_scrub() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        for dir in &[&source, &dest] {
+            fs::create_dir_all(dir.join("dir")).expect("Cannot create dir");
+            fs::write(dir.join("dir").join("file"), "content")
+                .expect("Cannot write file");
+            fs::write(dir.join("other"), "other").expect("Cannot write file");
+        }
+
+        let entry = Entry::directory(&dest, false, &Filter::new())
+            .expect("Cannot visit directory");
+        let mut manifest = Manifest::default();
+        let mut refresh = |algorithm| manifest.refresh(&entry, algorithm);
+        assert_eq!(refresh(HashAlgorithm::Sha256).unwrap().len(), 2);
+        assert_eq!(refresh(HashAlgorithm::Sha256).unwrap().len(), 0);
+        // a different algorithm hashes all the files again
+        assert_eq!(refresh(HashAlgorithm::Xxh3).unwrap().len(), 2);
+        manifest.save(&dest).expect("Cannot save manifest");
+        let mut manifest = Manifest::load(&dest).expect("Cannot load manifest");
+        assert_eq!(manifest.files.len(), 2);
+        assert!(manifest
+            .files
+            .values()
+            .all(|record| record.digest.algorithm() == HashAlgorithm::Xxh3));
+        assert!(manifest.scrub(&dest, None, false).unwrap().is_empty());
+
+        // flip the content without changing the size and modification time
+        let file = dest.join("dir").join("file");
+        let modified = fs::metadata(&file).unwrap().modified().unwrap();
+        fs::write(&file, "CONTENT").expect("Cannot write file");
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .and_then(|f| f.set_modified(modified))
+            .expect("Cannot set modification time");
+        fs::remove_file(dest.join("other")).expect("Cannot remove file");
+        let damages = manifest.scrub(&dest, Some(&source), false).unwrap();
+        assert_eq!(
+            damages,
+            vec![
+                Damage {
+                    path: PathBuf::from("dir/file"),
+                    status: ScrubStatus::Corrupted,
+                    repaired: true,
+                },
+                Damage {
+                    path: PathBuf::from("other"),
+                    status: ScrubStatus::Missing,
+                    repaired: true,
+                },
+            ]
+        );
+        assert_eq!(fs::read_to_string(&file).unwrap(), "content");
+        assert!(manifest.scrub(&dest, None, false).unwrap().is_empty());
+    }
+}
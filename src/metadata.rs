@@ -0,0 +1,122 @@
+//! Files stored by bkup in the destination directories, in their `.bkup`
+//! directory (or next to the copied files for the temporary files), that are
+//! never compared with the source files nor recorded.
+
+use std::path::{Path, PathBuf};
+
+/// Name of the directory containing the files of bkup, in the destination
+/// directory.
+pub const BKUP_DIR: &str = ".bkup";
+/// Name of the lock file, in the bkup directory.
+#[cfg(feature = "update")]
+pub const LOCK_NAME: &str = "lock";
+/// Name of the journal file, in the bkup directory.
+#[cfg(feature = "update")]
+pub const JOURNAL_NAME: &str = "journal";
+/// Name of the state file, in the bkup directory.
+pub const STATE_NAME: &str = "state";
+/// Name of the manifest file, in the bkup directory.
+#[cfg(feature = "update")]
+pub const MANIFEST_NAME: &str = "manifest";
+/// Name of the directory containing the parity of the destination files, in
+/// the bkup directory.
+#[cfg(feature = "update")]
+pub const PARITY_DIR: &str = "parity";
+/// Name of the directory containing the audit logs, in the bkup directory.
+#[cfg(feature = "update")]
+pub const LOGS_DIR: &str = "logs";
+/// Name of the directory containing the backups of the files migrated from
+/// the previous layout, in the bkup directory.
+#[cfg(feature = "update")]
+pub const BACKUP_DIR: &str = "backup";
+/// Name of the probe file written to measure the clock skew of the
+/// destination, in the bkup directory (removed with the temporary files if
+/// left behind).
+#[cfg(feature = "update")]
+pub const PROBE_NAME: &str = "probe";
+/// Suffix of the temporary files the contents are copied into, before
+/// atomically replacing the destination files.
+pub const TEMP_SUFFIX: &str = ".bkup-tmp";
+/// Suffix of the checkpoint files, next to the temporary files.
+pub const CHECKPOINT_SUFFIX: &str = ".bkup-ckpt";
+/// Names of the files of bkup in the root of the destination directory with
+/// the previous layout, that are not compared either until they are migrated
+/// to the bkup directory.
+pub const LEGACY_NAMES: [&str; 5] = [
+    ".bkup-lock",
+    ".bkup-journal",
+    ".bkup-state",
+    ".bkup-manifest",
+    ".bkup-parity",
+];
+
+/// Gets the path of the file of bkup with the given name, in the given
+/// destination directory.
+pub fn path(dest: &Path, name: &str) -> PathBuf {
+    dest.join(BKUP_DIR).join(name)
+}
+
+/// Creates the bkup directory of the given destination directory, if it
+/// doesn't exist yet (while the destination directory must exist).
+#[cfg(feature = "update")]
+pub fn create_dir(dest: &Path) -> std::io::Result<()> {
+    match std::fs::create_dir(dest.join(BKUP_DIR)) {
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        result => result,
+    }
+}
+
+/// Returns true if the given path, relative to the destination directory, is
+/// one of the files stored by bkup: the bkup directory with all its content
+/// (the lock, the journal, the state, the manifest, the parity files, the
+/// audit logs and the clock probe), the files of the previous layout, or the
+/// temporary and checkpoint files of the copies.
+pub fn is_metadata(path: &Path) -> bool {
+    if path.starts_with(BKUP_DIR)
+        || LEGACY_NAMES.iter().any(|name| path.starts_with(name))
+    {
+        return true;
+    }
+    path.file_name().is_some_and(|name| {
+        let name = name.to_string_lossy();
+        name.ends_with(TEMP_SUFFIX) || name.ends_with(CHECKPOINT_SUFFIX)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_is_metadata() {
+        let metadata = [
+            ".bkup",
+            ".bkup/lock",
+            ".bkup/journal",
+            ".bkup/state",
+            ".bkup/manifest",
+            ".bkup/parity/dir/file",
+            ".bkup/logs/20261016T101548Z.jsonl",
+            ".bkup/probe.bkup-tmp",
+            ".bkup-manifest",
+            ".bkup-parity/dir/file",
+            "dir/file.bkup-tmp",
+            "dir/file.bkup-ckpt",
+        ];
+        for path in &metadata {
+            assert!(is_metadata(Path::new(path)), "{}", path);
+        }
+        let other = [
+            "file",
+            "dir/.bkup",
+            "dir/.bkup/lock",
+            "dir/.bkup-manifest",
+            ".bkup-logs",
+            ".bkupfile",
+        ];
+        for path in &other {
+            assert!(!is_metadata(Path::new(path)), "{}", path);
+        }
+    }
+}
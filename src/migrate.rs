@@ -0,0 +1,243 @@
+//! Migration of the files stored by the previous releases of bkup in the root
+//! of a destination directory (as `.bkup-*` siblings of the copied files) to
+//! its `.bkup` directory.
+//!
+//! The files are moved in place, after a copy of all of them is made in the
+//! backup directory of the bkup directory, so that a migration that fails
+//! halfway never loses the manifest or the parity of the destination.
+
+use crate::{
+    date,
+    error::BkupError,
+    lock::Lock,
+    metadata::{
+        self, BACKUP_DIR, BKUP_DIR, JOURNAL_NAME, MANIFEST_NAME, PARITY_DIR,
+        STATE_NAME,
+    },
+};
+use log::*;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// Name of the lock file of the previous layout.
+const LEGACY_LOCK: &str = ".bkup-lock";
+/// Names of the files of the previous layout, with their names in the bkup
+/// directory.
+const MOVES: [(&str, &str); 4] = [
+    (".bkup-journal", JOURNAL_NAME),
+    (".bkup-state", STATE_NAME),
+    (".bkup-manifest", MANIFEST_NAME),
+    (".bkup-parity", PARITY_DIR),
+];
+
+/// File of the previous layout moved by a migration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Migration {
+    /// Path of the file (or directory) in the root of the destination.
+    pub from: PathBuf,
+    /// Path of the file (or directory) in the bkup directory.
+    pub to: PathBuf,
+    /// True if the file already exists in the bkup directory, written by an
+    /// update of this release after the previous one, in which case the file
+    /// of the previous layout is outdated and only kept in the backup.
+    pub outdated: bool,
+}
+
+/// Moves the files of the previous layout of the given destination directory
+/// to its bkup directory, after copying them in a new backup directory (whose
+/// path is logged), and gets the files moved. When `dry_run` is set the
+/// destination is not modified.
+pub fn migrate(
+    dest: &Path,
+    dry_run: bool,
+) -> Result<Vec<Migration>, BkupError> {
+    if !dest.is_dir() {
+        return Err(BkupError::Invalid(format!(
+            "The destination {:?} is not a directory",
+            dest
+        )));
+    }
+    check_lock(dest)?;
+    let migrations: Vec<_> = MOVES
+        .iter()
+        .map(|(from, to)| (dest.join(from), metadata::path(dest, to)))
+        .filter(|(from, _)| from.symlink_metadata().is_ok())
+        .map(|(from, to)| Migration {
+            outdated: to.symlink_metadata().is_ok(),
+            from,
+            to,
+        })
+        .collect();
+    if dry_run || migrations.is_empty() {
+        return Ok(migrations);
+    }
+
+    let _lock = Lock::acquire(dest, false, false)?;
+    let id = date::format_date(SystemTime::now()).replace([':', '-'], "");
+    let backup = metadata::path(dest, BACKUP_DIR).join(id);
+    fs::create_dir_all(&backup).map_err(|e| BkupError::Io(e).at(&backup))?;
+    for migration in &migrations {
+        let name = migration.from.file_name().unwrap_or_default();
+        copy_all(&migration.from, &backup.join(name))?;
+    }
+    info!("Backed up the files of {:?} to {:?}", dest, backup);
+
+    for migration in &migrations {
+        let (from, to) = (&migration.from, &migration.to);
+        if migration.outdated {
+            info!("Removing outdated {:?}", from);
+            remove_all(from)?;
+        } else {
+            info!("Moving {:?} to {:?}", from, to);
+            fs::rename(from, to).map_err(|e| BkupError::Io(e).at(from))?;
+        }
+    }
+    Ok(migrations)
+}
+
+/// Fails if the given destination directory is locked by an update of a
+/// previous release, that doesn't see the lock of the bkup directory, and
+/// warns if it has files of the previous layout to migrate.
+pub fn check(dest: &Path) -> Result<(), BkupError> {
+    check_lock(dest)?;
+    if MOVES.iter().any(|(name, _)| dest.join(name).exists()) {
+        warn!(
+            "The destination {:?} has files of a previous release of bkup \
+             that are ignored, run `bkup migrate -d {}` to move them to {}",
+            dest,
+            dest.display(),
+            BKUP_DIR
+        );
+    }
+    Ok(())
+}
+
+/// Fails if the lock of the previous layout exists in the given destination
+/// directory.
+fn check_lock(dest: &Path) -> Result<(), BkupError> {
+    let lock = dest.join(LEGACY_LOCK);
+    if lock.exists() {
+        return Err(BkupError::Locked(format!(
+            "The destination {:?} is locked by a previous release of bkup, \
+             remove {:?} if no update is running",
+            dest, lock
+        )));
+    }
+    Ok(())
+}
+
+/// Copies the given file, or directory with all its content, to the given
+/// path.
+fn copy_all(from: &Path, to: &Path) -> Result<(), BkupError> {
+    if from.is_dir() {
+        fs::create_dir_all(to).map_err(|e| BkupError::Io(e).at(to))?;
+        for entry in
+            fs::read_dir(from).map_err(|e| BkupError::Io(e).at(from))?
+        {
+            let entry = entry?;
+            copy_all(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to).map_err(|e| BkupError::Io(e).at(from))?;
+    }
+    Ok(())
+}
+
+/// Removes the given file, or directory with all its content.
+fn remove_all(path: &Path) -> Result<(), BkupError> {
+    let result = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    result.map_err(|e| BkupError::Io(e).at(path))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    /// Creates a destination with the files of the previous layout, and a
+    /// state already written with the current one.
+    fn legacy_dest() -> PathBuf {
+        let dest = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(dest.join(".bkup-parity").join("dir"))
+            .expect("Cannot create dir");
+        fs::create_dir(dest.join(BKUP_DIR)).expect("Cannot create dir");
+        fs::write(dest.join(".bkup-parity/dir/file"), "parity")
+            .expect("Cannot write file");
+        fs::write(dest.join(".bkup-manifest"), "manifest")
+            .expect("Cannot write file");
+        fs::write(dest.join(".bkup-state"), "old state")
+            .expect("Cannot write file");
+        fs::write(metadata::path(&dest, STATE_NAME), "state")
+            .expect("Cannot write file");
+        fs::write(dest.join("file"), "content").expect("Cannot write file");
+        dest
+    }
+
+    #[test]
+    fn test_migrate_dry_run() {
+        let dest = legacy_dest();
+        let migrations = migrate(&dest, true).expect("Cannot migrate");
+        assert_eq!(migrations.len(), 3);
+        assert!(migrations
+            .iter()
+            .all(|m| m.outdated == m.to.ends_with("state")));
+        // nothing is moved nor backed up
+        assert!(dest.join(".bkup-manifest").exists());
+        assert!(dest.join(".bkup-parity").exists());
+        assert!(!metadata::path(&dest, MANIFEST_NAME).exists());
+        assert!(!metadata::path(&dest, BACKUP_DIR).exists());
+        fs::remove_dir_all(dest).expect("Cannot remove dir");
+    }
+
+    #[test]
+    fn test_migrate() {
+        let dest = legacy_dest();
+        assert_eq!(migrate(&dest, false).expect("Cannot migrate").len(), 3);
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(metadata::path(&dest, MANIFEST_NAME)), "manifest");
+        assert_eq!(read(metadata::path(&dest, "parity/dir/file")), "parity");
+        // the state written by this release is kept
+        assert_eq!(read(metadata::path(&dest, STATE_NAME)), "state");
+        for (name, _) in &MOVES {
+            assert!(!dest.join(name).exists(), "{}", name);
+        }
+        assert_eq!(read(dest.join("file")), "content");
+        assert!(!metadata::path(&dest, "lock").exists());
+
+        // the backup has the files of the previous layout as they were
+        let backups = fs::read_dir(metadata::path(&dest, BACKUP_DIR))
+            .expect("Cannot read dir")
+            .map(|entry| entry.expect("Cannot read entry").path())
+            .collect::<Vec<_>>();
+        assert_eq!(backups.len(), 1);
+        let backup = &backups[0];
+        assert_eq!(read(backup.join(".bkup-manifest")), "manifest");
+        assert_eq!(read(backup.join(".bkup-parity/dir/file")), "parity");
+        assert_eq!(read(backup.join(".bkup-state")), "old state");
+
+        // a migrated destination has nothing left to migrate
+        assert!(migrate(&dest, false).expect("Cannot migrate").is_empty());
+        fs::remove_dir_all(dest).expect("Cannot remove dir");
+    }
+
+    #[test]
+    fn test_migrate_legacy_lock() {
+        let dest = legacy_dest();
+        fs::write(dest.join(LEGACY_LOCK), "").expect("Cannot write file");
+        assert!(matches!(migrate(&dest, true), Err(BkupError::Locked(_))));
+        assert!(matches!(check(&dest), Err(BkupError::Locked(_))));
+        fs::remove_dir_all(dest).expect("Cannot remove dir");
+    }
+}
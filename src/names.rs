@@ -0,0 +1,112 @@
+//! Matching of the source and destination entries by name, for destinations
+//! whose file system doesn't distinguish the names the source does, or that
+//! stores them in a different Unicode normalization form.
+
+use crate::error::BkupError;
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use unicode_normalization::UnicodeNormalization;
+
+/// Enumerates the Unicode normalization forms the names are matched in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    /// Composed form, used by Linux and Windows (`é` is a single code point).
+    Nfc,
+    /// Decomposed form, used by macOS (`é` is `e` followed by an accent).
+    Nfd,
+}
+
+impl FromStr for Normalization {
+    type Err = BkupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nfc" => Ok(Normalization::Nfc),
+            "nfd" => Ok(Normalization::Nfd),
+            _ => Err(BkupError::Invalid(format!(
+                "Invalid normalization form {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Represents how the names of the source and destination entries are
+/// matched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NameMatching {
+    /// When set the names that only differ by case match, as on FAT, exFAT,
+    /// NTFS and APFS (by default) destinations.
+    pub case_insensitive: bool,
+    /// When set the names are normalized to this form before being matched,
+    /// while the entries keep their original names.
+    pub normalization: Option<Normalization>,
+}
+
+impl NameMatching {
+    /// Returns true if the names are matched exactly as they are.
+    pub fn is_exact(&self) -> bool {
+        !self.case_insensitive && self.normalization.is_none()
+    }
+
+    /// Gets the key that identifies the given entry name among the other
+    /// entries of its directory. Names that are not valid Unicode are always
+    /// matched exactly.
+    pub fn key(&self, name: &OsStr) -> PathBuf {
+        let name = match name.to_str() {
+            Some(name) if !self.is_exact() => name,
+            _ => return PathBuf::from(name),
+        };
+        let name: String = match self.normalization {
+            Some(Normalization::Nfc) => name.nfc().collect(),
+            Some(Normalization::Nfd) => name.nfd().collect(),
+            None => name.to_string(),
+        };
+        if self.case_insensitive {
+            PathBuf::from(name.to_lowercase())
+        } else {
+            PathBuf::from(name)
+        }
+    }
+
+    /// Returns true if the two given names match.
+    pub fn matches(&self, a: &Path, b: &Path) -> bool {
+        self.key(a.as_os_str()) == self.key(b.as_os_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_name_matching() {
+        let exact = NameMatching::default();
+        assert!(!exact.matches(Path::new("Readme.md"), Path::new("README.md")));
+        let matching = NameMatching {
+            case_insensitive: true,
+            normalization: None,
+        };
+        assert!(
+            matching.matches(Path::new("Readme.md"), Path::new("README.md"))
+        );
+        assert!(!matching.matches(Path::new("Readme.md"), Path::new("Readme")));
+        assert_eq!(matching.key(OsStr::new("ÀB")), PathBuf::from("àb"));
+
+        let (nfc, nfd) = ("caf\u{e9}", "cafe\u{301}");
+        assert!(!exact.matches(Path::new(nfc), Path::new(nfd)));
+        for form in &[Normalization::Nfc, Normalization::Nfd] {
+            let matching = NameMatching {
+                case_insensitive: false,
+                normalization: Some(*form),
+            };
+            assert!(matching.matches(Path::new(nfc), Path::new(nfd)));
+        }
+        assert_eq!("nfd".parse::<Normalization>().unwrap(), Normalization::Nfd);
+        assert!("nfkc".parse::<Normalization>().is_err());
+    }
+}
@@ -0,0 +1,108 @@
+//! Asynchronous API for applications running on a tokio runtime.
+//!
+//! This is not an asynchronous engine: the scan and copy engine is
+//! synchronous, and these functions only run it with `spawn_blocking` on the
+//! blocking thread pool of the runtime, so that its workers are never blocked.
+//! Each operation holds a blocking thread until it completes.
+
+use crate::{
+    delta::{Delta, DiffOptions},
+    error::BkupError,
+    update::UpdateOptions,
+};
+use std::{panic, path::PathBuf};
+use tokio::task::{self, JoinError};
+
+/// Updates the destination directory with the given options, returning the
+/// number of applied actions, without blocking the runtime.
+///
+/// Dropping the returned future doesn't stop the update, that must be
+/// cancelled with the token of its options.
+pub async fn update_async(
+    source: PathBuf,
+    dest: PathBuf,
+    options: UpdateOptions,
+) -> Result<usize, BkupError> {
+    task::spawn_blocking(move || crate::update_with(source, dest, &options))
+        .await
+        .unwrap_or_else(joined)
+}
+
+/// Gets the changes needed to update the destination directory, without
+/// blocking the runtime.
+pub async fn diff_async(
+    source: PathBuf,
+    dest: PathBuf,
+    options: DiffOptions,
+) -> Result<Delta, BkupError> {
+    task::spawn_blocking(move || crate::diff(source, dest, &options))
+        .await
+        .unwrap_or_else(joined)
+}
+
+/// Gets the result of a blocking task that didn't complete, propagating its
+/// panic if any. Blocking tasks cannot be aborted, so the task was dropped by
+/// the runtime shutting down.
+fn joined<T>(error: JoinError) -> Result<T, BkupError> {
+    match error.try_into_panic() {
+        Ok(payload) => panic::resume_unwind(payload),
+        Err(error) => Err(BkupError::Shutdown(format!(
+            "The runtime was shut down: {}",
+            error
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::{env, fs};
+    use tokio::runtime::Builder;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_update_async() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(&source).expect("Cannot create dir");
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+        fs::write(source.join("file"), "content").expect("Cannot write file");
+
+        let runtime = Builder::new_current_thread()
+            .build()
+            .expect("Cannot build runtime");
+        runtime.block_on(async {
+            let delta =
+                diff_async(source.clone(), dest.clone(), DiffOptions::new())
+                    .await
+                    .expect("Cannot diff");
+            assert_eq!(delta.changes.len(), 1);
+            let applied = update_async(
+                source.clone(),
+                dest.clone(),
+                UpdateOptions::new(),
+            )
+            .await
+            .expect("Cannot update");
+            assert_eq!(applied, 1);
+        });
+        assert_eq!(fs::read_to_string(dest.join("file")).unwrap(), "content");
+    }
+
+    #[test]
+    fn test_joined() {
+        let runtime = Builder::new_current_thread()
+            .build()
+            .expect("Cannot build runtime");
+        let error = runtime.block_on(async {
+            let handle = tokio::spawn(std::future::pending::<()>());
+            handle.abort();
+            handle.await.expect_err("The task was not aborted")
+        });
+        // a task that didn't panic is reported as dropped by the runtime,
+        // not as an update cancelled with its token
+        assert!(matches!(joined::<()>(error), Err(BkupError::Shutdown(_))));
+    }
+}
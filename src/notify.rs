@@ -0,0 +1,160 @@
+//! Webhook notified with the summary of an update once it's over.
+//!
+//! The summary is sent as a JSON object with an HTTP POST request, so that an
+//! update can be monitored by services such as healthchecks.io or ntfy.
+
+use crate::{error::BkupError, json::Value};
+use std::{io, str::FromStr, time::Duration};
+
+/// Maximum time to connect to the webhook, and to send or receive its data.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Enumerates the outcomes of an update that are notified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NotifyOn {
+    /// Every update is notified.
+    #[default]
+    Always,
+    /// Only the updates that completed successfully are notified.
+    Success,
+    /// Only the updates that failed (or were cancelled) are notified.
+    Failure,
+}
+
+impl FromStr for NotifyOn {
+    type Err = BkupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(NotifyOn::Always),
+            "success" => Ok(NotifyOn::Success),
+            "failure" => Ok(NotifyOn::Failure),
+            _ => Err(BkupError::Invalid(format!(
+                "Invalid notification outcome {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// URL that receives the summary of the updates with an HTTP POST request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    url: String,
+    on: NotifyOn,
+}
+
+impl Webhook {
+    /// Creates a new webhook that posts to the given `http://` or `https://`
+    /// URL the summary of the updates with the given outcome.
+    pub fn new(url: &str, on: NotifyOn) -> Result<Self, BkupError> {
+        let rest = url
+            .strip_prefix("http://")
+            .or_else(|| url.strip_prefix("https://"));
+        match rest {
+            Some(rest) if !rest.starts_with(['/', '?']) && !rest.is_empty() => {
+                Ok(Webhook {
+                    url: url.to_string(),
+                    on,
+                })
+            }
+            _ => Err(BkupError::Invalid(format!(
+                "Invalid webhook URL {:?}",
+                url
+            ))),
+        }
+    }
+
+    /// Returns true if an update with the given outcome must be notified.
+    pub fn notifies(&self, success: bool) -> bool {
+        match self.on {
+            NotifyOn::Always => true,
+            NotifyOn::Success => success,
+            NotifyOn::Failure => !success,
+        }
+    }
+
+    /// Posts the given summary, failing if the server doesn't accept it.
+    pub fn post(&self, summary: &Value) -> Result<(), BkupError> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(TIMEOUT)
+            .user_agent(concat!("bkup/", env!("CARGO_PKG_VERSION")))
+            .build();
+        match agent
+            .post(&self.url)
+            .set("Content-Type", "application/json")
+            .send_string(&summary.to_string())
+        {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, response)) => {
+                Err(BkupError::Io(io::Error::other(format!(
+                    "Invalid webhook response {} {}",
+                    code,
+                    response.status_text()
+                ))))
+            }
+            Err(e) => Err(BkupError::Io(io::Error::other(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::json;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    #[test]
+    fn test_webhook() {
+        let webhook = Webhook::new("http://example.com", NotifyOn::Failure)
+            .expect("Cannot create webhook");
+        assert!(webhook.notifies(false));
+        assert!(!webhook.notifies(true));
+        assert!(
+            Webhook::new("https://example.com/ping", NotifyOn::Always).is_ok()
+        );
+        assert!(Webhook::new("https:///ping", NotifyOn::Always).is_err());
+        assert!(Webhook::new("example.com", NotifyOn::Always).is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Cannot bind");
+        let url =
+            format!("http://{}/ping?id=1", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().expect("Cannot accept");
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                if let Some(value) = header.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            (request_line, String::from_utf8(body).unwrap())
+        });
+
+        let webhook = Webhook::new(&url, NotifyOn::Always)
+            .expect("Cannot create webhook");
+        let summary = json::object(vec![("status", Value::from("completed"))]);
+        webhook.post(&summary).expect("Cannot post summary");
+        let (request_line, body) = server.join().unwrap();
+        assert_eq!(request_line, "POST /ping?id=1 HTTP/1.1\r\n");
+        assert_eq!(body, r#"{"status":"completed"}"#);
+    }
+}
@@ -0,0 +1,213 @@
+//! Parity data of the destination files, that can repair a limited amount of
+//! corruption without the source.
+//!
+//! Each file is split in blocks, whose digests are recorded together with
+//! one parity block (the XOR of the blocks) for every group of blocks, so
+//! that one corrupted block per group can be rebuilt from the other blocks of
+//! its group and their parity, as RAID 5 does with the disks of an array.
+
+use crate::{
+    copy,
+    error::BkupError,
+    hash::{Digest, Sha256},
+    metadata::{self, PARITY_DIR},
+};
+use log::*;
+use std::{
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Identifies the format of the parity files.
+const MAGIC: &[u8; 8] = b"BKUPPAR1";
+/// Size of the blocks the files are split in.
+const BLOCK_SIZE: usize = 64 * 1024;
+/// Number of blocks protected by each parity block.
+const GROUP_SIZE: usize = 16;
+/// Size of the header of the parity files: the magic number, the file size
+/// and the number of blocks.
+const HEADER_SIZE: usize = 8 + 8 + 8;
+
+/// Gets the path of the parity of the given file, relative to the given
+/// destination directory.
+pub fn parity_path(dest: &Path, path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".par");
+    metadata::path(dest, PARITY_DIR).join(name)
+}
+
+/// Computes the digest of the given block.
+fn digest(block: &[u8]) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update(block);
+    hasher.finalize()
+}
+
+/// Reads the next block of the given file, that is shorter than a full
+/// block only at the end of the file.
+fn read_block(file: &mut File, block: &mut Vec<u8>) -> io::Result<()> {
+    block.clear();
+    Read::by_ref(file)
+        .take(BLOCK_SIZE as u64)
+        .read_to_end(block)?;
+    Ok(())
+}
+
+/// XORs the given block into the parity block.
+fn xor(parity: &mut [u8], block: &[u8]) {
+    for (p, b) in parity.iter_mut().zip(block) {
+        *p ^= b;
+    }
+}
+
+/// Writes the parity of the given file.
+pub fn write(file: &Path, parity: &Path) -> Result<(), BkupError> {
+    debug!("Writing the parity of {:?}", file);
+    let mut source = File::open(file)?;
+    let len = source.metadata()?.len();
+    let mut digests = Vec::new();
+    let mut parities = Vec::new();
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    let mut group = vec![0; BLOCK_SIZE];
+    let blocks = len.div_ceil(BLOCK_SIZE as u64) as usize;
+    for i in 0..blocks {
+        read_block(&mut source, &mut block)?;
+        digests.extend_from_slice(digest(&block).as_bytes());
+        xor(&mut group, &block);
+        if (i + 1) % GROUP_SIZE == 0 || i + 1 == blocks {
+            parities.append(&mut group);
+            group = vec![0; BLOCK_SIZE];
+        }
+    }
+
+    if let Some(parent) = parity.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = copy::temp_path(parity);
+    let mut out = File::create(&temp)?;
+    out.write_all(MAGIC)?;
+    out.write_all(&len.to_le_bytes())?;
+    out.write_all(&(blocks as u64).to_le_bytes())?;
+    out.write_all(&digests)?;
+    out.write_all(&parities)?;
+    drop(out);
+    fs::rename(&temp, parity)?;
+    Ok(())
+}
+
+/// Rebuilds the corrupted blocks of the given file with its parity, and
+/// returns false if the file has no parity or it's too damaged (more than one
+/// corrupted block in the same group, or a different size).
+pub fn repair(file: &Path, parity: &Path) -> Result<bool, BkupError> {
+    if !parity.is_file() {
+        return Ok(false);
+    }
+    let data = fs::read(parity)?;
+    let invalid = || BkupError::Parse(format!("Invalid parity {:?}", parity));
+    if data.len() < HEADER_SIZE || &data[..8] != MAGIC {
+        return Err(invalid());
+    }
+    let number = |i: usize| {
+        let bytes = data[i..i + 8].try_into().expect("Invalid slice length");
+        u64::from_le_bytes(bytes)
+    };
+    let (len, blocks) = (number(8), number(16) as usize);
+    let groups = blocks.div_ceil(GROUP_SIZE);
+    let digests = &data[HEADER_SIZE..];
+    if digests.len() != blocks * 32 + groups * BLOCK_SIZE {
+        return Err(invalid());
+    }
+    let parities = &digests[blocks * 32..];
+    if fs::metadata(file)?.len() != len {
+        warn!("Cannot repair {:?}, its size changed", file);
+        return Ok(false);
+    }
+
+    // find the corrupted block of each group, and rebuild it
+    let mut source = File::open(file)?;
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    let mut rebuilt = Vec::new();
+    for g in 0..groups {
+        let mut group = parities[g * BLOCK_SIZE..(g + 1) * BLOCK_SIZE].to_vec();
+        let mut corrupted = None;
+        for i in g * GROUP_SIZE..blocks.min((g + 1) * GROUP_SIZE) {
+            read_block(&mut source, &mut block)?;
+            if digest(&block).as_bytes()[..] != digests[i * 32..(i + 1) * 32] {
+                if corrupted.replace((i, block.len())).is_some() {
+                    warn!(
+                        "Cannot repair {:?}, too many corrupted blocks",
+                        file
+                    );
+                    return Ok(false);
+                }
+            } else {
+                xor(&mut group, &block);
+            }
+        }
+        if let Some((i, size)) = corrupted {
+            group.truncate(size);
+            if digest(&group).as_bytes()[..] != digests[i * 32..(i + 1) * 32] {
+                warn!("Cannot repair {:?}, its parity is corrupted", file);
+                return Ok(false);
+            }
+            rebuilt.push((i, group));
+        }
+    }
+    if rebuilt.is_empty() {
+        return Ok(true);
+    }
+
+    // patch a copy, that atomically replaces the file once complete
+    let temp = copy::temp_path(file);
+    fs::copy(file, &temp)?;
+    let mut out = OpenOptions::new().write(true).open(&temp)?;
+    for (i, block) in &rebuilt {
+        info!("Rebuilding block {} of {:?}", i, file);
+        out.seek(SeekFrom::Start((i * BLOCK_SIZE) as u64))?;
+        out.write_all(block)?;
+    }
+    drop(out);
+    fs::rename(&temp, file)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_parity_repair() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        let file = root.join("file");
+        let parity = parity_path(&root, Path::new("file"));
+        // two groups, the last one with a partial block
+        let content: Vec<u8> = (0..(GROUP_SIZE + 2) * BLOCK_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        fs::write(&file, &content).expect("Cannot write file");
+        write(&file, &parity).expect("Cannot write parity");
+
+        // one corrupted block per group can be rebuilt
+        let corrupt = |offsets: &[usize]| {
+            let mut data = content.clone();
+            for offset in offsets {
+                data[*offset] ^= 0xff;
+            }
+            fs::write(&file, &data).expect("Cannot write file");
+        };
+        corrupt(&[3, (GROUP_SIZE + 2) * BLOCK_SIZE + 50]);
+        assert!(repair(&file, &parity).unwrap());
+        assert_eq!(fs::read(&file).unwrap(), content);
+
+        // two corrupted blocks in the same group cannot
+        corrupt(&[3, BLOCK_SIZE + 3]);
+        assert!(!repair(&file, &parity).unwrap());
+        assert!(!repair(&file, &root.join("missing.par")).unwrap());
+    }
+}
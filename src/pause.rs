@@ -0,0 +1,65 @@
+//! Pause of a running update, requested by the user with a signal.
+//!
+//! While paused, the update doesn't start new actions and the copies in
+//! progress stop before their next buffer, until the update is unpaused (or
+//! cancelled), keeping everything computed so far.
+
+use crate::cancel::CancellationToken;
+use log::*;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// Interval between two checks of a paused update.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Set while the running update must be paused.
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Pauses the running update.
+pub fn pause() {
+    PAUSED.store(true, Ordering::SeqCst);
+}
+
+/// Unpauses the running update.
+pub fn unpause() {
+    PAUSED.store(false, Ordering::SeqCst);
+}
+
+/// Returns true if the running update is paused.
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+/// Blocks while the running update is paused, unless it's cancelled.
+pub fn wait(cancel: &CancellationToken) {
+    if !is_paused() {
+        return;
+    }
+    info!("Update paused");
+    while is_paused() && !cancel.is_cancelled() {
+        thread::sleep(POLL_INTERVAL);
+    }
+    info!("Update unpaused");
+}
+
+/// Pauses the running update on SIGUSR1, and unpauses it on SIGUSR2.
+#[cfg(unix)]
+pub fn pause_on_signals() {
+    extern "C" fn handle(signal: libc::c_int) {
+        // only async-signal-safe operations are allowed here
+        PAUSED.store(signal == libc::SIGUSR1, Ordering::SeqCst);
+    }
+    let handler = handle as extern "C" fn(libc::c_int) as *const ();
+    // safe since the handler only accesses an atomic
+    unsafe {
+        libc::signal(libc::SIGUSR1, handler as libc::sighandler_t);
+        libc::signal(libc::SIGUSR2, handler as libc::sighandler_t);
+    }
+}
+
+/// Signals are not handled on this platform.
+#[cfg(not(unix))]
+pub fn pause_on_signals() {}
@@ -0,0 +1,1072 @@
+#[cfg(feature = "update")]
+use crate::{
+    copy::{self, CopyOptions},
+    device::{self, DeviceLimits},
+    error::BkupError,
+    hash::{self, Digest, HashAlgorithm},
+    pause,
+    rules::PriorityRules,
+    size::format_size,
+    special,
+};
+use crate::{
+    entry::{FileEntry, FileId},
+    json::{self, Value},
+};
+#[cfg(feature = "update")]
+use log::*;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "update")]
+use std::{
+    cmp::Ordering,
+    fs,
+    str::FromStr,
+    sync::{mpsc, Mutex},
+    thread,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+/// Enumerates the orders in which the files of a plan are copied.
+#[cfg(feature = "update")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransferOrder {
+    /// The files are copied folder by folder, so that the files of the same
+    /// folder are read and written together.
+    #[default]
+    Dirs,
+    /// The smallest files are copied first, so that most of the files are
+    /// copied early and an interrupted update leaves fewer of them behind.
+    SmallFirst,
+}
+
+#[cfg(feature = "update")]
+impl FromStr for TransferOrder {
+    type Err = BkupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dirs" => Ok(TransferOrder::Dirs),
+            "small-first" => Ok(TransferOrder::SmallFirst),
+            _ => Err(BkupError::Invalid(format!(
+                "Invalid transfer order {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Enumerates the operations needed to update the destination.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    /// Creates the directory at the given path.
+    CreateDir {
+        #[serde(serialize_with = "json::lossy")]
+        path: PathBuf,
+    },
+    /// Copies the source file into the destination path, where `overwrite`
+    /// tells whether the destination file already exists.
+    Copy {
+        #[serde(serialize_with = "json::lossy")]
+        source: PathBuf,
+        #[serde(serialize_with = "json::lossy")]
+        dest: PathBuf,
+        size: u64,
+        overwrite: bool,
+    },
+    /// Renames a destination file that was moved in the source directory.
+    Rename {
+        #[serde(serialize_with = "json::lossy")]
+        from: PathBuf,
+        #[serde(serialize_with = "json::lossy")]
+        to: PathBuf,
+    },
+    /// Creates a hard link to the target destination file, where `overwrite`
+    /// tells whether the destination file already exists.
+    Link {
+        #[serde(serialize_with = "json::lossy")]
+        target: PathBuf,
+        #[serde(serialize_with = "json::lossy")]
+        dest: PathBuf,
+        overwrite: bool,
+    },
+    /// Recreates the source special file (FIFO or device node) at the
+    /// destination path.
+    CreateSpecial {
+        #[serde(serialize_with = "json::lossy")]
+        source: PathBuf,
+        #[serde(serialize_with = "json::lossy")]
+        dest: PathBuf,
+    },
+}
+
+impl Action {
+    /// Serializes the action into a JSON value, whose `action` member is the
+    /// type of the action.
+    pub fn to_json(&self) -> Value {
+        // the paths are serialized lossily, so the serialization cannot fail
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    /// Gets the name of the type of the action.
+    #[cfg(feature = "update")]
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::CreateDir { .. } => "create_dir",
+            Action::Copy { .. } => "copy",
+            Action::Rename { .. } => "rename",
+            Action::Link { .. } => "link",
+            Action::CreateSpecial { .. } => "create_special",
+        }
+    }
+
+    /// Gets the destination path created or replaced by the action.
+    #[cfg(feature = "update")]
+    pub fn path(&self) -> &Path {
+        match self {
+            Action::CreateDir { path } => path,
+            Action::Copy { dest, .. }
+            | Action::Link { dest, .. }
+            | Action::CreateSpecial { dest, .. } => dest,
+            Action::Rename { to, .. } => to,
+        }
+    }
+
+    /// Deserializes an action serialized with `to_json`.
+    #[cfg(feature = "update")]
+    pub fn from_json(value: &Value) -> Result<Action, BkupError> {
+        Ok(Action::deserialize(value)?)
+    }
+
+    /// Applies the action to the destination, copying the files according to
+    /// the given options, and gets the digest of the copied file if it was
+    /// verified. The errors refer to the destination path.
+    #[cfg(feature = "update")]
+    pub(crate) fn apply(
+        &self,
+        options: &CopyOptions,
+    ) -> Result<Option<Digest>, BkupError> {
+        self.perform(options).map_err(|e| e.at(self.path()))
+    }
+
+    /// Applies the action to the destination.
+    #[cfg(feature = "update")]
+    fn perform(
+        &self,
+        options: &CopyOptions,
+    ) -> Result<Option<Digest>, BkupError> {
+        match self {
+            Action::CreateDir { path } => {
+                info!("Creating directory {:?}", path);
+                if !path.is_dir() {
+                    fs::create_dir(path)?;
+                    if options.fsync {
+                        copy::sync_parent(path)?;
+                    }
+                }
+            }
+            Action::Copy { source, dest, .. } => {
+                info!("Copying file {:?} to {:?}", source, dest);
+                return copy::copy(source, dest, options);
+            }
+            Action::Rename { from, to } => {
+                info!("Moving file {:?} to {:?}", from, to);
+                fs::rename(from, to)?;
+                if options.fsync {
+                    copy::sync_parent(from)?;
+                    copy::sync_parent(to)?;
+                }
+            }
+            Action::Link {
+                target,
+                dest,
+                overwrite,
+            } => {
+                info!("Linking file {:?} to {:?}", dest, target);
+                if *overwrite {
+                    // link a temporary file that atomically replaces the
+                    // destination file
+                    let temp = copy::temp_path(dest);
+                    fs::hard_link(target, &temp)?;
+                    if options.shred {
+                        copy::shred(dest)?;
+                    }
+                    if let Err(e) = fs::rename(&temp, dest) {
+                        let _ = fs::remove_file(&temp);
+                        return Err(e.into());
+                    }
+                } else {
+                    fs::hard_link(target, dest)?;
+                }
+                if options.fsync {
+                    copy::sync_parent(dest)?;
+                }
+            }
+            Action::CreateSpecial { source, dest } => {
+                info!("Creating special file {:?} like {:?}", dest, source);
+                special::create(source, dest)?;
+                if options.fsync {
+                    copy::sync_parent(dest)?;
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Represents the ordered list of actions that brings the destination up to
+/// date with the source.
+#[derive(Debug, Default, PartialEq)]
+pub struct Plan {
+    actions: Vec<Action>,
+    // destination of the first copy of each hard linked source file, only
+    // when hard links must be preserved
+    links: Option<HashMap<FileId, PathBuf>>,
+}
+
+impl Plan {
+    /// Creates a new empty plan.
+    pub fn new() -> Self {
+        Plan::default()
+    }
+
+    /// Creates a new empty plan that recreates the source hard links in the
+    /// destination, instead of copying each link independently.
+    #[cfg(feature = "update")]
+    pub fn with_hard_links() -> Self {
+        Plan {
+            links: Some(HashMap::new()),
+            ..Plan::default()
+        }
+    }
+
+    /// Appends the action needed to copy the given source file into the
+    /// destination path.
+    pub fn copy_file(
+        &mut self,
+        file: &FileEntry,
+        dest: &Path,
+        overwrite: bool,
+    ) {
+        if let (Some(links), Some(id)) = (self.links.as_mut(), file.id()) {
+            if let Some(target) = links.get(&id) {
+                let action = Action::Link {
+                    target: target.clone(),
+                    dest: dest.to_path_buf(),
+                    overwrite,
+                };
+                self.actions.push(action);
+                return;
+            }
+            links.insert(id, dest.to_path_buf());
+        }
+        self.actions.push(Action::Copy {
+            source: file.path().to_path_buf(),
+            dest: dest.to_path_buf(),
+            size: file.size(),
+            overwrite,
+        });
+    }
+
+    /// Sorts the copies of the plan by priority (matched against the paths
+    /// relative to the source directory), and then in the given order. The
+    /// folders are created first, then the moved files are renamed and the
+    /// files copied, and the hard links and special files are created last,
+    /// so that the copies they may depend on are completed.
+    #[cfg(feature = "update")]
+    pub fn order(
+        &mut self,
+        order: TransferOrder,
+        priorities: &PriorityRules,
+        source: &Path,
+    ) {
+        let priority = |path: &Path| {
+            priorities.priority(path.strip_prefix(source).unwrap_or(path))
+        };
+        let rank = |action: &Action| match action {
+            Action::CreateDir { .. } => 0,
+            Action::Rename { .. } => 1,
+            Action::Copy { .. } => 2,
+            Action::CreateSpecial { .. } => 3,
+            Action::Link { .. } => 4,
+        };
+        // the sort is stable, so the actions of the same rank (such as the
+        // parent folders and their subfolders) keep their relative order
+        self.actions.sort_by(|a1, a2| {
+            rank(a1).cmp(&rank(a2)).then_with(|| match (a1, a2) {
+                (
+                    Action::Copy {
+                        source: source1,
+                        dest: dest1,
+                        size: size1,
+                        ..
+                    },
+                    Action::Copy {
+                        source: source2,
+                        dest: dest2,
+                        size: size2,
+                        ..
+                    },
+                ) => {
+                    priority(source1).cmp(&priority(source2)).then_with(|| {
+                        match order {
+                            TransferOrder::Dirs => {
+                                dest1.parent().cmp(&dest2.parent())
+                            }
+                            TransferOrder::SmallFirst => size1.cmp(size2),
+                        }
+                    })
+                }
+                _ => Ordering::Equal,
+            })
+        });
+    }
+
+    /// Appends an action to the plan.
+    pub fn push(&mut self, action: Action) {
+        self.actions.push(action);
+    }
+
+    /// Gets the number of actions in the plan.
+    #[cfg(feature = "update")]
+    pub fn len(&self) -> usize {
+        self.actions.len()
+    }
+
+    /// Gets the total number of bytes copied by the plan.
+    pub fn bytes(&self) -> u64 {
+        self.actions
+            .iter()
+            .map(|action| match action {
+                Action::Copy { size, .. } => *size,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Gets the destination files replaced by the plan, with their current
+    /// size in bytes.
+    #[cfg(feature = "update")]
+    pub fn overwrites(&self) -> Vec<(&Path, u64)> {
+        self.actions
+            .iter()
+            .filter_map(|action| match action {
+                Action::Copy {
+                    dest,
+                    overwrite: true,
+                    ..
+                }
+                | Action::Link {
+                    dest,
+                    overwrite: true,
+                    ..
+                } => {
+                    let size = fs::metadata(dest).map_or(0, |m| m.len());
+                    Some((dest.as_path(), size))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns true if the plan doesn't contain any action.
+    #[cfg(feature = "update")]
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Serializes the actions of the plan into a JSON array.
+    pub fn to_json(&self) -> Value {
+        Value::Array(self.actions.iter().map(Action::to_json).collect())
+    }
+
+    /// Gets the actions of the plan, in the order they are applied.
+    pub fn actions(&self) -> &[Action] {
+        &self.actions
+    }
+
+    /// Replaces the copies of new files with the renaming of the given
+    /// destination files that have no counterpart in the source, when their
+    /// size and content (hashed with the given algorithm) are the same.
+    #[cfg(feature = "update")]
+    pub fn detect_moves(
+        &mut self,
+        orphans: &[&FileEntry],
+        algorithm: HashAlgorithm,
+    ) -> Result<(), BkupError> {
+        // group the candidates by size so that only files that may be equal
+        // need to be hashed
+        let mut candidates: HashMap<u64, Vec<Candidate>> = HashMap::new();
+        for orphan in orphans {
+            candidates
+                .entry(orphan.size())
+                .or_default()
+                .push(Candidate::new(orphan.path()));
+        }
+
+        for action in &mut self.actions {
+            let (source, dest, size) = match action {
+                Action::Copy {
+                    source,
+                    dest,
+                    size,
+                    overwrite: false,
+                } => (source.clone(), dest.clone(), *size),
+                _ => continue,
+            };
+            let candidates = match candidates.get_mut(&size) {
+                Some(candidates) => candidates,
+                None => continue,
+            };
+            let digest = hash::hash_file(&source, algorithm)?;
+            for candidate in candidates.iter_mut().filter(|c| !c.taken) {
+                if candidate.digest(algorithm)? == digest {
+                    debug!("{:?} was moved to {:?}", candidate.path, source);
+                    candidate.taken = true;
+                    *action = Action::Rename {
+                        from: candidate.path.clone(),
+                        to: dest,
+                    };
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies all the actions of the plan in order, copying the files
+    /// according to the given options.
+    #[cfg(all(test, feature = "update"))]
+    pub fn apply(&self, options: &CopyOptions) -> Result<(), BkupError> {
+        self.apply_with(options, |_, _, _| ()).map(|_| ())
+    }
+
+    /// Applies all the actions of the plan in order, invoking the given
+    /// progress callback with the index of each applied action (and the
+    /// digest of the copied file, if it was verified). When more
+    /// than one job is allowed, the files are copied concurrently and the
+    /// callback is invoked as they complete. The copies whose source file is
+    /// still locked by another process once retried are skipped, and
+    /// returned. Once the copies started reach the transfer quota, the
+    /// remaining actions are left to the next update.
+    #[cfg(feature = "update")]
+    pub fn apply_with<F>(
+        &self,
+        options: &CopyOptions,
+        mut progress: F,
+    ) -> Result<Vec<Failure>, BkupError>
+    where
+        F: FnMut(usize, &Action, Option<&Digest>),
+    {
+        if options.jobs > 1 {
+            return self.apply_concurrently(options, progress);
+        }
+        let mut failures = Vec::new();
+        let mut locked = Vec::new();
+        let mut quota = Quota::new(options.max_transfer);
+        for (i, action) in self.actions.iter().enumerate() {
+            pause::wait(&options.cancel);
+            options.cancel.check()?;
+            if !quota.start(i, self) {
+                break;
+            }
+            match action.apply(options) {
+                Ok(digest) => progress(i, action, digest.as_ref()),
+                Err(e) if copy::is_locked(&e) => {
+                    locked.push(Failure::new(action, e))
+                }
+                Err(e) if options.keep_going => {
+                    failures.push(Failure::new(action, e))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Failures::check(failures, locked)
+    }
+
+    /// Applies the actions of the plan copying up to `options.jobs` files at
+    /// the same time, while the other actions are applied in order once the
+    /// copies they may depend on are completed.
+    #[cfg(feature = "update")]
+    fn apply_concurrently<F>(
+        &self,
+        options: &CopyOptions,
+        mut progress: F,
+    ) -> Result<Vec<Failure>, BkupError>
+    where
+        F: FnMut(usize, &Action, Option<&Digest>),
+    {
+        let limits = DeviceLimits::new(options.device_jobs);
+        let (job_sender, jobs) = mpsc::channel::<(usize, &Action)>();
+        let jobs = Mutex::new(jobs);
+        let (done_sender, done) = mpsc::channel();
+
+        thread::scope(|scope| {
+            for _ in 0..options.jobs {
+                let (jobs, limits, done) =
+                    (&jobs, &limits, done_sender.clone());
+                scope.spawn(move || loop {
+                    let job = jobs.lock().expect("Poisoned job queue").recv();
+                    let (i, action) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let devices = match action {
+                        Action::Copy { source, dest, .. } => {
+                            device::devices(source, dest)
+                        }
+                        _ => Vec::new(),
+                    };
+                    let _slots = limits.acquire(devices);
+                    let result = action.apply(options);
+                    if done.send((i, result)).is_err() {
+                        break;
+                    }
+                });
+            }
+
+            // records the outcome of each action, keeping the first error
+            // (or every failure when going on after them), and returns
+            // whether the actions can go on
+            let mut error = None;
+            let mut failures = Vec::new();
+            let mut locked = Vec::new();
+            let mut complete =
+                |(i, result): (usize, Result<Option<Digest>, BkupError>)| {
+                    match result {
+                        Ok(digest) => {
+                            progress(i, &self.actions[i], digest.as_ref())
+                        }
+                        Err(e) if copy::is_locked(&e) => {
+                            locked.push(Failure::new(&self.actions[i], e))
+                        }
+                        Err(e) if options.keep_going && !is_cancelled(&e) => {
+                            failures.push(Failure::new(&self.actions[i], e))
+                        }
+                        Err(e) => {
+                            error.get_or_insert(e);
+                        }
+                    }
+                    error.is_none()
+                };
+            let mut pending = 0;
+            let mut ok = true;
+            let mut quota = Quota::new(options.max_transfer);
+            for (i, action) in self.actions.iter().enumerate() {
+                while let Ok(job) = done.try_recv() {
+                    ok &= complete(job);
+                    pending -= 1;
+                }
+                // links may point to files still being copied
+                if let Action::Link { .. } = action {
+                    while pending > 0 {
+                        ok &= complete(done.recv().expect("Copy job lost"));
+                        pending -= 1;
+                    }
+                }
+                pause::wait(&options.cancel);
+                if let Err(e) = options.cancel.check() {
+                    ok &= complete((i, Err(e)));
+                }
+                // stop scheduling new actions after the first error
+                if !ok || !quota.start(i, self) {
+                    break;
+                }
+                if let Action::Copy { .. } = action {
+                    job_sender
+                        .send((i, action))
+                        .expect("The copy jobs must be running");
+                    pending += 1;
+                } else {
+                    ok &= complete((i, action.apply(options)));
+                }
+            }
+            drop(job_sender);
+            while pending > 0 {
+                complete(done.recv().expect("Copy job lost"));
+                pending -= 1;
+            }
+            error.map_or_else(|| Failures::check(failures, locked), Err)
+        })
+    }
+}
+
+impl From<Vec<Action>> for Plan {
+    /// Creates a plan of the given actions, without hard links tracking.
+    fn from(actions: Vec<Action>) -> Self {
+        Plan {
+            actions,
+            links: None,
+        }
+    }
+}
+
+/// Counts the bytes of the copies started, against the transfer quota.
+#[cfg(feature = "update")]
+struct Quota {
+    max: Option<u64>,
+    started: u64,
+}
+
+#[cfg(feature = "update")]
+impl Quota {
+    /// Creates the quota of the given number of bytes, if any.
+    fn new(max: Option<u64>) -> Self {
+        Quota { max, started: 0 }
+    }
+
+    /// Returns true if the action with the given index of the plan can be
+    /// started, counting its bytes, or false once the quota is reached.
+    fn start(&mut self, i: usize, plan: &Plan) -> bool {
+        let max = match self.max {
+            Some(max) => max,
+            None => return true,
+        };
+        if self.started >= max {
+            warn!(
+                "Transfer quota of {} reached, leaving the {} remaining \
+                 actions to the next update",
+                format_size(max),
+                plan.actions.len() - i
+            );
+            return false;
+        }
+        if let Action::Copy { size, .. } = &plan.actions[i] {
+            self.started += size;
+        }
+        true
+    }
+}
+
+/// Represents an action that failed while the others were applied.
+#[derive(Debug)]
+pub struct Failure {
+    /// Destination path of the action.
+    pub path: PathBuf,
+    /// Error that made the action fail.
+    pub error: String,
+}
+
+impl Failure {
+    /// Creates the failure of the given action.
+    #[cfg(feature = "update")]
+    fn new(action: &Action, error: BkupError) -> Self {
+        warn!("Cannot update {:?}: {}", action.path(), error);
+        Failure {
+            path: action.path().to_path_buf(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// Error returned by a plan whose actions were all applied except the ones
+/// that failed.
+#[derive(Debug)]
+pub struct Failures(pub Vec<Failure>);
+
+impl Failures {
+    /// Fails with the given failures, if any, that also include the copies
+    /// skipped because their source file is locked. Otherwise gets the
+    /// skipped copies.
+    #[cfg(feature = "update")]
+    fn check(
+        mut failures: Vec<Failure>,
+        locked: Vec<Failure>,
+    ) -> Result<Vec<Failure>, BkupError> {
+        if failures.is_empty() {
+            Ok(locked)
+        } else {
+            failures.extend(locked);
+            Err(BkupError::Failures(Failures(failures)))
+        }
+    }
+}
+
+impl fmt::Display for Failures {
+    /// Writes the report of every failure.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} actions failed:", self.0.len())?;
+        for failure in &self.0 {
+            write!(f, "\n  {:?}: {}", failure.path, failure.error)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if the given error is the cancellation of the update.
+#[cfg(feature = "update")]
+fn is_cancelled(error: &BkupError) -> bool {
+    matches!(error, BkupError::Cancelled)
+}
+
+/// Represents a destination file that may be the target of a move.
+#[cfg(feature = "update")]
+struct Candidate {
+    path: PathBuf,
+    digest: Option<hash::Digest>,
+    taken: bool,
+}
+
+#[cfg(feature = "update")]
+impl Candidate {
+    /// Creates a new candidate for the given path.
+    fn new(path: &Path) -> Self {
+        Candidate {
+            path: path.to_path_buf(),
+            digest: None,
+            taken: false,
+        }
+    }
+
+    /// Gets the digest of the candidate content, hashing it only once.
+    fn digest(
+        &mut self,
+        algorithm: HashAlgorithm,
+    ) -> Result<hash::Digest, BkupError> {
+        match self.digest {
+            Some(digest) => Ok(digest),
+            None => {
+                let digest = hash::hash_file(&self.path, algorithm)?;
+                self.digest = Some(digest);
+                Ok(digest)
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "update"))]
+mod tests {
+
+    use super::*;
+    use crate::{
+        entry::{Accuracy, Entry},
+        filter::Filter,
+    };
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_detect_moves() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(source.join("dir")).expect("Cannot create dir");
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+
+        // the file was moved into a sub-directory of the source, while a
+        // different file with the same size was added
+        fs::write(source.join("dir").join("moved"), "content1")
+            .expect("Cannot write file");
+        fs::write(source.join("added"), "content2").expect("Cannot write file");
+        fs::write(dest.join("old"), "content1").expect("Cannot write file");
+
+        let ignore = false;
+        let filter = Filter::new();
+        let source =
+            Entry::directory(&source, ignore, &filter).expect("Cannot visit");
+        let dest_entry =
+            Entry::directory(&dest, ignore, &filter).expect("Cannot visit");
+        let mut plan = Plan::new();
+        source
+            .cmp(&dest_entry, &Accuracy::default())
+            .expect("Cannot compare entries")
+            .expect("Delta should be some")
+            .plan(&mut plan);
+        let orphans = dest_entry.missing_files(&source);
+        assert_eq!(orphans.len(), 1);
+        plan.detect_moves(&orphans, HashAlgorithm::Xxh3)
+            .expect("Cannot detect moves");
+
+        let renames: Vec<_> = plan
+            .actions
+            .iter()
+            .filter(|a| matches!(a, Action::Rename { .. }))
+            .collect();
+        assert_eq!(
+            renames,
+            vec![&Action::Rename {
+                from: dest.join("old"),
+                to: dest.join("dir").join("moved"),
+            }]
+        );
+
+        plan.apply(&CopyOptions::default())
+            .expect("Cannot apply plan");
+        assert!(!dest.join("old").exists());
+        assert_eq!(
+            fs::read_to_string(dest.join("dir").join("moved")).unwrap(),
+            "content1"
+        );
+        assert_eq!(fs::read_to_string(dest.join("added")).unwrap(), "content2");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_links() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(&source).expect("Cannot create dir");
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+        fs::write(source.join("file"), "content").expect("Cannot write file");
+        fs::hard_link(source.join("file"), source.join("link"))
+            .expect("Cannot create link");
+
+        let ignore = false;
+        let filter = Filter::new();
+        let source =
+            Entry::directory(&source, ignore, &filter).expect("Cannot visit");
+        let dest_entry =
+            Entry::directory(&dest, ignore, &filter).expect("Cannot visit");
+        let mut plan = Plan::with_hard_links();
+        source
+            .cmp(&dest_entry, &Accuracy::default())
+            .expect("Cannot compare entries")
+            .expect("Delta should be some")
+            .plan(&mut plan);
+        assert_eq!(plan.actions.len(), 2);
+        plan.apply(&CopyOptions::default())
+            .expect("Cannot apply plan");
+
+        // both destination files must point to the same inode
+        let file = fs::metadata(dest.join("file")).expect("Cannot stat file");
+        let link = fs::metadata(dest.join("link")).expect("Cannot stat link");
+        assert_eq!(file.ino(), link.ino());
+        assert_eq!(file.nlink(), 2);
+    }
+
+    #[test]
+    fn test_keep_going() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        fs::write(root.join("file"), "content").expect("Cannot write file");
+
+        for jobs in &[1, 2] {
+            let dest = root.join(format!("dest{}", jobs));
+            let mut plan = Plan::new();
+            plan.push(Action::CreateDir { path: dest.clone() });
+            for name in &["missing", "file"] {
+                plan.push(Action::Copy {
+                    source: root.join(name),
+                    dest: dest.join(name),
+                    size: 7,
+                    overwrite: false,
+                });
+            }
+
+            // the missing file doesn't stop the following copy
+            let options = CopyOptions {
+                keep_going: true,
+                jobs: *jobs,
+                ..CopyOptions::default()
+            };
+            let error = plan.apply(&options).expect_err("The copy must fail");
+            let failures = match error {
+                BkupError::Failures(failures) => failures,
+                e => panic!("The error must report the failures: {}", e),
+            };
+            assert_eq!(failures.0.len(), 1);
+            assert_eq!(failures.0[0].path, dest.join("missing"));
+            assert_eq!(
+                fs::read_to_string(dest.join("file")).unwrap(),
+                "content"
+            );
+        }
+    }
+
+    #[test]
+    fn test_max_transfer() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        fs::write(root.join("file"), "content").expect("Cannot write file");
+
+        for jobs in &[1, 2] {
+            let dest = root.join(format!("dest{}", jobs));
+            let mut plan = Plan::new();
+            plan.push(Action::CreateDir { path: dest.clone() });
+            for name in &["a", "b", "c"] {
+                plan.push(Action::Copy {
+                    source: root.join("file"),
+                    dest: dest.join(name),
+                    size: 7,
+                    overwrite: false,
+                });
+            }
+
+            // the copy that exceeds the quota is completed, but no other
+            let options = CopyOptions {
+                max_transfer: Some(10),
+                jobs: *jobs,
+                ..CopyOptions::default()
+            };
+            let mut applied = 0;
+            plan.apply_with(&options, |_, _, _| applied += 1)
+                .expect("Cannot apply plan");
+            assert_eq!(applied, 3);
+            assert!(dest.join("a").exists());
+            assert!(dest.join("b").exists());
+            assert!(!dest.join("c").exists());
+        }
+        fs::remove_dir_all(root).expect("Cannot remove test directory");
+    }
+
+    #[test]
+    fn test_order() {
+        let copy = |dest: &str, size| Action::Copy {
+            source: PathBuf::from("/source").join(dest),
+            dest: PathBuf::from("/dest").join(dest),
+            size,
+            overwrite: false,
+        };
+        let mut plan = Plan::new();
+        plan.push(Action::CreateDir {
+            path: PathBuf::from("/dest/a"),
+        });
+        plan.push(copy("a/b/big", 100));
+        plan.push(Action::Link {
+            target: PathBuf::from("/dest/a/b/big"),
+            dest: PathBuf::from("/dest/a/link"),
+            overwrite: false,
+        });
+        plan.push(copy("a/small", 1));
+        plan.push(Action::CreateDir {
+            path: PathBuf::from("/dest/a/c"),
+        });
+        plan.push(copy("a/c/medium", 10));
+        plan.push(copy("a/other", 50));
+        let paths = |plan: &Plan| -> Vec<PathBuf> {
+            plan.actions()
+                .iter()
+                .map(|action| {
+                    action.path().strip_prefix("/dest").unwrap().into()
+                })
+                .collect()
+        };
+        let expected = |paths: &[&str]| -> Vec<PathBuf> {
+            paths.iter().map(PathBuf::from).collect()
+        };
+
+        plan.order(
+            TransferOrder::Dirs,
+            &PriorityRules::default(),
+            Path::new("/source"),
+        );
+        assert_eq!(
+            paths(&plan),
+            expected(&[
+                "a",
+                "a/c",
+                "a/small",
+                "a/other",
+                "a/b/big",
+                "a/c/medium",
+                "a/link"
+            ])
+        );
+        plan.order(
+            TransferOrder::SmallFirst,
+            &PriorityRules::default(),
+            Path::new("/source"),
+        );
+        assert_eq!(
+            paths(&plan),
+            expected(&[
+                "a",
+                "a/c",
+                "a/small",
+                "a/c/medium",
+                "a/other",
+                "a/b/big",
+                "a/link"
+            ])
+        );
+
+        // the priorities come before the order
+        let priorities = PriorityRules::new(
+            &["b/**".to_string()],
+            &["/a/small".to_string()],
+        )
+        .expect("Invalid patterns");
+        plan.order(
+            TransferOrder::SmallFirst,
+            &priorities,
+            Path::new("/source"),
+        );
+        assert_eq!(
+            paths(&plan),
+            expected(&[
+                "a",
+                "a/c",
+                "a/b/big",
+                "a/c/medium",
+                "a/other",
+                "a/small",
+                "a/link"
+            ])
+        );
+        assert_eq!(
+            "small-first".parse::<TransferOrder>().ok(),
+            Some(TransferOrder::SmallFirst)
+        );
+        assert!("large-first".parse::<TransferOrder>().is_err());
+    }
+
+    #[test]
+    fn test_apply_concurrently() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+        for d in 0..4 {
+            let dir = source.join(format!("dir{}", d));
+            fs::create_dir_all(&dir).expect("Cannot create dir");
+            for f in 0..8 {
+                fs::write(
+                    dir.join(format!("file{}", f)),
+                    format!("{}{}", d, f),
+                )
+                .expect("Cannot write file");
+            }
+        }
+
+        let ignore = false;
+        let filter = Filter::new();
+        let source_entry =
+            Entry::directory(&source, ignore, &filter).expect("Cannot visit");
+        let dest_entry =
+            Entry::directory(&dest, ignore, &filter).expect("Cannot visit");
+        let mut plan = Plan::new();
+        source_entry
+            .cmp(&dest_entry, &Accuracy::default())
+            .expect("Cannot compare entries")
+            .expect("Delta should be some")
+            .plan(&mut plan);
+
+        let options = CopyOptions {
+            fsync: true,
+            jobs: 4,
+            device_jobs: Some(2),
+            ..CopyOptions::default()
+        };
+        let mut applied = vec![false; plan.len()];
+        plan.apply_with(&options, |i, _, _| applied[i] = true)
+            .expect("Cannot apply plan");
+        assert!(applied.iter().all(|applied| *applied));
+        for d in 0..4 {
+            for f in 0..8 {
+                let path =
+                    dest.join(format!("dir{}", d)).join(format!("file{}", f));
+                assert_eq!(
+                    fs::read_to_string(path).unwrap(),
+                    format!("{}{}", d, f)
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use crate::{
+    error::BkupError,
+    json::{self, Value},
+};
+use log::*;
+use std::{
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::Mutex,
+};
+
+/// Version of the plugin protocol.
+const PROTOCOL_VERSION: u64 = 1;
+
+/// Capability of the plugins that decide which entries must be included.
+const FILTER_CAPABILITY: &str = "filter";
+/// Capability of the plugins that want to be notified of the run outcome.
+const NOTIFY_CAPABILITY: &str = "notify";
+
+/// Represents an external plugin running as a subprocess.
+///
+/// The plugin exchanges one JSON object per line through its stdin and stdout:
+/// it is first sent a `hello` message, to which it replies with the list of
+/// its capabilities; then it is sent a `filter` request for each visited
+/// entry (if it has the `filter` capability), to which it replies whether the
+/// entry must be included, and a `completed` or `failed` event at the end of
+/// the run (if it has the `notify` capability).
+pub struct Plugin {
+    // command used to launch the plugin
+    command: String,
+    // running plugin process
+    child: Child,
+    // plugin stdin and stdout, used by one request at a time
+    io: Mutex<PluginIo>,
+    // whether the plugin filters the visited entries
+    filter: bool,
+    // whether the plugin must be notified of the run outcome
+    notify: bool,
+}
+
+impl Plugin {
+    /// Launches the plugin with the given shell command and negotiates its
+    /// capabilities.
+    pub fn spawn(command: &str) -> Result<Plugin, BkupError> {
+        info!("Launching plugin {:?}", command);
+        let mut child = shell(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| {
+                BkupError::Plugin(format!(
+                    "Cannot launch plugin {:?}: {}",
+                    command, e
+                ))
+            })?;
+        let stdin = child.stdin.take().expect("Plugin stdin must be piped");
+        let stdout = child.stdout.take().expect("Plugin stdout must be piped");
+        let mut plugin = Plugin {
+            command: command.to_string(),
+            child,
+            io: Mutex::new(PluginIo {
+                stdin: Some(stdin),
+                stdout: BufReader::new(stdout),
+            }),
+            filter: false,
+            notify: false,
+        };
+
+        let hello = json::object(vec![
+            ("type", Value::from("hello")),
+            ("version", Value::from(PROTOCOL_VERSION)),
+        ]);
+        let reply = plugin.request(&hello)?;
+        let capabilities = reply
+            .get("capabilities")
+            .and_then(Value::as_array)
+            .ok_or_else(|| {
+                BkupError::Plugin(format!(
+                    "Plugin {:?} didn't send its capabilities",
+                    command
+                ))
+            })?;
+        for capability in capabilities.iter().filter_map(Value::as_str) {
+            match capability {
+                FILTER_CAPABILITY => plugin.filter = true,
+                NOTIFY_CAPABILITY => plugin.notify = true,
+                _ => warn!("Unknown plugin capability {:?}", capability),
+            }
+        }
+        debug!(
+            "Plugin {:?} (filter: {}, notify: {})",
+            command, plugin.filter, plugin.notify
+        );
+        Ok(plugin)
+    }
+
+    /// Returns true if the plugin filters the visited entries.
+    pub fn filters(&self) -> bool {
+        self.filter
+    }
+
+    /// Returns true if the plugin must be notified of the run outcome.
+    pub fn notifies(&self) -> bool {
+        self.notify
+    }
+
+    /// Asks the plugin whether the given entry must be included.
+    pub fn is_included(
+        &self,
+        path: &Path,
+        is_dir: bool,
+        size: u64,
+    ) -> Result<bool, BkupError> {
+        let request = json::object(vec![
+            ("type", Value::from("filter")),
+            ("path", Value::from(path.to_string_lossy().into_owned())),
+            ("dir", Value::from(is_dir)),
+            ("size", Value::from(size)),
+        ]);
+        let reply = self.request(&request)?;
+        reply
+            .get("include")
+            .and_then(Value::as_bool)
+            .ok_or_else(|| {
+                BkupError::Plugin(format!(
+                    "Invalid filter reply from plugin {:?}",
+                    self.command
+                ))
+            })
+    }
+
+    /// Sends the given event to the plugin, without waiting for a reply.
+    pub fn notify(&self, event: &Value) -> Result<(), BkupError> {
+        let mut io = self.io.lock().expect("Plugin lock poisoned");
+        io.send(event)
+    }
+
+    /// Sends the given request to the plugin and waits for its reply.
+    fn request(&self, request: &Value) -> Result<Value, BkupError> {
+        let mut io = self.io.lock().expect("Plugin lock poisoned");
+        io.send(request)?;
+        let mut line = String::new();
+        if io.stdout.read_line(&mut line)? == 0 {
+            return Err(BkupError::Plugin(format!(
+                "Plugin {:?} exited",
+                self.command
+            )));
+        }
+        trace!("Plugin {:?} replied {:?}", self.command, line);
+        json::parse(&line)
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        // closing stdin lets the plugin know there are no more messages
+        let io = match self.io.get_mut() {
+            Ok(io) => io,
+            Err(e) => e.into_inner(),
+        };
+        io.stdin.take();
+        if let Err(e) = self.child.wait() {
+            warn!("Cannot wait for plugin {:?}: {}", self.command, e);
+        }
+    }
+}
+
+/// Represents the pipes connected to a plugin process.
+struct PluginIo {
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+impl PluginIo {
+    /// Sends the given message to the plugin on a single line.
+    fn send(&mut self, message: &Value) -> Result<(), BkupError> {
+        let stdin = self.stdin.as_mut().ok_or_else(|| {
+            BkupError::Plugin(
+                "The plugin input has already been closed".to_string(),
+            )
+        })?;
+        writeln!(stdin, "{}", message)?;
+        stdin.flush()?;
+        Ok(())
+    }
+}
+
+/// Creates the command that runs the given command line in the system shell.
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+/// Creates the command that runs the given command line in the system shell.
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_filter_plugin() {
+        // plugin that only includes directories
+        let command = r#"read hello; echo '{"capabilities": ["filter"]}';
+            while read request; do
+                case "$request" in
+                    *'"dir":true'*) echo '{"include": true}' ;;
+                    *) echo '{"include": false}' ;;
+                esac
+            done"#;
+        let plugin = Plugin::spawn(command).expect("Cannot spawn plugin");
+        assert!(plugin.filters());
+        assert!(!plugin.notifies());
+        assert!(plugin.is_included(Path::new("dir"), true, 0).unwrap());
+        assert!(!plugin.is_included(Path::new("file"), false, 1).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_plugin() {
+        assert!(Plugin::spawn("echo 'not json'").is_err());
+        assert!(Plugin::spawn("echo '{}'").is_err());
+        assert!(Plugin::spawn("exit 0").is_err());
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Limits the rate of the transferred bytes with a token bucket, shared by all
+/// the transfers of a run.
+#[derive(Debug)]
+pub struct RateLimiter {
+    // maximum number of bytes per second
+    rate: u64,
+    // bytes that can be transferred without waiting
+    bucket: Mutex<Bucket>,
+}
+
+/// State of the token bucket.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,   // available bytes, negative when in debt
+    last: Instant, // last time the bucket was refilled
+}
+
+impl RateLimiter {
+    /// Creates a new rate limiter with the given number of bytes per second,
+    /// that allows bursts of up to one second of transfer.
+    pub fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate: rate.max(1),
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes the given number of bytes from the bucket, blocking until the
+    /// transfer would not exceed the rate.
+    pub fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("Poisoned rate limiter");
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+            let rate = self.rate as f64;
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            bucket.last = now;
+            // go into debt and let the caller wait until it is paid back
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::from_secs(0)
+            }
+        };
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(10_000);
+        let start = Instant::now();
+        // the first second of transfer is available immediately
+        limiter.acquire(10_000);
+        assert!(start.elapsed() < Duration::from_millis(200));
+        // then the bytes are only available at the given rate
+        limiter.acquire(5_000);
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+}
@@ -0,0 +1,271 @@
+//! Destinations on the storage providers supported by rclone, that lists the
+//! destination and transfers the files of the update.
+//!
+//! The destination is given as `rclone:remote:path`, where `remote:path` is
+//! the path of a remote configured with `rclone config`. The listing of the
+//! destination (with `rclone lsjson`) is compared with the source as a scan,
+//! and the actions of the plan are run as rclone commands, that keep the
+//! modification times of the copied files.
+
+use crate::{
+    date,
+    entry::{Accuracy, Entry},
+    error::BkupError,
+    filter::Filter,
+    json::{self, Value},
+    plan::{Action, Plan},
+};
+use log::*;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// Prefix of the rclone destinations.
+pub const PREFIX: &str = "rclone:";
+/// Program run to list and transfer the files.
+const RCLONE_PROGRAM: &str = "rclone";
+/// Exit code of rclone when the listed directory doesn't exist.
+const DIR_NOT_FOUND_EXIT_CODE: i32 = 3;
+
+/// Updates the given rclone destination (`remote:path`) with the source
+/// directory, and returns the number of actions applied.
+pub fn push(
+    source: PathBuf,
+    dest: &str,
+    accuracy: Duration,
+    ignore: bool,
+) -> Result<usize, BkupError> {
+    match dest.split_once(':') {
+        Some((remote, path)) if !remote.is_empty() && !path.is_empty() => {}
+        _ => {
+            return Err(BkupError::Invalid(format!(
+                "Invalid rclone destination {:?}, expected remote:path",
+                dest
+            )))
+        }
+    }
+    let dest = dest.trim_end_matches('/');
+    info!("Listing {}", dest);
+    let dest = Entry::from_json(&scan(dest, &list(dest)?)?)?;
+    let source = Entry::directory(&source, ignore, &Filter::new())?;
+    let mut plan = Plan::new();
+    if let Some(delta) = source.cmp(&dest, &Accuracy::new(accuracy))? {
+        delta.plan(&mut plan);
+    }
+    info!("Applying {} actions ({} bytes)", plan.len(), plan.bytes());
+    let mut actions = 0;
+    for action in plan.actions() {
+        let args = match action {
+            // the folders are created with their files
+            Action::CreateDir { .. } => continue,
+            Action::Copy { source, dest, .. } => {
+                info!("Uploading file {:?} to {:?}", source, dest);
+                ["copyto", path(source)?, path(dest)?]
+            }
+            Action::Rename { from, to } => {
+                info!("Moving file {:?} to {:?}", from, to);
+                ["moveto", path(from)?, path(to)?]
+            }
+            Action::Link { .. } | Action::CreateSpecial { .. } => {
+                warn!("Skipping {:?}, not supported by rclone", action.path());
+                continue;
+            }
+        };
+        run(&args).map_err(|e| e.at(action.path()))?;
+        actions += 1;
+    }
+    Ok(actions)
+}
+
+/// Gets the given path as an argument of rclone.
+fn path(path: &Path) -> Result<&str, BkupError> {
+    path.to_str().ok_or_else(|| {
+        BkupError::Invalid(format!("The path {:?} is not valid UTF-8", path))
+    })
+}
+
+/// Lists the files of the destination recursively, that are none if the
+/// destination doesn't exist yet.
+fn list(dest: &str) -> Result<Value, BkupError> {
+    let output = Command::new(RCLONE_PROGRAM)
+        .args(["lsjson", "--recursive", "--no-mimetype", dest])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| spawn_error(&e))?;
+    match output.status.code() {
+        Some(0) => json::parse(&String::from_utf8_lossy(&output.stdout)),
+        Some(DIR_NOT_FOUND_EXIT_CODE) => Ok(Value::Array(Vec::new())),
+        _ => Err(BkupError::Io(std::io::Error::other(format!(
+            "Cannot list {} ({})",
+            dest, output.status
+        )))),
+    }
+}
+
+/// Runs rclone with the given arguments.
+fn run(args: &[&str]) -> Result<(), BkupError> {
+    let status = Command::new(RCLONE_PROGRAM)
+        .args(args)
+        .status()
+        .map_err(|e| spawn_error(&e))?;
+    if !status.success() {
+        return Err(BkupError::Io(std::io::Error::other(format!(
+            "rclone {} failed ({})",
+            args[0], status
+        ))));
+    }
+    Ok(())
+}
+
+/// Gets the error of rclone that cannot be spawned.
+fn spawn_error(error: &std::io::Error) -> BkupError {
+    BkupError::Io(std::io::Error::new(
+        error.kind(),
+        format!("Cannot run {}: {}", RCLONE_PROGRAM, error),
+    ))
+}
+
+/// Represents a directory of the listing.
+#[derive(Default)]
+struct Dir {
+    dirs: BTreeMap<String, Dir>,
+    files: BTreeMap<String, (u64, u64)>,
+}
+
+/// Converts the listing of the given destination into the JSON scan of its
+/// root directory.
+fn scan(dest: &str, listing: &Value) -> Result<Value, BkupError> {
+    let invalid = || BkupError::Parse("Invalid rclone listing".to_string());
+    let mut root = Dir::default();
+    for item in listing.as_array().ok_or_else(invalid)? {
+        let field = |name| item.get(name).ok_or_else(invalid);
+        let path = field("Path")?.as_str().ok_or_else(invalid)?;
+        let mut parts: Vec<&str> = path.split('/').collect();
+        let name = parts.pop().ok_or_else(invalid)?;
+        let dir = parts.into_iter().fold(&mut root, |dir, part| {
+            dir.dirs.entry(part.to_string()).or_default()
+        });
+        if field("IsDir")?.as_bool().ok_or_else(invalid)? {
+            dir.dirs.entry(name.to_string()).or_default();
+        } else {
+            let size = field("Size")?.as_u64().ok_or_else(invalid)?;
+            let modified =
+                parse_time(field("ModTime")?.as_str().ok_or_else(invalid)?)?;
+            dir.files.insert(name.to_string(), (size, modified));
+        }
+    }
+    Ok(root.to_json(dest))
+}
+
+impl Dir {
+    /// Serializes the directory with the given path as a scan.
+    fn to_json(&self, path: &str) -> Value {
+        let child = |name: &str| format!("{}/{}", path, name);
+        let dirs = self
+            .dirs
+            .iter()
+            .map(|(name, dir)| dir.to_json(&child(name)));
+        let files = self.files.iter().map(|(name, (size, modified))| {
+            json::object(vec![
+                ("type", Value::from("file")),
+                ("path", Value::from(child(name))),
+                ("size", Value::from(*size)),
+                ("modified", Value::from(*modified)),
+            ])
+        });
+        json::object(vec![
+            ("type", Value::from("dir")),
+            ("path", Value::from(path)),
+            ("entries", Value::Array(dirs.chain(files).collect())),
+        ])
+    }
+}
+
+/// Parses the given RFC 3339 time of rclone (such as
+/// `2021-03-14T15:09:26.535897932+01:00`) into nanoseconds since the epoch.
+fn parse_time(s: &str) -> Result<u64, BkupError> {
+    let invalid = || BkupError::Parse(format!("Invalid time {:?}", s));
+    if s.len() < 19 || !s.is_char_boundary(19) {
+        return Err(invalid());
+    }
+    let (time, rest) = s.split_at(19);
+    let seconds = date::parse_date(time)?
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| invalid())?
+        .as_secs();
+    let (nanos, offset) = match rest.strip_prefix('.') {
+        Some(rest) => {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let digits = &rest[..end.min(9)];
+            let nanos = format!("{:0<9}", digits).parse::<u64>();
+            (nanos.map_err(|_| invalid())?, &rest[end..])
+        }
+        None => (0, rest),
+    };
+    let offset = match offset {
+        "Z" | "z" => 0,
+        offset => {
+            let sign = match offset.get(..1) {
+                Some("+") => 1,
+                Some("-") => -1,
+                _ => return Err(invalid()),
+            };
+            let (hours, minutes) =
+                offset[1..].split_once(':').ok_or_else(invalid)?;
+            let hours = hours.parse::<i64>().map_err(|_| invalid())?;
+            let minutes = minutes.parse::<i64>().map_err(|_| invalid())?;
+            sign * (hours * 3600 + minutes * 60)
+        }
+    };
+    let seconds = (seconds as i64 - offset).max(0) as u64;
+    Ok(seconds * 1_000_000_000 + nanos)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_rclone_scan() {
+        let listing = json::parse(
+            r#"[
+                {"Path":"docs","Name":"docs","Size":-1,"ModTime":"2021-03-14T15:09:26Z","IsDir":true},
+                {"Path":"docs/a.txt","Name":"a.txt","Size":5,"ModTime":"2021-03-14T16:09:26.5+01:00","IsDir":false},
+                {"Path":"b/c.txt","Name":"c.txt","Size":7,"ModTime":"2021-03-14T15:09:26Z","IsDir":false}
+            ]"#,
+        )
+        .expect("Invalid listing");
+        let root = Entry::from_json(
+            &scan("s3:bucket/backup", &listing).expect("Invalid scan"),
+        )
+        .expect("Invalid entry");
+        let mut files = root.all_files();
+        files.sort_by_key(|f| f.path().to_path_buf());
+        let paths: Vec<_> = files.iter().map(|f| f.path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("s3:bucket/backup/b/c.txt"),
+                Path::new("s3:bucket/backup/docs/a.txt")
+            ]
+        );
+        assert_eq!(files[0].size(), 7);
+        assert_eq!(
+            files[1].modified(),
+            files[0].modified() + Duration::from_millis(500)
+        );
+
+        assert_eq!(
+            parse_time("1970-01-01T00:00:01.000000002Z").expect("Invalid time"),
+            1_000_000_002
+        );
+        assert!(parse_time("2021-03-14").is_err());
+        assert!(parse_time("2021-03-14T15:09:26+0100").is_err());
+    }
+}
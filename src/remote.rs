@@ -0,0 +1,560 @@
+//! Remote agent protocol, to update a destination directory on another
+//! machine without accessing its files through a network filesystem.
+//!
+//! The agent (`serve`) runs on the destination machine and scans the
+//! destination locally, while the client (`push`) scans the source, compares
+//! it with the scan of the destination and sends only the actions of the plan
+//! with the content of the copied files. Every message is a JSON line, and the
+//! copy actions are followed by the content of their file, either raw or as
+//! the compressed blocks of the compression named by the action.
+//!
+//! The agent either listens on a TCP address, or is spawned through SSH by the
+//! client and speaks the protocol over its standard input and output.
+
+use crate::{
+    compress::Compression,
+    copy::{self, CopyOptions},
+    entry::{Accuracy, Entry},
+    error::BkupError,
+    filter::Filter,
+    json::{self, Value},
+    lock::Lock,
+    plan::{Action, Plan},
+    rclone,
+};
+use log::*;
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+/// Version of the protocol, that must be the same on both sides.
+const VERSION: u64 = 2;
+/// Program used to spawn the agent on the remote machine.
+const SSH_PROGRAM: &str = "ssh";
+
+/// Listens on the given address and applies the updates of the destination
+/// directory sent by the clients, one at a time. It only returns if the
+/// address cannot be bound.
+pub fn serve(dest: PathBuf, addr: &str, ignore: bool) -> Result<(), BkupError> {
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving {:?} on {}", dest, listener.local_addr()?);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Cannot accept connection: {}", e);
+                continue;
+            }
+        };
+        let peer = stream
+            .peer_addr()
+            .map_or_else(|_| "unknown peer".to_string(), |a| a.to_string());
+        info!("Updating {:?} for {}", dest, peer);
+        match handle(&dest, ignore, stream) {
+            Ok(actions) => info!("Applied {} actions for {}", actions, peer),
+            Err(e) => warn!("Cannot update {:?} for {}: {}", dest, peer, e),
+        }
+    }
+    Ok(())
+}
+
+/// Applies the update of the destination sent by a single client over the
+/// given stream.
+fn handle(
+    dest: &Path,
+    ignore: bool,
+    stream: TcpStream,
+) -> Result<usize, BkupError> {
+    let reader = BufReader::new(stream.try_clone()?);
+    session(dest, ignore, reader, BufWriter::new(stream))
+}
+
+/// Applies the update of the destination sent by a single client over the
+/// standard input and output, as spawned through SSH by `push`.
+pub fn serve_stdio(dest: PathBuf, ignore: bool) -> Result<usize, BkupError> {
+    let reader = BufReader::new(io::stdin());
+    session(&dest, ignore, reader, BufWriter::new(io::stdout()))
+}
+
+/// Applies the update of the destination sent by a client, replying with
+/// the number of actions applied or the error that stopped it.
+fn session<R: BufRead, W: Write>(
+    dest: &Path,
+    ignore: bool,
+    mut reader: R,
+    mut writer: W,
+) -> Result<usize, BkupError> {
+    let result = receive(dest, ignore, &mut reader, &mut writer);
+    let reply = match &result {
+        Ok(actions) => json::object(vec![
+            ("type", Value::from("done")),
+            ("actions", Value::from(*actions)),
+        ]),
+        Err(e) => error_message(e),
+    };
+    send(&mut writer, &reply)?;
+    result
+}
+
+/// Sends the scan of the destination and applies the actions received.
+fn receive<R: BufRead, W: Write>(
+    dest: &Path,
+    ignore: bool,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<usize, BkupError> {
+    let hello = recv(reader)?;
+    if hello.get("version").and_then(Value::as_u64) != Some(VERSION) {
+        return Err(BkupError::Unsupported(format!(
+            "Unsupported protocol version, expected {}",
+            VERSION
+        )));
+    }
+    let _lock = Lock::acquire(dest, false, false)?;
+    let entry = Entry::directory(dest, ignore, &Filter::new())?;
+    send(
+        writer,
+        &json::object(vec![
+            ("type", Value::from("scan")),
+            ("scan", entry.to_json()),
+        ]),
+    )?;
+    let options = CopyOptions::default();
+    let mut actions = 0;
+    loop {
+        let message = recv(reader)?;
+        match message.get("type").and_then(Value::as_str) {
+            Some("done") => return Ok(actions),
+            Some("action") => {
+                let action = message.get("action").ok_or_else(|| {
+                    BkupError::Parse("Missing action".to_string())
+                })?;
+                let action = Action::from_json(action)?;
+                check_paths(dest, &action)?;
+                match &action {
+                    Action::Copy { dest, size, .. } => {
+                        info!("Receiving file {:?}", dest);
+                        let compression = message
+                            .get("compression")
+                            .and_then(Value::as_str)
+                            .map_or(Ok(Compression::None), str::parse)?;
+                        write_file(dest, *size, compression, reader)
+                            .map_err(|e| e.at(dest))?;
+                    }
+                    Action::CreateSpecial { .. } => {
+                        return Err(BkupError::Unsupported(
+                            "Special files cannot be recreated remotely"
+                                .to_string(),
+                        ));
+                    }
+                    action => {
+                        action.apply(&options)?;
+                    }
+                }
+                actions += 1;
+            }
+            t => {
+                return Err(BkupError::Parse(format!(
+                    "Invalid message type {:?}",
+                    t
+                )))
+            }
+        }
+    }
+}
+
+/// Checks that every destination path of the given action is inside the
+/// destination directory.
+fn check_paths(dest: &Path, action: &Action) -> Result<(), BkupError> {
+    let paths: Vec<&Path> = match action {
+        Action::CreateDir { path } => vec![path],
+        Action::Copy { dest, .. } | Action::CreateSpecial { dest, .. } => {
+            vec![dest]
+        }
+        Action::Rename { from, to } => vec![from, to],
+        Action::Link { target, dest, .. } => vec![target, dest],
+    };
+    for path in paths {
+        let inside = path.starts_with(dest)
+            && path.components().all(|c| c != Component::ParentDir);
+        if !inside {
+            return Err(BkupError::Invalid(format!(
+                "The path {:?} is outside of {:?}",
+                path, dest
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the given number of bytes read from the stream (compressed with
+/// the given compression) into a temporary file, that then replaces the
+/// destination file.
+fn write_file<R: Read>(
+    dest: &Path,
+    size: u64,
+    compression: Compression,
+    reader: &mut R,
+) -> Result<(), BkupError> {
+    let temp = copy::temp_path(dest);
+    let result = File::create(&temp)
+        .map_err(BkupError::from)
+        .and_then(|mut file| match compression {
+            Compression::None => {
+                Ok(io::copy(&mut reader.take(size), &mut file)?)
+            }
+            compression => compression.read_blocks(reader, size, &mut file),
+        })
+        .and_then(|written| {
+            if written < size {
+                return Err(BkupError::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "The connection was closed during the transfer",
+                )));
+            }
+            Ok(fs::rename(&temp, dest)?)
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Updates the destination directory served by the remote agent with the
+/// source directory, sending only the actions of the plan and the content of
+/// the copied files. It returns the number of actions applied.
+///
+/// The remote is either the address of an agent (`host:port`), a destination
+/// directory reachable through SSH (`[user@]host:path`), whose agent is
+/// spawned on the remote machine by `ssh` with `bkup serve --stdio`, or an
+/// rclone destination (`rclone:remote:path`), updated by rclone without any
+/// agent.
+///
+/// The content of the copied files is sent with the given compression, that
+/// doesn't apply to the rclone destinations.
+pub fn push(
+    source: PathBuf,
+    remote: &str,
+    accuracy: Duration,
+    ignore: bool,
+    compression: Compression,
+) -> Result<usize, BkupError> {
+    if let Some(dest) = remote.strip_prefix(rclone::PREFIX) {
+        if compression != Compression::None {
+            warn!("The compression {} is not supported by rclone", compression);
+        }
+        return rclone::push(source, dest, accuracy, ignore);
+    }
+    let options = ExchangeOptions {
+        accuracy,
+        ignore,
+        compression,
+    };
+    let (host, path) = match ssh_target(remote) {
+        Some(target) => target,
+        None => {
+            let stream = TcpStream::connect(remote)?;
+            let reader = BufReader::new(stream.try_clone()?);
+            let writer = BufWriter::new(stream);
+            return exchange(source, remote, &options, reader, writer);
+        }
+    };
+    let mut command = Command::new(SSH_PROGRAM);
+    command
+        .arg(host)
+        .args(["bkup", "serve", "--stdio", "--destination"])
+        .arg(quote(path));
+    if ignore {
+        command.arg("--ignore");
+    }
+    debug!("Spawning {:?}", command);
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            BkupError::Io(io::Error::new(
+                e.kind(),
+                format!("Cannot spawn {}: {}", SSH_PROGRAM, e),
+            ))
+        })?;
+    let reader = BufReader::new(child.stdout.take().expect("Piped stdout"));
+    let writer = BufWriter::new(child.stdin.take().expect("Piped stdin"));
+    let result = exchange(source, remote, &options, reader, writer);
+    let status = child.wait()?;
+    match result {
+        // the agent may not have started at all
+        Err(e) if !status.success() => Err(BkupError::Io(io::Error::other(
+            format!("The agent on {} exited with {} ({})", host, status, e),
+        ))),
+        result => result,
+    }
+}
+
+/// Gets the host and the path of the given SSH destination
+/// (`[user@]host:path`), that is none if it's the address of an agent
+/// (`host:port`).
+fn ssh_target(remote: &str) -> Option<(&str, &str)> {
+    if remote.starts_with('[') {
+        // IPv6 address
+        return None;
+    }
+    let (host, path) = remote.split_once(':')?;
+    if host.is_empty() || path.is_empty() || path.parse::<u16>().is_ok() {
+        return None;
+    }
+    Some((host, path))
+}
+
+/// Quotes the given path for the remote shell, that parses the command run
+/// by SSH.
+fn quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', "'\\''"))
+}
+
+/// Options of the update pushed to an agent.
+struct ExchangeOptions {
+    accuracy: Duration,
+    ignore: bool,
+    compression: Compression,
+}
+
+/// Sends the plan of the update of the destination scanned by the agent,
+/// with the content of the copied files, and gets the number of actions
+/// applied.
+fn exchange<R: BufRead, W: Write>(
+    source: PathBuf,
+    remote: &str,
+    options: &ExchangeOptions,
+    mut reader: R,
+    mut writer: W,
+) -> Result<usize, BkupError> {
+    send(
+        &mut writer,
+        &json::object(vec![
+            ("type", Value::from("hello")),
+            ("version", Value::from(VERSION)),
+        ]),
+    )?;
+    let message = recv(&mut reader)?;
+    let scan = match message.get("type").and_then(Value::as_str) {
+        Some("scan") => message.get("scan").ok_or_else(|| {
+            BkupError::Parse("Missing destination scan".to_string())
+        })?,
+        _ => return Err(remote_error(&message)),
+    };
+    let dest = Entry::from_json(scan)?;
+    info!(
+        "Comparing {:?} with {:?} on {}",
+        source,
+        dest.path(),
+        remote
+    );
+    let source = Entry::directory(&source, options.ignore, &Filter::new())?;
+    let mut plan = Plan::new();
+    if let Some(delta) = source.cmp(&dest, &Accuracy::new(options.accuracy))? {
+        delta.plan(&mut plan);
+    }
+    info!("Sending {} actions ({} bytes)", plan.len(), plan.bytes());
+
+    // the server closes the connection at the first error, that is then read
+    // from its reply
+    let sent = send_plan(&plan, options.compression, &mut writer);
+    let reply = recv(&mut reader);
+    match reply {
+        Ok(reply)
+            if reply.get("type").and_then(Value::as_str) == Some("done") =>
+        {
+            sent?;
+            Ok(reply.get("actions").and_then(Value::as_u64).unwrap_or(0)
+                as usize)
+        }
+        Ok(reply) => Err(remote_error(&reply)),
+        Err(e) => Err(sent.err().unwrap_or(e)),
+    }
+}
+
+/// Sends the actions of the plan, followed by the content of the copied
+/// files compressed with the given compression (unless already compressed).
+fn send_plan<W: Write>(
+    plan: &Plan,
+    compression: Compression,
+    writer: &mut W,
+) -> Result<(), BkupError> {
+    for action in plan.actions() {
+        let action = match action {
+            Action::Copy {
+                source,
+                dest,
+                overwrite,
+                ..
+            } => {
+                info!("Sending file {:?} to {:?}", source, dest);
+                // the file may have changed since it was scanned
+                let mut file = File::open(source)?;
+                let size = file.metadata()?.len();
+                let action = Action::Copy {
+                    source: source.clone(),
+                    dest: dest.clone(),
+                    size,
+                    overwrite: *overwrite,
+                };
+                let compression = compression
+                    .for_file(source, &mut file)
+                    .map_err(|e| e.at(source))?;
+                let message = match compression {
+                    Compression::None => action_message(&action),
+                    compression => json::object(vec![
+                        ("type", Value::from("action")),
+                        ("action", action.to_json()),
+                        ("compression", Value::from(compression.name())),
+                    ]),
+                };
+                send(writer, &message)?;
+                let sent = match compression {
+                    Compression::None => {
+                        io::copy(&mut (&mut file).take(size), writer)?
+                    }
+                    compression => {
+                        compression.write_blocks(&mut file, size, writer)?
+                    }
+                };
+                if sent < size {
+                    return Err(BkupError::Copy {
+                        path: source.clone(),
+                        source: io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            "The file was truncated during the transfer",
+                        ),
+                    });
+                }
+                continue;
+            }
+            Action::CreateSpecial { source, .. } => {
+                warn!("Skipping special file {:?}", source);
+                continue;
+            }
+            action => action,
+        };
+        send(writer, &action_message(action))?;
+    }
+    send(writer, &json::object(vec![("type", Value::from("done"))]))
+}
+
+/// Gets the message of the given action.
+fn action_message(action: &Action) -> Value {
+    json::object(vec![
+        ("type", Value::from("action")),
+        ("action", action.to_json()),
+    ])
+}
+
+/// Gets the message of the given error.
+fn error_message(error: &BkupError) -> Value {
+    json::object(vec![
+        ("type", Value::from("error")),
+        ("error", Value::from(error.to_string())),
+    ])
+}
+
+/// Gets the error reported by the other side in the given message.
+fn remote_error(message: &Value) -> BkupError {
+    let error = message
+        .get("error")
+        .and_then(Value::as_str)
+        .unwrap_or("Invalid reply");
+    BkupError::Io(io::Error::other(format!("Remote error: {}", error)))
+}
+
+/// Sends the given message as a JSON line.
+fn send<W: Write>(writer: &mut W, message: &Value) -> Result<(), BkupError> {
+    writeln!(writer, "{}", message)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Receives the next JSON line.
+fn recv<R: BufRead>(reader: &mut R) -> Result<Value, BkupError> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(BkupError::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "The connection was closed",
+        )));
+    }
+    json::parse(line.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::{env, thread};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_push() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let (source, dest) = (root.join("source"), root.join("dest"));
+        fs::create_dir_all(source.join("dir")).expect("Cannot create source");
+        fs::create_dir_all(&dest).expect("Cannot create dest");
+        fs::write(source.join("a"), "hello").expect("Cannot write file");
+        fs::write(source.join("dir/b"), vec![7u8; 100_000])
+            .expect("Cannot write file");
+
+        let listener =
+            TcpListener::bind("127.0.0.1:0").expect("Cannot bind address");
+        let addr = listener.local_addr().expect("Cannot get address");
+        let server = {
+            let dest = dest.clone();
+            thread::spawn(move || {
+                let (stream, _) = listener.accept().expect("Cannot accept");
+                handle(&dest, false, stream)
+            })
+        };
+        let actions = push(
+            source.clone(),
+            &addr.to_string(),
+            Duration::from_secs(2),
+            false,
+            Compression::Zstd(3),
+        )
+        .expect("Cannot push");
+        assert_eq!(actions, 3);
+        assert_eq!(server.join().expect("Server panicked").ok(), Some(3));
+        assert_eq!(fs::read(dest.join("a")).expect("Cannot read"), b"hello");
+        assert_eq!(
+            fs::read(dest.join("dir/b")).expect("Cannot read").len(),
+            100_000
+        );
+
+        let dest = PathBuf::from("/backup");
+        let action = Action::CreateDir {
+            path: dest.join("../etc"),
+        };
+        assert!(check_paths(&dest, &action).is_err());
+        let action = Action::CreateDir {
+            path: dest.join("etc"),
+        };
+        assert!(check_paths(&dest, &action).is_ok());
+        fs::remove_dir_all(root).expect("Cannot remove test directory");
+    }
+
+    #[test]
+    fn test_ssh_target() {
+        assert_eq!(
+            ssh_target("me@backup.lan:/mnt/backup"),
+            Some(("me@backup.lan", "/mnt/backup"))
+        );
+        assert_eq!(ssh_target("nas:backups"), Some(("nas", "backups")));
+        assert_eq!(ssh_target("backup.lan:7373"), None);
+        assert_eq!(ssh_target("[::1]:7373"), None);
+        assert_eq!(ssh_target("backup.lan"), None);
+        assert_eq!(quote("/mnt/it's mine"), "'/mnt/it'\\''s mine'");
+    }
+}
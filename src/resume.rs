@@ -0,0 +1,321 @@
+//! Copies of large files that can be resumed after an interruption.
+//!
+//! While the content is copied into the temporary file, a sidecar checkpoint
+//! records the number of bytes written (and flushed) so far, the digest of
+//! those bytes and the size and modification time of the source. A following
+//! copy of the same source continues from the recorded offset, if the source
+//! didn't change and the temporary file still starts with the same bytes.
+
+use crate::{
+    cancel::CancellationToken,
+    copy,
+    error::BkupError,
+    event::CopyProgress,
+    hash::Sha256,
+    metadata::{CHECKPOINT_SUFFIX, TEMP_SUFFIX},
+    pause,
+    rate::RateLimiter,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// Minimum size of the files copied with checkpoints.
+pub const MIN_SIZE: u64 = 64 * 1024 * 1024;
+/// Number of bytes copied between two checkpoints.
+const CHECKPOINT_INTERVAL: u64 = 64 * 1024 * 1024;
+/// Size of the buffer used to copy the files.
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Gets the path of the checkpoint of the copy into the given path.
+pub fn checkpoint_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(CHECKPOINT_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Returns true if the given path is a checkpoint file.
+pub fn is_checkpoint(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(CHECKPOINT_SUFFIX))
+}
+
+/// Returns true if the given temporary or checkpoint file belongs to a partial
+/// copy that can be resumed, that is if both files exist.
+pub fn is_partial(path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+    let dest = match name
+        .strip_suffix(TEMP_SUFFIX)
+        .or_else(|| name.strip_suffix(CHECKPOINT_SUFFIX))
+    {
+        Some(base) => path.with_file_name(base),
+        None => return false,
+    };
+    copy::temp_path(&dest).exists() && checkpoint_path(&dest).exists()
+}
+
+/// Copies the source file into the temporary file used to replace the
+/// destination path, resuming a previous interrupted copy if possible, and
+/// reporting the copied bytes to the progress, until the copy is cancelled.
+pub fn copy(
+    source: &Path,
+    dest: &Path,
+    temp: &Path,
+    limiter: Option<&RateLimiter>,
+    cancel: &CancellationToken,
+    progress: &mut CopyProgress,
+) -> Result<(), BkupError> {
+    copy_with_interval(
+        source,
+        dest,
+        temp,
+        limiter,
+        cancel,
+        progress,
+        CHECKPOINT_INTERVAL,
+    )
+}
+
+/// Copies the source file into the temporary file writing a checkpoint every
+/// given number of bytes.
+fn copy_with_interval(
+    source: &Path,
+    dest: &Path,
+    temp: &Path,
+    limiter: Option<&RateLimiter>,
+    cancel: &CancellationToken,
+    progress: &mut CopyProgress,
+    interval: u64,
+) -> Result<(), BkupError> {
+    let checkpoint = checkpoint_path(dest);
+    let mut src = File::open(source)?;
+    let metadata = src.metadata()?;
+    let source_id = SourceId {
+        size: metadata.len(),
+        modified: metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?
+            .as_nanos() as u64,
+    };
+
+    let (mut offset, mut hasher) =
+        match resume_point(&checkpoint, temp, &source_id) {
+            Ok(Some((offset, hasher))) => {
+                info!("Resuming copy of {:?} from byte {}", source, offset);
+                (offset, hasher)
+            }
+            Ok(None) => (0, Sha256::new()),
+            Err(e) => {
+                debug!("Cannot resume copy of {:?}: {}", source, e);
+                (0, Sha256::new())
+            }
+        };
+    let mut dst = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(temp)?;
+    dst.set_len(offset)?;
+    dst.seek(SeekFrom::Start(offset))?;
+    src.seek(SeekFrom::Start(offset))?;
+    progress.add(offset);
+
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut last_checkpoint = offset;
+    loop {
+        pause::wait(cancel);
+        cancel.check()?;
+        let n = match src.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(limiter) = limiter {
+            limiter.acquire(n as u64);
+        }
+        dst.write_all(&buffer[..n])?;
+        hasher.update(&buffer[..n]);
+        offset += n as u64;
+        progress.add(n as u64);
+        if offset - last_checkpoint >= interval {
+            // the bytes must be stored before being recorded as written
+            dst.sync_data()?;
+            write_checkpoint(&checkpoint, &source_id, offset, &hasher)?;
+            last_checkpoint = offset;
+        }
+    }
+    dst.set_permissions(metadata.permissions())?;
+    if checkpoint.exists() {
+        fs::remove_file(&checkpoint)?;
+    }
+    Ok(())
+}
+
+/// Identifies the version of the source file a checkpoint refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct SourceId {
+    size: u64,
+    modified: u64, // nanoseconds since the UNIX epoch
+}
+
+/// Content of a checkpoint file.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    #[serde(flatten)]
+    source: SourceId,
+    // number of bytes written in the temporary file
+    offset: u64,
+    // digest of the written bytes
+    digest: String,
+}
+
+/// Writes the checkpoint of a copy that has written the given number of bytes.
+fn write_checkpoint(
+    path: &Path,
+    source: &SourceId,
+    offset: u64,
+    hasher: &Sha256,
+) -> Result<(), BkupError> {
+    let checkpoint = Checkpoint {
+        source: *source,
+        offset,
+        digest: hasher.clone().finalize().to_string(),
+    };
+    fs::write(path, serde_json::to_string(&checkpoint)?)?;
+    Ok(())
+}
+
+/// Gets the offset (and the hasher of the bytes before it) from which the
+/// copy can be resumed, if the checkpoint exists and is still valid.
+fn resume_point(
+    checkpoint: &Path,
+    temp: &Path,
+    source: &SourceId,
+) -> Result<Option<(u64, Sha256)>, BkupError> {
+    if !checkpoint.exists() {
+        return Ok(None);
+    }
+    let Checkpoint {
+        source: recorded,
+        offset,
+        digest,
+    } = serde_json::from_str(&fs::read_to_string(checkpoint)?)?;
+    if recorded != *source {
+        debug!("The source changed since {:?}", checkpoint);
+        return Ok(None);
+    }
+
+    // the written bytes must still be the ones recorded
+    let mut file = File::open(temp)?.take(offset);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut read = 0;
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        read += n as u64;
+    }
+    if read == offset && hasher.clone().finalize().to_string() == digest {
+        Ok(Some((offset, hasher)))
+    } else {
+        debug!("The temporary file {:?} doesn't match its checkpoint", temp);
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_resume_copy() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        let source = root.join("source");
+        let dest = root.join("dest");
+        let temp = root.join("dest.tmp");
+        let content: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let mut progress = CopyProgress::new(None, &dest, content.len() as u64);
+        let cancel = CancellationToken::new();
+        fs::write(&source, &content).expect("Cannot write file");
+
+        // a complete copy doesn't leave any checkpoint
+        copy_with_interval(
+            &source,
+            &dest,
+            &temp,
+            None,
+            &cancel,
+            &mut progress,
+            1000,
+        )
+        .expect("Cannot copy");
+        assert_eq!(fs::read(&temp).unwrap(), content);
+        assert!(!checkpoint_path(&dest).exists());
+
+        // simulate an interrupted copy whose first bytes differ from the
+        // source, the copy must continue after them
+        let metadata = fs::metadata(&source).unwrap();
+        let source_id = SourceId {
+            size: metadata.len(),
+            modified: metadata
+                .modified()
+                .unwrap()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64,
+        };
+        let prefix = vec![7u8; 2000];
+        let mut hasher = Sha256::new();
+        hasher.update(&prefix);
+        fs::write(&temp, [&prefix[..], b"garbage"].concat()).unwrap();
+        write_checkpoint(&checkpoint_path(&dest), &source_id, 2000, &hasher)
+            .expect("Cannot write checkpoint");
+        copy_with_interval(
+            &source,
+            &dest,
+            &temp,
+            None,
+            &cancel,
+            &mut progress,
+            1000,
+        )
+        .expect("Cannot copy");
+        assert_eq!(
+            fs::read(&temp).unwrap(),
+            [&prefix[..], &content[2000..]].concat()
+        );
+
+        // a temporary file that doesn't match its checkpoint is copied again
+        fs::write(&temp, vec![8u8; 3000]).unwrap();
+        write_checkpoint(&checkpoint_path(&dest), &source_id, 2000, &hasher)
+            .expect("Cannot write checkpoint");
+        copy_with_interval(
+            &source,
+            &dest,
+            &temp,
+            None,
+            &cancel,
+            &mut progress,
+            1000,
+        )
+        .expect("Cannot copy");
+        assert_eq!(fs::read(&temp).unwrap(), content);
+    }
+}
@@ -0,0 +1,269 @@
+//! Ordered include and exclude rules, read from a file with the syntax of the
+//! rsync filter rules.
+//!
+//! Each line is a rule made of a `+` (include) or `-` (exclude) prefix, a
+//! space and a pattern, while empty lines and lines starting with `#` or `;`
+//! are ignored. The rules are matched in order against the path of every
+//! entry relative to the root directory, and the first matching rule decides
+//! whether the entry is included (the entries matching no rule are included).
+//!
+//! The patterns support `*` (any name part), `**` (any path part), `?` and
+//! character classes. A pattern starting with `/` is anchored to the root
+//! directory, otherwise it matches the end of the path, and a pattern ending
+//! with `/` only matches directories.
+//!
+//! The same patterns also set the priority of the copied files, so that the
+//! files matching a high priority pattern are copied before the others, and
+//! the ones matching a low priority pattern after them.
+
+use crate::error::BkupError;
+use regex::Regex;
+use std::{fs, path::Path};
+
+/// Represents a single include or exclude rule.
+#[derive(Debug, Clone)]
+struct Rule {
+    include: bool,
+    dir_only: bool,
+    regex: Regex,
+}
+
+impl Rule {
+    /// Parses the given line, that is none if it's empty or a comment.
+    fn parse(line: &str) -> Result<Option<Rule>, BkupError> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            return Ok(None);
+        }
+        let invalid = || BkupError::Invalid(format!("Invalid rule {:?}", line));
+        let (include, pattern) =
+            match line.split_at(line.find(' ').ok_or_else(invalid)?) {
+                ("+" | "include", pattern) => (true, &pattern[1..]),
+                ("-" | "exclude", pattern) => (false, &pattern[1..]),
+                _ => return Err(invalid()),
+            };
+        let (pattern, dir_only) = match pattern.strip_suffix('/') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        Ok(Some(Rule {
+            include,
+            dir_only,
+            regex: compile(pattern).ok_or_else(invalid)?,
+        }))
+    }
+
+    /// Returns true if the rule matches the given relative path.
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && self.regex.is_match(path)
+    }
+}
+
+/// Compiles the given pattern, anchored to the root directory if it starts
+/// with `/`, that is none if it's invalid.
+fn compile(pattern: &str) -> Option<Regex> {
+    if pattern.is_empty() {
+        return None;
+    }
+    let regex = match pattern.strip_prefix('/') {
+        Some(pattern) => format!("^{}$", translate(pattern)),
+        None => format!("(^|/){}$", translate(pattern)),
+    };
+    Regex::new(&regex).ok()
+}
+
+/// Translates the given pattern into a regular expression.
+fn translate(pattern: &str) -> String {
+    let mut regex = String::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                // character classes are kept as they are
+                regex.push('[');
+                for c in chars.by_ref() {
+                    match c {
+                        '\\' => regex.push_str("\\\\"),
+                        ']' => {
+                            regex.push(']');
+                            break;
+                        }
+                        c => regex.push(c),
+                    }
+                }
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+/// Gets the given relative path with `/` as separator on every platform, as
+/// the patterns are written.
+pub fn slash_path(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Ordered include and exclude rules, where the first matching rule wins.
+#[derive(Debug, Clone, Default)]
+pub struct FilterRules {
+    rules: Vec<Rule>,
+}
+
+impl FilterRules {
+    /// Parses the rules in the given text, one per line.
+    pub fn parse(text: &str) -> Result<Self, BkupError> {
+        let mut rules = Vec::new();
+        for line in text.lines() {
+            rules.extend(Rule::parse(line)?);
+        }
+        Ok(FilterRules { rules })
+    }
+
+    /// Reads the rules from the given file.
+    pub fn load(path: &Path) -> Result<Self, BkupError> {
+        FilterRules::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Returns true if there are no rules.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns true if the entry with the given path, relative to the root
+    /// directory, must be included.
+    pub fn includes(&self, relative: &Path, is_dir: bool) -> bool {
+        let path = slash_path(relative);
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(&path, is_dir))
+            .is_none_or(|rule| rule.include)
+    }
+}
+
+/// Enumerates the priorities of the copied files, from the first copied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+/// Patterns of the files copied before and after the others.
+#[derive(Debug, Clone, Default)]
+pub struct PriorityRules {
+    high: Vec<Regex>,
+    low: Vec<Regex>,
+}
+
+impl PriorityRules {
+    /// Compiles the given patterns of the high and low priority files.
+    pub fn new(high: &[String], low: &[String]) -> Result<Self, BkupError> {
+        let compile_all = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| {
+                    compile(pattern).ok_or_else(|| {
+                        BkupError::Invalid(format!(
+                            "Invalid pattern {:?}",
+                            pattern
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(PriorityRules {
+            high: compile_all(high)?,
+            low: compile_all(low)?,
+        })
+    }
+
+    /// Returns true if there are no patterns.
+    pub fn is_empty(&self) -> bool {
+        self.high.is_empty() && self.low.is_empty()
+    }
+
+    /// Gets the priority of the file with the given path, relative to the
+    /// root directory, where the high priority patterns win.
+    pub fn priority(&self, relative: &Path) -> Priority {
+        let path = slash_path(relative);
+        let matches =
+            |regexes: &[Regex]| regexes.iter().any(|r| r.is_match(&path));
+        if matches(&self.high) {
+            Priority::High
+        } else if matches(&self.low) {
+            Priority::Low
+        } else {
+            Priority::Normal
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_filter_rules() {
+        let rules = FilterRules::parse(
+            "# keep the notes\n\
+             + notes/*.log\n\
+             - *.log\n\
+             - /build/\n\
+             ; caches anywhere\n\
+             - cache/\n\
+             - **/tmp/*.[ch]\n",
+        )
+        .expect("Invalid rules");
+        let includes =
+            |path: &str, is_dir| rules.includes(Path::new(path), is_dir);
+        // first match wins
+        assert!(includes("notes/today.log", false));
+        assert!(!includes("src/today.log", false));
+        assert!(!includes("today.log", false));
+        // anchored and directory only
+        assert!(!includes("build", true));
+        assert!(includes("src/build", true));
+        assert!(includes("build", false));
+        assert!(!includes("a/b/cache", true));
+        assert!(includes("a/b/cache", false));
+        assert!(!includes("a/tmp/x.c", false));
+        assert!(includes("a/tmp/x.rs", false));
+        assert!(includes("src/main.rs", false));
+
+        assert!(FilterRules::parse("* pattern").is_err());
+        assert!(FilterRules::parse("-").is_err());
+    }
+
+    #[test]
+    fn test_priority_rules() {
+        let patterns = |patterns: &[&str]| -> Vec<String> {
+            patterns.iter().map(|p| p.to_string()).collect()
+        };
+        let rules = PriorityRules::new(
+            &patterns(&["Documents/**", "/notes.txt"]),
+            &patterns(&["Videos/**", "*.iso"]),
+        )
+        .expect("Invalid patterns");
+        let priority = |path: &str| rules.priority(Path::new(path));
+        assert_eq!(priority("Documents/taxes/2021.pdf"), Priority::High);
+        assert_eq!(priority("notes.txt"), Priority::High);
+        assert_eq!(priority("old/notes.txt"), Priority::Normal);
+        assert_eq!(priority("Videos/holidays.mp4"), Priority::Low);
+        assert_eq!(priority("Documents/ubuntu.iso"), Priority::High);
+        assert_eq!(priority("Downloads/ubuntu.iso"), Priority::Low);
+        assert!(PriorityRules::new(&patterns(&[""]), &[]).is_err());
+        assert!(PriorityRules::default().is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+//! Comparison of two scans of the same directory taken at different times,
+//! that tells which files were added, removed or modified in between without
+//! accessing the directory.
+
+use crate::{
+    entry::Entry,
+    json::{self, Value},
+};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Enumerates the changes of a file between two scans.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ScanStatus {
+    /// The file only exists in the newer scan.
+    Added,
+    /// The file only exists in the older scan.
+    Removed,
+    /// The size or the modification time of the file changed.
+    Modified,
+}
+
+impl ScanStatus {
+    /// Gets the name of the status.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScanStatus::Added => "added",
+            ScanStatus::Removed => "removed",
+            ScanStatus::Modified => "modified",
+        }
+    }
+}
+
+/// Represents the change of a file between two scans.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ScanChange {
+    /// Path of the file, relative to the scanned directory.
+    pub path: PathBuf,
+    /// How the file changed.
+    pub status: ScanStatus,
+}
+
+impl ScanChange {
+    /// Serializes self into a JSON object.
+    pub fn to_json(&self) -> Value {
+        json::object(vec![
+            (
+                "path",
+                Value::from(self.path.to_string_lossy().into_owned()),
+            ),
+            ("status", Value::from(self.status.name())),
+        ])
+    }
+}
+
+/// Gets the changes of the files from the older to the newer scan, sorted by
+/// path. The scans are matched by the paths relative to their root, so that
+/// they can be taken from different copies of the directory.
+pub fn changes(older: &Entry, newer: &Entry) -> Vec<ScanChange> {
+    let older = files(older);
+    let newer = files(newer);
+    let paths: BTreeSet<_> = older.keys().chain(newer.keys()).collect();
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let status = match (older.get(path), newer.get(path)) {
+                (None, Some(_)) => ScanStatus::Added,
+                (Some(_), None) => ScanStatus::Removed,
+                (Some(a), Some(b)) if a != b => ScanStatus::Modified,
+                _ => return None,
+            };
+            Some(ScanChange {
+                path: path.clone(),
+                status,
+            })
+        })
+        .collect()
+}
+
+/// Gets the size and modification time of all the files of the given scan,
+/// by path relative to its root.
+fn files(root: &Entry) -> BTreeMap<PathBuf, (u64, Duration)> {
+    root.all_files()
+        .into_iter()
+        .filter_map(|file| {
+            let path = file.path().strip_prefix(root.path()).ok()?;
+            Some((path.to_path_buf(), (file.size(), file.modified())))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_scan_changes() {
+        let older = r#"{"type": "dir", "path": "/mnt/a", "entries": [
+            {"type": "file", "path": "/mnt/a/same", "size": 1, "modified": 0},
+            {"type": "file", "path": "/mnt/a/gone", "size": 1, "modified": 0},
+            {"type": "file", "path": "/mnt/a/edit", "size": 1, "modified": 0}
+        ]}"#;
+        let newer = r#"{"type": "dir", "path": "/mnt/b", "entries": [
+            {"type": "file", "path": "/mnt/b/same", "size": 1, "modified": 0},
+            {"type": "file", "path": "/mnt/b/edit", "size": 1, "modified": 9},
+            {"type": "dir", "path": "/mnt/b/dir", "entries": [
+                {"type": "file", "path": "/mnt/b/dir/new", "size": 2,
+                 "modified": 0}
+            ]}
+        ]}"#;
+        let scan = |s| Entry::from_json(&json::parse(s).unwrap()).unwrap();
+        let changes: Vec<_> = changes(&scan(older), &scan(newer))
+            .into_iter()
+            .map(|change| (change.path, change.status))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (PathBuf::from("dir/new"), ScanStatus::Added),
+                (PathBuf::from("edit"), ScanStatus::Modified),
+                (PathBuf::from("gone"), ScanStatus::Removed),
+            ]
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use crate::error::BkupError;
+
+/// Units accepted by `parse_size`, with their multiplier.
+const UNITS: [(&str, u64); 13] = [
+    ("B", 1),
+    ("K", 1 << 10),
+    ("KB", 1_000),
+    ("KIB", 1 << 10),
+    ("M", 1 << 20),
+    ("MB", 1_000_000),
+    ("MIB", 1 << 20),
+    ("G", 1 << 30),
+    ("GB", 1_000_000_000),
+    ("GIB", 1 << 30),
+    ("T", 1 << 40),
+    ("TB", 1_000_000_000_000),
+    ("TIB", 1 << 40),
+];
+
+/// Parses a number of bytes with an optional unit suffix, such as `512`,
+/// `64KiB` or `10MB` (single letter suffixes are binary units).
+pub fn parse_size(s: &str) -> Result<u64, BkupError> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number = number
+        .parse::<f64>()
+        .map_err(|_| BkupError::Invalid(format!("Invalid size {:?}", s)))?;
+    let unit = unit.trim().to_ascii_uppercase();
+    let multiplier = if unit.is_empty() {
+        1
+    } else {
+        UNITS
+            .iter()
+            .find(|(u, _)| *u == unit)
+            .map(|(_, m)| *m)
+            .ok_or_else(|| {
+                BkupError::Invalid(format!("Invalid size unit in {:?}", s))
+            })?
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Formats a number of bytes with the largest binary unit that keeps the
+/// value above 1, such as `1.5 MiB`.
+pub fn format_size(bytes: u64) -> String {
+    const SUFFIXES: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64;
+    let mut suffix = "B";
+    for s in &SUFFIXES {
+        if value < 1024.0 {
+            break;
+        }
+        value /= 1024.0;
+        suffix = s;
+    }
+    format!("{:.1} {}", value, suffix)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64KiB").unwrap(), 64 * 1024);
+        assert_eq!(parse_size("10MiB").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_size("10 mb").unwrap(), 10 * 1000 * 1000);
+        assert_eq!(parse_size("1.5G").unwrap(), 3 << 29);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("10 parsecs").is_err());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(42), "42 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(10 * 1024 * 1024), "10.0 MiB");
+    }
+}
@@ -0,0 +1,157 @@
+//! Special files (FIFOs, sockets and device nodes), that have no content to
+//! copy and are handled according to the chosen policy.
+
+use crate::error::BkupError;
+#[cfg(feature = "update")]
+use std::path::Path;
+use std::{fs::Metadata, str::FromStr};
+
+/// Enumerates the types of special files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialKind {
+    /// Named pipe.
+    Fifo,
+    /// Unix domain socket.
+    Socket,
+    /// Block device node.
+    BlockDevice,
+    /// Character device node.
+    CharDevice,
+}
+
+impl SpecialKind {
+    /// Gets the type of the special file with the given metadata, if it's a
+    /// special file.
+    #[cfg(unix)]
+    pub fn of(metadata: &Metadata) -> Option<Self> {
+        use std::os::unix::fs::FileTypeExt;
+        let file_type = metadata.file_type();
+        if file_type.is_fifo() {
+            Some(SpecialKind::Fifo)
+        } else if file_type.is_socket() {
+            Some(SpecialKind::Socket)
+        } else if file_type.is_block_device() {
+            Some(SpecialKind::BlockDevice)
+        } else if file_type.is_char_device() {
+            Some(SpecialKind::CharDevice)
+        } else {
+            None
+        }
+    }
+
+    /// There are no special files on this platform.
+    #[cfg(not(unix))]
+    pub fn of(_metadata: &Metadata) -> Option<Self> {
+        None
+    }
+
+    /// Gets the name of the type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SpecialKind::Fifo => "fifo",
+            SpecialKind::Socket => "socket",
+            SpecialKind::BlockDevice => "block_device",
+            SpecialKind::CharDevice => "char_device",
+        }
+    }
+
+    /// Returns true if a special file of this type can be recreated by the
+    /// current user: FIFOs always, device nodes only by root, and sockets
+    /// never (they only exist while their server is listening).
+    #[cfg(unix)]
+    pub fn can_recreate(&self) -> bool {
+        match self {
+            SpecialKind::Fifo => true,
+            SpecialKind::Socket => false,
+            SpecialKind::BlockDevice | SpecialKind::CharDevice => {
+                // SAFETY: geteuid is always successful
+                unsafe { libc::geteuid() == 0 }
+            }
+        }
+    }
+
+    /// Special files cannot be created on this platform.
+    #[cfg(not(unix))]
+    pub fn can_recreate(&self) -> bool {
+        false
+    }
+}
+
+impl FromStr for SpecialKind {
+    type Err = BkupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fifo" => Ok(SpecialKind::Fifo),
+            "socket" => Ok(SpecialKind::Socket),
+            "block_device" => Ok(SpecialKind::BlockDevice),
+            "char_device" => Ok(SpecialKind::CharDevice),
+            _ => Err(BkupError::Parse(format!(
+                "Invalid special file type {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Enumerates how the special files found in the source directory are
+/// handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpecialFiles {
+    /// The special files are skipped with a warning.
+    #[default]
+    Skip,
+    /// The visit fails at the first special file.
+    Error,
+    /// The special files are recreated in the destination, when the current
+    /// user can (the others are skipped with a warning).
+    Recreate,
+}
+
+impl FromStr for SpecialFiles {
+    type Err = BkupError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(SpecialFiles::Skip),
+            "error" => Ok(SpecialFiles::Error),
+            "recreate" => Ok(SpecialFiles::Recreate),
+            _ => Err(BkupError::Invalid(format!(
+                "Invalid special files policy {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// Creates the given destination path as a special file of the same type,
+/// permissions and device number as the source one.
+#[cfg(all(unix, feature = "update"))]
+pub fn create(source: &Path, dest: &Path) -> Result<(), BkupError> {
+    use std::{
+        ffi::CString, io, os::unix::ffi::OsStrExt, os::unix::fs::MetadataExt,
+    };
+    let metadata = std::fs::symlink_metadata(source)?;
+    let path = CString::new(dest.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: the path is a valid NUL-terminated string
+    let result = unsafe {
+        libc::mknod(
+            path.as_ptr(),
+            metadata.mode() as libc::mode_t,
+            metadata.rdev() as libc::dev_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Special files cannot be created on this platform.
+#[cfg(all(not(unix), feature = "update"))]
+pub fn create(_source: &Path, _dest: &Path) -> Result<(), BkupError> {
+    Err(BkupError::Unsupported(
+        "Special files are only supported on Unix".to_string(),
+    ))
+}
@@ -0,0 +1,316 @@
+//! Baseline state of a source and destination directory after their last
+//! successful update.
+//!
+//! The state records the size and modification times of every file as they
+//! were once the destination was up to date, so that the following runs can
+//! tell whether a file changed in the source, in the destination or in both,
+//! and whether it was deleted from either of them.
+
+use crate::{
+    entry::{Entry, FileEntry},
+    error::BkupError,
+    json::{self, Value},
+    metadata::{self, STATE_NAME},
+};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Gets the path of the state of the given destination directory.
+pub fn state_path(dest: &Path) -> PathBuf {
+    metadata::path(dest, STATE_NAME)
+}
+
+/// Enumerates the changes of a file since the last successful update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SyncStatus {
+    /// The file was created in the source.
+    NewOnSource,
+    /// The file was created in the destination.
+    NewOnDest,
+    /// The file changed in the source only.
+    ChangedOnSource,
+    /// The file changed in the destination only.
+    ChangedOnDest,
+    /// The file was deleted from the source, and didn't change in the
+    /// destination.
+    DeletedOnSource,
+    /// The file was deleted from the destination, and didn't change in the
+    /// source.
+    DeletedOnDest,
+    /// The file changed (or was created) on both sides, or changed on one side
+    /// and was deleted from the other.
+    Conflict,
+}
+
+impl SyncStatus {
+    /// Gets the name of the status.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyncStatus::NewOnSource => "new_on_source",
+            SyncStatus::NewOnDest => "new_on_dest",
+            SyncStatus::ChangedOnSource => "changed_on_source",
+            SyncStatus::ChangedOnDest => "changed_on_dest",
+            SyncStatus::DeletedOnSource => "deleted_on_source",
+            SyncStatus::DeletedOnDest => "deleted_on_dest",
+            SyncStatus::Conflict => "conflict",
+        }
+    }
+}
+
+/// Represents the change of a file since the last successful update.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SyncChange {
+    /// Path of the file, relative to the source and destination directories.
+    pub path: PathBuf,
+    /// How the file changed.
+    pub status: SyncStatus,
+}
+
+impl SyncChange {
+    /// Serializes self into a JSON object.
+    pub fn to_json(&self) -> Value {
+        json::object(vec![
+            (
+                "path",
+                Value::from(self.path.to_string_lossy().into_owned()),
+            ),
+            ("status", Value::from(self.status.name())),
+        ])
+    }
+}
+
+/// Size and modification time of a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Version {
+    size: u64,
+    modified: Duration,
+}
+
+impl Version {
+    /// Gets the version of the given file.
+    fn of(file: &FileEntry) -> Self {
+        Version {
+            size: file.size(),
+            modified: file.modified(),
+        }
+    }
+}
+
+/// Versions of a file in the source and in the destination directories.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Record {
+    source: Version,
+    dest: Version,
+}
+
+/// Baseline state of the files of a source and destination directory.
+#[derive(Debug, Default, PartialEq)]
+pub struct State {
+    files: HashMap<PathBuf, Record>,
+}
+
+impl State {
+    /// Creates the state of the given source and destination directories,
+    /// recording the files that exist in both.
+    #[cfg(feature = "update")]
+    pub fn new(source: &Entry, dest: &Entry) -> Self {
+        let dest_files = files(dest);
+        let files = files(source)
+            .into_iter()
+            .filter_map(|(path, source)| {
+                let dest = *dest_files.get(&path)?;
+                Some((path, Record { source, dest }))
+            })
+            .collect();
+        State { files }
+    }
+
+    /// Loads the state of the given destination directory, that is empty if
+    /// it was never saved.
+    pub fn load(dest: &Path) -> Result<Self, BkupError> {
+        let path = state_path(dest);
+        if !path.is_file() {
+            return Ok(State::default());
+        }
+        let value = json::parse(&fs::read_to_string(&path)?)?;
+        let invalid = || BkupError::Parse(format!("Invalid state {:?}", path));
+        let mut files = HashMap::new();
+        for file in value.as_array().ok_or_else(invalid)? {
+            let number = |name| file.get(name).and_then(Value::as_u64);
+            let version = |size, modified| {
+                Some(Version {
+                    size: number(size)?,
+                    modified: Duration::from_nanos(number(modified)?),
+                })
+            };
+            let path = file.get("path").and_then(Value::as_str);
+            let source = version("size", "source_modified");
+            let dest = version("dest_size", "dest_modified");
+            match (path, source, dest) {
+                (Some(path), Some(source), Some(dest)) => {
+                    files.insert(PathBuf::from(path), Record { source, dest });
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(State { files })
+    }
+
+    /// Saves self as the state of the given destination directory.
+    #[cfg(feature = "update")]
+    pub fn save(&self, dest: &Path) -> Result<(), BkupError> {
+        let mut paths: Vec<_> = self.files.keys().collect();
+        paths.sort();
+        let files = paths
+            .into_iter()
+            .map(|path| {
+                let record = &self.files[path];
+                let nanos = |d: Duration| Value::from(d.as_nanos() as u64);
+                json::object(vec![
+                    ("path", Value::from(path.to_string_lossy().into_owned())),
+                    ("size", Value::from(record.source.size)),
+                    ("source_modified", nanos(record.source.modified)),
+                    ("dest_size", Value::from(record.dest.size)),
+                    ("dest_modified", nanos(record.dest.modified)),
+                ])
+            })
+            .collect();
+        // the previous state is only replaced once the new one is complete
+        metadata::create_dir(dest)?;
+        let path = state_path(dest);
+        let temp = crate::copy::temp_path(&path);
+        fs::write(&temp, Value::Array(files).to_string())?;
+        fs::rename(&temp, &path)?;
+        Ok(())
+    }
+
+    /// Gets the changes of the files of the given source and destination
+    /// directories since self was recorded, sorted by path.
+    pub fn changes(&self, source: &Entry, dest: &Entry) -> Vec<SyncChange> {
+        let source = files(source);
+        let dest = files(dest);
+        let paths: BTreeSet<&PathBuf> = source
+            .keys()
+            .chain(dest.keys())
+            .chain(self.files.keys())
+            .collect();
+        paths
+            .into_iter()
+            .filter_map(|path| {
+                let record = self.files.get(path);
+                let status = status(
+                    source
+                        .get(path)
+                        .map(|s| Some(*s) != record.map(|r| r.source)),
+                    dest.get(path).map(|d| Some(*d) != record.map(|r| r.dest)),
+                    record.is_some(),
+                )?;
+                Some(SyncChange {
+                    path: path.clone(),
+                    status,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Gets the status of a file given whether it changed in the source and in
+/// the destination (if it exists there) and whether it has a baseline.
+fn status(
+    source: Option<bool>,
+    dest: Option<bool>,
+    recorded: bool,
+) -> Option<SyncStatus> {
+    let status = match (source, dest, recorded) {
+        (Some(_), None, false) => SyncStatus::NewOnSource,
+        (None, Some(_), false) => SyncStatus::NewOnDest,
+        (Some(_), Some(_), false) => SyncStatus::Conflict,
+        (Some(false), Some(false), true) | (None, None, _) => return None,
+        (Some(true), Some(false), true) => SyncStatus::ChangedOnSource,
+        (Some(false), Some(true), true) => SyncStatus::ChangedOnDest,
+        (None, Some(false), true) => SyncStatus::DeletedOnSource,
+        (Some(false), None, true) => SyncStatus::DeletedOnDest,
+        (Some(true), Some(true), true)
+        | (None, Some(true), true)
+        | (Some(true), None, true) => SyncStatus::Conflict,
+    };
+    Some(status)
+}
+
+/// Gets the versions of all the files of the given directory, by path
+/// relative to it.
+fn files(root: &Entry) -> HashMap<PathBuf, Version> {
+    root.all_files()
+        .into_iter()
+        .filter_map(|file| {
+            let path = file.path().strip_prefix(root.path()).ok()?;
+            Some((path.to_path_buf(), Version::of(file)))
+        })
+        .collect()
+}
+
+#[cfg(all(test, feature = "update"))]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_sync_status() {
+        let scan = |root: &str, files: &[(&str, u64)]| {
+            let files: Vec<String> = files
+                .iter()
+                .map(|(name, modified)| {
+                    format!(
+                        r#"{{"type": "file", "path": "{}/{}", "size": 1,
+                           "modified": {}}}"#,
+                        root, name, modified
+                    )
+                })
+                .collect();
+            let json = format!(
+                r#"{{"type": "dir", "path": "{}", "entries": [{}]}}"#,
+                root,
+                files.join(",")
+            );
+            Entry::from_json(&json::parse(&json).unwrap()).unwrap()
+        };
+        let files = [("same", 1), ("src", 1), ("dst", 1), ("both", 1)];
+        let source = scan("/src", &files);
+        let dest = scan("/dst", &[&files[..], &[("gone", 1)]].concat());
+        let state = State::new(&source, &dest);
+        assert!(state.changes(&source, &dest).iter().all(|change| {
+            change.path == Path::new("gone")
+                && change.status == SyncStatus::NewOnDest
+        }));
+
+        let source = scan(
+            "/src",
+            &[("same", 1), ("src", 2), ("dst", 1), ("both", 2), ("new", 1)],
+        );
+        let dest = scan("/dst", &[("src", 1), ("dst", 2), ("both", 3)]);
+        let changes: Vec<_> = state
+            .changes(&source, &dest)
+            .into_iter()
+            .map(|change| (change.path, change.status))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (PathBuf::from("both"), SyncStatus::Conflict),
+                (PathBuf::from("dst"), SyncStatus::ChangedOnDest),
+                (PathBuf::from("new"), SyncStatus::NewOnSource),
+                (PathBuf::from("same"), SyncStatus::DeletedOnDest),
+                (PathBuf::from("src"), SyncStatus::ChangedOnSource),
+            ]
+        );
+    }
+}
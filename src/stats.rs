@@ -0,0 +1,286 @@
+//! Size breakdown of a directory, and its files with the same content, used
+//! to decide what to exclude (or clean up) before the first backup.
+
+use crate::{
+    entry::{self, Accuracy, Compare, Entry, FileEntry},
+    hash::HashAlgorithm,
+    json::{self, Value},
+};
+use log::*;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Represents the content of a directory, or of a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Usage {
+    /// Path of the entry.
+    pub path: PathBuf,
+    /// Number of directories contained in the entry.
+    pub dirs: usize,
+    /// Number of files contained in the entry (1 for a file).
+    pub files: usize,
+    /// Total size of the files in bytes.
+    pub bytes: u64,
+}
+
+impl Usage {
+    /// Gets the usage of the given entry.
+    fn of(entry: &Entry) -> Self {
+        let mut usage = Usage {
+            path: entry.path().to_path_buf(),
+            dirs: 0,
+            files: 0,
+            bytes: 0,
+        };
+        usage.add(entry);
+        usage
+    }
+
+    /// Adds the content of the given entry to self.
+    fn add(&mut self, entry: &Entry) {
+        match entry {
+            Entry::File(file) => {
+                self.files += 1;
+                self.bytes += file.size();
+            }
+            // special files have no content
+            Entry::Special(_) => self.files += 1,
+            Entry::Dir(_) => {
+                for entry in entry.entries() {
+                    if let Entry::Dir(_) = entry {
+                        self.dirs += 1;
+                    }
+                    self.add(entry);
+                }
+            }
+        }
+    }
+
+    /// Serializes self into a JSON object.
+    pub fn to_json(&self) -> Value {
+        json::object(vec![
+            (
+                "path",
+                Value::from(self.path.to_string_lossy().into_owned()),
+            ),
+            ("dirs", Value::from(self.dirs as u64)),
+            ("files", Value::from(self.files as u64)),
+            ("bytes", Value::from(self.bytes)),
+        ])
+    }
+}
+
+/// Represents the size breakdown of a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Content of the whole directory.
+    pub total: Usage,
+    /// Content of each top-level entry, sorted by decreasing size.
+    pub entries: Vec<Usage>,
+    /// Paths and sizes of the largest files, sorted by decreasing size.
+    pub largest: Vec<(PathBuf, u64)>,
+}
+
+impl Stats {
+    /// Computes the stats of the given directory, with at most the given
+    /// number of largest files.
+    pub(crate) fn new(root: &Entry, largest: usize) -> Self {
+        let mut entries: Vec<_> =
+            root.entries().into_iter().map(Usage::of).collect();
+        entries.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.path.cmp(&b.path)));
+        let mut files: Vec<_> = root
+            .all_files()
+            .into_iter()
+            .map(|file| (file.path().to_path_buf(), file.size()))
+            .collect();
+        files.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        files.truncate(largest);
+        Stats {
+            total: Usage::of(root),
+            entries,
+            largest: files,
+        }
+    }
+
+    /// Gets the path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.total.path
+    }
+
+    /// Serializes self into a JSON object.
+    pub fn to_json(&self) -> Value {
+        let largest = self
+            .largest
+            .iter()
+            .map(|(path, size)| {
+                json::object(vec![
+                    ("path", Value::from(path.to_string_lossy().into_owned())),
+                    ("size", Value::from(*size)),
+                ])
+            })
+            .collect();
+        json::object(vec![
+            ("total", self.total.to_json()),
+            (
+                "entries",
+                Value::Array(self.entries.iter().map(Usage::to_json).collect()),
+            ),
+            ("largest", Value::Array(largest)),
+        ])
+    }
+}
+
+/// Represents a set of files with the same content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Duplicates {
+    /// Size of each file in bytes.
+    pub size: u64,
+    /// Paths of the files, sorted.
+    pub paths: Vec<PathBuf>,
+}
+
+impl Duplicates {
+    /// Gets the number of bytes taken by all the files but one.
+    pub fn wasted(&self) -> u64 {
+        self.size * (self.paths.len() as u64).saturating_sub(1)
+    }
+
+    /// Serializes self into a JSON object.
+    pub fn to_json(&self) -> Value {
+        let paths = self
+            .paths
+            .iter()
+            .map(|path| Value::from(path.to_string_lossy().into_owned()))
+            .collect();
+        json::object(vec![
+            ("size", Value::from(self.size)),
+            ("wasted", Value::from(self.wasted())),
+            ("paths", Value::Array(paths)),
+        ])
+    }
+}
+
+/// Finds the files of the given directory with the same content, sorted by
+/// decreasing wasted space. Only the non-empty files whose size is shared
+/// with another file are hashed, and the hard links of the same file are
+/// counted once.
+pub(crate) fn duplicates(root: &Entry, hash: HashAlgorithm) -> Vec<Duplicates> {
+    let mut linked = HashSet::new();
+    let mut by_size: HashMap<u64, Vec<&FileEntry>> = HashMap::new();
+    for file in root.all_files() {
+        if file.size() == 0 || file.id().is_some_and(|id| !linked.insert(id)) {
+            continue;
+        }
+        by_size.entry(file.size()).or_default().push(file);
+    }
+    let files: Vec<_> = by_size
+        .into_values()
+        .filter(|files| files.len() > 1)
+        .flatten()
+        .collect();
+    let accuracy = Accuracy {
+        compare: Compare::Checksum,
+        hash,
+        ..Accuracy::new(Duration::ZERO)
+    };
+    entry::hash_files(&files, &accuracy);
+
+    let mut by_digest = HashMap::new();
+    for file in files {
+        match file.digest(&accuracy) {
+            Ok(digest) => by_digest
+                .entry((file.size(), digest))
+                .or_insert_with(Vec::new)
+                .push(file.path().to_path_buf()),
+            Err(e) => warn!("Cannot hash {:?}: {}", file.path(), e),
+        }
+    }
+    let mut duplicates: Vec<_> = by_digest
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|((size, _), mut paths)| {
+            paths.sort();
+            Duplicates { size, paths }
+        })
+        .collect();
+    duplicates.sort_by(|a, b| {
+        b.wasted().cmp(&a.wasted()).then(a.paths.cmp(&b.paths))
+    });
+    duplicates
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::filter::Filter;
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_stats() {
+        let root = r#"{"type": "dir", "path": "/src", "entries": [
+            {"type": "file", "path": "/src/a", "size": 5, "modified": 0},
+            {"type": "dir", "path": "/src/b", "entries": [
+                {"type": "file", "path": "/src/b/c", "size": 3, "modified": 0},
+                {"type": "dir", "path": "/src/b/d", "entries": [
+                    {"type": "file", "path": "/src/b/d/e", "size": 4,
+                     "modified": 0}
+                ]}
+            ]}
+        ]}"#;
+        let root = Entry::from_json(&json::parse(root).unwrap()).unwrap();
+        let stats = Stats::new(&root, 2);
+        let usage = |path: &str, dirs, files, bytes| Usage {
+            path: PathBuf::from(path),
+            dirs,
+            files,
+            bytes,
+        };
+        assert_eq!(stats.total, usage("/src", 2, 3, 12));
+        assert_eq!(
+            stats.entries,
+            vec![usage("/src/b", 1, 2, 7), usage("/src/a", 0, 1, 5)]
+        );
+        assert_eq!(
+            stats.largest,
+            vec![
+                (PathBuf::from("/src/a"), 5),
+                (PathBuf::from("/src/b/d/e"), 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(root.join("b")).expect("Cannot create dir");
+        fs::write(root.join("a"), "same content").expect("Cannot write file");
+        fs::write(root.join("b/a"), "same content").expect("Cannot write file");
+        fs::write(root.join("b/c"), "same content").expect("Cannot write file");
+        fs::write(root.join("d"), "other content").expect("Cannot write file");
+        fs::write(root.join("e"), "tiny").expect("Cannot write file");
+        fs::write(root.join("f"), "tinz").expect("Cannot write file");
+        fs::write(root.join("g"), "").expect("Cannot write file");
+        fs::write(root.join("h"), "").expect("Cannot write file");
+        #[cfg(unix)]
+        fs::hard_link(root.join("e"), root.join("link"))
+            .expect("Cannot link file");
+
+        let entry = Entry::directory(&root, false, &Filter::new())
+            .expect("Cannot scan directory");
+        let duplicates = duplicates(&entry, HashAlgorithm::Blake3);
+        assert_eq!(
+            duplicates,
+            vec![Duplicates {
+                size: 12,
+                paths: vec![root.join("a"), root.join("b/a"), root.join("b/c")],
+            }]
+        );
+        assert_eq!(duplicates[0].wasted(), 24);
+        fs::remove_dir_all(root).expect("Cannot remove test directory");
+    }
+}
@@ -0,0 +1,56 @@
+//! Strategies used to transfer the content of the source files into the
+//! destination, that can be provided by the applications (for example to
+//! upload the files to a remote storage, or to send only their changed
+//! blocks) without changing how the updates are planned.
+
+use crate::{
+    cancel::CancellationToken, error::BkupError, event::CopyProgress, pause,
+};
+use std::{fmt, path::Path};
+
+/// Strategy used to transfer the content of a source file into the temporary
+/// file that then atomically replaces its destination. When no transfer is
+/// set, the files are copied (or cloned) locally according to the options.
+pub trait Transfer: fmt::Debug + Send + Sync {
+    /// Gets the name of the transfer, used in the logs.
+    fn name(&self) -> &str;
+
+    /// Transfers the content of the source file into the given temporary
+    /// file, that may already exist and must be overwritten.
+    fn transfer(
+        &self,
+        source: &Path,
+        temp: &Path,
+        context: &mut TransferContext,
+    ) -> Result<(), BkupError>;
+}
+
+/// State of a single transfer, used to report its progress and to stop it
+/// when the update is paused or cancelled.
+pub struct TransferContext<'a> {
+    progress: CopyProgress<'a>,
+    cancel: &'a CancellationToken,
+}
+
+impl<'a> TransferContext<'a> {
+    /// Creates the context of a transfer with the given progress.
+    pub(crate) fn new(
+        progress: CopyProgress<'a>,
+        cancel: &'a CancellationToken,
+    ) -> Self {
+        TransferContext { progress, cancel }
+    }
+
+    /// Adds the given number of transferred bytes to the progress of the
+    /// transfer.
+    pub fn add(&mut self, bytes: u64) {
+        self.progress.add(bytes);
+    }
+
+    /// Blocks while the update is paused, and fails if it was cancelled. To
+    /// be called between two chunks of a long transfer.
+    pub fn check(&self) -> Result<(), BkupError> {
+        pause::wait(self.cancel);
+        self.cancel.check()
+    }
+}
@@ -0,0 +1,297 @@
+//! Terminal UI used to review the changes of a destination directory as a
+//! tree, and to choose which of them the update applies.
+
+use bkup::{BkupError as Error, Change, Delta};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    DefaultTerminal, Frame,
+};
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+/// Keys of the review, shown at the bottom of the screen.
+const HELP: &str = "space: toggle  ←/→: collapse/expand  a: all  n: none  \
+                    enter: apply  q: quit";
+
+/// Row of the tree, that is a changed entry or one of its ancestors.
+#[derive(Debug)]
+struct Row {
+    /// Path relative to the destination directory.
+    path: PathBuf,
+    /// Number of ancestors of the row in the tree.
+    depth: usize,
+    /// Index of the change of the entry, if it changed.
+    change: Option<usize>,
+}
+
+/// Changes under review, with the ones selected to be applied.
+#[derive(Debug)]
+pub struct Review {
+    changes: Vec<Change>,
+    paths: Vec<PathBuf>,
+    selected: Vec<bool>,
+    rows: Vec<Row>,
+    collapsed: HashSet<PathBuf>,
+    cursor: usize,
+}
+
+impl Review {
+    /// Creates the review of the given delta of the destination directory,
+    /// with all the changes selected.
+    pub fn new(delta: Delta, dest: &Path) -> Self {
+        let paths: Vec<PathBuf> = delta
+            .changes
+            .iter()
+            .map(|change| {
+                let path = change.dest().strip_prefix(dest);
+                path.unwrap_or_else(|_| change.dest()).to_path_buf()
+            })
+            .collect();
+        let mut rows: Vec<Row> = Vec::new();
+        for (i, path) in paths.iter().enumerate() {
+            // the changes are sorted by path, and so are their ancestors
+            let ancestors: Vec<&Path> = path.ancestors().skip(1).collect();
+            for ancestor in ancestors.into_iter().rev().skip(1) {
+                if !rows.iter().any(|row| row.path == ancestor) {
+                    rows.push(Row {
+                        path: ancestor.to_path_buf(),
+                        depth: ancestor.components().count() - 1,
+                        change: None,
+                    });
+                }
+            }
+            rows.push(Row {
+                path: path.clone(),
+                depth: path.components().count().saturating_sub(1),
+                change: Some(i),
+            });
+        }
+        Review {
+            selected: vec![true; paths.len()],
+            changes: delta.changes,
+            paths,
+            rows,
+            collapsed: HashSet::new(),
+            cursor: 0,
+        }
+    }
+
+    /// Gets the paths of the selected changes, relative to the destination
+    /// directory.
+    pub fn selection(&self) -> Vec<PathBuf> {
+        self.paths
+            .iter()
+            .zip(&self.selected)
+            .filter(|(_, selected)| **selected)
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Gets the indices of the rows that are not hidden by a collapsed
+    /// ancestor.
+    fn visible(&self) -> Vec<usize> {
+        (0..self.rows.len())
+            .filter(|i| {
+                let path = &self.rows[*i].path;
+                !self
+                    .collapsed
+                    .iter()
+                    .any(|c| path.starts_with(c) && path != c)
+            })
+            .collect()
+    }
+
+    /// Gets the indices of the changes below the given row (itself included).
+    fn below(&self, row: &Row) -> Vec<usize> {
+        (0..self.paths.len())
+            .filter(|i| self.paths[*i].starts_with(&row.path))
+            .collect()
+    }
+
+    /// Gets the row under the cursor.
+    fn current(&self) -> Option<&Row> {
+        let visible = self.visible();
+        visible.get(self.cursor).map(|i| &self.rows[*i])
+    }
+
+    /// Moves the cursor by the given number of visible rows.
+    fn scroll(&mut self, offset: isize) {
+        let last = self.visible().len().saturating_sub(1);
+        self.cursor = self.cursor.saturating_add_signed(offset).min(last);
+    }
+
+    /// Selects all the changes below the row under the cursor, or deselects
+    /// them if they are all selected already.
+    fn toggle(&mut self) {
+        let below = match self.current() {
+            Some(row) => self.below(row),
+            None => return,
+        };
+        let select = !below.iter().all(|i| self.selected[*i]);
+        for i in below {
+            self.selected[i] = select;
+        }
+    }
+
+    /// Collapses or expands the row under the cursor.
+    fn collapse(&mut self, collapse: bool) {
+        let path = match self.current() {
+            Some(row) if row.change.is_none() => row.path.clone(),
+            _ => return,
+        };
+        if collapse {
+            self.collapsed.insert(path);
+        } else {
+            self.collapsed.remove(&path);
+        }
+    }
+
+    /// Selects or deselects all the changes.
+    fn select_all(&mut self, select: bool) {
+        self.selected.iter_mut().for_each(|s| *s = select);
+    }
+
+    /// Gets the item that represents the given row.
+    fn item(&self, row: &Row) -> ListItem<'static> {
+        let below = self.below(row);
+        let count = below.iter().filter(|i| self.selected[**i]).count();
+        let mark = match count {
+            0 => "[ ]",
+            n if n == below.len() => "[x]",
+            _ => "[-]",
+        };
+        let name = row.path.file_name().unwrap_or(row.path.as_os_str());
+        let size: u64 = below.iter().map(|i| self.changes[*i].size()).sum();
+        let kind = match row.change.map(|i| &self.changes[i]) {
+            Some(Change::MissingDir { .. }) => "new dir",
+            Some(Change::MissingFile { .. }) => "new",
+            Some(Change::NewerFile { .. }) => "newer",
+            None if self.collapsed.contains(&row.path) => "+",
+            None => "",
+        };
+        ListItem::new(format!(
+            "{}{} {}  {} {}",
+            "  ".repeat(row.depth),
+            mark,
+            name.to_string_lossy(),
+            kind,
+            bkup::format_size(size)
+        ))
+    }
+
+    /// Draws the tree and the summary of the selection.
+    fn draw(&self, frame: &mut Frame) {
+        let [tree, summary] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(2)])
+                .areas(frame.area());
+        let visible = self.visible();
+        let items: Vec<_> =
+            visible.iter().map(|i| self.item(&self.rows[*i])).collect();
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title("Changes"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut state = ListState::default().with_selected(Some(self.cursor));
+        frame.render_stateful_widget(list, tree, &mut state);
+
+        let selected: Vec<_> = (0..self.changes.len())
+            .filter(|i| self.selected[*i])
+            .collect();
+        let bytes = selected.iter().map(|i| self.changes[*i].size()).sum();
+        let text = format!(
+            "{} of {} changes selected ({})\n{}",
+            selected.len(),
+            self.changes.len(),
+            bkup::format_size(bytes),
+            HELP
+        );
+        frame.render_widget(Paragraph::new(text), summary);
+    }
+}
+
+/// Shows the review until the user applies the selection (returning true) or
+/// quits (returning false).
+pub fn run(review: &mut Review) -> Result<bool, Error> {
+    let mut terminal = ratatui::init();
+    let result = run_in(&mut terminal, review);
+    ratatui::restore();
+    result
+}
+
+/// Handles the keys pressed by the user in the given terminal.
+fn run_in(
+    terminal: &mut DefaultTerminal,
+    review: &mut Review,
+) -> Result<bool, Error> {
+    loop {
+        terminal.draw(|frame| review.draw(frame))?;
+        let key = match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => continue,
+        };
+        match key.code {
+            KeyCode::Up | KeyCode::Char('k') => review.scroll(-1),
+            KeyCode::Down | KeyCode::Char('j') => review.scroll(1),
+            KeyCode::PageUp => review.scroll(-10),
+            KeyCode::PageDown => review.scroll(10),
+            KeyCode::Left | KeyCode::Char('h') => review.collapse(true),
+            KeyCode::Right | KeyCode::Char('l') => review.collapse(false),
+            KeyCode::Char(' ') => review.toggle(),
+            KeyCode::Char('a') => review.select_all(true),
+            KeyCode::Char('n') => review.select_all(false),
+            KeyCode::Enter => return Ok(true),
+            KeyCode::Esc | KeyCode::Char('q') => return Ok(false),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_review_selection() {
+        let change = |path: &str| Change::MissingFile {
+            source: Path::new("/src").join(path),
+            dest: Path::new("/dst").join(path),
+            size: 1,
+        };
+        let delta = Delta {
+            changes: vec![change("a/b/c"), change("a/d"), change("e")],
+        };
+        let mut review = Review::new(delta, Path::new("/dst"));
+        let rows: Vec<_> = review
+            .rows
+            .iter()
+            .map(|row| (row.path.to_str().unwrap(), row.depth))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![("a", 0), ("a/b", 1), ("a/b/c", 2), ("a/d", 1), ("e", 0)]
+        );
+
+        // deselect the directory a, then select a/d only
+        review.toggle();
+        assert_eq!(review.selection(), vec![PathBuf::from("e")]);
+        review.scroll(3);
+        review.toggle();
+        assert_eq!(
+            review.selection(),
+            vec![PathBuf::from("a/d"), PathBuf::from("e")]
+        );
+
+        // the rows of a collapsed directory are hidden
+        review.scroll(-3);
+        review.collapse(true);
+        assert_eq!(review.visible(), vec![0, 4]);
+        review.scroll(1);
+        review.toggle();
+        assert_eq!(review.selection(), vec![PathBuf::from("a/d")]);
+    }
+}
@@ -0,0 +1,1479 @@
+//! Updates of a destination directory, the only operations that modify it.
+
+use crate::{
+    audit::AuditLog,
+    cancel::CancellationToken,
+    copy::{self, CopyOptions, Engine},
+    device,
+    entry::{Accuracy, Compare, Entry},
+    error::BkupError,
+    event::{Event, EventHandler},
+    eventlog::{self, Level},
+    filter::{AttributeFilter, Caches, Filter, Predicate, RegexFilter},
+    format_size,
+    hash::HashAlgorithm,
+    itemize,
+    journal::Journal,
+    json::{self, Value},
+    lock::Lock,
+    manifest::Manifest,
+    metadata::{self, PROBE_NAME},
+    migrate,
+    names::NameMatching,
+    notify::Webhook,
+    parity,
+    plan::{Failures, Plan, TransferOrder},
+    plugin::Plugin,
+    rate::RateLimiter,
+    rules::{FilterRules, PriorityRules},
+    special::SpecialFiles,
+    state::State,
+    transfer::Transfer,
+};
+use log::*;
+use std::{
+    fs::{self, Metadata},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Maximum number of files reported by `Destruction::largest`.
+const LARGEST_DESTRUCTIONS: usize = 10;
+
+/// Represents the options used to update a destination directory.
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOptions {
+    /// Accuracy used to consider a source file newer than its destination.
+    pub accuracy: Duration,
+    /// When set the files whose modification times differ by one hour
+    /// (within the accuracy) are considered equal, as the ones on FAT
+    /// destinations after a daylight saving time change.
+    pub ignore_dst: bool,
+    /// Criterion used to tell whether a source file changed, the checksum one
+    /// hashing the files with the hash algorithm.
+    pub compare: Compare,
+    /// When set parse the `.gitignore` file of the visited directories.
+    pub ignore: bool,
+    /// When set rename the destination files that were moved or renamed in
+    /// the source directory instead of copying them again.
+    pub detect_moves: bool,
+    /// When set recreate the hard links of the source files in the
+    /// destination, instead of copying each link independently.
+    pub hard_links: bool,
+    /// Predicates that decide which entries are included.
+    pub predicates: Vec<Predicate>,
+    /// Limits on the size, modification time and type of the included source
+    /// entries.
+    pub attributes: AttributeFilter,
+    /// How the names of the source entries are matched with the ones of the
+    /// destination.
+    pub names: NameMatching,
+    /// When set skip the entries on a different file system than the source
+    /// (or destination) directory, such as mount points.
+    pub one_file_system: bool,
+    /// How the special files (FIFOs, sockets and device nodes) are handled.
+    pub specials: SpecialFiles,
+    /// Cache directories that are excluded.
+    pub caches: Caches,
+    /// Ordered include and exclude rules matched against the paths relative to
+    /// the source (and destination) directory.
+    pub rules: FilterRules,
+    /// Regular expressions matched against the paths relative to the source
+    /// (and destination) directory.
+    pub regexes: RegexFilter,
+    /// When set skip the hidden files and directories (whose name starts with
+    /// a dot, or with the hidden attribute on Windows).
+    pub skip_hidden: bool,
+    /// Paths relative to the source directory that are always included,
+    /// overriding the `.gitignore` rules and the plugins.
+    pub pins: Vec<PathBuf>,
+    /// Shell commands used to launch the external plugins.
+    pub plugins: Vec<String>,
+    /// When set try to clone the source files on copy-on-write filesystems,
+    /// falling back to a regular copy when cloning is not supported.
+    pub reflink: bool,
+    /// Engine used to copy the file contents.
+    pub engine: Engine,
+    /// Size of the buffer used to copy the file contents (if not set the
+    /// copy is delegated to the standard library).
+    pub buffer_size: Option<usize>,
+    /// When set bypass the page cache while copying the files.
+    pub direct_io: bool,
+    /// Maximum number of bytes per second copied into the destination.
+    pub bwlimit: Option<u64>,
+    /// Maximum number of bytes copied by the update, after which the
+    /// remaining actions are left to the next update (the copies in progress
+    /// are completed).
+    pub max_transfer: Option<u64>,
+    /// When set flush every copied file and its parent directory to the
+    /// storage device before the update completes.
+    pub fsync: bool,
+    /// When set the content of the destination files is overwritten with
+    /// zeros before they are replaced, as well as the one of the stale
+    /// temporary files before they are removed.
+    pub shred: bool,
+    /// When set every copied file is read again and compared with its source
+    /// before replacing the destination file.
+    pub verify: bool,
+    /// Algorithm of the digests used to verify the copies, to detect the
+    /// moved files and to record the manifest.
+    pub hash: HashAlgorithm,
+    /// When set apply the remaining actions of an interrupted update of the
+    /// destination recorded in its journal, instead of computing a new plan.
+    pub resume: bool,
+    /// When set the interrupted copies of large files are resumed by the
+    /// following update, instead of being restarted.
+    pub partial: bool,
+    /// Number of times a copy that failed with a transient I/O error is
+    /// retried.
+    pub retries: u32,
+    /// Delay before retrying a failed copy the first time, doubled at every
+    /// following retry.
+    pub retry_delay: Duration,
+    /// Number of times a copy whose source file is locked by another process
+    /// (Windows only) is retried, before the file is skipped and reported.
+    pub locked_retries: u32,
+    /// When set apply all the actions that don't fail, instead of stopping at
+    /// the first failure, and then fail with the report of every failure.
+    pub keep_going: bool,
+    /// Number of files copied at the same time (0 and 1 copy them in order).
+    pub jobs: usize,
+    /// Maximum number of files copied at the same time from or to the same
+    /// device, to avoid competing I/O on spinning disks.
+    pub device_jobs: Option<usize>,
+    /// Order in which the files are copied.
+    pub order: TransferOrder,
+    /// Patterns of the files copied before and after the others, whatever
+    /// the order.
+    pub priorities: PriorityRules,
+    /// When set wait for the update of the same destination by another
+    /// process to complete, instead of failing.
+    pub wait_lock: bool,
+    /// When set break the lock of the destination left by an update that is
+    /// no longer running.
+    pub break_stale_lock: bool,
+    /// Limits above which the replaced destination files must be confirmed.
+    pub confirm: ConfirmThreshold,
+    /// When set only warn if the destination doesn't have enough free space
+    /// for the update, instead of aborting it.
+    pub force: bool,
+    /// Handler of the events reported while the update runs.
+    pub events: Option<EventHandler>,
+    /// Webhook notified with the summary of the update once it's over.
+    pub webhook: Option<Webhook>,
+    /// When set show a desktop notification once the update is over
+    /// (requires the `desktop` feature).
+    pub notify_desktop: bool,
+    /// When set write the start and the outcome of the update to the
+    /// Application event log (Windows only).
+    pub event_log: bool,
+    /// When set measure the offset of the clock of the destination from the
+    /// local clock with a probe file, and compensate it when comparing the
+    /// modification times.
+    pub detect_skew: bool,
+    /// When set only compute and report the plan of the update, without
+    /// modifying the destination.
+    pub dry_run: bool,
+    /// When set print one line per differing entry to the standard output,
+    /// with rsync-style change flags.
+    pub itemize: bool,
+    /// When set record the digest of every destination file once the update
+    /// completes, used to detect the corrupted files with a scrub.
+    pub manifest: bool,
+    /// When set also write the parity of the new and changed destination
+    /// files with their digests (implies `manifest`), used to repair the
+    /// corrupted files without the source.
+    pub parity: bool,
+    /// When set record the state of the source and destination files once
+    /// the update completes, used to tell how they changed by the following
+    /// runs.
+    pub save_state: bool,
+    /// When set record every action applied by the update, with its outcome
+    /// and the digest of the verified copies, in an audit log of the
+    /// destination.
+    pub audit_log: bool,
+    /// Token used by the application to cancel the update, that stops
+    /// visiting the directories and applying new actions.
+    pub cancel: CancellationToken,
+    /// Transfer used to copy the files into the destination, instead of the
+    /// local copy.
+    pub transfer: Option<Arc<dyn Transfer>>,
+}
+
+impl UpdateOptions {
+    /// Creates the default options, to be customized with the following
+    /// methods:
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// let options = bkup::UpdateOptions::new()
+    ///     .accuracy(Duration::from_secs(2))
+    ///     .ignore(true)
+    ///     .jobs(4)
+    ///     .on_event(|event| println!("{}", event.to_json()));
+    /// bkup::update_with("source".into(), "dest".into(), &options)?;
+    /// # Ok::<(), bkup::BkupError>(())
+    /// ```
+    pub fn new() -> Self {
+        UpdateOptions::default()
+    }
+
+    /// Sets the accuracy used to consider a source file newer than its
+    /// destination.
+    pub fn accuracy(mut self, accuracy: Duration) -> Self {
+        self.accuracy = accuracy;
+        self
+    }
+
+    /// Sets whether the modification times that differ by one hour are
+    /// considered equal.
+    pub fn ignore_dst(mut self, ignore_dst: bool) -> Self {
+        self.ignore_dst = ignore_dst;
+        self
+    }
+
+    /// Sets the criterion used to tell whether a source file changed.
+    pub fn compare(mut self, compare: Compare) -> Self {
+        self.compare = compare;
+        self
+    }
+
+    /// Sets whether the `.gitignore` files of the visited directories are
+    /// parsed.
+    pub fn ignore(mut self, ignore: bool) -> Self {
+        self.ignore = ignore;
+        self
+    }
+
+    /// Sets whether the moved or renamed source files are renamed in the
+    /// destination.
+    pub fn detect_moves(mut self, detect_moves: bool) -> Self {
+        self.detect_moves = detect_moves;
+        self
+    }
+
+    /// Sets whether the hard links of the source files are recreated in the
+    /// destination.
+    pub fn hard_links(mut self, hard_links: bool) -> Self {
+        self.hard_links = hard_links;
+        self
+    }
+
+    /// Adds a path relative to the source directory that is always included.
+    pub fn pin<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.pins.push(path.into());
+        self
+    }
+
+    /// Adds a predicate that decides whether the entry with the given path and
+    /// metadata is included.
+    pub fn filter<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Path, &Metadata) -> bool + Send + Sync + 'static,
+    {
+        self.predicates.push(Predicate::new(predicate));
+        self
+    }
+
+    /// Sets the limits on the size, modification time and type of the
+    /// included source entries.
+    pub fn attributes(mut self, attributes: AttributeFilter) -> Self {
+        self.attributes = attributes;
+        self
+    }
+
+    /// Sets how the names of the source entries are matched with the ones of
+    /// the destination.
+    pub fn names(mut self, names: NameMatching) -> Self {
+        self.names = names;
+        self
+    }
+
+    /// Sets how the special files are handled.
+    pub fn specials(mut self, specials: SpecialFiles) -> Self {
+        self.specials = specials;
+        self
+    }
+
+    /// Sets which cache directories are excluded.
+    pub fn caches(mut self, caches: Caches) -> Self {
+        self.caches = caches;
+        self
+    }
+
+    /// Sets the ordered rules matched against the relative paths.
+    pub fn rules(mut self, rules: FilterRules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Sets the regular expressions matched against the relative paths.
+    pub fn regexes(mut self, regexes: RegexFilter) -> Self {
+        self.regexes = regexes;
+        self
+    }
+
+    /// Sets whether the hidden entries are skipped.
+    pub fn skip_hidden(mut self, skip_hidden: bool) -> Self {
+        self.skip_hidden = skip_hidden;
+        self
+    }
+
+    /// Sets whether the entries on other file systems are skipped.
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.one_file_system = one_file_system;
+        self
+    }
+
+    /// Adds the shell command used to launch an external plugin.
+    pub fn plugin<S: Into<String>>(mut self, command: S) -> Self {
+        self.plugins.push(command.into());
+        self
+    }
+
+    /// Sets whether the source files are cloned on copy-on-write filesystems.
+    pub fn reflink(mut self, reflink: bool) -> Self {
+        self.reflink = reflink;
+        self
+    }
+
+    /// Sets the engine used to copy the file contents.
+    pub fn engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// Sets the size of the buffer used to copy the file contents.
+    pub fn buffer_size(mut self, buffer_size: Option<usize>) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+
+    /// Sets whether the page cache is bypassed while copying the files.
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /// Sets the maximum number of bytes per second copied.
+    pub fn bwlimit(mut self, bwlimit: Option<u64>) -> Self {
+        self.bwlimit = bwlimit;
+        self
+    }
+
+    /// Sets the maximum number of bytes copied by the update.
+    pub fn max_transfer(mut self, max_transfer: Option<u64>) -> Self {
+        self.max_transfer = max_transfer;
+        self
+    }
+
+    /// Sets whether the copied files are flushed to the storage device.
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Sets whether the copied files are compared with their source.
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Sets whether the replaced destination files are overwritten.
+    pub fn shred(mut self, shred: bool) -> Self {
+        self.shred = shred;
+        self
+    }
+
+    /// Sets the algorithm of the digests.
+    pub fn hash(mut self, hash: HashAlgorithm) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// Sets whether an interrupted update of the destination is resumed.
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Sets whether the interrupted copies of large files are resumed.
+    pub fn partial(mut self, partial: bool) -> Self {
+        self.partial = partial;
+        self
+    }
+
+    /// Sets the number of times a copy that failed with a transient error is
+    /// retried.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets the delay before retrying a failed copy the first time.
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Sets the number of times a copy whose source file is locked is
+    /// retried.
+    pub fn locked_retries(mut self, locked_retries: u32) -> Self {
+        self.locked_retries = locked_retries;
+        self
+    }
+
+    /// Sets whether the update applies all the actions that don't fail.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Sets the number of files copied at the same time.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs;
+        self
+    }
+
+    /// Sets the order in which the files are copied.
+    pub fn order(mut self, order: TransferOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Sets the patterns of the files copied before and after the others.
+    pub fn priorities(mut self, priorities: PriorityRules) -> Self {
+        self.priorities = priorities;
+        self
+    }
+
+    /// Sets the maximum number of files copied at the same time from or to
+    /// the same device.
+    pub fn device_jobs(mut self, device_jobs: Option<usize>) -> Self {
+        self.device_jobs = device_jobs;
+        self
+    }
+
+    /// Sets whether the update waits for the lock of the destination.
+    pub fn wait_lock(mut self, wait_lock: bool) -> Self {
+        self.wait_lock = wait_lock;
+        self
+    }
+
+    /// Sets whether a stale lock of the destination is broken.
+    pub fn break_stale_lock(mut self, break_stale_lock: bool) -> Self {
+        self.break_stale_lock = break_stale_lock;
+        self
+    }
+
+    /// Sets the limits above which the replaced files must be confirmed.
+    pub fn confirm(mut self, confirm: ConfirmThreshold) -> Self {
+        self.confirm = confirm;
+        self
+    }
+
+    /// Sets whether a destination without enough free space is only
+    /// reported.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Sets the function invoked with every event reported by the update.
+    pub fn on_event<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Event) + Send + Sync + 'static,
+    {
+        self.events = Some(EventHandler::new(handler));
+        self
+    }
+
+    /// Sets the handler of the events reported by the update, such as the
+    /// one created by `EventHandler::channel`.
+    pub fn events(mut self, events: EventHandler) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Sets the token used to cancel the update.
+    pub fn cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// Sets the transfer used to copy the files into the destination.
+    pub fn transfer<T>(mut self, transfer: T) -> Self
+    where
+        T: Transfer + 'static,
+    {
+        self.transfer = Some(Arc::new(transfer));
+        self
+    }
+
+    /// Sets whether the differing entries are printed to the standard output.
+    pub fn itemize(mut self, itemize: bool) -> Self {
+        self.itemize = itemize;
+        self
+    }
+
+    /// Sets whether the digests of the destination files are recorded once the
+    /// update completes.
+    pub fn manifest(mut self, manifest: bool) -> Self {
+        self.manifest = manifest;
+        self
+    }
+
+    /// Sets whether the parity of the destination files is written with their
+    /// digests.
+    pub fn parity(mut self, parity: bool) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    /// Sets whether the state of the files is recorded once the update
+    /// completes.
+    pub fn save_state(mut self, save_state: bool) -> Self {
+        self.save_state = save_state;
+        self
+    }
+
+    /// Sets whether the actions of the update are recorded in an audit log of
+    /// the destination.
+    pub fn audit_log(mut self, audit_log: bool) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    /// Sets the webhook notified with the summary of the update.
+    pub fn webhook(mut self, webhook: Option<Webhook>) -> Self {
+        self.webhook = webhook;
+        self
+    }
+
+    /// Sets whether a desktop notification is shown once the update is over.
+    pub fn notify_desktop(mut self, notify_desktop: bool) -> Self {
+        self.notify_desktop = notify_desktop;
+        self
+    }
+
+    /// Sets whether the start and the outcome of the update are written to
+    /// the event log.
+    pub fn event_log(mut self, event_log: bool) -> Self {
+        self.event_log = event_log;
+        self
+    }
+
+    /// Sets whether the clock skew of the destination is measured and
+    /// compensated.
+    pub fn detect_skew(mut self, detect_skew: bool) -> Self {
+        self.detect_skew = detect_skew;
+        self
+    }
+
+    /// Sets whether the plan of the update is only reported.
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Gets the options used to copy the files.
+    pub(crate) fn copy_options(&self) -> CopyOptions {
+        CopyOptions {
+            reflink: self.reflink,
+            engine: self.engine,
+            buffer_size: self.buffer_size,
+            direct_io: self.direct_io,
+            limiter: self.bwlimit.map(|rate| Arc::new(RateLimiter::new(rate))),
+            max_transfer: self.max_transfer,
+            fsync: self.fsync,
+            shred: self.shred,
+            verify: self.verify,
+            hash: self.hash,
+            partial: self.partial,
+            retries: self.retries,
+            retry_delay: self.retry_delay,
+            locked_retries: self.locked_retries,
+            keep_going: self.keep_going,
+            jobs: self.jobs,
+            device_jobs: self.device_jobs,
+            events: self.events.clone(),
+            cancel: self.cancel.clone(),
+            transfer: self.transfer.clone(),
+        }
+    }
+
+    /// Reports the given event to the event handler, if any.
+    fn emit(&self, event: Event) {
+        if let Some(events) = &self.events {
+            events.emit(&event);
+        }
+    }
+}
+
+/// Counts the actions applied by an update.
+#[derive(Debug, Default)]
+struct Progress {
+    actions: usize,
+    bytes: u64,
+    // destination of the copies skipped because their source is locked
+    locked: Vec<PathBuf>,
+}
+
+/// Represents the limits above which the destination files replaced by an
+/// update must be confirmed before the destination is modified.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConfirmThreshold {
+    /// Number of replaced files above which confirmation is required.
+    pub files: Option<usize>,
+    /// Number of replaced bytes above which confirmation is required.
+    pub bytes: Option<u64>,
+}
+
+/// Summarizes the destination files that an update would replace, when they
+/// exceed the confirmation threshold.
+#[derive(Debug)]
+pub struct Destruction {
+    /// Number of destination files that would be replaced.
+    pub files: usize,
+    /// Total size in bytes of the destination files that would be replaced.
+    pub bytes: u64,
+    /// Largest destination files that would be replaced, with their size.
+    pub largest: Vec<(PathBuf, u64)>,
+}
+
+/// Updates the destination directory according to its delta with the source
+/// directory.
+pub fn update(
+    source: PathBuf,
+    dest: PathBuf,
+    accuracy: Duration,
+    ignore: bool,
+) -> Result<(), BkupError> {
+    let options = UpdateOptions::new().accuracy(accuracy).ignore(ignore);
+    update_with(source, dest, &options).map(|_| ())
+}
+
+/// Updates the destination directory according to its delta with the source
+/// directory, using the given options, and returns the number of applied
+/// actions. The update is aborted if it exceeds the confirmation threshold.
+pub fn update_with(
+    source: PathBuf,
+    dest: PathBuf,
+    options: &UpdateOptions,
+) -> Result<usize, BkupError> {
+    update_with_confirm(source, dest, options, |_| false)
+}
+
+/// Updates the destination directory according to its delta with the source
+/// directory, using the given options, and returns the number of applied
+/// actions. If the destination files that would be replaced exceed the
+/// confirmation threshold, the update is applied only if the given function
+/// confirms it.
+pub fn update_with_confirm<F>(
+    source: PathBuf,
+    dest: PathBuf,
+    options: &UpdateOptions,
+    confirm: F,
+) -> Result<usize, BkupError>
+where
+    F: FnOnce(&Destruction) -> bool,
+{
+    info!(
+        "Updating directory {:?} with content of {:?} ({:?} accuracy - \
+         ignore: {})",
+        dest, source, options.accuracy, options.ignore
+    );
+    debug!("Update options: {:?}", options);
+    if options.shred {
+        warn!(
+            "Shredding the replaced files is ineffective on SSDs and \
+            copy-on-write filesystems, that keep their previous content"
+        );
+    }
+    let mut progress = Progress::default();
+    let (_lock, plugins) = match start(&dest, options) {
+        Ok(started) => started,
+        Err(e) => {
+            report(&source, &dest, Some(&e), &progress, options);
+            return Err(e);
+        }
+    };
+    if options.event_log {
+        let message = format!("Backup of {:?} to {:?} started", source, dest);
+        if let Err(e) =
+            eventlog::write(Level::Information, eventlog::STARTED_ID, &message)
+        {
+            warn!("Cannot write to the event log: {}", e);
+        }
+    }
+    let mut filter = Filter::new();
+    filter.set_cancel(options.cancel.clone());
+    filter.set_names(options.names);
+    filter.set_specials(options.specials);
+    filter.set_caches(options.caches);
+    filter.set_skip_hidden(options.skip_hidden);
+    for predicate in &options.predicates {
+        filter.add_predicate(predicate.clone());
+    }
+    for plugin in plugins.iter().filter(|p| p.filters()) {
+        filter.add_plugin(Arc::clone(plugin));
+    }
+
+    let result = match interrupted(&source, &dest, options) {
+        Some((journal, plan)) => {
+            info!("Resuming interrupted update");
+            options.emit(Event::Planned {
+                actions: plan.len(),
+                bytes: plan.bytes(),
+            });
+            apply(&plan, &dest, journal, options, &mut progress).map(|_| plan)
+        }
+        None => {
+            options.emit(Event::ScanStarted {
+                source: source.clone(),
+                dest: dest.clone(),
+            });
+            plan(source.clone(), dest.clone(), options, &filter).and_then(
+                |plan| {
+                    options.emit(Event::Planned {
+                        actions: plan.len(),
+                        bytes: plan.bytes(),
+                    });
+                    if options.dry_run {
+                        for action in plan.actions() {
+                            info!(
+                                "Dry run: {} {:?}",
+                                action.name(),
+                                action.path()
+                            );
+                        }
+                        return Ok(plan);
+                    }
+                    check_destruction(&plan, &options.confirm, confirm)?;
+                    check_free_space(&plan, &dest, options.force)?;
+                    if plan.is_empty() {
+                        return Ok(plan);
+                    }
+                    info!("Updating destination");
+                    let journal =
+                        Journal::create(&source, &dest, &plan, options.fsync)?;
+                    apply(&plan, &dest, journal, options, &mut progress)?;
+                    Ok(plan)
+                },
+            )
+        }
+    };
+    // the directories may have been visited only partially
+    let result = result.map_err(|e| match options.cancel.check() {
+        Ok(()) => e,
+        Err(cancelled) => cancelled,
+    });
+    if result.is_ok() && options.save_state && !options.dry_run {
+        if let Err(e) = save_state(&source, &dest, options, &filter) {
+            warn!("Cannot save the state of {:?}: {}", dest, e);
+        }
+    }
+    if result.is_ok()
+        && (options.manifest || options.parity)
+        && !options.dry_run
+    {
+        if let Err(e) = save_manifest(&dest, options, &filter) {
+            warn!("Cannot save the manifest of {:?}: {}", dest, e);
+        }
+    }
+    report(&source, &dest, result.as_ref().err(), &progress, options);
+
+    // let the plugins know the outcome of the run
+    let event = match &result {
+        Ok(plan) => json::object(vec![
+            ("type", Value::from("completed")),
+            ("actions", Value::from(plan.len())),
+            ("bytes", Value::from(plan.bytes())),
+        ]),
+        Err(e) => json::object(vec![
+            ("type", Value::from("failed")),
+            ("error", Value::from(e.to_string())),
+        ]),
+    };
+    for plugin in plugins.iter().filter(|p| p.notifies()) {
+        if let Err(e) = plugin.notify(&event) {
+            warn!("Cannot notify plugin: {}", e);
+        }
+    }
+
+    result.map(|_| {
+        info!("Update completed");
+        progress.actions
+    })
+}
+
+/// Visits the updated source and destination directories again to save
+/// their state.
+fn save_state(
+    source: &Path,
+    dest: &Path,
+    options: &UpdateOptions,
+    filter: &Filter,
+) -> Result<(), BkupError> {
+    info!("Saving the state of {:?}", dest);
+    let source_entry = Entry::directory(source, options.ignore, filter)?;
+    let dest_entry = Entry::directory(dest, options.ignore, filter)?;
+    State::new(&source_entry, &dest_entry).save(dest)
+}
+
+/// Visits the updated destination directory again to record the digests of
+/// its new and changed files, and to write their parity if enabled.
+fn save_manifest(
+    dest: &Path,
+    options: &UpdateOptions,
+    filter: &Filter,
+) -> Result<(), BkupError> {
+    let mut manifest = Manifest::load(dest)?;
+    let dest_entry = Entry::directory(dest, options.ignore, filter)?;
+    let hashed = manifest.refresh(&dest_entry, options.hash)?;
+    if options.parity {
+        info!("Writing the parity of {} files", hashed.len());
+        for path in &hashed {
+            parity::write(&dest.join(path), &parity::parity_path(dest, path))?;
+        }
+    }
+    info!(
+        "Saving the manifest of {:?} ({} files hashed)",
+        dest,
+        hashed.len()
+    );
+    manifest.save(dest)
+}
+
+/// Measures the offset in nanoseconds of the clock of the destination from
+/// the local clock, as the modification time of a probe file written to the
+/// destination, warning if it exceeds the accuracy window.
+fn clock_skew(dest: &Path, window: Duration) -> Result<i64, BkupError> {
+    metadata::create_dir(dest).map_err(|e| BkupError::Io(e).at(dest))?;
+    let probe = copy::temp_path(&metadata::path(dest, PROBE_NAME));
+    let before = SystemTime::now();
+    let modified = fs::write(&probe, [])
+        .and_then(|()| fs::metadata(&probe)?.modified())
+        .map_err(|e| BkupError::Io(e).at(&probe));
+    let after = SystemTime::now();
+    let _ = fs::remove_file(&probe);
+    let nanos = |time: SystemTime| {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as i64
+    };
+    let local = (nanos(before) + nanos(after)) / 2;
+    let skew = nanos(modified?) - local;
+    let offset = Duration::from_nanos(skew.unsigned_abs());
+    if offset > window {
+        warn!(
+            "The clock of {:?} is {:?} {} the local clock, beyond the accuracy \
+             of {:?} (compensated)",
+            dest,
+            offset,
+            if skew > 0 { "ahead of" } else { "behind" },
+            window
+        );
+    } else {
+        debug!("Clock skew of {:?}: {} ns", dest, skew);
+    }
+    Ok(skew)
+}
+
+/// Takes the lock of the destination (unless it's a dry run, that doesn't
+/// modify it), after checking that it was migrated to the current layout, and
+/// launches the plugins.
+fn start(
+    dest: &Path,
+    options: &UpdateOptions,
+) -> Result<(Option<Lock>, Vec<Arc<Plugin>>), BkupError> {
+    if options.notify_desktop && !cfg!(feature = "desktop") {
+        return Err(BkupError::Unsupported(
+            "The desktop notifications require the desktop feature".to_string(),
+        ));
+    }
+    if options.event_log && !cfg!(windows) {
+        return Err(BkupError::Unsupported(
+            "The event log is only available on Windows".to_string(),
+        ));
+    }
+    // an update of a previous release would not see the lock
+    migrate::check(dest)?;
+    let lock = if options.dry_run {
+        None
+    } else {
+        Some(Lock::acquire(
+            dest,
+            options.wait_lock,
+            options.break_stale_lock,
+        )?)
+    };
+    let plugins = options
+        .plugins
+        .iter()
+        .map(|command| Plugin::spawn(command).map(Arc::new))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((lock, plugins))
+}
+
+/// Reports the errors and the summary of the update that failed with the
+/// given error, if any, and notifies the webhook.
+fn report(
+    source: &Path,
+    dest: &Path,
+    error: Option<&BkupError>,
+    progress: &Progress,
+    options: &UpdateOptions,
+) {
+    let (status, failures) = match error {
+        None => ("completed", 0),
+        Some(BkupError::Cancelled) => ("cancelled", 0),
+        Some(e) => match e {
+            BkupError::Failures(Failures(failures)) => {
+                for failure in failures {
+                    options.emit(Event::Error {
+                        path: Some(failure.path.clone()),
+                        error: failure.error.clone(),
+                    });
+                }
+                ("failed", failures.len())
+            }
+            _ => {
+                options.emit(Event::Error {
+                    path: None,
+                    error: e.to_string(),
+                });
+                ("failed", 0)
+            }
+        },
+    };
+    if !progress.locked.is_empty() {
+        let paths: Vec<_> = progress
+            .locked
+            .iter()
+            .map(|path| format!("\n  {:?}", path))
+            .collect();
+        warn!(
+            "{} files were skipped, locked by other processes:{}",
+            progress.locked.len(),
+            paths.concat()
+        );
+        for path in &progress.locked {
+            options.emit(Event::Locked { path: path.clone() });
+        }
+    }
+    options.emit(Event::Summary {
+        status,
+        actions: progress.actions,
+        bytes: progress.bytes,
+        failures,
+        locked: progress.locked.len(),
+    });
+
+    #[cfg(feature = "desktop")]
+    if options.notify_desktop {
+        if let Err(e) =
+            crate::desktop::notify(status, progress.bytes, failures, error)
+        {
+            warn!("Cannot show desktop notification: {}", e);
+        }
+    }
+
+    // the update fails to start without an event log
+    if options.event_log && cfg!(windows) {
+        let (level, id) = match status {
+            "completed" => (Level::Information, eventlog::COMPLETED_ID),
+            "cancelled" => (Level::Warning, eventlog::CANCELLED_ID),
+            _ if failures > 0 => (Level::Warning, eventlog::FAILURES_ID),
+            _ => (Level::Error, eventlog::FAILED_ID),
+        };
+        let mut message = format!(
+            "Backup of {:?} to {:?} {}: {} actions, {} copied, {} failures, \
+             {} locked files",
+            source,
+            dest,
+            status,
+            progress.actions,
+            format_size(progress.bytes),
+            failures,
+            progress.locked.len()
+        );
+        if let Some(e) = error {
+            message.push_str(&format!("\n{}", e));
+        }
+        if let Err(e) = eventlog::write(level, id, &message) {
+            warn!("Cannot write to the event log: {}", e);
+        }
+    }
+
+    if let Some(webhook) = &options.webhook {
+        if !webhook.notifies(error.is_none()) {
+            return;
+        }
+        let path = |p: &Path| Value::from(p.to_string_lossy().into_owned());
+        let summary = json::object(vec![
+            ("status", Value::from(status)),
+            ("source", path(source)),
+            ("dest", path(dest)),
+            ("actions", Value::from(progress.actions)),
+            ("bytes", Value::from(progress.bytes)),
+            ("failures", Value::from(failures)),
+            ("locked", Value::from(progress.locked.len())),
+            ("error", Value::from(error.map(|e| e.to_string()))),
+        ]);
+        match webhook.post(&summary) {
+            Ok(()) => debug!("Webhook notified"),
+            Err(e) => warn!("Cannot notify webhook: {}", e),
+        }
+    }
+}
+
+/// Gets the journal and the remaining actions of an interrupted update of the
+/// destination from the same source, if it must be resumed.
+fn interrupted(
+    source: &Path,
+    dest: &Path,
+    options: &UpdateOptions,
+) -> Option<(Journal, Plan)> {
+    if !options.resume || options.dry_run {
+        return None;
+    }
+    match Journal::resume(dest, options.fsync) {
+        Ok(Some((journal, plan))) if journal.source() == source => {
+            Some((journal, plan))
+        }
+        Ok(Some((journal, _))) => {
+            warn!(
+                "The interrupted update of {:?} was from {:?}, not resuming it",
+                dest,
+                journal.source()
+            );
+            None
+        }
+        Ok(None) => {
+            info!("No interrupted update of {:?} to resume", dest);
+            None
+        }
+        Err(e) => {
+            warn!("Cannot resume the update of {:?}: {}", dest, e);
+            None
+        }
+    }
+}
+
+/// Applies the plan recording every completed action in the given journal,
+/// that is removed once the whole plan was applied, and reporting them.
+fn apply(
+    plan: &Plan,
+    dest: &Path,
+    mut journal: Journal,
+    options: &UpdateOptions,
+    progress: &mut Progress,
+) -> Result<(), BkupError> {
+    let mut audit = if options.audit_log {
+        match AuditLog::create(dest) {
+            Ok(log) => {
+                info!("Recording the actions in {:?}", log.path());
+                Some(log)
+            }
+            Err(e) => {
+                warn!("Cannot create the audit log: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let result =
+        plan.apply_with(&options.copy_options(), |i, action, digest| {
+            let event = Event::applied(action);
+            if let Event::Applied { bytes, .. } = event {
+                progress.bytes += bytes;
+            }
+            progress.actions += 1;
+            options.emit(event);
+            if let Err(e) = journal.record(i) {
+                warn!("Cannot record action in the journal: {}", e);
+            }
+            record(&mut audit, |log| log.applied(action, digest));
+        });
+    // the failed actions were applied in order, and the locked ones skipped
+    let failures = match &result {
+        Ok(locked) => locked.iter().map(|l| ("locked", l)).collect(),
+        Err(BkupError::Failures(failures)) => {
+            failures.0.iter().map(|f| ("failed", f)).collect()
+        }
+        Err(_) => Vec::new(),
+    };
+    for (outcome, failure) in failures {
+        let action = plan.actions().iter().find(|a| a.path() == failure.path);
+        if let Some(action) = action {
+            record(&mut audit, |log| {
+                log.failed(action, outcome, &failure.error)
+            });
+        }
+    }
+    if options.cancel.is_cancelled() {
+        warn!(
+            "Update cancelled after {} of {} actions, run it again with \
+             --resume to complete it",
+            progress.actions,
+            plan.len()
+        );
+        return Err(BkupError::Cancelled);
+    }
+    // the skipped files are still outdated, and copied by the next update
+    progress.locked = result?.into_iter().map(|skip| skip.path).collect();
+    journal.finish()
+}
+
+/// Writes a record to the audit log of the update, if any, only warning if it
+/// cannot be written.
+fn record<F>(audit: &mut Option<AuditLog>, write: F)
+where
+    F: FnOnce(&mut AuditLog) -> Result<(), BkupError>,
+{
+    if let Some(log) = audit {
+        if let Err(e) = write(log) {
+            warn!("Cannot write to the audit log: {}", e);
+        }
+    }
+}
+
+/// Visits the source and destination directories and computes the plan that
+/// brings the destination up to date.
+pub fn plan(
+    source: PathBuf,
+    dest: PathBuf,
+    options: &UpdateOptions,
+    filter: &Filter,
+) -> Result<Plan, BkupError> {
+    // spawn thread used to visit the destination directory
+    let ignore = options.ignore;
+    let filter_at = |root: &Path| {
+        let filter = filter
+            .with_pins(root, &options.pins)
+            .with_rules(root, &options.rules)
+            .with_regexes(root, &options.regexes);
+        if options.one_file_system {
+            filter.on_device_of(root)
+        } else {
+            filter
+        }
+    };
+    let dest_filter = filter_at(&dest);
+    let handle = thread::spawn(move || {
+        info!("Exploring destination directory {:?}", dest);
+        Entry::directory(&dest, ignore, &dest_filter)
+    });
+
+    info!("Exploring source directory {:?}", source);
+    // the destination entries are kept even if their attributes differ, so
+    // that they are not copied again
+    let source_filter = filter_at(&source).with_attributes(&options.attributes);
+    let source = Entry::directory(&source, ignore, &source_filter)?;
+
+    options.emit(Event::scanned(&source));
+
+    let mut dest = handle
+        .join()
+        .expect("Couldn't join on the destination visit thread")?;
+    dest.remove_temp_files(options.partial, options.shred, options.dry_run)?;
+    options.emit(Event::scanned(&dest));
+
+    info!("Computing difference");
+    // the probe file cannot be written by a dry run
+    let skew = if options.detect_skew && !options.dry_run {
+        clock_skew(dest.path(), options.accuracy)?
+    } else {
+        0
+    };
+    let accuracy = Accuracy {
+        compare: options.compare,
+        hash: options.hash,
+        window: options.accuracy,
+        ignore_dst: options.ignore_dst,
+        skew,
+    };
+    source.hash_contents(&dest, &accuracy);
+    let delta = source.cmp(&dest, &accuracy)?;
+    debug!("Delta: {:?}", delta);
+    if options.itemize {
+        let mut items = Vec::new();
+        if let Some(delta) = &delta {
+            delta.itemize(&mut items);
+        }
+        itemize::print(items, dest.path());
+    }
+
+    let mut plan = if options.hard_links {
+        Plan::with_hard_links()
+    } else {
+        Plan::new()
+    };
+    if let Some(delta) = delta {
+        delta.plan(&mut plan);
+    }
+
+    if options.detect_moves && !plan.is_empty() {
+        info!("Detecting moved files");
+        let orphans = dest.missing_files(&source);
+        plan.detect_moves(&orphans, options.hash)?;
+    }
+    plan.order(options.order, &options.priorities, source.path());
+
+    Ok(plan)
+}
+
+/// Checks whether the destination files replaced by the plan exceed the given
+/// threshold, and if so fails unless the given function confirms them.
+fn check_destruction<F>(
+    plan: &Plan,
+    threshold: &ConfirmThreshold,
+    confirm: F,
+) -> Result<(), BkupError>
+where
+    F: FnOnce(&Destruction) -> bool,
+{
+    if threshold.files.is_none() && threshold.bytes.is_none() {
+        return Ok(());
+    }
+    let mut overwrites = plan.overwrites();
+    let bytes = overwrites.iter().map(|(_, size)| size).sum();
+    let exceeded = threshold.files.is_some_and(|max| overwrites.len() > max)
+        || threshold.bytes.is_some_and(|max| bytes > max);
+    if !exceeded {
+        return Ok(());
+    }
+
+    overwrites.sort_by(|(_, s1), (_, s2)| s2.cmp(s1));
+    let destruction = Destruction {
+        files: overwrites.len(),
+        bytes,
+        largest: overwrites
+            .iter()
+            .take(LARGEST_DESTRUCTIONS)
+            .map(|(path, size)| (path.to_path_buf(), *size))
+            .collect(),
+    };
+    if confirm(&destruction) {
+        Ok(())
+    } else {
+        Err(BkupError::Aborted(format!(
+            "Update aborted: {} destination files ({}) would be replaced",
+            destruction.files,
+            format_size(destruction.bytes)
+        )))
+    }
+}
+
+/// Checks whether the destination filesystem has enough free space for the
+/// bytes written by the plan (net of the replaced files), and fails if it
+/// doesn't unless forced.
+fn check_free_space(
+    plan: &Plan,
+    dest: &Path,
+    force: bool,
+) -> Result<(), BkupError> {
+    let replaced: u64 = plan.overwrites().iter().map(|(_, size)| size).sum();
+    let needed = plan.bytes().saturating_sub(replaced);
+    let available = match device::free_space(dest) {
+        Ok(available) => available,
+        Err(e) => {
+            debug!("Cannot get the free space of {:?}: {}", dest, e);
+            return Ok(());
+        }
+    };
+    if needed <= available {
+        return Ok(());
+    }
+    let message = format!(
+        "The update needs {} but only {} are available in {:?}",
+        format_size(needed),
+        format_size(available),
+        dest
+    );
+    if force {
+        warn!("{}", message);
+        Ok(())
+    } else {
+        Err(BkupError::Aborted(message.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::plan::Action;
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_confirm_threshold() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(&source).expect("Cannot create dir");
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+        fs::write(dest.join("file"), "old").expect("Cannot write file");
+        thread::sleep(Duration::from_millis(20));
+        fs::write(source.join("file"), "new").expect("Cannot write file");
+
+        let options = UpdateOptions {
+            confirm: ConfirmThreshold {
+                files: Some(0),
+                bytes: None,
+            },
+            ..UpdateOptions::default()
+        };
+        // the overwrite exceeds the threshold and is not confirmed
+        let result = update_with(source.clone(), dest.clone(), &options);
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(dest.join("file")).unwrap(), "old");
+
+        let mut confirmed = None;
+        update_with_confirm(source, dest.clone(), &options, |destruction| {
+            confirmed = Some((destruction.files, destruction.bytes));
+            true
+        })
+        .expect("Cannot update");
+        assert_eq!(confirmed, Some((1, 3)));
+        assert_eq!(fs::read_to_string(dest.join("file")).unwrap(), "new");
+    }
+
+    #[test]
+    fn test_cancellation_token() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(&source).expect("Cannot create dir");
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+        fs::write(source.join("file"), "content").expect("Cannot write file");
+
+        let token = CancellationToken::new();
+        let options = UpdateOptions::new().cancel(token.clone());
+        token.cancel();
+        let result = update_with(source.clone(), dest.clone(), &options);
+        assert!(matches!(result, Err(BkupError::Cancelled)));
+        assert!(!dest.join("file").exists());
+
+        // the token only cancels the updates that use it
+        let applied = update_with(source, dest.clone(), &UpdateOptions::new())
+            .expect("Cannot update");
+        assert_eq!(applied, 1);
+        assert!(dest.join("file").exists());
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(&source).expect("Cannot create dir");
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+        fs::write(source.join("file"), "content").expect("Cannot write file");
+        let temp = dest.join("file.bkup-tmp");
+        fs::write(&temp, "partial").expect("Cannot write file");
+
+        let options = UpdateOptions::new()
+            .dry_run(true)
+            .shred(true)
+            .detect_skew(true);
+        let applied =
+            update_with(source, dest.clone(), &options).expect("Cannot update");
+        assert_eq!(applied, 0);
+        // neither the lock nor the probe were written, and the leftover
+        // temporary file was kept
+        let names: Vec<_> = fs::read_dir(&dest)
+            .expect("Cannot read dir")
+            .map(|entry| entry.expect("Cannot read entry").file_name())
+            .collect();
+        assert_eq!(names, vec!["file.bkup-tmp"]);
+        assert_eq!(fs::read_to_string(&temp).unwrap(), "partial");
+
+        fs::remove_dir_all(root).expect("Cannot remove dir");
+    }
+
+    #[test]
+    fn test_metadata_files() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        let parity = dest.join(".bkup").join("parity");
+        fs::create_dir_all(&source).expect("Cannot create dir");
+        fs::create_dir_all(&parity).expect("Cannot create dir");
+        fs::write(source.join("file"), "content").expect("Cannot write file");
+        // the files of bkup have the content of the new file, so they would
+        // be renamed into it if they were compared
+        fs::write(dest.join(".bkup").join("manifest"), "content")
+            .expect("Cannot write file");
+        fs::write(parity.join("file"), "content").expect("Cannot write file");
+
+        let options = UpdateOptions::new().detect_moves(true);
+        let applied =
+            update_with(source, dest.clone(), &options).expect("Cannot update");
+        assert_eq!(applied, 1);
+        assert_eq!(fs::read_to_string(dest.join("file")).unwrap(), "content");
+        assert_eq!(
+            fs::read_to_string(dest.join(".bkup").join("manifest")).unwrap(),
+            "content"
+        );
+        assert_eq!(fs::read_to_string(parity.join("file")).unwrap(), "content");
+
+        fs::remove_dir_all(root).expect("Cannot remove dir");
+    }
+
+    #[test]
+    fn test_attribute_filter() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(source.join("dir")).expect("Cannot create dir");
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+        fs::write(source.join("dir").join("small"), "1").expect("Cannot write");
+        fs::write(source.join("large"), "123456").expect("Cannot write");
+
+        let attributes = AttributeFilter {
+            max_size: Some(4),
+            ..AttributeFilter::default()
+        };
+        let options = UpdateOptions::new().attributes(attributes);
+        update_with(source, dest.clone(), &options).expect("Cannot update");
+        assert!(dest.join("dir").join("small").exists());
+        assert!(!dest.join("large").exists());
+    }
+
+    #[test]
+    fn test_check_free_space() {
+        let dest = env::temp_dir();
+        let mut plan = Plan::new();
+        assert!(check_free_space(&plan, &dest, false).is_ok());
+        plan.push(Action::Copy {
+            source: dest.join("source"),
+            dest: dest.join("dest"),
+            size: u64::MAX,
+            overwrite: false,
+        });
+        assert!(check_free_space(&plan, &dest, false).is_err());
+        assert!(check_free_space(&plan, &dest, true).is_ok());
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let dest = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&dest).expect("Cannot create dir");
+        let skew = clock_skew(&dest, Duration::from_secs(1))
+            .expect("Cannot measure the skew");
+        assert!(
+            Duration::from_nanos(skew.unsigned_abs()) < Duration::from_secs(1)
+        );
+        // the probe is removed from the bkup directory
+        let bkup = dest.join(".bkup");
+        assert_eq!(fs::read_dir(bkup).expect("Cannot read dir").count(), 0);
+        assert!(clock_skew(&dest.join("missing"), Duration::ZERO).is_err());
+    }
+}
@@ -0,0 +1,466 @@
+//! Copy engine based on io_uring, that keeps multiple reads and writes in
+//! flight so that fast devices are not bottlenecked by synchronous I/O.
+
+use crate::rate::RateLimiter;
+use std::{
+    fs::File,
+    io, mem,
+    os::unix::io::{AsRawFd, RawFd},
+    path::Path,
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// Number of blocks that can be read or written at the same time.
+const QUEUE_DEPTH: usize = 8;
+/// Size of each block read from the source and written to the destination.
+const BLOCK_SIZE: usize = 256 * 1024;
+
+// system calls and constants from linux/io_uring.h
+const SYS_IO_URING_SETUP: libc::c_long = 425;
+const SYS_IO_URING_ENTER: libc::c_long = 426;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x0800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+/// Submission queue entry.
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad: [u64; 2],
+}
+
+/// Completion queue entry.
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// Memory region shared with the kernel.
+struct Mmap {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    /// Maps the region of the ring with the given offset.
+    fn new(fd: RawFd, offset: libc::off_t, len: usize) -> io::Result<Self> {
+        // safe since the kernel validates the file descriptor and offset
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Mmap { ptr, len })
+        }
+    }
+
+    /// Gets a pointer at the given byte offset of the region.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        debug_assert!((offset as usize) < self.len);
+        // safe since the offsets are provided by the kernel
+        unsafe { (self.ptr as *mut u8).add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        // safe since the region was mapped with the same length
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Represents an io_uring instance with its submission and completion queues.
+struct Ring {
+    fd: RawFd,
+    sq: Mmap,
+    cq: Mmap,
+    sqes: Mmap,
+    params: Params,
+    // number of entries pushed but not yet submitted
+    pending: u32,
+}
+
+impl Ring {
+    /// Creates a new ring with the given number of entries.
+    fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        // safe since params outlives the call
+        let fd = unsafe {
+            libc::syscall(SYS_IO_URING_SETUP, entries, &mut params as *mut _)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+        let map = || -> io::Result<(Mmap, Mmap, Mmap)> {
+            let sq_len = params.sq_off.array as usize
+                + params.sq_entries as usize * mem::size_of::<u32>();
+            let cq_len = params.cq_off.cqes as usize
+                + params.cq_entries as usize * mem::size_of::<Cqe>();
+            let sqes_len = params.sq_entries as usize * mem::size_of::<Sqe>();
+            Ok((
+                Mmap::new(fd, IORING_OFF_SQ_RING, sq_len)?,
+                Mmap::new(fd, IORING_OFF_CQ_RING, cq_len)?,
+                Mmap::new(fd, IORING_OFF_SQES, sqes_len)?,
+            ))
+        };
+        match map() {
+            Ok((sq, cq, sqes)) => Ok(Ring {
+                fd,
+                sq,
+                cq,
+                sqes,
+                params,
+                pending: 0,
+            }),
+            Err(e) => {
+                // safe since the file descriptor is owned
+                unsafe { libc::close(fd) };
+                Err(e)
+            }
+        }
+    }
+
+    /// Pushes a read or write operation into the submission queue.
+    fn push(
+        &mut self,
+        opcode: u8,
+        fd: RawFd,
+        buf: *mut u8,
+        len: usize,
+        offset: u64,
+        user_data: u64,
+    ) {
+        let off = &self.params.sq_off;
+        let head: &AtomicU32 = unsafe { &*self.sq.at(off.head) };
+        let tail: &AtomicU32 = unsafe { &*self.sq.at(off.tail) };
+        let mask = unsafe { *self.sq.at::<u32>(off.ring_mask) };
+        let t = tail.load(Ordering::Relaxed);
+        assert!(
+            t.wrapping_sub(head.load(Ordering::Acquire))
+                < self.params.sq_entries,
+            "The submission queue is full"
+        );
+        let index = t & mask;
+        // safe since the index is within the entries mapped by the kernel
+        unsafe {
+            let sqe = self.sqes.at::<Sqe>(0).add(index as usize);
+            ptr::write(
+                sqe,
+                Sqe {
+                    opcode,
+                    flags: 0,
+                    ioprio: 0,
+                    fd,
+                    off: offset,
+                    addr: buf as u64,
+                    len: len as u32,
+                    rw_flags: 0,
+                    user_data,
+                    buf_index: 0,
+                    personality: 0,
+                    splice_fd_in: 0,
+                    pad: [0; 2],
+                },
+            );
+            *self.sq.at::<u32>(off.array).add(index as usize) = index;
+        }
+        tail.store(t.wrapping_add(1), Ordering::Release);
+        self.pending += 1;
+    }
+
+    /// Submits the pending operations and waits for at least one completion.
+    fn submit_and_wait(&mut self) -> io::Result<()> {
+        loop {
+            // safe since the ring file descriptor is valid
+            let ret = unsafe {
+                libc::syscall(
+                    SYS_IO_URING_ENTER,
+                    self.fd,
+                    self.pending,
+                    1u32,
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<libc::sigset_t>(),
+                    0usize,
+                )
+            };
+            if ret >= 0 {
+                self.pending -= ret as u32;
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    /// Pops the next completion, if any, as a pair of user data and result.
+    fn pop(&mut self) -> Option<(u64, i32)> {
+        let off = &self.params.cq_off;
+        let head: &AtomicU32 = unsafe { &*self.cq.at(off.head) };
+        let tail: &AtomicU32 = unsafe { &*self.cq.at(off.tail) };
+        let mask = unsafe { *self.cq.at::<u32>(off.ring_mask) };
+        let h = head.load(Ordering::Relaxed);
+        if h == tail.load(Ordering::Acquire) {
+            return None;
+        }
+        // safe since the index is within the entries mapped by the kernel
+        let cqe =
+            unsafe { &*self.cq.at::<Cqe>(off.cqes).add((h & mask) as usize) };
+        let completion = (cqe.user_data, cqe.res);
+        head.store(h.wrapping_add(1), Ordering::Release);
+        Some(completion)
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // safe since the file descriptor is owned
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// State of each block buffer.
+#[derive(Clone, Copy)]
+enum Slot {
+    Idle,
+    // the block at the given offset is being read, `done` bytes so far
+    Reading {
+        offset: u64,
+        len: usize,
+        done: usize,
+    },
+    // the block at the given offset is being written, `done` bytes so far
+    Writing {
+        offset: u64,
+        len: usize,
+        done: usize,
+    },
+}
+
+/// Copies the source file into the destination path, overwriting it if it
+/// already exists, and returns the number of bytes copied. If a rate limiter
+/// is given, each block is read only once its bytes are available.
+pub fn copy(
+    source: &Path,
+    dest: &Path,
+    limiter: Option<&RateLimiter>,
+) -> io::Result<u64> {
+    let src = File::open(source)?;
+    let metadata = src.metadata()?;
+    let dst = File::create(dest)?;
+    let len = metadata.len();
+
+    let mut ring = Ring::new(QUEUE_DEPTH as u32)?;
+    let mut buffers = vec![vec![0u8; BLOCK_SIZE]; QUEUE_DEPTH];
+    let mut slots = [Slot::Idle; QUEUE_DEPTH];
+    let mut next_offset = 0;
+    let mut in_flight = 0;
+    let mut error = None;
+
+    loop {
+        // keep every idle buffer busy reading the next block
+        if error.is_none() {
+            for (i, slot) in slots.iter_mut().enumerate() {
+                if next_offset >= len {
+                    break;
+                }
+                if let Slot::Idle = slot {
+                    let n = (len - next_offset).min(BLOCK_SIZE as u64) as usize;
+                    if let Some(limiter) = limiter {
+                        limiter.acquire(n as u64);
+                    }
+                    let buf = buffers[i].as_mut_ptr();
+                    let fd = src.as_raw_fd();
+                    ring.push(
+                        IORING_OP_READ,
+                        fd,
+                        buf,
+                        n,
+                        next_offset,
+                        i as u64,
+                    );
+                    *slot = Slot::Reading {
+                        offset: next_offset,
+                        len: n,
+                        done: 0,
+                    };
+                    next_offset += n as u64;
+                    in_flight += 1;
+                }
+            }
+        }
+        if in_flight == 0 {
+            break;
+        }
+
+        if let Err(e) = ring.submit_and_wait() {
+            // the buffers cannot be released while the kernel may use them
+            if in_flight == QUEUE_DEPTH || error.is_some() {
+                return Err(e);
+            }
+            error = Some(e);
+            continue;
+        }
+
+        while let Some((i, res)) = ring.pop() {
+            let i = i as usize;
+            in_flight -= 1;
+            let next = match (res, slots[i]) {
+                (res, _) if res < 0 => {
+                    error.get_or_insert(io::Error::from_raw_os_error(-res));
+                    Slot::Idle
+                }
+                (0, Slot::Reading { .. }) => {
+                    error.get_or_insert(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "The source file was truncated during the copy",
+                    ));
+                    Slot::Idle
+                }
+                (res, Slot::Reading { offset, len, done }) => {
+                    let done = done + res as usize;
+                    if done < len {
+                        Slot::Reading { offset, len, done }
+                    } else {
+                        Slot::Writing {
+                            offset,
+                            len,
+                            done: 0,
+                        }
+                    }
+                }
+                (res, Slot::Writing { offset, len, done }) => {
+                    let done = done + res as usize;
+                    if done < len {
+                        Slot::Writing { offset, len, done }
+                    } else {
+                        Slot::Idle
+                    }
+                }
+                (_, Slot::Idle) => unreachable!("Completion of an idle buffer"),
+            };
+            slots[i] = next;
+            if error.is_some() {
+                slots[i] = Slot::Idle;
+                continue;
+            }
+            // submit the remaining part of the block operation
+            let (opcode, fd, offset, len, done) = match next {
+                Slot::Reading { offset, len, done } => {
+                    (IORING_OP_READ, src.as_raw_fd(), offset, len, done)
+                }
+                Slot::Writing { offset, len, done } => {
+                    (IORING_OP_WRITE, dst.as_raw_fd(), offset, len, done)
+                }
+                Slot::Idle => continue,
+            };
+            // safe since done is always less than the buffer length
+            let buf = unsafe { buffers[i].as_mut_ptr().add(done) };
+            let offset = offset + done as u64;
+            ring.push(opcode, fd, buf, len - done, offset, i as u64);
+            in_flight += 1;
+        }
+    }
+
+    if let Some(e) = error {
+        return Err(e);
+    }
+    dst.set_permissions(metadata.permissions())?;
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::{env, fs};
+    use uuid::Uuid;
+
+    #[test]
+    fn test_copy() {
+        let root = env::temp_dir().join(Uuid::new_v4().to_simple().to_string());
+        fs::create_dir_all(&root).expect("Cannot create dir");
+        // content spanning more blocks than buffers, with a partial last block
+        let content: Vec<u8> = (0..BLOCK_SIZE * QUEUE_DEPTH * 2 + 123)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        for (name, content) in &[("empty", &content[..0]), ("big", &content)] {
+            let source = root.join(name);
+            let dest = root.join(format!("{}.copy", name));
+            fs::write(&source, content).expect("Cannot write file");
+            let copied = copy(&source, &dest, None).expect("Cannot copy file");
+            assert_eq!(copied, content.len() as u64);
+            assert_eq!(&fs::read(&dest).unwrap()[..], *content);
+        }
+    }
+}